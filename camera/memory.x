@@ -2,4 +2,24 @@ MEMORY
 {
   FLASH  : ORIGIN = 0x08000000, LENGTH = 2M
   RAM : ORIGIN = 0x24000000, LENGTH = 512K
+  /* D2 domain SRAMs, reachable by DMA1 and DMA2, clocks enabled by the binaries.
+     SRAM1 and SRAM2 are contiguous and used as a single region. */
+  SRAM12 : ORIGIN = 0x30000000, LENGTH = 256K
+  SRAM3 : ORIGIN = 0x30040000, LENGTH = 32K
 }
+
+/* Not initialised by the runtime */
+SECTIONS
+{
+  .sram12 (NOLOAD) : ALIGN(4)
+  {
+    *(.sram12 .sram12.*);
+    . = ALIGN(4);
+  } > SRAM12
+
+  .sram3 (NOLOAD) : ALIGN(4)
+  {
+    *(.sram3 .sram3.*);
+    . = ALIGN(4);
+  } > SRAM3
+} INSERT AFTER .bss;
@@ -1,5 +1,5 @@
 extern crate alloc;
-use std::{io::{Read, Write}, net::TcpListener};
+use std::{io::{Read, Write}, net::{TcpListener, TcpStream}, thread, time::{Duration, Instant}};
 
 // use nokhwa::Camera;
 // use nokhwa::utils::{CameraIndex, RequestedFormat, RequestedFormatType};
@@ -7,324 +7,112 @@ use std::{io::{Read, Write}, net::TcpListener};
 // use nokhwa::query;
 // use opencv;
 use rscam::{Camera, Config};
-
-
-
-const IMAGE_HEADER: [u8; 1078] = [
-  // BMP header : 14 bytes
-  0x42, 0x4D,              // BM
-  0x36, 0x30, 0x01, 0x00,  // Size : 77878 bytes
-  0x00, 0x00, 0x00, 0x00,  // Application
-  0x36, 0x04, 0x00, 0x00,  // Offset : 1078 bytes
-
-  // DIB Header : 40 bytes
-  0x28, 0x00, 0x00, 0x00,  // DIB Header size : 40 bytes
-  0x40, 0x01, 0x00, 0x00,  // Image width : 320 px
-  0xF0, 0x00, 0x00, 0x00,  // Image height : 240 px
-  0x01, 0x00,              // Number of planes (1)
-  0x08, 0x00,              // Bits per pixel : 8 bits
-  0x00, 0x00, 0x00, 0x00,  // Compression method : 0 (NONE)
-  0x00, 0x2C, 0x01, 0x00,  // Raw Bitmap size : 76800 bytes
-  0xC4, 0x0E, 0x00, 0x00,  // Horizontal resolution : 3780 px/m
-  0xC4, 0x0E, 0x00, 0x00,  // Vertical resolution : 3780 px/m
-  0x00, 0x00, 0x00, 0x00,  // Colors in palette : 0 (256)
-  0x00, 0x00, 0x00, 0x00,  // Importants colors : 0
-
-  // Color Table : 256 * 4 = 1024 bytes
-  0, 0, 0, 255,        //   0
-  1, 1, 1, 255,        //   1
-  2, 2, 2, 255,        //   2
-  3, 3, 3, 255,        //   3
-  4, 4, 4, 255,        //   4
-  5, 5, 5, 255,        //   5
-  6, 6, 6, 255,        //   6
-  7, 7, 7, 255,        //   7
-  8, 8, 8, 255,        //   8
-  9, 9, 9, 255,        //   9
-  10, 10, 10, 255,     //  10
-  11, 11, 11, 255,     //  11
-  12, 12, 12, 255,     //  12
-  13, 13, 13, 255,     //  13
-  14, 14, 14, 255,     //  14
-  15, 15, 15, 255,     //  15
-  16, 16, 16, 255,     //  16
-  17, 17, 17, 255,     //  17
-  18, 18, 18, 255,     //  18
-  19, 19, 19, 255,     //  19
-  20, 20, 20, 255,     //  20
-  21, 21, 21, 255,     //  21
-  22, 22, 22, 255,     //  22
-  23, 23, 23, 255,     //  23
-  24, 24, 24, 255,     //  24
-  25, 25, 25, 255,     //  25
-  26, 26, 26, 255,     //  26
-  27, 27, 27, 255,     //  27
-  28, 28, 28, 255,     //  28
-  29, 29, 29, 255,     //  29
-  30, 30, 30, 255,     //  30
-  31, 31, 31, 255,     //  31
-  32, 32, 32, 255,     //  32
-  33, 33, 33, 255,     //  33
-  34, 34, 34, 255,     //  34
-  35, 35, 35, 255,     //  35
-  36, 36, 36, 255,     //  36
-  37, 37, 37, 255,     //  37
-  38, 38, 38, 255,     //  38
-  39, 39, 39, 255,     //  39
-  40, 40, 40, 255,     //  40
-  41, 41, 41, 255,     //  41
-  42, 42, 42, 255,     //  42
-  43, 43, 43, 255,     //  43
-  44, 44, 44, 255,     //  44
-  45, 45, 45, 255,     //  45
-  46, 46, 46, 255,     //  46
-  47, 47, 47, 255,     //  47
-  48, 48, 48, 255,     //  48
-  49, 49, 49, 255,     //  49
-  50, 50, 50, 255,     //  50
-  51, 51, 51, 255,     //  51
-  52, 52, 52, 255,     //  52
-  53, 53, 53, 255,     //  53
-  54, 54, 54, 255,     //  54
-  55, 55, 55, 255,     //  55
-  56, 56, 56, 255,     //  56
-  57, 57, 57, 255,     //  57
-  58, 58, 58, 255,     //  58
-  59, 59, 59, 255,     //  59
-  60, 60, 60, 255,     //  60
-  61, 61, 61, 255,     //  61
-  62, 62, 62, 255,     //  62
-  63, 63, 63, 255,     //  63
-  64, 64, 64, 255,     //  64
-  65, 65, 65, 255,     //  65
-  66, 66, 66, 255,     //  66
-  67, 67, 67, 255,     //  67
-  68, 68, 68, 255,     //  68
-  69, 69, 69, 255,     //  69
-  70, 70, 70, 255,     //  70
-  71, 71, 71, 255,     //  71
-  72, 72, 72, 255,     //  72
-  73, 73, 73, 255,     //  73
-  74, 74, 74, 255,     //  74
-  75, 75, 75, 255,     //  75
-  76, 76, 76, 255,     //  76
-  77, 77, 77, 255,     //  77
-  78, 78, 78, 255,     //  78
-  79, 79, 79, 255,     //  79
-  80, 80, 80, 255,     //  80
-  81, 81, 81, 255,     //  81
-  82, 82, 82, 255,     //  82
-  83, 83, 83, 255,     //  83
-  84, 84, 84, 255,     //  84
-  85, 85, 85, 255,     //  85
-  86, 86, 86, 255,     //  86
-  87, 87, 87, 255,     //  87
-  88, 88, 88, 255,     //  88
-  89, 89, 89, 255,     //  89
-  90, 90, 90, 255,     //  90
-  91, 91, 91, 255,     //  91
-  92, 92, 92, 255,     //  92
-  93, 93, 93, 255,     //  93
-  94, 94, 94, 255,     //  94
-  95, 95, 95, 255,     //  95
-  96, 96, 96, 255,     //  96
-  97, 97, 97, 255,     //  97
-  98, 98, 98, 255,     //  98
-  99, 99, 99, 255,     //  99
-  100, 100, 100, 255,  // 100
-  101, 101, 101, 255,  // 101
-  102, 102, 102, 255,  // 102
-  103, 103, 103, 255,  // 103
-  104, 104, 104, 255,  // 104
-  105, 105, 105, 255,  // 105
-  106, 106, 106, 255,  // 106
-  107, 107, 107, 255,  // 107
-  108, 108, 108, 255,  // 108
-  109, 109, 109, 255,  // 109
-  110, 110, 110, 255,  // 110
-  111, 111, 111, 255,  // 111
-  112, 112, 112, 255,  // 112
-  113, 113, 113, 255,  // 113
-  114, 114, 114, 255,  // 114
-  115, 115, 115, 255,  // 115
-  116, 116, 116, 255,  // 116
-  117, 117, 117, 255,  // 117
-  118, 118, 118, 255,  // 118
-  119, 119, 119, 255,  // 119
-  120, 120, 120, 255,  // 120
-  121, 121, 121, 255,  // 121
-  122, 122, 122, 255,  // 122
-  123, 123, 123, 255,  // 123
-  124, 124, 124, 255,  // 124
-  125, 125, 125, 255,  // 125
-  126, 126, 126, 255,  // 126
-  127, 127, 127, 255,  // 127
-  128, 128, 128, 255,  // 128
-  129, 129, 129, 255,  // 129
-  130, 130, 130, 255,  // 130
-  131, 131, 131, 255,  // 131
-  132, 132, 132, 255,  // 132
-  133, 133, 133, 255,  // 133
-  134, 134, 134, 255,  // 134
-  135, 135, 135, 255,  // 135
-  136, 136, 136, 255,  // 136
-  137, 137, 137, 255,  // 137
-  138, 138, 138, 255,  // 138
-  139, 139, 139, 255,  // 139
-  140, 140, 140, 255,  // 140
-  141, 141, 141, 255,  // 141
-  142, 142, 142, 255,  // 142
-  143, 143, 143, 255,  // 143
-  144, 144, 144, 255,  // 144
-  145, 145, 145, 255,  // 145
-  146, 146, 146, 255,  // 146
-  147, 147, 147, 255,  // 147
-  148, 148, 148, 255,  // 148
-  149, 149, 149, 255,  // 149
-  150, 150, 150, 255,  // 150
-  151, 151, 151, 255,  // 151
-  152, 152, 152, 255,  // 152
-  153, 153, 153, 255,  // 153
-  154, 154, 154, 255,  // 154
-  155, 155, 155, 255,  // 155
-  156, 156, 156, 255,  // 156
-  157, 157, 157, 255,  // 157
-  158, 158, 158, 255,  // 158
-  159, 159, 159, 255,  // 159
-  160, 160, 160, 255,  // 160
-  161, 161, 161, 255,  // 161
-  162, 162, 162, 255,  // 162
-  163, 163, 163, 255,  // 163
-  164, 164, 164, 255,  // 164
-  165, 165, 165, 255,  // 165
-  166, 166, 166, 255,  // 166
-  167, 167, 167, 255,  // 167
-  168, 168, 168, 255,  // 168
-  169, 169, 169, 255,  // 169
-  170, 170, 170, 255,  // 170
-  171, 171, 171, 255,  // 171
-  172, 172, 172, 255,  // 172
-  173, 173, 173, 255,  // 173
-  174, 174, 174, 255,  // 174
-  175, 175, 175, 255,  // 175
-  176, 176, 176, 255,  // 176
-  177, 177, 177, 255,  // 177
-  178, 178, 178, 255,  // 178
-  179, 179, 179, 255,  // 179
-  180, 180, 180, 255,  // 180
-  181, 181, 181, 255,  // 181
-  182, 182, 182, 255,  // 182
-  183, 183, 183, 255,  // 183
-  184, 184, 184, 255,  // 184
-  185, 185, 185, 255,  // 185
-  186, 186, 186, 255,  // 186
-  187, 187, 187, 255,  // 187
-  188, 188, 188, 255,  // 188
-  189, 189, 189, 255,  // 189
-  190, 190, 190, 255,  // 190
-  191, 191, 191, 255,  // 191
-  192, 192, 192, 255,  // 192
-  193, 193, 193, 255,  // 193
-  194, 194, 194, 255,  // 194
-  195, 195, 195, 255,  // 195
-  196, 196, 196, 255,  // 196
-  197, 197, 197, 255,  // 197
-  198, 198, 198, 255,  // 198
-  199, 199, 199, 255,  // 199
-  200, 200, 200, 255,  // 200
-  201, 201, 201, 255,  // 201
-  202, 202, 202, 255,  // 202
-  203, 203, 203, 255,  // 203
-  204, 204, 204, 255,  // 204
-  205, 205, 205, 255,  // 205
-  206, 206, 206, 255,  // 206
-  207, 207, 207, 255,  // 207
-  208, 208, 208, 255,  // 208
-  209, 209, 209, 255,  // 209
-  210, 210, 210, 255,  // 210
-  211, 211, 211, 255,  // 211
-  212, 212, 212, 255,  // 212
-  213, 213, 213, 255,  // 213
-  214, 214, 214, 255,  // 214
-  215, 215, 215, 255,  // 215
-  216, 216, 216, 255,  // 216
-  217, 217, 217, 255,  // 217
-  218, 218, 218, 255,  // 218
-  219, 219, 219, 255,  // 219
-  220, 220, 220, 255,  // 220
-  221, 221, 221, 255,  // 221
-  222, 222, 222, 255,  // 222
-  223, 223, 223, 255,  // 223
-  224, 224, 224, 255,  // 224
-  225, 225, 225, 255,  // 225
-  226, 226, 226, 255,  // 226
-  227, 227, 227, 255,  // 227
-  228, 228, 228, 255,  // 228
-  229, 229, 229, 255,  // 229
-  230, 230, 230, 255,  // 230
-  231, 231, 231, 255,  // 231
-  232, 232, 232, 255,  // 232
-  233, 233, 233, 255,  // 233
-  234, 234, 234, 255,  // 234
-  235, 235, 235, 255,  // 235
-  236, 236, 236, 255,  // 236
-  237, 237, 237, 255,  // 237
-  238, 238, 238, 255,  // 238
-  239, 239, 239, 255,  // 239
-  240, 240, 240, 255,  // 240
-  241, 241, 241, 255,  // 241
-  242, 242, 242, 255,  // 242
-  243, 243, 243, 255,  // 243
-  244, 244, 244, 255,  // 244
-  245, 245, 245, 255,  // 245
-  246, 246, 246, 255,  // 246
-  247, 247, 247, 255,  // 247
-  248, 248, 248, 255,  // 248
-  249, 249, 249, 255,  // 249
-  250, 250, 250, 255,  // 250
-  251, 251, 251, 255,  // 251
-  252, 252, 252, 255,  // 252
-  253, 253, 253, 255,  // 253
-  254, 254, 254, 255,  // 254
-  255, 255, 255, 255,  // 255
-];
-
-
-fn rgb565_to_gray(pixel: u16) -> u8 {
-    let r = (pixel >> 11) & 0x1F;
-    let g = (pixel >> 5) & 0x3F;
-    let b = pixel & 0x1F;
-
-    // Mise à l’échelle intégrée
-    let y =
-        77 * (r * 255 / 31) +
-            150 * (g * 255 / 63) +
-            29 * (b * 255 / 31);
-
-    (y >> 8) as u8
+use stm32h755zi::bmp;
+use stm32h755zi::http::{self, Connection, Parsed, Part, Request, Response, Status};
+use stm32h755zi::image::{AnyEncoder, Color, Encoder, ImageFormat, Input, Layout, Options};
+use stm32h755zi::ov2640::Mounting;
+
+const WIDTH: u32 = 320;
+const HEIGHT: u32 = 240;
+
+/// Frame rate of the streams without an `fps` parameter, like the firmware.
+const STREAM_FPS: u32 = 10;
+const MAX_STREAM_FPS: u32 = 30;
+
+/// Order of the rows of the BMP frames.
+const ORIENTATION: bmp::Orientation = bmp::Orientation::TopDown;
+
+/// Reorders the rows and pixels of an RGB3 frame to read them in the
+/// direction of `mounting`.
+fn orient(frame: &[u8], mounting: Mounting) -> Vec<u8> {
+    let mut pixels = Vec::with_capacity((WIDTH * HEIGHT * 3) as usize);
+    for y in 0..HEIGHT {
+        let y = if mounting.flip { HEIGHT - 1 - y } else { y };
+        for x in 0..WIDTH {
+            let x = if mounting.mirror { WIDTH - 1 - x } else { x };
+            let i = ((y * WIDTH + x) * 3) as usize;
+            pixels.extend_from_slice(&frame[i..i + 3]);
+        }
+    }
+    pixels
 }
 
-fn rgb888_to_gray(pixel: u32) -> u8 {
-    let r = (pixel >> 16) & 0xFF;
-    let g = (pixel >> 8) & 0xFF;
-    let b = pixel & 0xFF;
+/// Reads the format and colours of the frame asked by a request, from its
+/// target or else its `Accept` header, BMP and grayscale by default like the
+/// firmware.
+fn requested_image(request: &Request) -> (ImageFormat, Color) {
+    let accepted = request.header("accept").and_then(ImageFormat::from_accept);
+    let format = ImageFormat::from_target(request.target).or(accepted).unwrap_or(ImageFormat::Bmp);
+    let color = bmp::Format::from_target(request.target).unwrap_or(bmp::Format::Gray8).into();
+    (format, color)
+}
 
-    // Mise à l’échelle intégrée
-    let y =
-        77 * (r * 255 / 255) +
-            150 * (g * 255 / 255) +
-            29 * (b * 255 / 255);
+/// Encodes a frame from the top row in `format` and `color`.
+fn encode(frame: &[u8], format: ImageFormat, color: Color) -> (&'static str, Vec<u8>) {
+    let layout = Layout::new(WIDTH as u16, HEIGHT as u16, Input::Rgb888, color);
+    let options = Options { orientation: ORIENTATION, ..Options::default() };
+    let mut encoder = AnyEncoder::new(format, layout, &options);
+    let mut image = vec![0u8; encoder.max_length()];
+    encoder.start(&mut image).expect("Buffer sized for the frame");
+    encoder.write_rows(frame, &mut image).expect("Buffer sized for the frame");
+    let length = encoder.finish(&mut image).expect("Buffer sized for the frame");
+    image.truncate(length);
+    (encoder.content_type(), image)
+}
 
-    (y >> 8) as u8
+/// Writes a response with its whole `body`.
+fn respond(stream: &mut TcpStream, response: &Response, body: &[u8]) {
+    match write!(stream, "{}", response).and(stream.write_all(body)) {
+        Ok (_v) => {}
+        Err (_e) => {println!("Warning: Could not write to socket")}
+    }
+    match stream.flush() {
+        Ok (_v) => {}
+        Err (_e) => {println!("Warning: Could not flush socket")}
+    }
 }
 
+/// Pushes frames to the client of `/stream` until it leaves, JPEG unless the
+/// path asks for BMP.
+fn stream_frames(stream: &mut TcpStream, camera: &Camera, mounting: Mounting, request: &Request) {
+    let fps = http::parameters(request.query.unwrap_or(""))
+        .find(|&(key, _)| key == "fps")
+        .and_then(|(_, value)| value.parse().ok())
+        .filter(|fps| (1..=MAX_STREAM_FPS).contains(fps))
+        .unwrap_or(STREAM_FPS);
+    let format = match ImageFormat::from_target(request.path) {
+        Some(ImageFormat::Bmp) => ImageFormat::Bmp,
+        _ => ImageFormat::Jpeg,
+    };
+    let color = bmp::Format::from_target(request.target).unwrap_or(bmp::Format::Gray8).into();
+    let interval = Duration::from_secs(1) / fps;
+    println!("Streaming at {} fps", fps);
+
+    let response = Response { content_length: None, ..Response::new(Status::Ok, http::STREAM_CONTENT_TYPE, 0) };
+    if write!(stream, "{}", response).is_err() {
+        return;
+    }
+    let mut next = Instant::now();
+    loop {
+        let frame = camera.capture().expect("Could not capture from the webcam");
+        let (content_type, image) = encode(&orient(&frame, mounting), format, color);
+        let part = Part { content_type, content_length: image.len() };
+        if write!(stream, "{}", part).and(stream.write_all(&image)).and(stream.flush()).is_err() {
+            println!("Stream closed");
+            return;
+        }
+        // Late frames delay the next ones rather than bunching up
+        next = (next + interval).max(Instant::now());
+        thread::sleep(next.saturating_duration_since(Instant::now()));
+    }
+}
 
 fn open_webcam() -> Camera {
     let mut camera = Camera::new("/dev/video0").unwrap();
 
     camera.start(&Config {
         interval: (1, 30),
-        resolution: (320, 240),
+        resolution: (WIDTH, HEIGHT),
         format: b"RGB3",
         ..Default::default()
     }).unwrap();
@@ -332,7 +120,25 @@ fn open_webcam() -> Camera {
     camera
 }
 
+/// Reads the direction the webcam frames are read in, depending on how it is
+/// mounted, from the `--mounting <name>` argument, upright by default.
+fn mounting_argument() -> Mounting {
+    let arguments: Vec<String> = std::env::args().skip(1).collect();
+    match arguments.as_slice() {
+        [] => Mounting::UPRIGHT,
+        [option, name] if option == "--mounting" => Mounting::from_name(name).unwrap_or_else(|| {
+            eprintln!("Unknown mounting {}, use upright, mirrored, flipped or upside-down", name);
+            std::process::exit(2)
+        }),
+        _ => {
+            eprintln!("Usage: sim [--mounting upright|mirrored|flipped|upside-down]");
+            std::process::exit(2)
+        }
+    }
+}
+
 fn main() {
+    let mounting = mounting_argument();
     let listener = TcpListener::bind("127.0.0.1:8080").expect("Could not open TCP server");
     let camera: Camera = open_webcam();
 
@@ -340,41 +146,54 @@ fn main() {
     for stream in listener.incoming() {
         println!("Opening socket");
         let mut stream = stream.expect("Connection failed");
+        // Requests not answered yet, the first `received` bytes are valid
         let mut buffer = [0; 1024];
+        let mut received = 0;
         loop {
-            let read_length = match stream.read(&mut buffer) {
-                Ok (read_length) => { read_length }
-                Err (_e) => { println!("Warning: Could not read from the socket"); continue; }
-            };
-            if read_length == 0 {
-                println!("Socket closed, listening for other sockets");
-                break;
-            }
-            let frame = camera.capture().expect("Could not capture from the webcam");
-            match stream.write(format!("HTTP/1.1 200\nContent-Type: image/bmp\nContent-Length: {}\n\n", 1078 + 76800).as_bytes())
-                      .and(stream.write(&IMAGE_HEADER)) {
-                Ok (_v) => {}
-                Err (_e) => {println!("Warning: Could not write to socket")}
-            }
-            let mut frame_iterator = frame.iter();
-            loop {
-                let r = frame_iterator.next();
-                if r.is_none() {
-                    break
+            let (length, connection) = match http::parse(&buffer[..received], buffer.len()) {
+                Ok (Parsed::Complete { request, .. }) if request.path.starts_with("/stream") => {
+                    stream_frames(&mut stream, &camera, mounting, &request);
+                    break;
                 }
-                let r = *r.unwrap() as u32;
-                let g = *frame_iterator.next().expect("Pixel should have 3 bytes") as u32;
-                let b = *frame_iterator.next().expect("Pixel should have 3 bytes") as u32;
-                let color = (r << 16) | (g << 8) | b;
-                let gray = rgb888_to_gray(color);
-                match stream.write(&[gray]) {
-                    Ok (_v) => {}
-                    Err (_e) => { println!("Warning: Could not write to socket"); continue; }
+                Ok (Parsed::Complete { request, length }) => {
+                    let (format, color) = requested_image(&request);
+                    let frame = camera.capture().expect("Could not capture from the webcam");
+                    let (content_type, image) = encode(&orient(&frame, mounting), format, color);
+                    let response = Response {
+                        connection: request.connection(),
+                        ..Response::new(Status::Ok, content_type, image.len())
+                    };
+                    respond(&mut stream, &response, &image);
+                    (length, response.connection())
                 }
-            }
-            match stream.flush() {
-                Ok (_v) => {}
-                Err (_e) => { println!("Warning: Could not flush socket"); continue; }
+                Ok (Parsed::Partial) if received < buffer.len() => {
+                    let read_length = match stream.read(&mut buffer[received..]) {
+                        Ok (read_length) => { read_length }
+                        Err (_e) => { println!("Warning: Could not read from the socket"); continue; }
+                    };
+                    if read_length == 0 {
+                        println!("Socket closed, listening for other sockets");
+                        break;
+                    }
+                    received += read_length;
+                    continue;
+                }
+                Ok (Parsed::Partial) | Err (_) => {
+                    println!("Warning: Invalid request, closing the socket");
+                    let body = b"Bad Request\n";
+                    let response = Response {
+                        connection: Connection::Close,
+                        ..Response::new(Status::BadRequest, "text/plain", body.len())
+                    };
+                    respond(&mut stream, &response, body);
+                    break;
+                }
+            };
+            buffer.copy_within(length..received, 0);
+            received -= length;
+            if connection == Connection::Close {
+                println!("Closing socket, listening for other sockets");
+                break;
             }
         }
     }
@@ -0,0 +1,280 @@
+//! Driver for the ArduChip, the SPI controller of the ArduCAM that triggers
+//! captures and buffers frames in its FIFO.
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::digital::OutputPin;
+use embedded_hal::spi::{self, SpiBus};
+
+pub const ARDUCHIP_TEST1: u8 = 0x00;
+pub const ARDUCHIP_FIFO: u8 = 0x04;
+pub const ARDUCHIP_RESET: u8 = 0x07;
+pub const ARDUCHIP_BURST_FIFO_READ: u8 = 0x3C;
+pub const ARDUCHIP_TRIG: u8 = 0x41;
+pub const ARDUCHIP_FIFO_SIZE1: u8 = 0x42;
+pub const ARDUCHIP_FIFO_SIZE2: u8 = 0x43;
+pub const ARDUCHIP_FIFO_SIZE3: u8 = 0x44;
+
+/// Bit set on a register address to write to it.
+pub const WRITE_FLAG: u8 = 0x80;
+
+pub const FIFO_CLEAR_MASK: u8 = 0x01;
+pub const FIFO_START_MASK: u8 = 0x02;
+pub const CAP_DONE_MASK: u8 = 0x08;
+pub const RESET_CPLD_MASK: u8 = 0x80;
+pub const FIFO_SIZE_MASK: u32 = 0x7f_ffff;
+
+/// Size of the frame FIFO of the ArduCAM Mini 2MP, in bytes.
+pub const FIFO_SIZE: u32 = 384 * 1024;
+
+const TEST_PATTERN: u8 = 0x55;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+    /// The SPI bus reported an error.
+    Spi(spi::ErrorKind),
+    /// The chip select pin could not be driven.
+    ChipSelect,
+    /// The test register did not read back the value written to it, the
+    /// ArduChip is missing or the SPI link is faulty.
+    TestRegister { written: u8, read: u8 },
+}
+
+/// ArduChip on an SPI bus, selected by the `CS` pin.
+pub struct ArduChip<SPI, CS> {
+    spi: SPI,
+    cs: CS,
+}
+
+impl<SPI: SpiBus, CS: OutputPin> ArduChip<SPI, CS> {
+    pub fn new(spi: SPI, cs: CS) -> Self {
+        Self { spi, cs }
+    }
+
+    /// Returns the bus and the chip select pin.
+    pub fn release(self) -> (SPI, CS) {
+        (self.spi, self.cs)
+    }
+
+    pub fn read_reg(&mut self, reg: u8) -> Result<u8, Error> {
+        let mut value = [0u8];
+        self.transaction(|spi| {
+            spi.write(&[reg & !WRITE_FLAG])?;
+            spi.read(&mut value)
+        })?;
+        Ok(value[0])
+    }
+
+    pub fn write_reg(&mut self, reg: u8, value: u8) -> Result<(), Error> {
+        self.transaction(|spi| spi.write(&[reg | WRITE_FLAG, value]))
+    }
+
+    /// Resets the CPLD of the ArduChip.
+    pub fn reset(&mut self, delay: &mut impl DelayNs) -> Result<(), Error> {
+        self.hold_reset()?;
+        delay.delay_ms(100);
+        self.release_reset()?;
+        delay.delay_ms(100);
+        Ok(())
+    }
+
+    /// Puts the CPLD in reset, for 100 ms before [`release_reset`](Self::release_reset).
+    pub fn hold_reset(&mut self) -> Result<(), Error> {
+        self.write_reg(ARDUCHIP_RESET, RESET_CPLD_MASK)
+    }
+
+    /// Takes the CPLD out of reset, which it needs 100 ms to leave.
+    pub fn release_reset(&mut self) -> Result<(), Error> {
+        self.write_reg(ARDUCHIP_RESET, 0x00)
+    }
+
+    /// Writes a pattern to the test register and checks that it reads back.
+    pub fn check_test_register(&mut self) -> Result<(), Error> {
+        self.write_reg(ARDUCHIP_TEST1, TEST_PATTERN)?;
+        let read = self.read_reg(ARDUCHIP_TEST1)?;
+        if read != TEST_PATTERN {
+            return Err(Error::TestRegister {
+                written: TEST_PATTERN,
+                read,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn clear_fifo_flag(&mut self) -> Result<(), Error> {
+        self.write_reg(ARDUCHIP_FIFO, FIFO_CLEAR_MASK)
+    }
+
+    pub fn start_capture(&mut self) -> Result<(), Error> {
+        self.write_reg(ARDUCHIP_FIFO, FIFO_START_MASK)
+    }
+
+    pub fn capture_done(&mut self) -> Result<bool, Error> {
+        Ok(self.read_reg(ARDUCHIP_TRIG)? & CAP_DONE_MASK != 0)
+    }
+
+    /// Number of bytes written to the FIFO by the last capture.
+    pub fn fifo_length(&mut self) -> Result<u32, Error> {
+        let size1 = self.read_reg(ARDUCHIP_FIFO_SIZE1)? as u32;
+        let size2 = self.read_reg(ARDUCHIP_FIFO_SIZE2)? as u32;
+        let size3 = self.read_reg(ARDUCHIP_FIFO_SIZE3)? as u32;
+        Ok((size3 << 16 | size2 << 8 | size1) & FIFO_SIZE_MASK)
+    }
+
+    /// Reads `buffer.len()` bytes from the FIFO in a single burst.
+    pub fn burst_read(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+        self.start_burst_read()?;
+        let result = self.read_burst(buffer);
+        self.end_burst_read().and(result)
+    }
+
+    /// Selects the chip and starts a burst read of the FIFO, which is then
+    /// read in chunks with [`read_burst`](Self::read_burst) and terminated with
+    /// [`end_burst_read`](Self::end_burst_read).
+    ///
+    /// No other register can be accessed until the burst is terminated.
+    pub fn start_burst_read(&mut self) -> Result<(), Error> {
+        self.cs.set_low().map_err(|_| Error::ChipSelect)?;
+        self.spi
+            .write(&[ARDUCHIP_BURST_FIFO_READ])
+            .map_err(|e| Error::Spi(spi::Error::kind(&e)))
+    }
+
+    pub fn read_burst(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+        self.spi
+            .read(buffer)
+            .map_err(|e| Error::Spi(spi::Error::kind(&e)))
+    }
+
+    pub fn end_burst_read(&mut self) -> Result<(), Error> {
+        let flushed = self.spi.flush();
+        self.cs.set_high().map_err(|_| Error::ChipSelect)?;
+        flushed.map_err(|e| Error::Spi(spi::Error::kind(&e)))
+    }
+
+    /// Runs `f` with the chip selected, always deselecting it afterwards.
+    fn transaction(
+        &mut self,
+        f: impl FnOnce(&mut SPI) -> Result<(), SPI::Error>,
+    ) -> Result<(), Error> {
+        self.cs.set_low().map_err(|_| Error::ChipSelect)?;
+        let result = f(&mut self.spi).and_then(|()| self.spi.flush());
+        self.cs.set_high().map_err(|_| Error::ChipSelect)?;
+        result.map_err(|e| Error::Spi(spi::Error::kind(&e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_hal_mock::eh1::digital::{Mock as PinMock, State, Transaction as PinTransaction};
+    use embedded_hal_mock::eh1::spi::{Mock, Transaction};
+
+    /// Chip select of `transactions` SPI transactions.
+    fn selections(transactions: usize) -> Vec<PinTransaction> {
+        (0..transactions)
+            .flat_map(|_| {
+                [
+                    PinTransaction::set(State::Low),
+                    PinTransaction::set(State::High),
+                ]
+            })
+            .collect()
+    }
+
+    fn done(arduchip: ArduChip<Mock<u8>, PinMock>) {
+        let (mut spi, mut cs) = arduchip.release();
+        spi.done();
+        cs.done();
+    }
+
+    #[test]
+    fn registers_are_written_with_the_write_flag() {
+        let expectations = [
+            Transaction::write_vec(vec![ARDUCHIP_FIFO | WRITE_FLAG, FIFO_START_MASK]),
+            Transaction::flush(),
+            Transaction::write_vec(vec![ARDUCHIP_TRIG]),
+            Transaction::read_vec(vec![CAP_DONE_MASK]),
+            Transaction::flush(),
+        ];
+        let mut arduchip = ArduChip::new(Mock::new(&expectations), PinMock::new(&selections(2)));
+        arduchip.start_capture().unwrap();
+        assert_eq!(arduchip.capture_done(), Ok(true));
+        done(arduchip);
+    }
+
+    #[test]
+    fn reads_clear_the_write_flag() {
+        let expectations = [
+            Transaction::write_vec(vec![0x01]),
+            Transaction::read_vec(vec![0xA5]),
+            Transaction::flush(),
+        ];
+        let mut arduchip = ArduChip::new(Mock::new(&expectations), PinMock::new(&selections(1)));
+        assert_eq!(arduchip.read_reg(0x81), Ok(0xA5));
+        done(arduchip);
+    }
+
+    #[test]
+    fn fifo_length_is_read_from_three_registers() {
+        let expectations = [
+            Transaction::write_vec(vec![ARDUCHIP_FIFO_SIZE1]),
+            Transaction::read_vec(vec![0x56]),
+            Transaction::flush(),
+            Transaction::write_vec(vec![ARDUCHIP_FIFO_SIZE2]),
+            Transaction::read_vec(vec![0x34]),
+            Transaction::flush(),
+            Transaction::write_vec(vec![ARDUCHIP_FIFO_SIZE3]),
+            // The high bit is not part of the length
+            Transaction::read_vec(vec![0x92]),
+            Transaction::flush(),
+        ];
+        let mut arduchip = ArduChip::new(Mock::new(&expectations), PinMock::new(&selections(3)));
+        assert_eq!(arduchip.fifo_length(), Ok(0x12_3456));
+        done(arduchip);
+    }
+
+    #[test]
+    fn burst_reads_keep_the_chip_selected() {
+        let expectations = [
+            Transaction::write_vec(vec![ARDUCHIP_BURST_FIFO_READ]),
+            Transaction::read_vec(vec![1, 2, 3]),
+            Transaction::read_vec(vec![4, 5]),
+            Transaction::flush(),
+        ];
+        let mut arduchip = ArduChip::new(Mock::new(&expectations), PinMock::new(&selections(1)));
+        let mut buffer = [0; 5];
+        arduchip.start_burst_read().unwrap();
+        arduchip.read_burst(&mut buffer[..3]).unwrap();
+        arduchip.read_burst(&mut buffer[3..]).unwrap();
+        arduchip.end_burst_read().unwrap();
+        assert_eq!(buffer, [1, 2, 3, 4, 5]);
+        done(arduchip);
+    }
+
+    #[test]
+    fn test_register_must_read_back() {
+        let expectations = [
+            Transaction::write_vec(vec![ARDUCHIP_TEST1 | WRITE_FLAG, TEST_PATTERN]),
+            Transaction::flush(),
+            Transaction::write_vec(vec![ARDUCHIP_TEST1]),
+            Transaction::read_vec(vec![TEST_PATTERN]),
+            Transaction::flush(),
+            Transaction::write_vec(vec![ARDUCHIP_TEST1 | WRITE_FLAG, TEST_PATTERN]),
+            Transaction::flush(),
+            Transaction::write_vec(vec![ARDUCHIP_TEST1]),
+            // A missing ArduChip leaves MISO high
+            Transaction::read_vec(vec![0xFF]),
+            Transaction::flush(),
+        ];
+        let mut arduchip = ArduChip::new(Mock::new(&expectations), PinMock::new(&selections(4)));
+        assert_eq!(arduchip.check_test_register(), Ok(()));
+        assert_eq!(
+            arduchip.check_test_register(),
+            Err(Error::TestRegister {
+                written: TEST_PATTERN,
+                read: 0xFF
+            })
+        );
+        done(arduchip);
+    }
+}
@@ -2,10 +2,33 @@
 #![no_std]
 
 extern crate alloc;
-use alloc::vec;
-
-use stm32h7xx_hal::{ethernet, pac, prelude::*, spi, stm32};
+use alloc::string::{String, ToString};
+use core::mem::MaybeUninit;
+use core::ops::Range;
+use alloc::{format, vec, vec::Vec};
+
+use embedded_hal::digital::OutputPin;
+use embedded_hal::i2c::I2c;
+use embedded_hal::spi::SpiBus;
+use cortex_m::peripheral::DWT;
+use stm32h7xx_hal::rcc::rec::ResetEnable;
+use stm32h7xx_hal::{ethernet, interrupt, pac, prelude::*, spi, stm32};
 use stm32h755zi as _;
+use stm32h755zi::arduchip::ArduChip;
+use stm32h755zi::capture::{Capture, CaptureStats, FramePolicy, Prefetch};
+use stm32h755zi::clock::CycleClock;
+use stm32h755zi::compat::Compat;
+use stm32h755zi::dma::{self, SpiRxDma};
+use stm32h755zi::error::{CameraError, NetworkError};
+use stm32h755zi::blob::{self, BlobOptions, Label};
+use stm32h755zi::bmp;
+use stm32h755zi::http::{self, Connection, Header, Method, Parsed, Part, Response, Route, Status};
+use stm32h755zi::image::{self, AnyEncoder, Encoder as _, ImageFormat};
+use stm32h755zi::jpeg;
+use stm32h755zi::motion::{self, Background, Detector, Mixture, Model, MotionResult};
+use stm32h755zi::png;
+use stm32h755zi::reset::Reset;
+use stm32h755zi::ov2640::{FrameBuffers, Mounting, Ov2640, OutputFormat, Resolution};
 
 use smoltcp::{
     iface::{Config, Interface, SocketSet},
@@ -14,667 +37,910 @@ use smoltcp::{
     wire::{EthernetAddress, IpAddress, IpCidr},
 };
 
-use embedded_alloc::LlffHeap as EmbeddedAllocator;
 
 // Locally administered MAC address
 const MAC_ADDRESS: [u8; 6] = [0x02, 0x00, 0x11, 0x22, 0x33, 0x44];
 
-const ARDUCHIP_TEST1: u8 = 0x00;
-const ARDUCHIP_FIFO: u8 = 0x04;
-const ARDUCHIP_TRIG: u8 = 0x41;
-const ARDUCHIP_FIFO_SIZE1: u8 = 0x42;
-const ARDUCHIP_FIFO_SIZE2: u8 = 0x43;
-const ARDUCHIP_FIFO_SIZE3: u8 = 0x44;
-const ARDUCHIP_BURST_FIFO_READ: u8 = 0x3C;
-
-const SENSOR_ADDRESS: u8 = 0x30;
-
-const OV2640_CHIPID_HIGH: u8 = 0x0A;
-const OV2640_CHIPID_LOW: u8 = 0x0B;
-
-const OV2640_QVGA: [[u8; 2]; 193] = [
-    [0xff, 0x0],
-    [0x2c, 0xff],
-    [0x2e, 0xdf],
-    [0xff, 0x1],
-    [0x3c, 0x32],
-    [0x11, 0x0],
-    [0x9, 0x2],
-    [0x4, 0xa8],
-    [0x13, 0xe5],
-    [0x14, 0x48],
-    [0x2c, 0xc],
-    [0x33, 0x78],
-    [0x3a, 0x33],
-    [0x3b, 0xfb],
-    [0x3e, 0x0],
-    [0x43, 0x11],
-    [0x16, 0x10],
-    [0x39, 0x2],
-    [0x35, 0x88],
-    [0x22, 0xa],
-    [0x37, 0x40],
-    [0x23, 0x0],
-    [0x34, 0xa0],
-    [0x6, 0x2],
-    [0x6, 0x88],
-    [0x7, 0xc0],
-    [0xd, 0xb7],
-    [0xe, 0x1],
-    [0x4c, 0x0],
-    [0x4a, 0x81],
-    [0x21, 0x99],
-    [0x24, 0x40],
-    [0x25, 0x38],
-    [0x26, 0x82],
-    [0x5c, 0x0],
-    [0x63, 0x0],
-    [0x46, 0x22],
-    [0xc, 0x3a],
-    [0x5d, 0x55],
-    [0x5e, 0x7d],
-    [0x5f, 0x7d],
-    [0x60, 0x55],
-    [0x61, 0x70],
-    [0x62, 0x80],
-    [0x7c, 0x5],
-    [0x20, 0x80],
-    [0x28, 0x30],
-    [0x6c, 0x0],
-    [0x6d, 0x80],
-    [0x6e, 0x0],
-    [0x70, 0x2],
-    [0x71, 0x94],
-    [0x73, 0xc1],
-    [0x3d, 0x34],
-    [0x12, 0x4],
-    [0x5a, 0x57],
-    [0x4f, 0xbb],
-    [0x50, 0x9c],
-    [0xff, 0x0],
-    [0xe5, 0x7f],
-    [0xf9, 0xc0],
-    [0x41, 0x24],
-    [0xe0, 0x14],
-    [0x76, 0xff],
-    [0x33, 0xa0],
-    [0x42, 0x20],
-    [0x43, 0x18],
-    [0x4c, 0x0],
-    [0x87, 0xd0],
-    [0x88, 0x3f],
-    [0xd7, 0x3],
-    [0xd9, 0x10],
-    [0xd3, 0x82],
-    [0xc8, 0x8],
-    [0xc9, 0x80],
-    [0x7c, 0x0],
-    [0x7d, 0x0],
-    [0x7c, 0x3],
-    [0x7d, 0x48],
-    [0x7d, 0x48],
-    [0x7c, 0x8],
-    [0x7d, 0x20],
-    [0x7d, 0x10],
-    [0x7d, 0xe],
-    [0x90, 0x0],
-    [0x91, 0xe],
-    [0x91, 0x1a],
-    [0x91, 0x31],
-    [0x91, 0x5a],
-    [0x91, 0x69],
-    [0x91, 0x75],
-    [0x91, 0x7e],
-    [0x91, 0x88],
-    [0x91, 0x8f],
-    [0x91, 0x96],
-    [0x91, 0xa3],
-    [0x91, 0xaf],
-    [0x91, 0xc4],
-    [0x91, 0xd7],
-    [0x91, 0xe8],
-    [0x91, 0x20],
-    [0x92, 0x0],
-    [0x93, 0x6],
-    [0x93, 0xe3],
-    [0x93, 0x3],
-    [0x93, 0x3],
-    [0x93, 0x0],
-    [0x93, 0x2],
-    [0x93, 0x0],
-    [0x93, 0x0],
-    [0x93, 0x0],
-    [0x93, 0x0],
-    [0x93, 0x0],
-    [0x93, 0x0],
-    [0x93, 0x0],
-    [0x96, 0x0],
-    [0x97, 0x8],
-    [0x97, 0x19],
-    [0x97, 0x2],
-    [0x97, 0xc],
-    [0x97, 0x24],
-    [0x97, 0x30],
-    [0x97, 0x28],
-    [0x97, 0x26],
-    [0x97, 0x2],
-    [0x97, 0x98],
-    [0x97, 0x80],
-    [0x97, 0x0],
-    [0x97, 0x0],
-    [0xa4, 0x0],
-    [0xa8, 0x0],
-    [0xc5, 0x11],
-    [0xc6, 0x51],
-    [0xbf, 0x80],
-    [0xc7, 0x10],
-    [0xb6, 0x66],
-    [0xb8, 0xa5],
-    [0xb7, 0x64],
-    [0xb9, 0x7c],
-    [0xb3, 0xaf],
-    [0xb4, 0x97],
-    [0xb5, 0xff],
-    [0xb0, 0xc5],
-    [0xb1, 0x94],
-    [0xb2, 0xf],
-    [0xc4, 0x5c],
-    [0xa6, 0x0],
-    [0xa7, 0x20],
-    [0xa7, 0xd8],
-    [0xa7, 0x1b],
-    [0xa7, 0x31],
-    [0xa7, 0x0],
-    [0xa7, 0x18],
-    [0xa7, 0x20],
-    [0xa7, 0xd8],
-    [0xa7, 0x19],
-    [0xa7, 0x31],
-    [0xa7, 0x0],
-    [0xa7, 0x18],
-    [0xa7, 0x20],
-    [0xa7, 0xd8],
-    [0xa7, 0x19],
-    [0xa7, 0x31],
-    [0xa7, 0x0],
-    [0xa7, 0x18],
-    [0x7f, 0x0],
-    [0xe5, 0x1f],
-    [0xe1, 0x77],
-    [0xdd, 0x7f],
-    [0xc2, 0xe],
-    [0xff, 0x0],
-    [0xe0, 0x4],
-    [0xc0, 0xc8],
-    [0xc1, 0x96],
-    [0x86, 0x3d],
-    [0x51, 0x90],
-    [0x52, 0x2c],
-    [0x53, 0x0],
-    [0x54, 0x0],
-    [0x55, 0x88],
-    [0x57, 0x0],
-    [0x50, 0x92],
-    [0x5a, 0x50],
-    [0x5b, 0x3c],
-    [0x5c, 0x0],
-    [0xd3, 0x4],
-    [0xe0, 0x0],
-    [0xff, 0x0],
-    [0x5, 0x0],
-    [0xda, 0x8],
-    [0xd7, 0x3],
-    [0xe0, 0x0],
-    [0x5, 0x0],
-];
+/// Size of the buffer the responses are built in, which bounds the size of
+/// the JPEG frames, read whole from the FIFO to find their end, and holds a
+/// 24-bit QVGA BMP.
+const FRAME_BUFFER_SIZE: usize = 240 * 1024;
 
-const IMAGE_HEADER_SIZE: usize = 1078;
-
-const BMP_HEADER_GRAYSCALED: [u8; 1078] = [
-  // BMP header : 14 bytes
-  0x42, 0x4D,              // BM
-  0x36, 0x30, 0x01, 0x00,  // Size : 77878 bytes
-  0x00, 0x00,              // Application
-  0x00, 0x00,              // Application
-  0x36, 0x04, 0x00, 0x00,  // Offset : 1078 bytes
-
-  // DIB Header : 40 bytes
-  0x28, 0x00, 0x00, 0x00,  // DIB Header size : 40 bytes
-  0x40, 0x01, 0x00, 0x00,  // Image width : 320 px
-  0xF0, 0x00, 0x00, 0x00,  // Image height : 240 px
-  0x01, 0x00,              // Number of planes (1)
-  0x08, 0x00,              // Bits per pixel : 8 bits
-  0x00, 0x00, 0x00, 0x00,  // Compression method : 0 (NONE)
-  0x00, 0x2C, 0x01, 0x00,  // Raw Bitmap size : 76800 bytes
-  0xC4, 0x0E, 0x00, 0x00,  // Horizontal resolution : 3780 px/m
-  0xC4, 0x0E, 0x00, 0x00,  // Vertical resolution : 3780 px/m
-  0x00, 0x00, 0x00, 0x00,  // Colors in palette : 0 (256)
-  0x00, 0x00, 0x00, 0x00,  // Importants colors : 0
-
-  // Color Table : 256 * 4 = 1024 bytes
-  0, 0, 0, 255,        //   0
-  1, 1, 1, 255,        //   1
-  2, 2, 2, 255,        //   2
-  3, 3, 3, 255,        //   3
-  4, 4, 4, 255,        //   4
-  5, 5, 5, 255,        //   5
-  6, 6, 6, 255,        //   6
-  7, 7, 7, 255,        //   7
-  8, 8, 8, 255,        //   8
-  9, 9, 9, 255,        //   9
-  10, 10, 10, 255,     //  10
-  11, 11, 11, 255,     //  11
-  12, 12, 12, 255,     //  12
-  13, 13, 13, 255,     //  13
-  14, 14, 14, 255,     //  14
-  15, 15, 15, 255,     //  15
-  16, 16, 16, 255,     //  16
-  17, 17, 17, 255,     //  17
-  18, 18, 18, 255,     //  18
-  19, 19, 19, 255,     //  19
-  20, 20, 20, 255,     //  20
-  21, 21, 21, 255,     //  21
-  22, 22, 22, 255,     //  22
-  23, 23, 23, 255,     //  23
-  24, 24, 24, 255,     //  24
-  25, 25, 25, 255,     //  25
-  26, 26, 26, 255,     //  26
-  27, 27, 27, 255,     //  27
-  28, 28, 28, 255,     //  28
-  29, 29, 29, 255,     //  29
-  30, 30, 30, 255,     //  30
-  31, 31, 31, 255,     //  31
-  32, 32, 32, 255,     //  32
-  33, 33, 33, 255,     //  33
-  34, 34, 34, 255,     //  34
-  35, 35, 35, 255,     //  35
-  36, 36, 36, 255,     //  36
-  37, 37, 37, 255,     //  37
-  38, 38, 38, 255,     //  38
-  39, 39, 39, 255,     //  39
-  40, 40, 40, 255,     //  40
-  41, 41, 41, 255,     //  41
-  42, 42, 42, 255,     //  42
-  43, 43, 43, 255,     //  43
-  44, 44, 44, 255,     //  44
-  45, 45, 45, 255,     //  45
-  46, 46, 46, 255,     //  46
-  47, 47, 47, 255,     //  47
-  48, 48, 48, 255,     //  48
-  49, 49, 49, 255,     //  49
-  50, 50, 50, 255,     //  50
-  51, 51, 51, 255,     //  51
-  52, 52, 52, 255,     //  52
-  53, 53, 53, 255,     //  53
-  54, 54, 54, 255,     //  54
-  55, 55, 55, 255,     //  55
-  56, 56, 56, 255,     //  56
-  57, 57, 57, 255,     //  57
-  58, 58, 58, 255,     //  58
-  59, 59, 59, 255,     //  59
-  60, 60, 60, 255,     //  60
-  61, 61, 61, 255,     //  61
-  62, 62, 62, 255,     //  62
-  63, 63, 63, 255,     //  63
-  64, 64, 64, 255,     //  64
-  65, 65, 65, 255,     //  65
-  66, 66, 66, 255,     //  66
-  67, 67, 67, 255,     //  67
-  68, 68, 68, 255,     //  68
-  69, 69, 69, 255,     //  69
-  70, 70, 70, 255,     //  70
-  71, 71, 71, 255,     //  71
-  72, 72, 72, 255,     //  72
-  73, 73, 73, 255,     //  73
-  74, 74, 74, 255,     //  74
-  75, 75, 75, 255,     //  75
-  76, 76, 76, 255,     //  76
-  77, 77, 77, 255,     //  77
-  78, 78, 78, 255,     //  78
-  79, 79, 79, 255,     //  79
-  80, 80, 80, 255,     //  80
-  81, 81, 81, 255,     //  81
-  82, 82, 82, 255,     //  82
-  83, 83, 83, 255,     //  83
-  84, 84, 84, 255,     //  84
-  85, 85, 85, 255,     //  85
-  86, 86, 86, 255,     //  86
-  87, 87, 87, 255,     //  87
-  88, 88, 88, 255,     //  88
-  89, 89, 89, 255,     //  89
-  90, 90, 90, 255,     //  90
-  91, 91, 91, 255,     //  91
-  92, 92, 92, 255,     //  92
-  93, 93, 93, 255,     //  93
-  94, 94, 94, 255,     //  94
-  95, 95, 95, 255,     //  95
-  96, 96, 96, 255,     //  96
-  97, 97, 97, 255,     //  97
-  98, 98, 98, 255,     //  98
-  99, 99, 99, 255,     //  99
-  100, 100, 100, 255,  // 100
-  101, 101, 101, 255,  // 101
-  102, 102, 102, 255,  // 102
-  103, 103, 103, 255,  // 103
-  104, 104, 104, 255,  // 104
-  105, 105, 105, 255,  // 105
-  106, 106, 106, 255,  // 106
-  107, 107, 107, 255,  // 107
-  108, 108, 108, 255,  // 108
-  109, 109, 109, 255,  // 109
-  110, 110, 110, 255,  // 110
-  111, 111, 111, 255,  // 111
-  112, 112, 112, 255,  // 112
-  113, 113, 113, 255,  // 113
-  114, 114, 114, 255,  // 114
-  115, 115, 115, 255,  // 115
-  116, 116, 116, 255,  // 116
-  117, 117, 117, 255,  // 117
-  118, 118, 118, 255,  // 118
-  119, 119, 119, 255,  // 119
-  120, 120, 120, 255,  // 120
-  121, 121, 121, 255,  // 121
-  122, 122, 122, 255,  // 122
-  123, 123, 123, 255,  // 123
-  124, 124, 124, 255,  // 124
-  125, 125, 125, 255,  // 125
-  126, 126, 126, 255,  // 126
-  127, 127, 127, 255,  // 127
-  128, 128, 128, 255,  // 128
-  129, 129, 129, 255,  // 129
-  130, 130, 130, 255,  // 130
-  131, 131, 131, 255,  // 131
-  132, 132, 132, 255,  // 132
-  133, 133, 133, 255,  // 133
-  134, 134, 134, 255,  // 134
-  135, 135, 135, 255,  // 135
-  136, 136, 136, 255,  // 136
-  137, 137, 137, 255,  // 137
-  138, 138, 138, 255,  // 138
-  139, 139, 139, 255,  // 139
-  140, 140, 140, 255,  // 140
-  141, 141, 141, 255,  // 141
-  142, 142, 142, 255,  // 142
-  143, 143, 143, 255,  // 143
-  144, 144, 144, 255,  // 144
-  145, 145, 145, 255,  // 145
-  146, 146, 146, 255,  // 146
-  147, 147, 147, 255,  // 147
-  148, 148, 148, 255,  // 148
-  149, 149, 149, 255,  // 149
-  150, 150, 150, 255,  // 150
-  151, 151, 151, 255,  // 151
-  152, 152, 152, 255,  // 152
-  153, 153, 153, 255,  // 153
-  154, 154, 154, 255,  // 154
-  155, 155, 155, 255,  // 155
-  156, 156, 156, 255,  // 156
-  157, 157, 157, 255,  // 157
-  158, 158, 158, 255,  // 158
-  159, 159, 159, 255,  // 159
-  160, 160, 160, 255,  // 160
-  161, 161, 161, 255,  // 161
-  162, 162, 162, 255,  // 162
-  163, 163, 163, 255,  // 163
-  164, 164, 164, 255,  // 164
-  165, 165, 165, 255,  // 165
-  166, 166, 166, 255,  // 166
-  167, 167, 167, 255,  // 167
-  168, 168, 168, 255,  // 168
-  169, 169, 169, 255,  // 169
-  170, 170, 170, 255,  // 170
-  171, 171, 171, 255,  // 171
-  172, 172, 172, 255,  // 172
-  173, 173, 173, 255,  // 173
-  174, 174, 174, 255,  // 174
-  175, 175, 175, 255,  // 175
-  176, 176, 176, 255,  // 176
-  177, 177, 177, 255,  // 177
-  178, 178, 178, 255,  // 178
-  179, 179, 179, 255,  // 179
-  180, 180, 180, 255,  // 180
-  181, 181, 181, 255,  // 181
-  182, 182, 182, 255,  // 182
-  183, 183, 183, 255,  // 183
-  184, 184, 184, 255,  // 184
-  185, 185, 185, 255,  // 185
-  186, 186, 186, 255,  // 186
-  187, 187, 187, 255,  // 187
-  188, 188, 188, 255,  // 188
-  189, 189, 189, 255,  // 189
-  190, 190, 190, 255,  // 190
-  191, 191, 191, 255,  // 191
-  192, 192, 192, 255,  // 192
-  193, 193, 193, 255,  // 193
-  194, 194, 194, 255,  // 194
-  195, 195, 195, 255,  // 195
-  196, 196, 196, 255,  // 196
-  197, 197, 197, 255,  // 197
-  198, 198, 198, 255,  // 198
-  199, 199, 199, 255,  // 199
-  200, 200, 200, 255,  // 200
-  201, 201, 201, 255,  // 201
-  202, 202, 202, 255,  // 202
-  203, 203, 203, 255,  // 203
-  204, 204, 204, 255,  // 204
-  205, 205, 205, 255,  // 205
-  206, 206, 206, 255,  // 206
-  207, 207, 207, 255,  // 207
-  208, 208, 208, 255,  // 208
-  209, 209, 209, 255,  // 209
-  210, 210, 210, 255,  // 210
-  211, 211, 211, 255,  // 211
-  212, 212, 212, 255,  // 212
-  213, 213, 213, 255,  // 213
-  214, 214, 214, 255,  // 214
-  215, 215, 215, 255,  // 215
-  216, 216, 216, 255,  // 216
-  217, 217, 217, 255,  // 217
-  218, 218, 218, 255,  // 218
-  219, 219, 219, 255,  // 219
-  220, 220, 220, 255,  // 220
-  221, 221, 221, 255,  // 221
-  222, 222, 222, 255,  // 222
-  223, 223, 223, 255,  // 223
-  224, 224, 224, 255,  // 224
-  225, 225, 225, 255,  // 225
-  226, 226, 226, 255,  // 226
-  227, 227, 227, 255,  // 227
-  228, 228, 228, 255,  // 228
-  229, 229, 229, 255,  // 229
-  230, 230, 230, 255,  // 230
-  231, 231, 231, 255,  // 231
-  232, 232, 232, 255,  // 232
-  233, 233, 233, 255,  // 233
-  234, 234, 234, 255,  // 234
-  235, 235, 235, 255,  // 235
-  236, 236, 236, 255,  // 236
-  237, 237, 237, 255,  // 237
-  238, 238, 238, 255,  // 238
-  239, 239, 239, 255,  // 239
-  240, 240, 240, 255,  // 240
-  241, 241, 241, 255,  // 241
-  242, 242, 242, 255,  // 242
-  243, 243, 243, 255,  // 243
-  244, 244, 244, 255,  // 244
-  245, 245, 245, 255,  // 245
-  246, 246, 246, 255,  // 246
-  247, 247, 247, 255,  // 247
-  248, 248, 248, 255,  // 248
-  249, 249, 249, 255,  // 249
-  250, 250, 250, 255,  // 250
-  251, 251, 251, 255,  // 251
-  252, 252, 252, 255,  // 252
-  253, 253, 253, 255,  // 253
-  254, 254, 254, 255,  // 254
-  255, 255, 255, 255,  // 255
-];
+/// Room for the HTTP headers in front of the frame in the frame buffer.
+const HTTP_HEADER_ROOM: usize = http::HEAD_ROOM;
 
-const BMP_HEADER_COLORED: [u8; 66] = [
-    // BMP header : 14 bytes
-    0x42, 0x4D, // BM
-    0x42, 0x58, 0x02, 0x00, // Size : 153666 bytes
-    0x00, 0x00, // Application
-    0x00, 0x00, // Application
-    0x42, 0x00, 0x00, 0x00, // Offset : 66 bytes
-    // DIB Header : 52 bytes
-    0x28, 0x00, 0x00, 0x00, // DIB Header size : 40 bytes
-    0x40, 0x01, 0x00, 0x00, // Image width : 320 px
-    0xF0, 0x00, 0x00, 0x00, // Image height : 240 px
-    0x01, 0x00,             // Color plane : 1
-    0x10, 0x00,             // Bits per pixel : 16 bits
-    0x03, 0x00, 0x00, 0x00, // Compression method : 3 (BITFIELDS)
-    0x00, 0x58, 0x02, 0x00, // Raw Bitmap size : 153600 bytes
-    0xC4, 0x0E, 0x00, 0x00, // Horizontal resolution : 3780 px/m
-    0xC4, 0x0E, 0x00, 0x00, // Vertical resolution : 3780 px/m
-    0x00, 0x00, 0x00, 0x00, // Colors in palette : 0 (256)
-    0x00, 0x00, 0x00, 0x00, // Importants colors : 0
-    // Bit masks
-    0x00, 0xF8, 0x00, 0x00, // Red mask :   1111 1000  0000 0000
-    0xE0, 0x07, 0x00, 0x00, // Green mask : 0000 0111  1110 0000
-    0x1F, 0x00, 0x00, 0x00, // Blue mask :  0000 0000  0001 1111
-];
+/// RGB565 or YUV422 bytes received by DMA before being encoded, in whole
+/// strips of rows.
+const RAW_CHUNK_SIZE: usize = 16 * 1024;
 
+/// Widest frames of the motion detector, QVGA.
+const MOTION_MAX_WIDTH: usize = 320;
 
-const OV2640_JPEG_INIT: [[u8; 2]; 14] = [
-    // Reset
-    [0xff, 0x01],
-    [0x12, 0x80],
+/// Pixels of the model of the motion detector, up to QVGA.
+///
+/// The variance of the background does not fit in the AXI SRAM next to the
+/// heap, see the memory table of [`motion`].
+const MOTION_MODEL_PIXELS: usize = MOTION_MAX_WIDTH * 240;
 
-    // Delay ~100 ms nécessaire après reset
+/// Buffers of the frames, which bound their resolution.
+const FRAME_BUFFERS: FrameBuffers = FrameBuffers {
+    file: FRAME_BUFFER_SIZE - HTTP_HEADER_ROOM,
+    motion_pixels: MOTION_MODEL_PIXELS,
+    motion_width: MOTION_MAX_WIDTH,
+};
 
-    // Sélection banque DSP
-    [0xff, 0x00],
+/// Blobs crossing a row of the motion mask that can be labelled, the pixels
+/// of the others being left unlabelled.
+const MOTION_BLOB_LABELS: usize = 64;
+
+/// Model of the motion detector, sharing a buffer of 3 bytes per pixel.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum MotionModel {
+    /// Difference with the previous frame, like `server.py`.
+    Difference,
+    /// Running average of the frames.
+    Background,
+    /// Two Gaussians per pixel, which ignore the pixels swaying between two
+    /// backgrounds.
+    Mixture,
+}
 
-    // Activer JPEG
-    [0x2c, 0xff],
-    [0x2e, 0xdf],
+impl MotionModel {
+    /// The model named `name` in the `motion` parameter.
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "difference" => Some(Self::Difference),
+            "background" => Some(Self::Background),
+            "mixture" => Some(Self::Mixture),
+            _ => None,
+        }
+    }
 
-    // Format JPEG
-    [0xff, 0x01],
-    [0x12, 0x40], // COM7 = JPEG
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Difference => "difference",
+            Self::Background => "background",
+            Self::Mixture => "mixture",
+        }
+    }
+}
+
+/// Requests received and not answered yet, the pipelined ones included.
+const REQUEST_BUFFER_SIZE: usize = 1024;
+
+/// Handlers of the routes of the server.
+#[derive(Clone, Copy)]
+enum Handler {
+    /// A frame, in the format of the extension of the path, else of the
+    /// `Accept` header.
+    Frame,
+    /// The capture statistics, as JSON.
+    Status,
+    /// The settings of the frames, changed by the parameters of a form body.
+    Config,
+    /// Frames pushed until the client leaves, JPEG unless the path asks for
+    /// BMP.
+    Stream,
+}
 
-    // Configuration DSP
-    [0xff, 0x00],
-    [0xe0, 0x14],
-    [0xe1, 0x77],
-    [0xe5, 0x1f],
-    [0xd7, 0x03],
-    [0xda, 0x10],
-    [0xe0, 0x00],
+const GET: &[Method] = &[Method::Get];
+
+const ROUTES: &[Route<Handler>] = &[
+    Route::new("/", GET, Handler::Frame),
+    Route::new("/frame", GET, Handler::Frame),
+    Route::new("/frame.bmp", GET, Handler::Frame),
+    Route::new("/frame.jpg", GET, Handler::Frame),
+    Route::new("/frame.jpeg", GET, Handler::Frame),
+    Route::new("/frame.png", GET, Handler::Frame),
+    Route::new("/frame.qoi", GET, Handler::Frame),
+    Route::new("/frame.pgm", GET, Handler::Frame),
+    Route::new("/frame.ppm", GET, Handler::Frame),
+    Route::new("/frame.pnm", GET, Handler::Frame),
+    Route::new("/stream", GET, Handler::Stream),
+    Route::new("/stream.jpg", GET, Handler::Stream),
+    Route::new("/stream.bmp", GET, Handler::Stream),
+    Route::new("/status", GET, Handler::Status),
+    Route::new("/config", &[Method::Get, Method::Post], Handler::Config),
 ];
 
-const IMAGE_HEADER: [u8; IMAGE_HEADER_SIZE] = BMP_HEADER_GRAYSCALED;
+/// Response to a request, decided while it is in the request buffer.
+enum Action {
+    Frame { policy: FramePolicy, format: ImageFormat, color: image::Color },
+    Stream(Stream),
+    Status,
+    Config,
+    /// An error, with the methods of the path for a 405.
+    Reject { status: Status, allow: &'static [Method] },
+}
 
-macro_rules! i2c_read {
-    ($i2c:ident, $reg:expr) => {{
-        let mut value = [0u8];
-        $i2c.write_read(SENSOR_ADDRESS, &[$reg], &mut value)
-            .expect("I2C read");
-        value[0]
-    }};
+impl Action {
+    const BAD_REQUEST: Action = Action::Reject { status: Status::BadRequest, allow: &[] };
 }
 
-macro_rules! i2c_write {
-    ($i2c:ident, $reg:expr, $val:expr) => {
-        $i2c.write(SENSOR_ADDRESS, &[$reg, $val])
-            .expect("I2C write");
+/// Routes `request` to its action, applying the settings it posts.
+fn action(request: &http::Request, settings: &mut Settings) -> Action {
+    let handler = match http::route(ROUTES, request) {
+        Ok(handler) => handler,
+        Err(rejection) => {
+            let allow = match rejection {
+                http::Rejection::MethodNotAllowed { allowed } => allowed,
+                http::Rejection::NotFound => &[],
+            };
+            return Action::Reject { status: rejection.status(), allow };
+        }
     };
+    match handler {
+        Handler::Frame => {
+            let target = request.target;
+            let format = ImageFormat::from_target(target)
+                .or_else(|| request.header("accept").and_then(ImageFormat::from_accept))
+                .unwrap_or(settings.image_format);
+            let color = bmp::Format::from_target(target).unwrap_or(settings.bmp_format);
+            Action::Frame {
+                policy: FramePolicy::from_target(target).unwrap_or(settings.frame_policy),
+                format,
+                color: color.into(),
+            }
+        }
+        Handler::Stream => {
+            let query = request.query.unwrap_or("");
+            let fps = match http::parameters(query).find(|&(key, _)| key == "fps") {
+                Some((_, value)) => match parse_fps(value) {
+                    Some(fps) => fps,
+                    None => return Action::BAD_REQUEST,
+                },
+                None => settings.stream_fps,
+            };
+            let format = match ImageFormat::from_target(request.path) {
+                Some(ImageFormat::Bmp) => ImageFormat::Bmp,
+                _ => ImageFormat::Jpeg,
+            };
+            let color = bmp::Format::from_target(request.target).unwrap_or(settings.bmp_format);
+            Action::Stream(Stream {
+                policy: FramePolicy::from_target(request.target).unwrap_or(settings.frame_policy),
+                format,
+                color: color.into(),
+                interval_ms: 1000 / fps as u64,
+                next_ms: 0,
+            })
+        }
+        Handler::Status => Action::Status,
+        Handler::Config if request.method == Method::Post => {
+            let form = str::from_utf8(request.body).ok();
+            match form.and_then(|form| settings.configured(form)) {
+                Some(configured) => {
+                    *settings = configured;
+                    Action::Config
+                }
+                None => Action::BAD_REQUEST,
+            }
+        }
+        Handler::Config => Action::Config,
+    }
 }
 
-macro_rules! spi_read {
-    ($spi:ident, $cs:ident, $reg:expr) => {{
-        $cs.set_low();
-        $spi.write(&[$reg]).expect("SPI write");
-        let value = $spi.transfer(&mut [0u8]).expect("SPI read")[0];
-        $cs.set_high();
-        value
-    }};
+/// Highest frame rate of the streams, above what the capture and the network
+/// sustain anyway.
+const MAX_STREAM_FPS: u8 = 30;
+
+/// Reads a frame rate of the streams, from 1 to [`MAX_STREAM_FPS`].
+fn parse_fps(value: &str) -> Option<u8> {
+    value.parse().ok().filter(|fps| (1..=MAX_STREAM_FPS).contains(fps))
 }
 
-macro_rules! spi_write {
-    ($spi:ident, $cs:ident, $reg:expr, $val:expr) => {
-        $cs.set_low();
-        $spi.write(&[$reg | 0x80, $val]).expect("SPI write");
-        $cs.set_high();
-    };
+/// Frames pushed to the client of `/stream`.
+#[derive(Clone, Copy)]
+struct Stream {
+    policy: FramePolicy,
+    format: ImageFormat,
+    color: image::Color,
+    /// Time between the starts of two frames.
+    interval_ms: u64,
+    /// Time to start the next frame at.
+    next_ms: u64,
 }
 
-macro_rules! clear_fifo_flag {
-    ($spi:ident, $cs:ident) => {
-        spi_write!($spi, $cs, ARDUCHIP_FIFO, 0x01)
-    };
+/// Output of the camera, applied again when it is reinitialised.
+#[derive(Clone, Copy)]
+struct Settings {
+    resolution: Resolution,
+    format: OutputFormat,
+    /// Quantization scale, lower is better quality and larger frames
+    jpeg_quality: u8,
+    /// Frame answering the requests without a `frame` parameter
+    frame_policy: FramePolicy,
+    /// Format of the frames answering the requests without an extension or
+    /// a supported `Accept` header
+    image_format: ImageFormat,
+    /// Pixels of the frames answering the requests without a `color`
+    /// parameter
+    bmp_format: bmp::Format,
+    /// Readout direction of the sensor, depending on how the ArduCAM is
+    /// mounted
+    mounting: Mounting,
+    /// Order of the rows of the BMP frames
+    orientation: bmp::Orientation,
+    /// Quality of the JPEG frames compressed on the device, from 1 to 100
+    encoder_quality: u8,
+    /// Compression of the PNG frames
+    png_compression: png::Compression,
+    /// What the motion detection compares the frames with
+    motion: MotionModel,
+    /// Learning of the background, when it is the model
+    background: motion::BackgroundOptions,
+    /// Learning of the mixture, when it is the model
+    mixture: motion::MixtureOptions,
+    /// Connectivity and minimum area of the blobs of moving pixels
+    blobs: BlobOptions,
+    /// Frame rate of the streams without an `fps` parameter
+    stream_fps: u8,
 }
 
-macro_rules! start_capture {
-    ($spi:ident, $cs:ident) => {
-        spi_write!($spi, $cs, ARDUCHIP_FIFO, 0x02)
-    };
+impl Settings {
+    /// Settings with the `resolution`, `qs`, `mounting`, `frame`, `color`,
+    /// `format`, `quality`, `png`, `fps`, `motion`, `learning` and `freeze`
+    /// parameters of `form`, `None` if any of them is invalid.
+    fn configured(&self, form: &str) -> Option<Settings> {
+        let mut settings = *self;
+        for (key, value) in http::parameters(form) {
+            match key {
+                "resolution" => {
+                    let resolution = Resolution::from_name(value)?;
+                    // The frames of the sensor are read whole
+                    if !resolution.fits(settings.format, &FRAME_BUFFERS) {
+                        return None;
+                    }
+                    settings.resolution = resolution;
+                }
+                "qs" => {
+                    // The quantization scale of the sensor, 2 being the best
+                    let scale = value.parse().ok().filter(|&scale| scale >= 2);
+                    settings.jpeg_quality = scale?;
+                }
+                "mounting" => settings.mounting = Mounting::from_name(value)?,
+                "frame" => settings.frame_policy = FramePolicy::from_name(value)?,
+                "color" => settings.bmp_format = bmp::Format::from_name(value)?,
+                "format" => settings.image_format = ImageFormat::from_extension(value)?,
+                "quality" => {
+                    let quality = value.parse().ok().filter(|quality| (1..=100).contains(quality));
+                    settings.encoder_quality = quality?;
+                }
+                "png" => settings.png_compression = png::Compression::from_name(value)?,
+                "fps" => settings.stream_fps = parse_fps(value)?,
+                "motion" => settings.motion = MotionModel::from_name(value)?,
+                "learning" => {
+                    // In 256ths, a background that never learns is never right
+                    let rate = value.parse().ok().filter(|&rate| rate > 0);
+                    settings.background.learning_rate = rate?;
+                }
+                "freeze" => settings.background.freeze_on_motion = parse_switch(value)?,
+                _ => return None,
+            }
+        }
+        Some(settings)
+    }
+
+    /// The form of the settings changed by `/config`.
+    fn form(&self) -> String {
+        format!(
+            "resolution={}&qs={}&mounting={}&frame={}&color={}&format={}&quality={}&png={}&fps={}&motion={}&learning={}&freeze={}\n",
+            self.resolution.name(),
+            self.jpeg_quality,
+            self.mounting.name(),
+            self.frame_policy.name(),
+            self.bmp_format.name(),
+            self.image_format.extension(),
+            self.encoder_quality,
+            self.png_compression.name(),
+            self.stream_fps,
+            self.motion.name(),
+            self.background.learning_rate,
+            if self.background.freeze_on_motion { "on" } else { "off" }
+        )
+    }
+}
+
+/// Reads a switch of the settings, `on` or `off`.
+fn parse_switch(value: &str) -> Option<bool> {
+    match value {
+        "on" => Some(true),
+        "off" => Some(false),
+        _ => None,
+    }
 }
 
-macro_rules! capture_done {
-    ($spi:ident, $cs:ident) => {{ spi_read!($spi, $cs, ARDUCHIP_TRIG) & 0x08 != 0 }};
+/// Step of the response to the current request, advanced once per iteration
+/// of the main loop so that the network stack keeps being polled.
+enum State {
+    /// Waiting for a request.
+    Idle,
+    /// Waiting for the sensor to fill the FIFO.
+    Capturing(Capture),
+    /// Reading `length` bytes of the FIFO into the frame buffer, of which
+    /// `read` are done and `receiving` are being received by DMA.
+    Draining { length: usize, read: usize, receiving: usize },
+    /// Sending the `response` bytes of the frame buffer, of which `sent` are
+    /// done.
+    Sending { response: Range<usize>, sent: usize },
 }
 
-macro_rules! read_fifo_flag {
-    ($spi:ident, $cs:ident) => {{
-        ((spi_read!($spi, $cs, ARDUCHIP_FIFO_SIZE3) as u32) << 16
-            | (spi_read!($spi, $cs, ARDUCHIP_FIFO_SIZE2) as u32) << 8
-            | (spi_read!($spi, $cs, ARDUCHIP_FIFO_SIZE1) as u32))
-            & 0x7fffff
-    }};
+/// The ArduCAM and what is needed to capture frames and recover from errors.
+struct Camera<SPI, CS, I2C> {
+    arduchip: ArduChip<SPI, CS>,
+    sensor: Ov2640<I2C>,
+    /// Reset of the ArduCAM in progress, during which the frames are
+    /// unavailable.
+    reset: Option<Reset>,
+    settings: Settings,
+    stats: CaptureStats,
+    state: State,
+    /// Frame captured in the FIFO while the previous one is sent.
+    prefetch: Prefetch,
+    /// Encodes the frame being served in the format of the request, `None`
+    /// when the sensor outputs JPEG frames, which are served as they are.
+    encoder: Option<AnyEncoder>,
+    /// Bytes of the requests not answered yet, the first `received` are
+    /// valid.
+    request: [u8; REQUEST_BUFFER_SIZE],
+    received: usize,
+    /// What becomes of the connection once the response is sent.
+    connection: Connection,
+    /// Stream sent instead of answering the requests, until the client
+    /// leaves.
+    stream: Option<Stream>,
+    /// Whether a frame is being captured or read for a request not answered
+    /// yet, which is answered if the camera fails.
+    answering: bool,
+    /// Compares each frame read with the previous one, `None` for the JPEG
+    /// frames of the sensor.
+    motion: Option<Detector<'static>>,
+    /// Motion of the last frame read.
+    motion_result: Option<MotionResult>,
+    /// Response being built or sent, the HTTP headers go in the first
+    /// [`HTTP_HEADER_ROOM`] bytes in front of the frame.
+    buffer: &'static mut [u8],
+    /// RGB565 or YUV422 bytes being received, before their conversion.
+    raw: &'static mut [u8],
+    dma: SpiRxDma,
 }
 
-macro_rules! burst_fifo_read {
-    ($spi:ident, $cs:ident, $length:expr) => {{
-        let mut v = [0u8; 153666];
-        for i in 0..BMP_HEADER.len() {
-            v[i] = BMP_HEADER[i];
+impl<SPI: SpiBus, CS: OutputPin, I2C: I2c> Camera<SPI, CS, I2C> {
+    /// Starts resetting and checking the ArduCAM, then configuring the
+    /// sensor, which [`step`](Self::step) advances.
+    fn start_reset(&mut self) {
+        self.prefetch = Prefetch::Empty;
+        if let Some(motion) = &mut self.motion {
+            motion.reset();
         }
-        $cs.set_low();
-        $spi.write(&[ARDUCHIP_BURST_FIFO_READ]).expect("SPI write");
-        let mut i = BMP_HEADER.len();
-        for _ in 0..153600 / 2 {
-            let vh = $spi.transfer(&mut [0u8]).expect("SPI read")[0];
-            v[i] = $spi.transfer(&mut [0u8]).expect("SPI read")[0];
-            v[i + 1] = vh;
-            i += 2;
+        self.reset = Some(Reset::new());
+    }
+
+    /// Advances the reset in progress, retrying it later if it fails.
+    fn poll_reset(&mut self, now_ms: u64) {
+        let Some(reset) = &mut self.reset else {
+            return;
+        };
+        let settings = self.settings;
+        let polled = reset.poll(&mut self.arduchip, &mut self.sensor, now_ms, |sensor| {
+            sensor.configure(settings.format, settings.resolution)?;
+            sensor.set_mounting(settings.mounting)?;
+            if settings.format == OutputFormat::Jpeg {
+                sensor.set_jpeg_quality(settings.jpeg_quality)?;
+            }
+            Ok(())
+        });
+        match polled {
+            Ok(true) => {
+                defmt::println!("Camera ready");
+                self.reset = None;
+            }
+            Ok(false) => {}
+            Err(error) => {
+                // A missing camera is retried less and less often
+                let retry = reset.retry(now_ms);
+                self.stats.reinits += 1;
+                defmt::println!("Reset failed: {}, failures: {=u32}", error, retry.failures());
+                self.reset = Some(retry);
+            }
         }
-        $cs.set_high();
-        v
-    }};
-}
+    }
+
+    /// Logs `error`, drops the response in progress, answering its request
+    /// or ending the stream unless the connection failed, and reinitialises
+    /// the camera if it cannot recover otherwise.
+    fn recover(&mut self, error: CameraError) {
+        defmt::println!("Error: {}", error);
+        let (answering, streaming) = (self.answering, self.stream.is_some());
+        self.cancel();
+        if error.needs_reinit() {
+            self.stats.reinits += 1;
+            defmt::println!("Reinitialising camera: {}", self.stats);
+            self.start_reset();
+        }
+        if let CameraError::Network(_) = error {
+            // The socket is aborted
+            return;
+        }
+        if answering {
+            // The requests pipelined after it were dropped
+            self.connection = Connection::Close;
+            self.unavailable();
+        } else if streaming {
+            // Nothing is left to send before closing the connection
+            self.connection = Connection::Close;
+            self.state = State::Sending { response: 0..0, sent: 0 };
+        }
+    }
+
+    /// Answers that the frames are unavailable, the camera failing or being
+    /// reset.
+    fn unavailable(&mut self) {
+        let status = Status::ServiceUnavailable;
+        let body = format!("{}\n", status.reason());
+        self.respond(status, "text/plain", body.as_bytes(), &[]);
+    }
+
+    /// Drops the response in progress and the requests of the client.
+    fn cancel(&mut self) {
+        self.received = 0;
+        self.stream = None;
+        self.answering = false;
+        if let State::Draining { .. } = self.state {
+            // The reception cannot be aborted, it ends within 100 ms.
+            while self.dma.is_busy() {
+                let _ = self.dma.poll();
+            }
+            // The burst is terminated even if the bus failed, the ArduChip is
+            // reset if it did.
+            let _ = self.arduchip.end_burst_read();
+            // The previous frame was partly replaced
+            if let Some(motion) = &mut self.motion {
+                motion.reset();
+            }
+        }
+        self.state = State::Idle;
+    }
+
+    /// Advances the response to the request received on `socket` by a step.
+    fn step(&mut self, socket: &mut tcp::Socket, now_ms: u64) -> Result<(), CameraError> {
+        if !socket.is_open() {
+            defmt::println!("Socket OPEN");
+            self.received = 0;
+            socket.listen(80).map_err(|_| NetworkError::Listen)?;
+        }
+        let busy = !matches!(self.state, State::Idle) || self.stream.is_some();
+        if busy && !socket.is_active() {
+            defmt::println!("Client left");
+            self.cancel();
+            return Ok(());
+        }
+        self.poll_reset(now_ms);
+
+        if self.reset.is_none() && matches!(self.state, State::Idle | State::Sending { .. }) {
+            // The FIFO is free
+            self.prefetch.poll(&mut self.arduchip, now_ms, &mut self.stats)?;
+        }
+
+        match self.state {
+            State::Idle if self.stream.is_some() => {
+                let stream = self.stream.as_mut().expect("streaming");
+                if now_ms >= stream.next_ms && socket.can_send() {
+                    // Late frames delay the next ones rather than bunching up
+                    stream.next_ms = (stream.next_ms + stream.interval_ms).max(now_ms);
+                    let Stream { policy, format, color, .. } = *stream;
+                    self.start_frame(policy, format, color, now_ms)?;
+                }
+            }
+            State::Idle => {
+                if socket.can_recv() && self.received < self.request.len() {
+                    self.received += socket
+                        .recv_slice(&mut self.request[self.received..])
+                        .map_err(|_| NetworkError::Recv)?;
+                }
+                if self.received > 0 && socket.can_send() {
+                    self.answer(now_ms)?;
+                }
+            }
+            State::Capturing(ref mut capture) => {
+                if let Some(length) = capture.poll(&mut self.arduchip, now_ms, &mut self.stats)? {
+                    self.start_draining(length)?;
+                }
+            }
+            State::Draining { length, read, receiving } => {
+                let Some(received) = self.dma.poll() else {
+                    return Ok(());
+                };
+                received.map_err(|_| CameraError::Dma)?;
+                if let Some(encoder) = &mut self.encoder {
+                    let output = &mut self.buffer[HTTP_HEADER_ROOM..];
+                    encoder.write_rows(&self.raw[..receiving], output)?;
+                }
+                if let Some(motion) = &mut self.motion {
+                    motion.write_rows(&self.raw[..receiving]);
+                }
+                let read = read + receiving;
+                if read == length {
+                    self.finish_draining(length)?;
+                } else {
+                    let receiving = self.receive(length, read);
+                    self.state = State::Draining { length, read, receiving };
+                }
+            }
+            State::Sending { ref response, ref mut sent } => {
+                *sent += socket
+                    .send_slice(&self.buffer[response.start + *sent..response.end])
+                    .map_err(|_| NetworkError::Send)?;
+                if *sent == response.len() {
+                    defmt::println!("RESPONSE SENT");
+                    self.state = State::Idle;
+                    if self.stream.is_none() && self.connection == Connection::Close {
+                        // The requests pipelined after it are dropped
+                        self.received = 0;
+                        socket.close();
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Parses the first request received and starts its response, once it
+    /// is whole.
+    fn answer(&mut self, now_ms: u64) -> Result<(), CameraError> {
+        let (action, length) = match http::parse(&self.request[..self.received], self.request.len()) {
+            Ok(Parsed::Complete { request, length }) => {
+                defmt::println!("{} {=str}", request.method, request.target);
+                self.connection = request.connection();
+                let previous = self.settings;
+                let action = action(&request, &mut self.settings);
+                let settings = &self.settings;
+                let resized = settings.resolution != previous.resolution;
+                if resized || settings.motion != previous.motion || settings.background != previous.background {
+                    // The new model learns from the next frame
+                    self.motion = None;
+                    self.motion = unsafe { motion_detector(&self.settings) };
+                }
+                let requantized = settings.format == OutputFormat::Jpeg
+                    && settings.jpeg_quality != previous.jpeg_quality;
+                let remounted = settings.mounting != previous.mounting;
+                if resized || requantized || remounted {
+                    // The sensor is configured again with the settings
+                    self.start_reset();
+                }
+                (action, length)
+            }
+            Ok(Parsed::Partial) if self.received < self.request.len() => return Ok(()),
+            Ok(Parsed::Partial) => {
+                defmt::println!("Request too large");
+                self.connection = Connection::Close;
+                (Action::BAD_REQUEST, self.received)
+            }
+            Err(error) => {
+                // The end of the invalid request is unknown, what follows is
+                // dropped with it and the connection.
+                defmt::println!("Invalid request: {}", error);
+                self.connection = Connection::Close;
+                (Action::BAD_REQUEST, self.received)
+            }
+        };
+        self.request.copy_within(length..self.received, 0);
+        self.received -= length;
+
+        match action {
+            Action::Frame { .. } | Action::Stream(_) if self.reset.is_some() => self.unavailable(),
+            Action::Frame { policy, format, color } => {
+                self.answering = true;
+                self.start_frame(policy, format, color, now_ms)?;
+            }
+            Action::Stream(stream) => {
+                defmt::println!("STREAM every {=u64} ms", stream.interval_ms);
+                self.stream = Some(Stream { next_ms: now_ms, ..stream });
+                // The parts are delimited by their boundaries, the body ends
+                // with the connection.
+                let response = Response {
+                    content_length: None,
+                    ..Response::new(Status::Ok, http::STREAM_CONTENT_TYPE, 0)
+                };
+                self.send(response, HTTP_HEADER_ROOM..HTTP_HEADER_ROOM);
+            }
+            Action::Status => {
+                let resolution = self.settings.resolution;
+                let (moving, blobs) = match &self.motion_result {
+                    Some(result) => (result.score.moving.to_string(), blobs_json(result.blobs())),
+                    None => ("null".to_string(), "null".to_string()),
+                };
+                let body = format!(
+                    "{{\"width\":{},\"height\":{},\"output\":\"{:?}\",\"uptime_ms\":{},\"timeouts\":{},\"reinits\":{},\"moving\":{},\"blobs\":{}}}\n",
+                    resolution.width(),
+                    resolution.height(),
+                    self.settings.format,
+                    now_ms,
+                    self.stats.timeouts,
+                    self.stats.reinits,
+                    moving,
+                    blobs
+                );
+                self.respond(Status::Ok, "application/json", body.as_bytes(), &[]);
+            }
+            Action::Config => {
+                let body = self.settings.form();
+                self.respond(Status::Ok, "text/plain", body.as_bytes(), &[]);
+            }
+            Action::Reject { status, allow } => {
+                let methods: Vec<_> = allow.iter().map(|method| method.name()).collect();
+                let methods = methods.join(", ");
+                let allow_header = [Header { name: "Allow", value: &methods }];
+                let headers: &[Header] = if allow.is_empty() { &[] } else { &allow_header };
+                let body = format!("{}\n", status.reason());
+                self.respond(status, "text/plain", body.as_bytes(), headers);
+            }
+        }
+        Ok(())
+    }
+
+    /// Starts capturing or reading a frame to answer a request or continue a
+    /// stream.
+    fn start_frame(
+        &mut self,
+        policy: FramePolicy,
+        format: ImageFormat,
+        color: image::Color,
+        now_ms: u64,
+    ) -> Result<(), CameraError> {
+        self.encoder = self.encoder(format, color);
+        match self.prefetch.take(policy, &mut self.arduchip, now_ms)? {
+            Prefetch::Ready { length, .. } => self.start_draining(length)?,
+            Prefetch::Capturing(capture) => self.state = State::Capturing(capture),
+            Prefetch::Empty => unreachable!("taken frames are never empty"),
+        }
+        Ok(())
+    }
+
+    /// Sends a response with a short `body`, copied to the frame buffer.
+    fn respond(&mut self, status: Status, content_type: &str, body: &[u8], headers: &[Header]) {
+        let body_range = HTTP_HEADER_ROOM..HTTP_HEADER_ROOM + body.len();
+        self.buffer[body_range.clone()].copy_from_slice(body);
+        let response = Response { headers, ..Response::new(status, content_type, body.len()) };
+        self.send(response, body_range);
+    }
+
+    /// Puts the head of `response` in front of the `body` bytes of the frame
+    /// buffer and starts sending them.
+    fn send(&mut self, response: Response, body: Range<usize>) {
+        defmt::println!("RESPONSE {}", response.status.code());
+        self.answering = false;
+        // The capture failures are reported so that a hung camera is visible
+        // to clients.
+        let timeouts = self.stats.timeouts.to_string();
+        let reinits = self.stats.reinits.to_string();
+        let mut headers = response.headers.to_vec();
+        headers.push(Header { name: "X-Capture-Timeouts", value: &timeouts });
+        headers.push(Header { name: "X-Camera-Reinits", value: &reinits });
+        let mut head = Response { connection: self.connection, headers: &headers, ..response }.to_string();
+        if head.len() > body.start {
+            // The body is still served, without the headers that do not fit
+            defmt::println!("RESPONSE HEAD OF {} BYTES TRUNCATED", head.len());
+            head = Response { connection: self.connection, headers: &[], ..response }.to_string();
+        }
+        self.send_head(&head, body);
+    }
+
+    /// Puts `head` in front of the `body` bytes of the frame buffer and
+    /// starts sending them, or an empty 500 response closing the connection
+    /// if it does not fit.
+    fn send_head(&mut self, head: &str, body: Range<usize>) {
+        if head.len() > body.start {
+            defmt::println!("RESPONSE HEAD OF {} BYTES DOES NOT FIT", head.len());
+            self.stream = None;
+            self.connection = Connection::Close;
+            let error = Response {
+                connection: Connection::Close,
+                ..Response::new(Status::InternalServerError, "text/plain", 0)
+            };
+            return self.send_head(&error.to_string(), body.start..body.start);
+        }
+        let start = body.start - head.len();
+        self.buffer[start..body.start].copy_from_slice(head.as_bytes());
+        self.state = State::Sending { response: start..body.end, sent: 0 };
+    }
+
+    /// Starts reading the frame of `length` bytes captured in the FIFO.
+    fn start_draining(&mut self, length: u32) -> Result<(), CameraError> {
+        let length = match self.settings.format {
+            OutputFormat::Jpeg => {
+                let max = self.buffer.len() - HTTP_HEADER_ROOM;
+                if length as usize > max {
+                    return Err(CameraError::FrameTooLarge { length, max: max as u32 });
+                }
+                length
+            }
+            OutputFormat::Rgb565 | OutputFormat::Yuv422 => {
+                let expected = self.settings.resolution.rgb565_length();
+                if length < expected {
+                    return Err(CameraError::ShortFifo { length, expected });
+                }
+                if let Some(encoder) = &mut self.encoder {
+                    encoder.start(&mut self.buffer[HTTP_HEADER_ROOM..])?;
+                }
+                if let Some(motion) = &mut self.motion {
+                    motion.start();
+                }
+                expected
+            }
+        };
+        self.arduchip.start_burst_read()?;
+        let length = length as usize;
+        let receiving = self.receive(length, 0);
+        self.state = State::Draining { length, read: 0, receiving };
+        Ok(())
+    }
 
-fn rgb565_to_gray(pixel: u16) -> u8 {
-    let r = (pixel >> 11) & 0x1F;
-    let g = (pixel >> 5) & 0x3F;
-    let b = pixel & 0x1F;
+    /// Starts the DMA reception of the next bytes of the frame of `length`
+    /// bytes, of which `read` are done, and returns how many.
+    fn receive(&mut self, length: usize, read: usize) -> usize {
+        let (destination, count) = match self.settings.format {
+            OutputFormat::Jpeg => (
+                &mut self.buffer[HTTP_HEADER_ROOM + read..],
+                dma::MAX_TRANSFER.min(length - read),
+            ),
+            OutputFormat::Rgb565 | OutputFormat::Yuv422 => {
+                // Whole strips for the encoder
+                let chunk = match &self.encoder {
+                    Some(encoder) => {
+                        let strip = encoder.strip_rows() * self.settings.resolution.width() as usize * 2;
+                        RAW_CHUNK_SIZE / strip * strip
+                    }
+                    None => RAW_CHUNK_SIZE,
+                };
+                (&mut self.raw[..], chunk.min(length - read))
+            }
+        };
+        // SAFETY: the buffers are static and not accessed while draining
+        unsafe { self.dma.start(destination[..count].as_mut_ptr(), count as u16) };
+        count
+    }
 
-    // Mise à l’échelle intégrée
-    let y =
-        77 * (r * 255 / 31) +
-            150 * (g * 255 / 63) +
-            29 * (b * 255 / 31);
+    /// Ends the read of the FIFO and puts the HTTP headers in front of the
+    /// frame.
+    fn finish_draining(&mut self, length: usize) -> Result<(), CameraError> {
+        self.state = State::Idle;
+        self.arduchip.end_burst_read()?;
+
+        let (content_type, body) = match self.settings.format {
+            OutputFormat::Jpeg => {
+                let data = &self.buffer[HTTP_HEADER_ROOM..HTTP_HEADER_ROOM + length];
+                let bounds = jpeg::frame_bounds(data).ok_or(CameraError::InvalidJpeg)?;
+                ("image/jpeg", HTTP_HEADER_ROOM + bounds.start..HTTP_HEADER_ROOM + bounds.end)
+            }
+            OutputFormat::Rgb565 | OutputFormat::Yuv422 => {
+                let encoder = self.encoder.as_mut().expect("raw frames are encoded");
+                let length = encoder.finish(&mut self.buffer[HTTP_HEADER_ROOM..])?;
+                self.motion_result = self.motion.as_mut().and_then(|motion| motion.finish());
+                (encoder.content_type(), HTTP_HEADER_ROOM..HTTP_HEADER_ROOM + length)
+            }
+        };
+
+        if self.stream.is_some() {
+            let part = Part { content_type, content_length: body.len() };
+            self.send_head(&part.to_string(), body);
+        } else {
+            let motion = self.motion_result.map(|result| {
+                (result.score.moving.to_string(), result.blobs.found.to_string())
+            });
+            let headers = match &motion {
+                Some((moving, blobs)) => vec![
+                    Header { name: "X-Motion-Pixels", value: moving },
+                    Header { name: "X-Motion-Blobs", value: blobs },
+                ],
+                None => Vec::new(),
+            };
+            let response = Response {
+                headers: &headers,
+                ..Response::new(Status::Ok, content_type, body.len())
+            };
+            self.send(response, body);
+        }
+        Ok(())
+    }
 
-    (y >> 8) as u8
+    /// Encoder of the frame being served in `format` and `color`, `None`
+    /// for the JPEG frames of the sensor.
+    fn encoder(&self, format: ImageFormat, color: image::Color) -> Option<AnyEncoder> {
+        let input = match self.settings.format {
+            OutputFormat::Rgb565 => image::Input::Rgb565,
+            OutputFormat::Yuv422 => image::Input::Yuv422,
+            OutputFormat::Jpeg => return None,
+        };
+        let resolution = self.settings.resolution;
+        let layout = image::Layout::new(
+            resolution.width() as u16,
+            resolution.height() as u16,
+            input,
+            color,
+        );
+        let options = image::Options {
+            orientation: self.settings.orientation,
+            png_compression: self.settings.png_compression,
+            jpeg_quality: self.settings.encoder_quality,
+        };
+        Some(AnyEncoder::new(format, layout, &options))
+    }
 }
 
-pub fn rgb565_buffer_to_gray(
-    src: &[u8],
-    dst: &mut [u8],
-) {
-    let mut j = 0;
+/// JSON array of the area, bounding box and centroid of `blobs`.
+fn blobs_json(blobs: &[blob::Blob]) -> String {
+    let blobs: Vec<_> = blobs
+        .iter()
+        .map(|blob| {
+            let (x, y) = blob.centroid();
+            format!(
+                "{{\"area\":{},\"box\":[{},{},{},{}],\"centroid\":[{},{}]}}",
+                blob.area, blob.left, blob.top, blob.right, blob.bottom, x, y
+            )
+        })
+        .collect();
+    format!("[{}]", blobs.join(","))
+}
 
-    for i in (0..src.len()).step_by(2) {
-        let pixel = u16::from_be_bytes([src[i], src[i + 1]]);
-        dst[j] = rgb565_to_gray(pixel);
-        j += 1;
+/// Zeroes a buffer placed in a section the runtime does not initialise.
+///
+/// # Safety
+///
+/// Must be called on a buffer of integers or of [`Label::FREE`] entries,
+/// which are zeroes, once the previous slice of the buffer is dropped.
+unsafe fn zeroed<T, const N: usize>(buffer: *mut MaybeUninit<[T; N]>) -> &'static mut [T] {
+    unsafe {
+        let elements = buffer.cast::<T>();
+        elements.write_bytes(0, N);
+        core::slice::from_raw_parts_mut(elements, N)
     }
 }
 
+/// The motion detector of the frames of `settings`, none for JPEG frames,
+/// with its model in a buffer shared by all of them.
+///
+/// # Safety
+///
+/// The detector previously returned must be dropped.
+unsafe fn motion_detector(settings: &Settings) -> Option<Detector<'static>> {
+    static mut MOTION_MODEL: MaybeUninit<[u16; MOTION_MODEL_PIXELS * 3 / 2]> = MaybeUninit::uninit();
+    static mut BLOB_ROW: MaybeUninit<[u16; MOTION_MAX_WIDTH]> = MaybeUninit::uninit();
+    static mut BLOB_TABLE: MaybeUninit<[Label; MOTION_BLOB_LABELS]> = MaybeUninit::uninit();
+    let input = match settings.format {
+        OutputFormat::Rgb565 => image::Input::Rgb565,
+        OutputFormat::Yuv422 => image::Input::Yuv422,
+        OutputFormat::Jpeg => return None,
+    };
+    let buffer = &raw mut MOTION_MODEL;
+    let model = match settings.motion {
+        MotionModel::Difference => {
+            let previous = buffer.cast::<MaybeUninit<[u8; MOTION_MODEL_PIXELS]>>();
+            Model::Previous(unsafe { zeroed(previous) })
+        }
+        MotionModel::Background => {
+            let mean = buffer.cast::<MaybeUninit<[u16; MOTION_MODEL_PIXELS]>>();
+            Model::Background(Background::new(settings.background, unsafe { zeroed(mean) }))
+        }
+        MotionModel::Mixture => {
+            let pixels = buffer.cast::<MaybeUninit<[[u8; 3]; MOTION_MODEL_PIXELS]>>();
+            Model::Mixture(Mixture::new(settings.mixture, unsafe { zeroed(pixels) }))
+        }
+    };
+    let (width, height) = (settings.resolution.width(), settings.resolution.height());
+    let (labels, table) = unsafe { (zeroed(&raw mut BLOB_ROW), zeroed(&raw mut BLOB_TABLE)) };
+    match Detector::new(width as u16, height as u16, input, model) {
+        Ok(detector) => Some(detector.with_blobs(settings.blobs, labels, table)),
+        Err(error) => {
+            defmt::println!("NO MOTION DETECTION: {}", error);
+            None
+        }
+    }
+}
 
-#[global_allocator]
-static ALLOCATOR: EmbeddedAllocator = EmbeddedAllocator::empty();
+#[interrupt]
+fn DMA1_STR0() {
+    SpiRxDma::on_interrupt();
+}
 
 // the program entry point
 #[cortex_m_rt::entry]
@@ -685,27 +951,35 @@ fn main() -> ! {
         static mut ALLOCATION_BUFFER: [MaybeUninit<u8>; ALLOCATION_BUFFER_SIZE] =
             [MaybeUninit::uninit(); ALLOCATION_BUFFER_SIZE];
         unsafe {
-            ALLOCATOR.init(&raw mut ALLOCATION_BUFFER as usize, ALLOCATION_BUFFER_SIZE);
+            stm32h755zi::ALLOCATOR.init(&raw mut ALLOCATION_BUFFER as usize, ALLOCATION_BUFFER_SIZE);
         }
     }
 
     let dp = stm32::Peripherals::take().unwrap();
-    let cp = stm32::CorePeripherals::take().unwrap();
+    let mut cp = stm32::CorePeripherals::take().unwrap();
 
     // Power
     let pwrcfg = dp.PWR.constrain().freeze();
 
     // Clocks...
+    // SRAM1 to SRAM3 hold the DMA buffers
+    dp.RCC
+        .ahb2enr
+        .modify(|_, w| w.sram1en().set_bit().sram2en().set_bit().sram3en().set_bit());
     let rcc = dp.RCC.constrain();
     let ccdr = rcc
         .sys_ck(200.MHz())
         .hclk(200.MHz())
         .pll1_r_ck(100.MHz()) // for TRACECK
-        .pll1_q_ck(48.MHz())
+        // Kernel clock of SPI1, the 400 MHz VCO divided by 25, which the
+        // SPI divides by 2 for the 8 MHz of the ArduCAM
+        .pll1_q_ck(16.MHz())
         .freeze(pwrcfg, &dp.SYSCFG);
 
-    // Get the delay provider.
-    let mut delay = cp.SYST.delay(ccdr.clocks);
+    // Monotonic clock from the cycle counter, sampled at least every loop
+    cp.DCB.enable_trace();
+    cp.DWT.enable_cycle_counter();
+    let mut clock = CycleClock::new(ccdr.clocks.sys_ck().raw() / 1_000_000, DWT::cycle_count());
 
     // Initialise IO...
     let gpioa = dp.GPIOA.split(ccdr.peripheral.GPIOA);
@@ -738,6 +1012,7 @@ fn main() -> ! {
     assert_eq!(ccdr.clocks.pclk1().raw(), 100_000_000); // PCLK 100MHz
     assert_eq!(ccdr.clocks.pclk2().raw(), 100_000_000); // PCLK 100MHz
     assert_eq!(ccdr.clocks.pclk4().raw(), 100_000_000); // PCLK 100MHz
+    assert_eq!(ccdr.clocks.pll1_q_ck().map(|ck| ck.raw()), Some(16_000_000)); // SPI1 16MHz
 
     let mac_addr = smoltcp::wire::EthernetAddress::from_bytes(&MAC_ADDRESS);
     let (mut eth_dma, _eth_mac) = unsafe {
@@ -769,7 +1044,7 @@ fn main() -> ! {
     let mut iface = Interface::new(
         Config::new(EthernetAddress::from_bytes(&MAC_ADDRESS).into()),
         &mut eth_dma,
-        Instant::from_micros(cortex_m::peripheral::DWT::cycle_count() / 200),
+        Instant::from_micros(clock.update(DWT::cycle_count()) as i64),
     );
     iface.update_ip_addrs(|ip_addrs| {
         ip_addrs
@@ -784,123 +1059,80 @@ fn main() -> ! {
     let mut sockets = SocketSet::new(vec![]);
     let socket_handle = sockets.add(tcp_socket);
 
-    let mut spi: spi::Spi<pac::SPI1, _, u8> = dp.SPI1.spi(
+    let spi: spi::Spi<pac::SPI1, _, u8> = dp.SPI1.spi(
         (sck, miso, mosi),
         spi::MODE_0,
-        3.MHz(),
+        // Fastest clock of the ArduCAM, half of the 16 MHz kernel clock
+        8.MHz(),
         ccdr.peripheral.SPI1,
         &ccdr.clocks,
     );
-    let mut i2c = dp
+    let i2c = dp
         .I2C1
         .i2c((scl, sda), 100.kHz(), ccdr.peripheral.I2C1, &ccdr.clocks);
 
-    // ArduCAM reset
-    spi_write!(spi, cs, 0x07, 0x80);
-    delay.delay_ms(100_u16);
-    spi_write!(spi, cs, 0x07, 0x00);
-    delay.delay_ms(100_u16);
-
-    // SPI Test
-    spi_write!(spi, cs, ARDUCHIP_TEST1, 0x55);
-    //defmt::println!("SPI_TEST = 0x{=u8:X}", spi_read!(spi, cs, ARDUCHIP_TEST1));
-
-    // I2C Test
-    i2c_write!(i2c, 0xFF, 0x01);
-    //defmt::println!("VID = 0x{=u8:X}", i2c_read!(i2c, OV2640_CHIPID_HIGH));
-    //defmt::println!("PID = 0x{=u8:X}", i2c_read!(i2c, OV2640_CHIPID_LOW));
-
-    // Camera Init
-    i2c_write!(i2c, 0xFF, 0x01);
-    i2c_write!(i2c, 0x12, 0x80);
-    delay.delay_ms(100_u16);
-
     // Camera config
-    for [reg, val] in OV2640_QVGA {
-        i2c_write!(i2c, reg, val);
-    }
-    delay.delay_ms(1000_u16);
+    let settings = Settings {
+        resolution: Resolution::Qvga,
+        // Luma for the motion detection, colour converted on request
+        format: OutputFormat::Yuv422,
+        jpeg_quality: 12,
+        frame_policy: FramePolicy::Latest,
+        image_format: ImageFormat::Bmp,
+        bmp_format: bmp::Format::Gray8,
+        // Upright frames, read top row first
+        mounting: Mounting::UPRIGHT,
+        orientation: bmp::Orientation::TopDown,
+        encoder_quality: 75,
+        png_compression: png::Compression::Fixed,
+        motion: MotionModel::Difference,
+        background: motion::BackgroundOptions::default(),
+        mixture: motion::MixtureOptions::default(),
+        blobs: BlobOptions::default(),
+        stream_fps: 10,
+    };
+    assert!(settings.resolution.fits(settings.format, &FRAME_BUFFERS));
+    #[unsafe(link_section = ".sram12")]
+    static mut FRAME_BUFFER: MaybeUninit<[u8; FRAME_BUFFER_SIZE]> = MaybeUninit::uninit();
+    #[unsafe(link_section = ".sram3")]
+    static mut RAW_CHUNK: MaybeUninit<[u8; RAW_CHUNK_SIZE]> = MaybeUninit::uninit();
+    let motion = unsafe { motion_detector(&settings) };
+    ccdr.peripheral.DMA1.enable();
+    let mut camera = Camera {
+        arduchip: ArduChip::new(Compat(spi), Compat(cs)),
+        sensor: Ov2640::new(Compat(i2c)),
+        reset: None,
+        settings,
+        stats: CaptureStats::default(),
+        state: State::Idle,
+        prefetch: Prefetch::Empty,
+        encoder: None,
+        request: [0; REQUEST_BUFFER_SIZE],
+        received: 0,
+        connection: Connection::KeepAlive,
+        stream: None,
+        answering: false,
+        motion,
+        motion_result: None,
+        buffer: unsafe { zeroed(&raw mut FRAME_BUFFER) },
+        raw: unsafe { zeroed(&raw mut RAW_CHUNK) },
+        dma: SpiRxDma::new(dp.DMA1, dp.DMAMUX1),
+    };
+    camera.start_reset();
+
     defmt::println!("BEGIN LOOP");
-    let mut capture_requested = false;
     loop {
 
-        let timestamp = Instant::from_micros(cortex_m::peripheral::DWT::cycle_count() / 200);
-        iface.poll(timestamp, &mut eth_dma, &mut sockets);
+        let now_us = clock.update(DWT::cycle_count());
+        iface.poll(Instant::from_micros(now_us as i64), &mut eth_dma, &mut sockets);
 
         let socket = sockets.get_mut::<tcp::Socket>(socket_handle);
 
-        if !socket.is_open() {
-            defmt::println!("Socket OPEN");
-            socket.listen(80).unwrap();
-        }
-
-        if socket.may_recv() {
-            let data_received = socket
-                .recv(|buffer| {
-                    if !buffer.is_empty() {
-                        defmt::println!("{=str}", str::from_utf8(buffer).unwrap());
-                        (buffer.len(), true)
-                    } else {
-                        (0, false)
-                    }
-                })
-                .unwrap();
-            if socket.can_send() && data_received {
-                // Take photo
-                if !capture_requested {
-                    capture_requested = true;
-                    clear_fifo_flag!(spi, cs);
-                    start_capture!(spi, cs);
-                }
-
-                while !capture_done!(spi, cs) {
-                    delay.delay_ms(10_u16);
-                }
-                delay.delay_ms(50_u16);
-
-                let length = read_fifo_flag!(spi, cs);
-
-                if length >= 153600 {
-                    capture_requested = false;
-                    defmt::println!("RESPONSE");
-                    socket
-                        .send_slice("HTTP/1.1 200\nContent-Type: image/bmp\nContent-Length: 77878\n\n".as_bytes())
-                        .unwrap();
-                    socket.send_slice(&IMAGE_HEADER).unwrap();
-                    let mut finished = false;
-                    cs.set_low();
-                    spi.write(&[ARDUCHIP_BURST_FIFO_READ]).expect("SPI write");
-                    let mut send_length = 0;
-                    while send_length < 76800 {
-                        send_length += socket
-                            .send(|buffer| {
-                                let mut written_length = 0;
-                                let mut raw = [0u8; 153600];
-                                spi.transfer(&mut raw).expect("SPI burst read");
-                                for i in 0..buffer.len() {
-                                    if send_length + written_length == 76800 {
-                                        break;
-                                    }
-                                    let byte1 = raw[2*i] as u16;
-                                    let byte2 = raw[2*i + 1] as u16;
-                                    let color = (byte1 << 8) | byte2;
-                                    buffer[i] = rgb565_to_gray(color);
-                                    written_length += 1;
-                                    if written_length < 1 {
-                                        defmt::println!("{=u8:02X}", buffer[i]);
-                                    }
-                                }
-                                (written_length, written_length)
-                            })
-                            .unwrap();
-                    }
-                    //defmt::println!("Socket CLOSE");
-                    //socket.close();
-                }
+        if let Err(error) = camera.step(socket, now_us / 1000) {
+            if let CameraError::Network(_) = error {
+                socket.abort();
             }
-        } else if socket.may_send() {
-            //defmt::println!("Socket CLOSE");
-            //socket.close();
+            camera.recover(error);
         }
     }
 }
@@ -0,0 +1,612 @@
+//! Connected-component labelling of the moving pixels, into blobs with their
+//! area, bounding box and centroid.
+//!
+//! The mask is labelled one row at a time as the motion detector votes on
+//! it, so that it is never kept whole: only the labels of the last row and a
+//! union-find table of the components touching it are, both in buffers of
+//! the caller. At the end of each row the labels of the row are replaced by
+//! the roots of their components, the other entries of the table freed, and
+//! the components that did not reach the row are finished, so that the table
+//! only needs room for the components crossing a row, not for the whole
+//! frame. The pixels of a component that finds the table full are left
+//! unlabelled and counted.
+//!
+//! ```ignore
+//! let mut labeller = Labeller::new(320, 240, BlobOptions::default(), &mut row, &mut table);
+//! labeller.start();
+//! // 10 words of 32 pixels by row
+//! for row in mask.chunks(10) {
+//!     labeller.write_row(row);
+//! }
+//! let blobs = labeller.finish();
+//! ```
+
+/// Largest blobs kept of each frame.
+pub const MAX_BLOBS: usize = 8;
+
+/// Neighbours of a pixel that belong to its blob.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Connectivity {
+    /// The pixels above, below, left and right.
+    Four,
+    /// The diagonals too.
+    Eight,
+}
+
+/// Settings of the [`Labeller`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct BlobOptions {
+    pub connectivity: Connectivity,
+    /// Pixels of the smallest blobs reported, the smaller ones being noise.
+    pub min_area: u32,
+}
+
+impl Default for BlobOptions {
+    fn default() -> Self {
+        Self {
+            connectivity: Connectivity::Eight,
+            // A 5x5 square, about the smallest that survives the 3x3 vote
+            min_area: 25,
+        }
+    }
+}
+
+/// Connected moving pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Blob {
+    /// Pixels of the blob, 0 for the free entries of the table.
+    pub area: u32,
+    /// Bounding box, its edges included.
+    pub left: u16,
+    pub top: u16,
+    pub right: u16,
+    pub bottom: u16,
+    /// Sums of the coordinates of the pixels, which fit up to 1600x1600
+    /// frames.
+    sum_x: u32,
+    sum_y: u32,
+}
+
+impl Blob {
+    const EMPTY: Blob = Blob {
+        area: 0,
+        left: 0,
+        top: 0,
+        right: 0,
+        bottom: 0,
+        sum_x: 0,
+        sum_y: 0,
+    };
+
+    fn pixel(x: u16, y: u16) -> Self {
+        Self {
+            area: 1,
+            left: x,
+            top: y,
+            right: x,
+            bottom: y,
+            sum_x: x as u32,
+            sum_y: y as u32,
+        }
+    }
+
+    fn merge(&mut self, other: &Blob) {
+        self.area += other.area;
+        self.left = self.left.min(other.left);
+        self.top = self.top.min(other.top);
+        self.right = self.right.max(other.right);
+        self.bottom = self.bottom.max(other.bottom);
+        self.sum_x += other.sum_x;
+        self.sum_y += other.sum_y;
+    }
+
+    pub fn width(&self) -> u16 {
+        self.right - self.left + 1
+    }
+
+    pub fn height(&self) -> u16 {
+        self.bottom - self.top + 1
+    }
+
+    /// Average position of the pixels, rounded to the nearest pixel.
+    pub fn centroid(&self) -> (u16, u16) {
+        let average = |sum: u32| ((sum + self.area / 2) / self.area) as u16;
+        (average(self.sum_x), average(self.sum_y))
+    }
+}
+
+/// Entry of the union-find table of a [`Labeller`], for the label one past
+/// its index.
+#[derive(Debug, Clone, Copy)]
+pub struct Label {
+    /// Label of the parent, itself for a root, or of the next free entry for
+    /// a free one, 0 ending the list.
+    parent: u16,
+    /// Pixels of the component, merged into its root once it has a parent,
+    /// empty for a free entry.
+    blob: Blob,
+}
+
+impl Label {
+    /// Free entry, all zeroes.
+    pub const FREE: Label = Label {
+        parent: 0,
+        blob: Blob::EMPTY,
+    };
+}
+
+/// Largest blobs of a frame, the largest first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Blobs {
+    blobs: [Blob; MAX_BLOBS],
+    kept: u8,
+    /// Blobs at least as large as the minimum area, kept or not.
+    pub found: u32,
+    /// Moving pixels that found the table full, in no blob.
+    pub unlabelled: u32,
+}
+
+impl Blobs {
+    pub const EMPTY: Blobs = Blobs {
+        blobs: [Blob::EMPTY; MAX_BLOBS],
+        kept: 0,
+        found: 0,
+        unlabelled: 0,
+    };
+
+    /// The [`MAX_BLOBS`] largest blobs at most, the largest first.
+    pub fn as_slice(&self) -> &[Blob] {
+        &self.blobs[..self.kept as usize]
+    }
+
+    /// Keeps `blob` if it is among the largest so far.
+    fn push(&mut self, blob: Blob) {
+        self.found += 1;
+        let kept = self.kept as usize;
+        if kept < MAX_BLOBS {
+            self.blobs[kept] = blob;
+            self.kept += 1;
+        } else {
+            let smallest = (0..MAX_BLOBS)
+                .min_by_key(|&i| self.blobs[i].area)
+                .expect("MAX_BLOBS is not 0");
+            if blob.area > self.blobs[smallest].area {
+                self.blobs[smallest] = blob;
+            }
+        }
+    }
+
+    fn sort(&mut self) {
+        self.blobs[..self.kept as usize]
+            .sort_unstable_by_key(|blob| (u32::MAX - blob.area, blob.top, blob.left));
+    }
+}
+
+/// Labels a bit-packed mask one row at a time, from the top.
+pub struct Labeller<'a> {
+    width: u16,
+    height: u16,
+    options: BlobOptions,
+    /// Labels of the pixels of the last row, 0 for the still ones.
+    labels: &'a mut [u16],
+    table: &'a mut [Label],
+    /// Label of the first free entry of the table, 0 if it is full.
+    free: u16,
+    /// Rows of the current frame given so far.
+    row: u16,
+    blobs: Blobs,
+}
+
+impl<'a> Labeller<'a> {
+    /// Labeller of masks of `width` by `height` pixels, keeping the labels of
+    /// a row in the first `width` of `labels` and the components crossing it
+    /// in `table`.
+    pub fn new(
+        width: u16,
+        height: u16,
+        options: BlobOptions,
+        labels: &'a mut [u16],
+        table: &'a mut [Label],
+    ) -> Self {
+        assert!(
+            width <= 1600 && height <= 1600,
+            "frame larger than 1600x1600"
+        );
+        assert!(labels.len() >= width as usize, "label row too small");
+        assert!(!table.is_empty(), "empty label table");
+        let table_length = table.len().min(u16::MAX as usize);
+        Self {
+            width,
+            height,
+            options,
+            labels: &mut labels[..width as usize],
+            table: &mut table[..table_length],
+            free: 0,
+            row: 0,
+            blobs: Blobs::EMPTY,
+        }
+    }
+
+    /// Starts a mask, forgetting the blobs of the previous one.
+    pub fn start(&mut self) {
+        self.labels.fill(0);
+        self.free = 0;
+        for label in 1..=self.table.len() {
+            self.release(label as u16);
+        }
+        self.row = 0;
+        self.blobs = Blobs::EMPTY;
+    }
+
+    /// Labels the next row of the mask, one bit per pixel from the least
+    /// significant bit of the first word.
+    pub fn write_row(&mut self, row: &[u32]) {
+        let width = self.width as usize;
+        let y = self.row;
+        assert!(row.len() * 32 >= width, "row too short");
+        assert!(y < self.height, "more rows than the mask");
+        let eight = self.options.connectivity == Connectivity::Eight;
+        // Label above the previous pixel, overwritten by then
+        let mut up_left = 0;
+        for x in 0..width {
+            let up = self.labels[x];
+            let neighbours = [
+                if x > 0 { self.labels[x - 1] } else { 0 },
+                up,
+                if eight { up_left } else { 0 },
+                if eight && x + 1 < width {
+                    self.labels[x + 1]
+                } else {
+                    0
+                },
+            ];
+            up_left = up;
+            self.labels[x] = if row[x / 32] >> (x % 32) & 1 == 0 {
+                0
+            } else if let Some(&label) = neighbours.iter().find(|&&label| label != 0) {
+                for neighbour in neighbours {
+                    if neighbour != 0 {
+                        self.union(label, neighbour);
+                    }
+                }
+                let root = self.find(label);
+                self.entry(root).blob.merge(&Blob::pixel(x as u16, y));
+                label
+            } else {
+                self.allocate(Blob::pixel(x as u16, y))
+            };
+        }
+        self.end_row();
+        self.row += 1;
+    }
+
+    /// Ends the mask and returns its blobs.
+    pub fn finish(&mut self) -> Blobs {
+        assert_eq!(self.row, self.height, "mask not whole");
+        for label in 1..=self.table.len() as u16 {
+            let entry = *self.entry(label);
+            if entry.blob.area != 0 {
+                self.report(entry.blob);
+                self.release(label);
+            }
+        }
+        self.labels.fill(0);
+        self.blobs.sort();
+        self.blobs
+    }
+
+    fn entry(&mut self, label: u16) -> &mut Label {
+        &mut self.table[label as usize - 1]
+    }
+
+    /// Label of a new root of `blob`, 0 with the blob counted as unlabelled
+    /// if the table is full.
+    fn allocate(&mut self, blob: Blob) -> u16 {
+        let label = self.free;
+        if label == 0 {
+            self.blobs.unlabelled += blob.area;
+            return 0;
+        }
+        self.free = self.entry(label).parent;
+        *self.entry(label) = Label {
+            parent: label,
+            blob,
+        };
+        label
+    }
+
+    fn release(&mut self, label: u16) {
+        let free = self.free;
+        *self.entry(label) = Label {
+            parent: free,
+            blob: Blob::EMPTY,
+        };
+        self.free = label;
+    }
+
+    /// Root of the component of `label`, halving its path.
+    fn find(&mut self, mut label: u16) -> u16 {
+        loop {
+            let parent = self.entry(label).parent;
+            if parent == label {
+                return label;
+            }
+            let grandparent = self.entry(parent).parent;
+            self.entry(label).parent = grandparent;
+            label = grandparent;
+        }
+    }
+
+    /// Joins the components of `a` and `b` under the smaller root.
+    fn union(&mut self, a: u16, b: u16) {
+        let (a, b) = (self.find(a), self.find(b));
+        if a != b {
+            let (root, child) = (a.min(b), a.max(b));
+            let blob = self.entry(child).blob;
+            self.entry(root).blob.merge(&blob);
+            self.entry(child).parent = root;
+        }
+    }
+
+    /// Relabels the row with the roots of its components, then frees the
+    /// entries no longer referenced and reports the finished components.
+    fn end_row(&mut self) {
+        for x in 0..self.width as usize {
+            if self.labels[x] != 0 {
+                self.labels[x] = self.find(self.labels[x]);
+            }
+        }
+        for label in 1..=self.table.len() as u16 {
+            let entry = *self.entry(label);
+            if entry.blob.area == 0 {
+                continue;
+            }
+            if entry.parent != label {
+                self.release(label);
+            } else if entry.blob.bottom < self.row {
+                self.report(entry.blob);
+                self.release(label);
+            }
+        }
+    }
+
+    fn report(&mut self, blob: Blob) {
+        if blob.area >= self.options.min_area {
+            self.blobs.push(blob);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Blobs of a byte mask by flood fill, the largest first.
+    fn flood(mask: &[bool], width: usize, height: usize, connectivity: Connectivity) -> Vec<Blob> {
+        let mut seen = vec![false; mask.len()];
+        let mut blobs = Vec::new();
+        for start in 0..mask.len() {
+            if !mask[start] || seen[start] {
+                continue;
+            }
+            seen[start] = true;
+            let mut pending = vec![start];
+            let mut blob = Blob::pixel((start % width) as u16, (start / width) as u16);
+            while let Some(i) = pending.pop() {
+                let (x, y) = ((i % width) as isize, (i / width) as isize);
+                if i != start {
+                    blob.merge(&Blob::pixel(x as u16, y as u16));
+                }
+                for dy in -1..=1isize {
+                    for dx in -1..=1isize {
+                        let diagonal = dx != 0 && dy != 0;
+                        if (dx, dy) == (0, 0) || diagonal && connectivity == Connectivity::Four {
+                            continue;
+                        }
+                        let (u, v) = (x + dx, y + dy);
+                        if u < 0 || v < 0 || u >= width as isize || v >= height as isize {
+                            continue;
+                        }
+                        let j = v as usize * width + u as usize;
+                        if mask[j] && !seen[j] {
+                            seen[j] = true;
+                            pending.push(j);
+                        }
+                    }
+                }
+            }
+            blobs.push(blob);
+        }
+        blobs.sort_by_key(|blob| (u32::MAX - blob.area, blob.top, blob.left));
+        blobs
+    }
+
+    fn pack(row: &[bool]) -> Vec<u32> {
+        let mut words = vec![0; row.len().div_ceil(32)];
+        for (x, &moving) in row.iter().enumerate() {
+            words[x / 32] |= (moving as u32) << (x % 32);
+        }
+        words
+    }
+
+    fn label(
+        mask: &[bool],
+        width: usize,
+        height: usize,
+        options: BlobOptions,
+        table_length: usize,
+    ) -> Blobs {
+        let mut labels = vec![0xAAAA; width];
+        let mut table = vec![Label::FREE; table_length];
+        let mut labeller = Labeller::new(
+            width as u16,
+            height as u16,
+            options,
+            &mut labels,
+            &mut table,
+        );
+        labeller.start();
+        for row in mask.chunks(width) {
+            labeller.write_row(&pack(row));
+        }
+        labeller.finish()
+    }
+
+    /// Mask of random pixels, about `density` in 256 of them moving.
+    fn random_mask(width: usize, height: usize, seed: u32, density: u32) -> Vec<bool> {
+        let mut state = seed;
+        (0..width * height)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state % 256 < density
+            })
+            .collect()
+    }
+
+    fn mask_of(rows: &[&str]) -> (Vec<bool>, usize, usize) {
+        let mask = rows
+            .iter()
+            .flat_map(|row| row.bytes().map(|b| b == b'#'))
+            .collect();
+        (mask, rows[0].len(), rows.len())
+    }
+
+    #[test]
+    fn agrees_with_a_flood_fill() {
+        for connectivity in [Connectivity::Four, Connectivity::Eight] {
+            let options = BlobOptions {
+                connectivity,
+                min_area: 1,
+            };
+            for (width, height) in [(1, 1), (1, 9), (31, 7), (32, 12), (33, 20), (70, 50)] {
+                for (seed, density) in [(1, 40), (2, 100), (3, 128), (4, 160), (5, 230)] {
+                    let mask = random_mask(width, height, seed * 7919, density);
+                    let expected = flood(&mask, width, height, connectivity);
+                    let blobs = label(&mask, width, height, options, 1000);
+                    let context = (connectivity, width, height, seed);
+                    assert_eq!(blobs.found, expected.len() as u32, "{:?}", context);
+                    assert_eq!(blobs.unlabelled, 0, "{:?}", context);
+                    let kept = expected.len().min(MAX_BLOBS);
+                    let areas: Vec<_> = blobs.as_slice().iter().map(|blob| blob.area).collect();
+                    let expected_areas: Vec<_> =
+                        expected[..kept].iter().map(|blob| blob.area).collect();
+                    assert_eq!(areas, expected_areas, "{:?}", context);
+                    if expected.len() <= MAX_BLOBS {
+                        assert_eq!(blobs.as_slice(), &expected[..], "{:?}", context);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn components_joined_below_are_one_blob() {
+        let (mask, width, height) =
+            mask_of(&["#...#.#...", "#...#.#..#", "#...#.#.#.", "#####.###."]);
+        let options = BlobOptions {
+            connectivity: Connectivity::Four,
+            min_area: 1,
+        };
+        let blobs = label(&mask, width, height, options, 4);
+        assert_eq!(blobs.found, 3);
+        let u = blobs.as_slice()[0];
+        assert_eq!((u.area, u.left, u.top, u.right, u.bottom), (11, 0, 0, 4, 3));
+        assert_eq!(u.centroid(), (2, 2));
+        let options = BlobOptions {
+            connectivity: Connectivity::Eight,
+            ..options
+        };
+        let blobs = label(&mask, width, height, options, 4);
+        assert_eq!(blobs.found, 2);
+        let j = blobs.as_slice()[1];
+        assert_eq!((j.area, j.width(), j.height()), (8, 4, 4));
+    }
+
+    #[test]
+    fn diagonal_neighbours_join_only_with_eight_connectivity() {
+        let (mask, width, height) = mask_of(&["#.#", ".#.", "#.#"]);
+        for (connectivity, found) in [(Connectivity::Four, 5), (Connectivity::Eight, 1)] {
+            let options = BlobOptions {
+                connectivity,
+                min_area: 1,
+            };
+            assert_eq!(label(&mask, width, height, options, 8).found, found);
+        }
+    }
+
+    #[test]
+    fn small_blobs_are_filtered_out() {
+        let (mask, width, height) = mask_of(&["##..#", "##...", "....#", "###.#"]);
+        let options = BlobOptions {
+            connectivity: Connectivity::Four,
+            min_area: 2,
+        };
+        let blobs = label(&mask, width, height, options, 8);
+        let areas: Vec<_> = blobs.as_slice().iter().map(|blob| blob.area).collect();
+        assert_eq!(areas, [4, 3, 2]);
+        assert_eq!(blobs.found, 3);
+    }
+
+    #[test]
+    fn the_largest_blobs_are_kept() {
+        // Vertical bars of 1 to 12 pixels
+        let (width, height): (usize, usize) = (24, 12);
+        let mask: Vec<bool> = (0..width * height)
+            .map(|i| (i % width).is_multiple_of(2) && i / width <= i % width / 2)
+            .collect();
+        let blobs = label(
+            &mask,
+            width,
+            height,
+            BlobOptions {
+                min_area: 1,
+                ..BlobOptions::default()
+            },
+            16,
+        );
+        assert_eq!(blobs.found, 12);
+        let areas: Vec<_> = blobs.as_slice().iter().map(|blob| blob.area).collect();
+        assert_eq!(areas, [12, 11, 10, 9, 8, 7, 6, 5]);
+    }
+
+    #[test]
+    fn the_table_only_holds_the_components_crossing_a_row() {
+        // 60 dots, at most 10 per row
+        let (width, height): (usize, usize) = (20, 12);
+        let mask: Vec<bool> = (0..width * height)
+            .map(|i| (i % width).is_multiple_of(2) && (i / width).is_multiple_of(2))
+            .collect();
+        let options = BlobOptions {
+            min_area: 1,
+            ..BlobOptions::default()
+        };
+        let blobs = label(&mask, width, height, options, 10);
+        assert_eq!((blobs.found, blobs.unlabelled), (60, 0));
+        // A full table leaves the components it has no room for unlabelled
+        let blobs = label(&mask, width, height, options, 7);
+        assert_eq!((blobs.found, blobs.unlabelled), (42, 18));
+    }
+
+    #[test]
+    fn each_mask_starts_afresh() {
+        let (mask, width, _) = mask_of(&["##.", "...", ".##"]);
+        let mut labels = [0; 3];
+        let mut table = [Label::FREE; 2];
+        let options = BlobOptions {
+            min_area: 1,
+            ..BlobOptions::default()
+        };
+        let mut labeller = Labeller::new(3, 3, options, &mut labels, &mut table);
+        for _ in 0..3 {
+            labeller.start();
+            for row in mask.chunks(width) {
+                labeller.write_row(&pack(row));
+            }
+            let blobs = labeller.finish();
+            assert_eq!((blobs.found, blobs.unlabelled), (2, 0));
+            assert_eq!(blobs.as_slice()[0].centroid(), (1, 0));
+        }
+    }
+}
@@ -0,0 +1,532 @@
+//! BMP encoder for the frames sent to the clients.
+//!
+//! Writes a BITMAPFILEHEADER and a BITMAPINFOHEADER, followed by the palette
+//! or the bit masks of the format, in front of pixel rows padded to 4 bytes.
+//!
+//! ```ignore
+//! let header = Header::new(320, 240, Format::Gray8);
+//! let mut bytes = [0u8; MAX_HEADER_LENGTH];
+//! let length = header.write(&mut bytes);
+//! ```
+//!
+//! The [`Encoder`] writes the rows of a frame as they are read, converting
+//! them from the layout of the capture.
+
+use crate::image::{self, Color, Error, Input, Layout};
+use crate::pixel;
+
+const FILE_HEADER_LENGTH: usize = 14;
+const INFO_HEADER_LENGTH: usize = 40;
+/// 256 BGRA entries of the grayscale palette.
+const PALETTE_LENGTH: usize = 256 * 4;
+/// Red, green and blue masks of the BITFIELDS compression.
+const MASKS_LENGTH: usize = 3 * 4;
+
+/// Length of the longest header, the one of [`Format::Gray8`].
+pub const MAX_HEADER_LENGTH: usize = FILE_HEADER_LENGTH + INFO_HEADER_LENGTH + PALETTE_LENGTH;
+
+const BI_RGB: u32 = 0;
+const BI_BITFIELDS: u32 = 3;
+/// 96 DPI.
+const PIXELS_PER_METER: u32 = 3780;
+
+const RGB565_MASKS: [u32; 3] = [0xF800, 0x07E0, 0x001F];
+
+/// Layout of a pixel, as stored in the rows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Format {
+    /// One byte of luminance, through a grayscale palette.
+    Gray8,
+    /// 16-bit little endian RGB565, with BITFIELDS masks.
+    Rgb565,
+    /// Blue, green and red bytes.
+    Bgr888,
+}
+
+impl Format {
+    pub const fn bits_per_pixel(self) -> u16 {
+        match self {
+            Format::Gray8 => 8,
+            Format::Rgb565 => 16,
+            Format::Bgr888 => 24,
+        }
+    }
+
+    pub const fn bytes_per_pixel(self) -> usize {
+        self.bits_per_pixel() as usize / 8
+    }
+
+    /// Reads the format from the value of a `color` parameter, `gray`,
+    /// `rgb565` or `rgb24`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "gray" => Some(Self::Gray8),
+            "rgb565" => Some(Self::Rgb565),
+            "rgb24" => Some(Self::Bgr888),
+            _ => None,
+        }
+    }
+
+    /// Value of the `color` parameter selecting the format.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Format::Gray8 => "gray",
+            Format::Rgb565 => "rgb565",
+            Format::Bgr888 => "rgb24",
+        }
+    }
+
+    /// Reads the format from the `color` parameter of the query of a request
+    /// target.
+    pub fn from_target(target: &str) -> Option<Self> {
+        let (_, query) = target.split_once('?')?;
+        query
+            .split('&')
+            .find_map(|parameter| Self::from_name(parameter.strip_prefix("color=")?))
+    }
+
+    /// Length of the palette or masks following the info header.
+    const fn table_length(self) -> usize {
+        match self {
+            Format::Gray8 => PALETTE_LENGTH,
+            Format::Rgb565 => MASKS_LENGTH,
+            Format::Bgr888 => 0,
+        }
+    }
+}
+
+/// Order of the rows in the file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Orientation {
+    /// Last row first, the default of the format.
+    BottomUp,
+    /// First row first, stored as a negative height.
+    TopDown,
+}
+
+/// Header of a BMP image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Header {
+    pub width: u32,
+    pub height: u32,
+    pub format: Format,
+    pub orientation: Orientation,
+}
+
+impl Header {
+    /// Header of a bottom-up image.
+    pub const fn new(width: u32, height: u32, format: Format) -> Self {
+        Self {
+            width,
+            height,
+            format,
+            orientation: Orientation::BottomUp,
+        }
+    }
+
+    pub const fn with_orientation(self, orientation: Orientation) -> Self {
+        Self {
+            orientation,
+            ..self
+        }
+    }
+
+    /// Length of the header, which is also the offset of the pixel data.
+    pub const fn length(&self) -> usize {
+        FILE_HEADER_LENGTH + INFO_HEADER_LENGTH + self.format.table_length()
+    }
+
+    /// Length of a row of pixels without its padding.
+    pub const fn row_length(&self) -> usize {
+        self.width as usize * self.format.bytes_per_pixel()
+    }
+
+    /// Length of a row of pixels padded to 4 bytes.
+    pub const fn stride(&self) -> usize {
+        self.row_length().next_multiple_of(4)
+    }
+
+    /// Length of the pixel data.
+    pub const fn image_size(&self) -> usize {
+        self.stride() * self.height as usize
+    }
+
+    /// Length of the file.
+    pub const fn file_size(&self) -> usize {
+        self.length() + self.image_size()
+    }
+
+    /// Writes the header in the first [`length`](Self::length) bytes of
+    /// `out` and returns that length.
+    ///
+    /// Panics if `out` is too short.
+    pub fn write(&self, out: &mut [u8]) -> usize {
+        let length = self.length();
+        let out = &mut out[..length];
+        let height = match self.orientation {
+            Orientation::BottomUp => self.height as i32,
+            Orientation::TopDown => -(self.height as i32),
+        };
+        let compression = match self.format {
+            Format::Rgb565 => BI_BITFIELDS,
+            Format::Gray8 | Format::Bgr888 => BI_RGB,
+        };
+
+        let mut writer = Writer { out, position: 0 };
+        // BITMAPFILEHEADER
+        writer.bytes(b"BM");
+        writer.u32(self.file_size() as u32);
+        writer.u32(0); // Reserved
+        writer.u32(length as u32);
+        // BITMAPINFOHEADER
+        writer.u32(INFO_HEADER_LENGTH as u32);
+        writer.u32(self.width);
+        writer.u32(height as u32);
+        writer.u16(1); // Planes
+        writer.u16(self.format.bits_per_pixel());
+        writer.u32(compression);
+        writer.u32(self.image_size() as u32);
+        writer.u32(PIXELS_PER_METER);
+        writer.u32(PIXELS_PER_METER);
+        writer.u32(0); // Colors, 0 for all of the palette
+        writer.u32(0); // Important colors
+        match self.format {
+            Format::Gray8 => {
+                for level in 0..=255 {
+                    writer.bytes(&[level, level, level, 255]);
+                }
+            }
+            Format::Rgb565 => {
+                for mask in RGB565_MASKS {
+                    writer.u32(mask);
+                }
+            }
+            Format::Bgr888 => {}
+        }
+        length
+    }
+
+    /// Reads a header written by [`write`](Self::write), or `None` if the
+    /// data is not a BMP image in one of the supported formats.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < FILE_HEADER_LENGTH + INFO_HEADER_LENGTH || &data[..2] != b"BM" {
+            return None;
+        }
+        let width = read_u32(data, 18);
+        let height = read_u32(data, 22) as i32;
+        let format = match (read_u16(data, 28), read_u32(data, 30)) {
+            (8, BI_RGB) => Format::Gray8,
+            (16, BI_BITFIELDS) => Format::Rgb565,
+            (24, BI_RGB) => Format::Bgr888,
+            _ => return None,
+        };
+        let orientation = if height < 0 {
+            Orientation::TopDown
+        } else {
+            Orientation::BottomUp
+        };
+        let header = Self {
+            width,
+            height: height.unsigned_abs(),
+            format,
+            orientation,
+        };
+        (read_u32(data, 10) as usize == header.length()).then_some(header)
+    }
+
+    /// Offset in the file of the row `y`, counted from the top of the image.
+    pub const fn row_offset(&self, y: u32) -> usize {
+        let row = match self.orientation {
+            Orientation::BottomUp => self.height - 1 - y,
+            Orientation::TopDown => y,
+        };
+        self.length() + row as usize * self.stride()
+    }
+
+    /// Writes the header and the pixels, rows of
+    /// [`row_length`](Self::row_length) bytes from the top of the image, to
+    /// `out` and returns the length of the file.
+    ///
+    /// Panics if `pixels` or `out` are too short.
+    pub fn encode(&self, pixels: &[u8], out: &mut [u8]) -> usize {
+        self.write(out);
+        let row_length = self.row_length();
+        for y in 0..self.height {
+            let row = &pixels[y as usize * row_length..][..row_length];
+            let offset = self.row_offset(y);
+            out[offset..offset + row_length].copy_from_slice(row);
+            out[offset + row_length..offset + self.stride()].fill(0);
+        }
+        self.file_size()
+    }
+}
+
+/// Encoder of the frames into BMP files, through the [`image::Encoder`]
+/// interface.
+pub struct Encoder {
+    header: Header,
+    input: Input,
+    /// Rows already written.
+    rows: u32,
+}
+
+impl Encoder {
+    pub fn new(layout: Layout, orientation: Orientation) -> Self {
+        let format = match layout.color {
+            Color::Gray => Format::Gray8,
+            Color::Rgb565 => Format::Rgb565,
+            Color::Rgb888 => Format::Bgr888,
+        };
+        Self {
+            header: Header::new(layout.width as u32, layout.height as u32, format)
+                .with_orientation(orientation),
+            input: layout.input,
+            rows: 0,
+        }
+    }
+
+    pub const fn header(&self) -> &Header {
+        &self.header
+    }
+}
+
+impl image::Encoder for Encoder {
+    fn content_type(&self) -> &'static str {
+        "image/bmp"
+    }
+
+    fn max_length(&self) -> usize {
+        self.header.file_size()
+    }
+
+    fn start(&mut self, out: &mut [u8]) -> Result<(), Error> {
+        if out.len() < self.header.length() {
+            return Err(Error::BufferFull);
+        }
+        self.header.write(out);
+        self.rows = 0;
+        Ok(())
+    }
+
+    /// Converts the rows and writes them at their place in the file, the
+    /// bottom-up images are filled from their end.
+    fn write_rows(&mut self, rows: &[u8], out: &mut [u8]) -> Result<(), Error> {
+        let header = self.header;
+        let row_length = self.header.width as usize * self.input.bytes_per_pixel();
+        assert_eq!(rows.len() % row_length, 0, "partial row");
+        for src in rows.chunks_exact(row_length) {
+            assert!(self.rows < header.height, "too many rows");
+            let offset = header.row_offset(self.rows);
+            let dst = out
+                .get_mut(offset..offset + header.stride())
+                .ok_or(Error::BufferFull)?;
+            let (pixels, padding) = dst.split_at_mut(header.row_length());
+            convert_row(self.input, src, pixels, header.format);
+            padding.fill(0);
+            self.rows += 1;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self, _out: &mut [u8]) -> Result<usize, Error> {
+        Ok(self.header.file_size())
+    }
+}
+
+/// Converts a row of the capture to the pixels of a BMP row of `format`.
+fn convert_row(input: Input, src: &[u8], dst: &mut [u8], format: Format) {
+    match (input, format) {
+        (Input::Rgb565, Format::Gray8) => image::GRAY.rgb565_to_gray_slice(src, dst),
+        // BMP pixels are little endian
+        (Input::Rgb565, Format::Rgb565) => pixel::rgb565_swap_slice(src, dst),
+        (Input::Rgb565, Format::Bgr888) => pixel::rgb565_to_bgr888_slice(src, dst),
+        (Input::Yuv422, Format::Gray8) => pixel::yuv422_to_gray_slice(src, dst),
+        (Input::Yuv422, Format::Rgb565) => pixel::yuv422_to_rgb565_slice(src, dst),
+        (Input::Yuv422, Format::Bgr888) => pixel::yuv422_to_bgr888_slice(src, dst),
+        (input, Format::Gray8) => {
+            for (x, gray) in dst.iter_mut().enumerate() {
+                *gray = input.gray(src, x);
+            }
+        }
+        (input, Format::Rgb565) => {
+            for (x, pixel) in dst.chunks_exact_mut(2).enumerate() {
+                pixel.copy_from_slice(&pixel::rgb888_to_rgb565(input.rgb(src, x)).to_le_bytes());
+            }
+        }
+        (input, Format::Bgr888) => {
+            for (x, pixel) in dst.chunks_exact_mut(3).enumerate() {
+                let [r, g, b] = input.rgb(src, x);
+                pixel.copy_from_slice(&[b, g, r]);
+            }
+        }
+    }
+}
+
+struct Writer<'a> {
+    out: &'a mut [u8],
+    position: usize,
+}
+
+impl Writer<'_> {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.out[self.position..self.position + bytes.len()].copy_from_slice(bytes);
+        self.position += bytes.len();
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORMATS: [Format; 3] = [Format::Gray8, Format::Rgb565, Format::Bgr888];
+    const ORIENTATIONS: [Orientation; 2] = [Orientation::BottomUp, Orientation::TopDown];
+
+    /// Reads back the rows of an encoded image, from the top.
+    fn decode(file: &[u8]) -> (Header, Vec<u8>) {
+        let header = Header::parse(file).unwrap();
+        assert_eq!(read_u32(file, 2) as usize, file.len());
+        let mut pixels = Vec::new();
+        for y in 0..header.height {
+            let offset = header.row_offset(y);
+            pixels.extend_from_slice(&file[offset..offset + header.row_length()]);
+            let padding = &file[offset + header.row_length()..offset + header.stride()];
+            assert!(padding.iter().all(|&byte| byte == 0));
+        }
+        (header, pixels)
+    }
+
+    #[test]
+    fn images_round_trip() {
+        for format in FORMATS {
+            for orientation in ORIENTATIONS {
+                // Every padding length, 0 to 3 bytes.
+                for width in 1..=5 {
+                    let header = Header::new(width, 3, format).with_orientation(orientation);
+                    let pixels: Vec<u8> = (0..header.row_length() * 3)
+                        .map(|i| (i * 37 + 1) as u8)
+                        .collect();
+                    let mut file = vec![0xAA; header.file_size()];
+                    assert_eq!(header.encode(&pixels, &mut file), file.len());
+                    assert_eq!(decode(&file), (header, pixels));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn grayscale_header_matches_the_former_literal() {
+        let mut header = [0u8; MAX_HEADER_LENGTH];
+        assert_eq!(
+            Header::new(320, 240, Format::Gray8).write(&mut header),
+            1078
+        );
+        assert_eq!(
+            header[..54],
+            [
+                0x42, 0x4D, 0x36, 0x30, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x36, 0x04, 0x00, 0x00,
+                0x28, 0x00, 0x00, 0x00, 0x40, 0x01, 0x00, 0x00, 0xF0, 0x00, 0x00, 0x00, 0x01, 0x00,
+                0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x2C, 0x01, 0x00, 0xC4, 0x0E, 0x00, 0x00,
+                0xC4, 0x0E, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            ]
+        );
+        assert_eq!(header[54 + 4 * 200..54 + 4 * 201], [200, 200, 200, 255]);
+    }
+
+    #[test]
+    fn rgb565_header_has_the_bit_masks() {
+        let header = Header::new(320, 240, Format::Rgb565);
+        let mut bytes = [0u8; MAX_HEADER_LENGTH];
+        assert_eq!(header.write(&mut bytes), 66);
+        assert_eq!(read_u32(&bytes, 2), 66 + 153600);
+        assert_eq!(read_u32(&bytes, 30), BI_BITFIELDS);
+        assert_eq!(
+            bytes[54..66],
+            [0x00, 0xF8, 0, 0, 0xE0, 0x07, 0, 0, 0x1F, 0x00, 0, 0]
+        );
+    }
+
+    #[test]
+    fn top_down_images_have_a_negative_height() {
+        let header = Header::new(4, 2, Format::Bgr888).with_orientation(Orientation::TopDown);
+        let mut bytes = [0u8; MAX_HEADER_LENGTH];
+        header.write(&mut bytes);
+        assert_eq!(read_u32(&bytes, 22) as i32, -2);
+        assert_eq!(header.row_offset(0), 54);
+        assert_eq!(Header::new(4, 2, Format::Bgr888).row_offset(0), 54 + 12);
+    }
+
+    #[test]
+    fn format_is_read_from_the_query() {
+        assert_eq!(Format::from_target("/?color=rgb565"), Some(Format::Rgb565));
+        assert_eq!(
+            Format::from_target("/?frame=next&color=rgb24"),
+            Some(Format::Bgr888)
+        );
+        assert_eq!(Format::from_target("/?color=gray"), Some(Format::Gray8));
+        assert_eq!(Format::from_target("/?color=cmyk"), None);
+        assert_eq!(Format::from_target("/"), None);
+        for format in FORMATS {
+            assert_eq!(Format::from_name(format.name()), Some(format));
+        }
+    }
+
+    #[test]
+    fn encoder_converts_the_rows_of_the_capture() {
+        use crate::image::Encoder as _;
+
+        let rgb = [[200, 100, 50], [0, 255, 128], [10, 20, 30], [255, 255, 255]];
+        let rgb888: Vec<u8> = rgb.concat();
+        let rgb565: Vec<u8> = rgb
+            .iter()
+            .flat_map(|&rgb| pixel::rgb888_to_rgb565(rgb).to_be_bytes())
+            .collect();
+        for orientation in ORIENTATIONS {
+            for (input, rows) in [(Input::Rgb888, &rgb888), (Input::Rgb565, &rgb565)] {
+                // 2x2 pixels, the rows are padded
+                let layout = Layout::new(2, 2, input, Color::Rgb888);
+                let mut encoder = Encoder::new(layout, orientation);
+                let mut file = vec![0xAA; encoder.max_length()];
+                encoder.start(&mut file).unwrap();
+                for row in rows.chunks(layout.row_length()) {
+                    encoder.write_rows(row, &mut file).unwrap();
+                }
+                assert_eq!(encoder.finish(&mut file), Ok(file.len()));
+
+                let (header, pixels) = decode(&file);
+                assert_eq!(header, *encoder.header());
+                for (x, bgr) in pixels.chunks(3).enumerate() {
+                    let expected = input.rgb(rows, x);
+                    assert_eq!(bgr, [expected[2], expected[1], expected[0]]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn foreign_images_are_rejected() {
+        let mut bytes = [0u8; MAX_HEADER_LENGTH];
+        Header::new(2, 2, Format::Gray8).write(&mut bytes);
+        assert!(Header::parse(&bytes).is_some());
+        assert_eq!(Header::parse(&bytes[..40]), None);
+        bytes[28] = 32;
+        assert_eq!(Header::parse(&bytes), None);
+    }
+}
@@ -0,0 +1,352 @@
+//! Frame capture with a bounded wait for the sensor.
+
+use embedded_hal::digital::OutputPin;
+use embedded_hal::spi::SpiBus;
+
+use crate::arduchip::ArduChip;
+use crate::error::CameraError;
+
+/// Time given to a capture to complete, a VGA frame takes about 100 ms.
+pub const CAPTURE_TIMEOUT_MS: u64 = 1000;
+/// Number of times a capture that timed out is restarted before giving up.
+pub const CAPTURE_RETRIES: u32 = 2;
+/// Interval between polls of the capture done flag.
+const POLL_INTERVAL_MS: u64 = 10;
+/// Time for the FIFO length to settle once the capture is done.
+const SETTLE_MS: u64 = 50;
+
+/// Counters of the failed captures since boot, reported to clients.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct CaptureStats {
+    /// Captures that were not done in time.
+    pub timeouts: u32,
+    /// Reinitialisations of the camera after an error.
+    pub reinits: u32,
+}
+
+/// Capture in progress, advanced without blocking by [`poll`](Self::poll)
+/// so that the network stack keeps being serviced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Capture {
+    attempt: u32,
+    deadline: u64,
+    next_poll: u64,
+    /// When the capture was seen done, to let the FIFO length settle.
+    done_at: Option<u64>,
+}
+
+impl Capture {
+    /// Starts a capture at `now_ms`.
+    pub fn start<SPI: SpiBus, CS: OutputPin>(
+        arduchip: &mut ArduChip<SPI, CS>,
+        now_ms: u64,
+    ) -> Result<Self, CameraError> {
+        let mut capture = Self {
+            attempt: 0,
+            deadline: 0,
+            next_poll: 0,
+            done_at: None,
+        };
+        capture.restart(arduchip, now_ms)?;
+        Ok(capture)
+    }
+
+    /// Returns the number of bytes in the FIFO once the capture is done, or
+    /// `None` while it is in progress.
+    ///
+    /// A capture that is not done after [`CAPTURE_TIMEOUT_MS`] is counted in
+    /// `stats` and restarted, up to [`CAPTURE_RETRIES`] times, after which the
+    /// sensor is considered stuck and [`CameraError::CaptureTimeout`] is
+    /// returned.
+    pub fn poll<SPI: SpiBus, CS: OutputPin>(
+        &mut self,
+        arduchip: &mut ArduChip<SPI, CS>,
+        now_ms: u64,
+        stats: &mut CaptureStats,
+    ) -> Result<Option<u32>, CameraError> {
+        if let Some(done_at) = self.done_at {
+            if now_ms < done_at + SETTLE_MS {
+                return Ok(None);
+            }
+            return Ok(Some(arduchip.fifo_length()?));
+        }
+        if now_ms < self.next_poll {
+            return Ok(None);
+        }
+        if arduchip.capture_done()? {
+            self.done_at = Some(now_ms);
+            return Ok(None);
+        }
+        if now_ms >= self.deadline {
+            stats.timeouts += 1;
+            if self.attempt == CAPTURE_RETRIES {
+                return Err(CameraError::CaptureTimeout);
+            }
+            self.attempt += 1;
+            self.restart(arduchip, now_ms)?;
+            return Ok(None);
+        }
+        self.next_poll = now_ms + POLL_INTERVAL_MS;
+        Ok(None)
+    }
+
+    fn restart<SPI: SpiBus, CS: OutputPin>(
+        &mut self,
+        arduchip: &mut ArduChip<SPI, CS>,
+        now_ms: u64,
+    ) -> Result<(), CameraError> {
+        arduchip.clear_fifo_flag()?;
+        arduchip.start_capture()?;
+        self.deadline = now_ms + CAPTURE_TIMEOUT_MS;
+        self.next_poll = now_ms;
+        Ok(())
+    }
+}
+
+/// Which frame answers a request.
+///
+/// Capturing a frame only needs the ArduChip, so the next one is captured into
+/// the FIFO while the previous one is sent from RAM. With
+/// [`Latest`](Self::Latest), a request is answered with that frame, so a
+/// client fetching frames back to back only waits for the transfer and gets
+/// about one frame per `max(capture, transfer)` instead of one per
+/// `capture + transfer`, but the frame may be up to [`MAX_FRAME_AGE_MS`] old.
+/// With [`Next`](Self::Next), the frame is captured after the request
+/// arrived, which costs a capture of latency.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum FramePolicy {
+    /// The last frame captured in the background.
+    #[default]
+    Latest,
+    /// A frame captured after the request.
+    Next,
+}
+
+impl FramePolicy {
+    /// Reads the policy from the value of a `frame` parameter, `latest` or
+    /// `next`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "latest" => Some(Self::Latest),
+            "next" => Some(Self::Next),
+            _ => None,
+        }
+    }
+
+    /// Value of the `frame` parameter selecting the policy.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Latest => "latest",
+            Self::Next => "next",
+        }
+    }
+
+    /// Reads the policy from the `frame` parameter of the query of a request
+    /// target.
+    pub fn from_target(target: &str) -> Option<Self> {
+        let (_, query) = target.split_once('?')?;
+        query
+            .split('&')
+            .find_map(|parameter| Self::from_name(parameter.strip_prefix("frame=")?))
+    }
+}
+
+/// Age after which a frame captured in the background is replaced.
+pub const MAX_FRAME_AGE_MS: u64 = 1000;
+
+/// Frame captured in the FIFO ahead of the requests.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Prefetch {
+    /// The FIFO holds no frame, or one that was already read.
+    #[default]
+    Empty,
+    Capturing(Capture),
+    /// The FIFO holds `length` bytes of a frame captured at `captured_at`.
+    Ready { length: u32, captured_at: u64 },
+}
+
+impl Prefetch {
+    /// Starts capturing in the background.
+    pub fn start<SPI: SpiBus, CS: OutputPin>(
+        arduchip: &mut ArduChip<SPI, CS>,
+        now_ms: u64,
+    ) -> Result<Self, CameraError> {
+        Ok(Self::Capturing(Capture::start(arduchip, now_ms)?))
+    }
+
+    /// Starts or advances the background capture, and replaces the frame once
+    /// it is older than [`MAX_FRAME_AGE_MS`]. The FIFO must not be in use.
+    pub fn poll<SPI: SpiBus, CS: OutputPin>(
+        &mut self,
+        arduchip: &mut ArduChip<SPI, CS>,
+        now_ms: u64,
+        stats: &mut CaptureStats,
+    ) -> Result<(), CameraError> {
+        match self {
+            Self::Empty => *self = Self::start(arduchip, now_ms)?,
+            Self::Capturing(capture) => {
+                if let Some(length) = capture.poll(arduchip, now_ms, stats)? {
+                    *self = Self::Ready {
+                        length,
+                        captured_at: now_ms,
+                    };
+                }
+            }
+            Self::Ready { captured_at, .. } => {
+                if now_ms >= *captured_at + MAX_FRAME_AGE_MS {
+                    *self = Self::start(arduchip, now_ms)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes the frame answering a request with `policy`, either ready in the
+    /// FIFO or being captured, leaving the prefetch empty.
+    pub fn take<SPI: SpiBus, CS: OutputPin>(
+        &mut self,
+        policy: FramePolicy,
+        arduchip: &mut ArduChip<SPI, CS>,
+        now_ms: u64,
+    ) -> Result<Self, CameraError> {
+        match (policy, core::mem::take(self)) {
+            (FramePolicy::Latest, frame @ (Self::Capturing(_) | Self::Ready { .. })) => Ok(frame),
+            _ => Self::start(arduchip, now_ms),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake::FakeArduChip;
+
+    /// Polls a capture at each time of `clock` until it is done or fails,
+    /// like the main loop of the server, and returns its length.
+    fn poll_until_done<SPI: SpiBus, CS: OutputPin>(
+        arduchip: &mut ArduChip<SPI, CS>,
+        clock: &mut impl FnMut() -> u64,
+        stats: &mut CaptureStats,
+    ) -> Result<u32, CameraError> {
+        let mut capture = Capture::start(arduchip, clock())?;
+        loop {
+            if let Some(length) = capture.poll(arduchip, clock(), stats)? {
+                return Ok(length);
+            }
+        }
+    }
+
+    #[test]
+    fn stuck_sensor_times_out_after_the_retries() {
+        let chip = FakeArduChip::new(&[1, 2, 3]).with_capture_polls(None);
+        let mut arduchip = ArduChip::new(chip.spi(), chip.cs());
+        let mut now = 0;
+        let mut clock = || {
+            now += 10;
+            now
+        };
+        let mut stats = CaptureStats::default();
+
+        let result = poll_until_done(&mut arduchip, &mut clock, &mut stats);
+        assert_eq!(result, Err(CameraError::CaptureTimeout));
+        assert_eq!(stats.timeouts, CAPTURE_RETRIES + 1);
+        // Each attempt polled for the whole timeout.
+        assert!(now >= (CAPTURE_RETRIES as u64 + 1) * CAPTURE_TIMEOUT_MS);
+    }
+
+    #[test]
+    fn restarted_capture_recovers() {
+        let chip = FakeArduChip::new(&[1, 2, 3]).with_capture_polls(None);
+        let mut arduchip = ArduChip::new(chip.spi(), chip.cs());
+        let mut now = 0;
+        let mut clock = || {
+            now += 10;
+            // The sensor comes back once the first attempt has timed out.
+            if now == CAPTURE_TIMEOUT_MS {
+                chip.set_capture_polls(Some(2));
+            }
+            now
+        };
+        let mut stats = CaptureStats::default();
+
+        let result = poll_until_done(&mut arduchip, &mut clock, &mut stats);
+        assert_eq!(result, Ok(3));
+        assert_eq!(stats.timeouts, 1);
+        assert_eq!(chip.captures(), 1);
+    }
+
+    #[test]
+    fn poll_waits_between_reads_and_for_the_fifo_to_settle() {
+        let chip = FakeArduChip::new(&[1, 2, 3]).with_capture_polls(Some(2));
+        let mut arduchip = ArduChip::new(chip.spi(), chip.cs());
+        let mut stats = CaptureStats::default();
+
+        let mut capture = Capture::start(&mut arduchip, 0).unwrap();
+        assert_eq!(capture.poll(&mut arduchip, 0, &mut stats), Ok(None));
+        // Too early for another read of the trigger register.
+        assert_eq!(capture.poll(&mut arduchip, 5, &mut stats), Ok(None));
+        assert_eq!(chip.captures(), 0);
+        assert_eq!(capture.poll(&mut arduchip, 10, &mut stats), Ok(None));
+        assert_eq!(chip.captures(), 1);
+        assert_eq!(capture.poll(&mut arduchip, 59, &mut stats), Ok(None));
+        assert_eq!(capture.poll(&mut arduchip, 60, &mut stats), Ok(Some(3)));
+        assert_eq!(stats, CaptureStats::default());
+    }
+
+    #[test]
+    fn policy_is_read_from_the_query() {
+        assert_eq!(FramePolicy::from_target("/?frame=next"), Some(FramePolicy::Next));
+        assert_eq!(
+            FramePolicy::from_target("/frame.bmp?a=1&frame=latest"),
+            Some(FramePolicy::Latest)
+        );
+        assert_eq!(FramePolicy::from_target("/frame=next"), None);
+        assert_eq!(FramePolicy::from_target("/?frame=old"), None);
+        for policy in [FramePolicy::Latest, FramePolicy::Next] {
+            assert_eq!(FramePolicy::from_name(policy.name()), Some(policy));
+        }
+    }
+
+    #[test]
+    fn latest_frame_is_captured_ahead_and_refreshed() {
+        let chip = FakeArduChip::new(&[1, 2, 3]).with_capture_polls(Some(1));
+        let mut arduchip = ArduChip::new(chip.spi(), chip.cs());
+        let mut stats = CaptureStats::default();
+
+        let mut prefetch = Prefetch::start(&mut arduchip, 0).unwrap();
+        prefetch.poll(&mut arduchip, 0, &mut stats).unwrap();
+        prefetch.poll(&mut arduchip, SETTLE_MS, &mut stats).unwrap();
+        let ready = Prefetch::Ready {
+            length: 3,
+            captured_at: SETTLE_MS,
+        };
+        assert_eq!(prefetch, ready);
+
+        // Too old, replaced by a new capture.
+        let now = SETTLE_MS + MAX_FRAME_AGE_MS;
+        prefetch.poll(&mut arduchip, now, &mut stats).unwrap();
+        assert!(matches!(prefetch, Prefetch::Capturing(_)));
+        prefetch.poll(&mut arduchip, now, &mut stats).unwrap();
+        prefetch.poll(&mut arduchip, now + SETTLE_MS, &mut stats).unwrap();
+        assert_eq!(chip.captures(), 2);
+
+        let taken = prefetch.take(FramePolicy::Latest, &mut arduchip, now + SETTLE_MS);
+        assert!(matches!(taken, Ok(Prefetch::Ready { length: 3, .. })));
+        assert_eq!(prefetch, Prefetch::Empty);
+    }
+
+    #[test]
+    fn next_frame_discards_the_prefetched_one() {
+        let chip = FakeArduChip::new(&[1, 2, 3]);
+        let mut arduchip = ArduChip::new(chip.spi(), chip.cs());
+        let mut prefetch = Prefetch::Ready {
+            length: 3,
+            captured_at: 0,
+        };
+
+        let taken = prefetch.take(FramePolicy::Next, &mut arduchip, 10);
+        assert!(matches!(taken, Ok(Prefetch::Capturing(_))));
+        assert_eq!(prefetch, Prefetch::Empty);
+        assert_eq!(chip.captures(), 0);
+    }
+}
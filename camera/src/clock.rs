@@ -0,0 +1,49 @@
+//! Monotonic time for timeouts.
+
+/// Monotonic clock extending a wrapping 32-bit cycle counter, such as the DWT
+/// cycle counter of the Cortex-M7, to 64 bits.
+///
+/// The counter must be sampled at least once per wrap, every 21 s at 200 MHz.
+pub struct CycleClock {
+    cycles_per_us: u32,
+    last: u32,
+    cycles: u64,
+}
+
+impl CycleClock {
+    /// Creates a clock counting from `cycles`, the current counter value.
+    pub const fn new(cycles_per_us: u32, cycles: u32) -> Self {
+        Self {
+            cycles_per_us,
+            last: cycles,
+            cycles: 0,
+        }
+    }
+
+    /// Takes a new sample of the counter and returns the microseconds elapsed
+    /// since the creation of the clock.
+    pub fn update(&mut self, cycles: u32) -> u64 {
+        self.cycles += cycles.wrapping_sub(self.last) as u64;
+        self.last = cycles;
+        self.now_us()
+    }
+
+    /// Microseconds elapsed at the last sample.
+    pub fn now_us(&self) -> u64 {
+        self.cycles / self.cycles_per_us as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counter_wraps_are_extended() {
+        let mut clock = CycleClock::new(200, u32::MAX - 199);
+        assert_eq!(clock.update(u32::MAX - 199), 0);
+        assert_eq!(clock.update(0), 1);
+        assert_eq!(clock.update(u32::MAX - 199), (1 << 32) / 200);
+        assert_eq!(clock.update(199), (1 << 32) / 200 + 2);
+    }
+}
@@ -0,0 +1,259 @@
+//! Adapter from the `embedded-hal` 0.2 traits implemented by `stm32h7xx-hal`
+//! to the `embedded-hal` 1.0 traits the drivers of this crate are written
+//! against.
+
+use embedded_hal::{delay, digital, i2c, spi};
+use embedded_hal_02::blocking::delay::DelayUs as DelayUs02;
+use embedded_hal_02::blocking::i2c as i2c02;
+use embedded_hal_02::blocking::spi as spi02;
+use embedded_hal_02::digital::v2::OutputPin as OutputPin02;
+
+/// Wraps an `embedded-hal` 0.2 peripheral so that it implements the
+/// equivalent `embedded-hal` 1.0 trait.
+///
+/// ```ignore
+/// let arduchip = ArduChip::new(Compat(spi), Compat(cs));
+/// ```
+pub struct Compat<T>(pub T);
+
+impl<T> Compat<T> {
+    /// Returns the wrapped peripheral.
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+/// Error of a wrapped `embedded-hal` 0.2 peripheral.
+///
+/// The 0.2 error types carry no [`ErrorKind`](spi::ErrorKind), so every error
+/// is reported as `Other`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompatError<E>(pub E);
+
+impl<E: core::fmt::Debug> spi::Error for CompatError<E> {
+    fn kind(&self) -> spi::ErrorKind {
+        spi::ErrorKind::Other
+    }
+}
+
+impl<E: core::fmt::Debug> i2c::Error for CompatError<E> {
+    fn kind(&self) -> i2c::ErrorKind {
+        i2c::ErrorKind::Other
+    }
+}
+
+impl<E: core::fmt::Debug> digital::Error for CompatError<E> {
+    fn kind(&self) -> digital::ErrorKind {
+        digital::ErrorKind::Other
+    }
+}
+
+impl<T, E> spi::ErrorType for Compat<T>
+where
+    T: spi02::Transfer<u8, Error = E> + spi02::Write<u8, Error = E>,
+    E: core::fmt::Debug,
+{
+    type Error = CompatError<E>;
+}
+
+impl<T, E> spi::SpiBus for Compat<T>
+where
+    T: spi02::Transfer<u8, Error = E> + spi02::Write<u8, Error = E>,
+    E: core::fmt::Debug,
+{
+    fn read(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+        words.fill(0);
+        self.0.transfer(words).map_err(CompatError)?;
+        Ok(())
+    }
+
+    fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
+        self.0.write(words).map_err(CompatError)
+    }
+
+    fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), Self::Error> {
+        // Bytes past the end of `write` are clocked out as zeroes and bytes
+        // past the end of `read` are discarded, as required by `SpiBus`.
+        let common = read.len().min(write.len());
+        read[..common].copy_from_slice(&write[..common]);
+        read[common..].fill(0);
+        self.0.transfer(read).map_err(CompatError)?;
+        if write.len() > common {
+            self.0.write(&write[common..]).map_err(CompatError)?;
+        }
+        Ok(())
+    }
+
+    fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+        self.0.transfer(words).map_err(CompatError)?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        // The 0.2 blocking calls only return once the transfer is complete.
+        Ok(())
+    }
+}
+
+impl<T, E> i2c::ErrorType for Compat<T>
+where
+    T: i2c02::Write<Error = E> + i2c02::Read<Error = E> + i2c02::WriteRead<Error = E>,
+    E: core::fmt::Debug,
+{
+    type Error = CompatError<E>;
+}
+
+impl<T, E> i2c::I2c for Compat<T>
+where
+    T: i2c02::Write<Error = E> + i2c02::Read<Error = E> + i2c02::WriteRead<Error = E>,
+    E: core::fmt::Debug,
+{
+    fn read(&mut self, address: u8, read: &mut [u8]) -> Result<(), Self::Error> {
+        self.0.read(address, read).map_err(CompatError)
+    }
+
+    fn write(&mut self, address: u8, write: &[u8]) -> Result<(), Self::Error> {
+        self.0.write(address, write).map_err(CompatError)
+    }
+
+    fn write_read(
+        &mut self,
+        address: u8,
+        write: &[u8],
+        read: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        self.0.write_read(address, write, read).map_err(CompatError)
+    }
+
+    /// The 0.2 traits have no generic transaction, so a write followed by a
+    /// read uses a repeated start and every other sequence of operations is
+    /// issued as separate transfers.
+    fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [i2c::Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        let mut operations = operations.iter_mut().peekable();
+        while let Some(operation) = operations.next() {
+            match operation {
+                i2c::Operation::Write(write) => {
+                    if let Some(i2c::Operation::Read(read)) = operations.peek_mut() {
+                        self.write_read(address, write, read)?;
+                        operations.next();
+                    } else {
+                        self.write(address, write)?;
+                    }
+                }
+                i2c::Operation::Read(read) => self.read(address, read)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T, E> digital::ErrorType for Compat<T>
+where
+    T: OutputPin02<Error = E>,
+    E: core::fmt::Debug,
+{
+    type Error = CompatError<E>;
+}
+
+impl<T, E> digital::OutputPin for Compat<T>
+where
+    T: OutputPin02<Error = E>,
+    E: core::fmt::Debug,
+{
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.0.set_low().map_err(CompatError)
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.0.set_high().map_err(CompatError)
+    }
+}
+
+impl<T: DelayUs02<u32>> delay::DelayNs for Compat<T> {
+    fn delay_ns(&mut self, ns: u32) {
+        self.0.delay_us(ns.div_ceil(1000));
+    }
+
+    fn delay_us(&mut self, us: u32) {
+        self.0.delay_us(us);
+    }
+
+    fn delay_ms(&mut self, ms: u32) {
+        for _ in 0..ms {
+            self.0.delay_us(1000);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_hal::i2c::{I2c, Operation};
+    use embedded_hal::spi::SpiBus;
+    use embedded_hal_mock::eh0::i2c::{Mock as I2cMock, Transaction as I2cTransaction};
+    use embedded_hal_mock::eh0::spi::{Mock as SpiMock, Transaction as SpiTransaction};
+
+    #[test]
+    fn reads_clock_out_zeroes() {
+        let expectations = [SpiTransaction::transfer(vec![0, 0], vec![7, 8])];
+        let mut spi = Compat(SpiMock::new(&expectations));
+        let mut read = [0xFF; 2];
+        spi.read(&mut read).unwrap();
+        assert_eq!(read, [7, 8]);
+        spi.into_inner().done();
+    }
+
+    #[test]
+    fn transfers_pad_the_shorter_buffer() {
+        let expectations = [
+            // Longer write, the rest of it is written after the transfer
+            SpiTransaction::transfer(vec![1, 2], vec![7, 8]),
+            SpiTransaction::write(vec![3]),
+            // Longer read, clocking out zeroes
+            SpiTransaction::transfer(vec![4, 0, 0], vec![7, 8, 9]),
+            SpiTransaction::transfer(vec![5, 6], vec![7, 8]),
+        ];
+        let mut spi = Compat(SpiMock::new(&expectations));
+        let mut read = [0; 2];
+        spi.transfer(&mut read, &[1, 2, 3]).unwrap();
+        assert_eq!(read, [7, 8]);
+        let mut read = [0; 3];
+        spi.transfer(&mut read, &[4]).unwrap();
+        assert_eq!(read, [7, 8, 9]);
+        let mut words = [5, 6];
+        spi.transfer_in_place(&mut words).unwrap();
+        assert_eq!(words, [7, 8]);
+        spi.into_inner().done();
+    }
+
+    #[test]
+    fn transactions_repeat_the_start_between_a_write_and_a_read() {
+        let expectations = [
+            I2cTransaction::write_read(0x30, vec![0x0A], vec![0x26]),
+            I2cTransaction::write(0x30, vec![0xFF, 0x01]),
+            I2cTransaction::write(0x30, vec![0x12]),
+            I2cTransaction::read(0x30, vec![0x40]),
+        ];
+        let mut i2c = Compat(I2cMock::new(&expectations));
+        let (mut id, mut com7) = ([0], [0]);
+        i2c.transaction(
+            0x30,
+            &mut [Operation::Write(&[0x0A]), Operation::Read(&mut id)],
+        )
+        .unwrap();
+        // Two writes, then a read not preceded by a write
+        i2c.transaction(
+            0x30,
+            &mut [Operation::Write(&[0xFF, 0x01]), Operation::Write(&[0x12])],
+        )
+        .unwrap();
+        i2c.transaction(0x30, &mut [Operation::Read(&mut com7)])
+            .unwrap();
+        assert_eq!((id, com7), ([0x26], [0x40]));
+        i2c.into_inner().done();
+    }
+}
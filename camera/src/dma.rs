@@ -0,0 +1,190 @@
+//! Reception on SPI1 with DMA1, to drain the ArduChip FIFO while the main
+//! loop keeps running.
+//!
+//! SPI1 stays owned by the `stm32h7xx-hal` driver used for the register
+//! accesses. Between two of its transfers, [`SpiRxDma::start`] switches the
+//! peripheral to receive-only mode, where it clocks bytes in on its own, and
+//! the completion interrupt of the stream restores full-duplex mode once
+//! [`SpiRxDma::poll`] sees it.
+//!
+//! ```ignore
+//! #[interrupt]
+//! fn DMA1_STR0() {
+//!     SpiRxDma::on_interrupt();
+//! }
+//! ```
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use stm32h7xx_hal::pac::{self, DMA1, DMAMUX1, SPI1, dmamux1::ccr::DMAREQ_ID_A};
+
+/// Largest transfer of a DMA1 stream.
+pub const MAX_TRANSFER: usize = u16::MAX as usize;
+
+/// Stream of DMA1 used for the reception.
+const STREAM: usize = 0;
+
+static DONE: AtomicBool = AtomicBool::new(false);
+static FAILED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+    /// The DMA stream reported a transfer or FIFO error.
+    Transfer,
+}
+
+/// DMA1 stream 0, receiving from SPI1.
+pub struct SpiRxDma {
+    dma: DMA1,
+    dmamux: DMAMUX1,
+    busy: bool,
+}
+
+impl SpiRxDma {
+    /// Takes DMA1, whose clock must be enabled, and unmasks the completion
+    /// interrupt.
+    pub fn new(dma: DMA1, dmamux: DMAMUX1) -> Self {
+        dmamux.ccr[STREAM].write(|w| w.dmareq_id().variant(DMAREQ_ID_A::Spi1RxDma));
+        // SAFETY: the handler only touches the flags of the stream
+        unsafe { cortex_m::peripheral::NVIC::unmask(pac::Interrupt::DMA1_STR0) };
+        Self {
+            dma,
+            dmamux,
+            busy: false,
+        }
+    }
+
+    /// Returns DMA1 and DMAMUX1.
+    pub fn release(self) -> (DMA1, DMAMUX1) {
+        (self.dma, self.dmamux)
+    }
+
+    /// Whether a reception is in progress.
+    pub fn is_busy(&self) -> bool {
+        self.busy
+    }
+
+    /// Starts receiving `length` bytes at `buffer`, clocking out zeroes.
+    ///
+    /// SPI1 must be idle, with the chip select already driven and any
+    /// command written.
+    ///
+    /// # Safety
+    ///
+    /// `buffer` must be valid for `length` bytes, in memory reachable by DMA1
+    /// (not the DTCM), and must neither be accessed nor freed until
+    /// [`poll`](Self::poll) returns `Some`.
+    pub unsafe fn start(&mut self, buffer: *mut u8, length: u16) {
+        assert!(!self.busy, "SPI1 reception already in progress");
+        let spi = spi1();
+        let stream = &self.dma.st[STREAM];
+
+        // Let the last byte written by the HAL go out, then reconfigure, which
+        // is only allowed while the SPI is disabled.
+        while spi.sr.read().txc().is_ongoing() {}
+        if spi.cr1.read().cstart().is_started() {
+            spi.cr1.modify(|_, w| w.csusp().requested());
+            while spi.sr.read().susp().is_not_suspended() {}
+            spi.ifcr.write(|w| w.suspc().clear());
+        }
+        spi.cr1.modify(|_, w| w.spe().disabled());
+        spi.cfg2.modify(|_, w| w.comm().receiver());
+        spi.cr2.write(|w| w.tsize().bits(length));
+        spi.ifcr.write(|w| w.eotc().clear().txtfc().clear());
+        spi.cfg1.modify(|_, w| w.rxdmaen().enabled());
+
+        stream.cr.modify(|_, w| w.en().disabled());
+        while stream.cr.read().en().is_enabled() {}
+        clear_flags(&self.dma);
+        stream.par.write(|w| unsafe { w.pa().bits(&spi.rxdr as *const _ as u32) });
+        stream.m0ar.write(|w| unsafe { w.m0a().bits(buffer as u32) });
+        stream.ndtr.write(|w| w.ndt().bits(length));
+        stream.fcr.write(|w| w.dmdis().enabled().fth().half());
+        stream.cr.write(|w| {
+            w.dir()
+                .peripheral_to_memory()
+                .minc()
+                .incremented()
+                .pinc()
+                .fixed()
+                .psize()
+                .bits8()
+                .msize()
+                .bits8()
+                .pl()
+                .high()
+                .tcie()
+                .enabled()
+                .teie()
+                .enabled()
+                .dmeie()
+                .enabled()
+        });
+
+        DONE.store(false, Ordering::Release);
+        FAILED.store(false, Ordering::Release);
+        self.busy = true;
+        stream.cr.modify(|_, w| w.en().enabled());
+
+        spi.cr1.write(|w| w.ssi().slave_not_selected().spe().enabled());
+        spi.cr1.modify(|_, w| w.cstart().started());
+    }
+
+    /// Returns the outcome of the reception once the completion interrupt
+    /// fired, after handing SPI1 back to the HAL in full-duplex mode.
+    pub fn poll(&mut self) -> Option<Result<(), Error>> {
+        if !self.busy || !DONE.load(Ordering::Acquire) {
+            return None;
+        }
+        let spi = spi1();
+        while spi.sr.read().eot().is_not_completed() && !FAILED.load(Ordering::Acquire) {}
+        spi.cr1.modify(|_, w| w.spe().disabled());
+        spi.ifcr.write(|w| w.eotc().clear().txtfc().clear());
+        spi.cfg1.modify(|_, w| w.rxdmaen().disabled());
+        spi.cfg2.modify(|_, w| w.comm().full_duplex());
+        spi.cr2.write(|w| w.tsize().bits(0));
+        spi.cr1.write(|w| w.ssi().slave_not_selected().spe().enabled());
+        self.busy = false;
+
+        if FAILED.load(Ordering::Acquire) {
+            Some(Err(Error::Transfer))
+        } else {
+            Some(Ok(()))
+        }
+    }
+
+    /// Records the end of the reception, to be called from the `DMA1_STR0`
+    /// interrupt handler.
+    pub fn on_interrupt() {
+        // SAFETY: only the flags of the stream are read and cleared
+        let dma = unsafe { &*DMA1::ptr() };
+        let status = dma.lisr.read();
+        if status.teif0().is_error() || status.dmeif0().is_error() {
+            FAILED.store(true, Ordering::Release);
+        }
+        clear_flags(dma);
+        DONE.store(true, Ordering::Release);
+    }
+}
+
+fn clear_flags(dma: &pac::dma1::RegisterBlock) {
+    dma.lifcr.write(|w| {
+        w.ctcif0()
+            .clear()
+            .chtif0()
+            .clear()
+            .cteif0()
+            .clear()
+            .cdmeif0()
+            .clear()
+            .cfeif0()
+            .clear()
+    });
+}
+
+/// SPI1, shared with the HAL driver which is not accessed while a reception
+/// is in progress.
+fn spi1() -> &'static pac::spi1::RegisterBlock {
+    // SAFETY: see above
+    unsafe { &*SPI1::ptr() }
+}
@@ -0,0 +1,84 @@
+//! Errors of the capture path, from the camera buses to the client socket.
+
+use crate::{arduchip, image, ov2640};
+
+/// Socket operation that failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum NetworkError {
+    Listen,
+    Recv,
+    Send,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum CameraError {
+    /// The ArduChip could not be accessed over SPI.
+    ArduChip(arduchip::Error),
+    /// The sensor could not be accessed over I2C.
+    Sensor(ov2640::Error),
+    /// The capture was not done in time, the sensor is likely stuck.
+    CaptureTimeout,
+    /// The DMA reception of the FIFO failed.
+    Dma,
+    /// The FIFO holds fewer bytes than a frame.
+    ShortFifo { length: u32, expected: u32 },
+    /// The frame is larger than the buffer it is read into.
+    FrameTooLarge { length: u32, max: u32 },
+    /// The JPEG markers were not found in the FIFO.
+    InvalidJpeg,
+    /// The frame could not be encoded on the device.
+    Encoding(image::Error),
+    /// The frame could not be exchanged with the client.
+    Network(NetworkError),
+}
+
+impl CameraError {
+    /// Whether the camera must be reinitialised to recover from the error,
+    /// the others only affect the current client.
+    pub fn needs_reinit(&self) -> bool {
+        !matches!(
+            self,
+            CameraError::FrameTooLarge { .. } | CameraError::Encoding(_) | CameraError::Network(_)
+        )
+    }
+}
+
+impl From<arduchip::Error> for CameraError {
+    fn from(error: arduchip::Error) -> Self {
+        CameraError::ArduChip(error)
+    }
+}
+
+impl From<ov2640::Error> for CameraError {
+    fn from(error: ov2640::Error) -> Self {
+        CameraError::Sensor(error)
+    }
+}
+
+impl From<image::Error> for CameraError {
+    fn from(error: image::Error) -> Self {
+        CameraError::Encoding(error)
+    }
+}
+
+impl From<NetworkError> for CameraError {
+    fn from(error: NetworkError) -> Self {
+        CameraError::Network(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_camera_failures_need_a_reinit() {
+        let spi = arduchip::Error::Spi(embedded_hal::spi::ErrorKind::Other);
+        assert!(CameraError::from(spi).needs_reinit());
+        assert!(CameraError::CaptureTimeout.needs_reinit());
+        assert!(CameraError::InvalidJpeg.needs_reinit());
+        assert!(!CameraError::from(NetworkError::Send).needs_reinit());
+        assert!(!CameraError::FrameTooLarge { length: 2, max: 1 }.needs_reinit());
+        assert!(!CameraError::from(image::Error::BufferFull).needs_reinit());
+    }
+}
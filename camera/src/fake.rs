@@ -0,0 +1,474 @@
+//! Simulated ArduCAM for running the drivers on the build machine.
+//!
+//! [`FakeArduChip`] models the ArduChip registers behind an SPI bus and a chip
+//! select pin, and fills its FIFO with a frame supplied by the test when a
+//! capture is started. [`FakeOv2640`] models the two register banks of the
+//! OV2640 behind an I2C bus.
+//!
+//! ```ignore
+//! let chip = FakeArduChip::new(&frame);
+//! let mut arduchip = ArduChip::new(chip.spi(), chip.cs());
+//! let mut sensor = Ov2640::new(FakeOv2640::new());
+//! ```
+
+use core::cell::RefCell;
+use core::convert::Infallible;
+
+use embedded_hal::{digital, i2c, spi};
+
+use crate::arduchip::{
+    ARDUCHIP_BURST_FIFO_READ, ARDUCHIP_FIFO, ARDUCHIP_FIFO_SIZE1, ARDUCHIP_FIFO_SIZE2,
+    ARDUCHIP_FIFO_SIZE3, ARDUCHIP_RESET, ARDUCHIP_TRIG, CAP_DONE_MASK, FIFO_CLEAR_MASK,
+    FIFO_START_MASK, RESET_CPLD_MASK, WRITE_FLAG,
+};
+use crate::ov2640::{
+    BANK_SEL, Bank, COM7, OV2640_CHIPID_HIGH, OV2640_CHIPID_LOW, Register, SENSOR_ADDRESS,
+};
+
+/// Where the ArduChip is in the transaction started by the last chip select.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    /// Chip not selected.
+    Deselected,
+    /// Waiting for the command byte.
+    Command,
+    /// Waiting for the value to write to the register.
+    Write(u8),
+    /// Shifting out the value of the register.
+    Read(u8),
+    /// Shifting out the FIFO.
+    Burst,
+}
+
+struct ArduChipState<'a> {
+    registers: [u8; 0x80],
+    phase: Phase,
+    /// Frame written to the FIFO by the next capture.
+    frame: &'a [u8],
+    fifo: &'a [u8],
+    read_pointer: usize,
+    /// Number of polls of [`ARDUCHIP_TRIG`] before a capture completes, the
+    /// capture never completes when `None`.
+    capture_polls: Option<u32>,
+    /// Polls left before the capture in progress completes.
+    capturing: Option<u32>,
+    captures: u32,
+}
+
+impl<'a> ArduChipState<'a> {
+    fn exchange(&mut self, out: u8) -> u8 {
+        match self.phase {
+            Phase::Deselected => panic!("SPI transfer while the ArduChip is not selected"),
+            Phase::Command => {
+                self.phase = if out == ARDUCHIP_BURST_FIFO_READ {
+                    Phase::Burst
+                } else if out & WRITE_FLAG != 0 {
+                    Phase::Write(out & !WRITE_FLAG)
+                } else {
+                    Phase::Read(out)
+                };
+                0
+            }
+            Phase::Write(reg) => {
+                self.write(reg, out);
+                self.phase = Phase::Command;
+                0
+            }
+            Phase::Read(reg) => self.read(reg),
+            Phase::Burst => {
+                let value = self.fifo.get(self.read_pointer).copied().unwrap_or(0);
+                self.read_pointer += 1;
+                value
+            }
+        }
+    }
+
+    fn read(&mut self, reg: u8) -> u8 {
+        let length = self.fifo.len() as u32;
+        match reg {
+            ARDUCHIP_TRIG => {
+                if let Some(polls) = self.capturing {
+                    if polls == 0 {
+                        self.capturing = None;
+                        self.fifo = self.frame;
+                        self.read_pointer = 0;
+                        self.captures += 1;
+                        self.registers[ARDUCHIP_TRIG as usize] |= CAP_DONE_MASK;
+                    } else {
+                        self.capturing = Some(polls - 1);
+                    }
+                }
+                self.registers[ARDUCHIP_TRIG as usize]
+            }
+            ARDUCHIP_FIFO_SIZE1 => length as u8,
+            ARDUCHIP_FIFO_SIZE2 => (length >> 8) as u8,
+            ARDUCHIP_FIFO_SIZE3 => (length >> 16) as u8,
+            _ => self.registers[reg as usize],
+        }
+    }
+
+    fn write(&mut self, reg: u8, value: u8) {
+        match reg {
+            ARDUCHIP_FIFO => {
+                if value & FIFO_CLEAR_MASK != 0 {
+                    self.registers[ARDUCHIP_TRIG as usize] &= !CAP_DONE_MASK;
+                }
+                if value & FIFO_START_MASK != 0 {
+                    // The poll that sees the capture complete is one of them.
+                    self.capturing = self.capture_polls.map(|polls| polls.saturating_sub(1));
+                }
+            }
+            ARDUCHIP_RESET if value & RESET_CPLD_MASK != 0 => {
+                self.registers = [0; 0x80];
+                self.fifo = &[];
+                self.read_pointer = 0;
+                self.capturing = None;
+            }
+            _ => self.registers[reg as usize] = value,
+        }
+    }
+}
+
+/// Simulated ArduChip, accessed through [`spi`](Self::spi) and
+/// [`cs`](Self::cs).
+pub struct FakeArduChip<'a> {
+    state: RefCell<ArduChipState<'a>>,
+}
+
+impl<'a> FakeArduChip<'a> {
+    /// Creates an ArduChip that captures `frame` into its FIFO.
+    pub fn new(frame: &'a [u8]) -> Self {
+        Self {
+            state: RefCell::new(ArduChipState {
+                registers: [0; 0x80],
+                phase: Phase::Deselected,
+                frame,
+                fifo: &[],
+                read_pointer: 0,
+                capture_polls: Some(1),
+                capturing: None,
+                captures: 0,
+            }),
+        }
+    }
+
+    /// Sets the number of polls of the trigger register before a capture
+    /// completes, or `None` to simulate a stuck sensor.
+    pub fn with_capture_polls(self, polls: Option<u32>) -> Self {
+        self.set_capture_polls(polls);
+        self
+    }
+
+    /// Same as [`with_capture_polls`](Self::with_capture_polls), from the next
+    /// capture started.
+    pub fn set_capture_polls(&self, polls: Option<u32>) {
+        self.state.borrow_mut().capture_polls = polls;
+    }
+
+    /// Sets the frame captured by the next capture.
+    pub fn set_frame(&self, frame: &'a [u8]) {
+        self.state.borrow_mut().frame = frame;
+    }
+
+    /// Number of captures completed since creation.
+    pub fn captures(&self) -> u32 {
+        self.state.borrow().captures
+    }
+
+    /// Number of FIFO bytes read since the last capture completed.
+    pub fn fifo_bytes_read(&self) -> usize {
+        self.state.borrow().read_pointer
+    }
+
+    /// Value of a register as last written.
+    pub fn register(&self, reg: u8) -> u8 {
+        self.state.borrow().registers[reg as usize]
+    }
+
+    pub fn spi(&self) -> FakeSpi<'_, 'a> {
+        FakeSpi { chip: self }
+    }
+
+    pub fn cs(&self) -> FakeCs<'_, 'a> {
+        FakeCs { chip: self }
+    }
+}
+
+/// SPI bus of a [`FakeArduChip`].
+pub struct FakeSpi<'c, 'a> {
+    chip: &'c FakeArduChip<'a>,
+}
+
+impl spi::ErrorType for FakeSpi<'_, '_> {
+    type Error = Infallible;
+}
+
+impl spi::SpiBus for FakeSpi<'_, '_> {
+    fn read(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+        let mut state = self.chip.state.borrow_mut();
+        for word in words {
+            *word = state.exchange(0);
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
+        let mut state = self.chip.state.borrow_mut();
+        for &word in words {
+            state.exchange(word);
+        }
+        Ok(())
+    }
+
+    fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), Self::Error> {
+        let mut state = self.chip.state.borrow_mut();
+        for i in 0..read.len().max(write.len()) {
+            let value = state.exchange(write.get(i).copied().unwrap_or(0));
+            if let Some(word) = read.get_mut(i) {
+                *word = value;
+            }
+        }
+        Ok(())
+    }
+
+    fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+        let mut state = self.chip.state.borrow_mut();
+        for word in words {
+            *word = state.exchange(*word);
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// Chip select pin of a [`FakeArduChip`], active low.
+pub struct FakeCs<'c, 'a> {
+    chip: &'c FakeArduChip<'a>,
+}
+
+impl digital::ErrorType for FakeCs<'_, '_> {
+    type Error = Infallible;
+}
+
+impl digital::OutputPin for FakeCs<'_, '_> {
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.chip.state.borrow_mut().phase = Phase::Command;
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.chip.state.borrow_mut().phase = Phase::Deselected;
+        Ok(())
+    }
+}
+
+/// Simulated OV2640 on an I2C bus, answering at [`SENSOR_ADDRESS`].
+pub struct FakeOv2640 {
+    banks: [[u8; 0x100]; 2],
+    bank_sel: u8,
+    /// Register addressed by the last write, read by the next read.
+    address: u8,
+}
+
+impl FakeOv2640 {
+    pub fn new() -> Self {
+        let mut sensor = Self {
+            banks: [[0; 0x100]; 2],
+            bank_sel: 0,
+            address: 0,
+        };
+        sensor.reset();
+        sensor
+    }
+
+    /// Value of a register as last written.
+    pub fn register(&self, reg: Register) -> u8 {
+        self.banks[reg.bank as usize][reg.address as usize]
+    }
+
+    /// Value of the bank selection register.
+    pub fn bank_sel(&self) -> u8 {
+        self.bank_sel
+    }
+
+    fn reset(&mut self) {
+        self.banks = [[0; 0x100]; 2];
+        self.bank_sel = 0;
+        self.banks[Bank::Sensor as usize][OV2640_CHIPID_HIGH.address as usize] = 0x26;
+        self.banks[Bank::Sensor as usize][OV2640_CHIPID_LOW.address as usize] = 0x42;
+    }
+
+    fn selected_bank(&self) -> usize {
+        (self.bank_sel & 0x01) as usize
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        let Some((&address, values)) = bytes.split_first() else {
+            return;
+        };
+        self.address = address;
+        // Registers auto-increment on the OV2640 but the drivers never rely
+        // on it, only the first value is stored.
+        let Some(&value) = values.first() else {
+            return;
+        };
+        if address == BANK_SEL {
+            self.bank_sel = value;
+        } else if self.selected_bank() == Bank::Sensor as usize
+            && address == COM7.address
+            && value & 0x80 != 0
+        {
+            self.reset();
+        } else {
+            self.banks[self.selected_bank()][address as usize] = value;
+        }
+    }
+
+    fn read(&mut self, bytes: &mut [u8]) {
+        let value = if self.address == BANK_SEL {
+            self.bank_sel
+        } else {
+            self.banks[self.selected_bank()][self.address as usize]
+        };
+        bytes.fill(value);
+    }
+}
+
+impl Default for FakeOv2640 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl i2c::ErrorType for FakeOv2640 {
+    type Error = i2c::ErrorKind;
+}
+
+impl i2c::I2c for FakeOv2640 {
+    fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [i2c::Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        if address != SENSOR_ADDRESS {
+            return Err(i2c::ErrorKind::NoAcknowledge(
+                i2c::NoAcknowledgeSource::Address,
+            ));
+        }
+        for operation in operations {
+            match operation {
+                i2c::Operation::Write(bytes) => self.write(bytes),
+                i2c::Operation::Read(bytes) => self.read(bytes),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arduchip::{ARDUCHIP_TEST1, ArduChip};
+    use crate::ov2640::{OV2640_QVGA, Ov2640};
+    use embedded_hal_mock::eh1::delay::NoopDelay;
+
+    fn frame(length: usize) -> Vec<u8> {
+        (0..length).map(|i| (i * 7 + i / 256) as u8).collect()
+    }
+
+    #[test]
+    fn capture_path_reads_back_the_frame() {
+        let frame = frame(320 * 240 * 2);
+        let chip = FakeArduChip::new(&frame).with_capture_polls(Some(3));
+        let mut arduchip = ArduChip::new(chip.spi(), chip.cs());
+        let mut sensor = Ov2640::new(FakeOv2640::new());
+
+        arduchip.reset(&mut NoopDelay).unwrap();
+        arduchip.check_test_register().unwrap();
+        sensor.probe().unwrap();
+        sensor.soft_reset(&mut NoopDelay).unwrap();
+        sensor.write_table(&OV2640_QVGA).unwrap();
+
+        arduchip.clear_fifo_flag().unwrap();
+        arduchip.start_capture().unwrap();
+        let mut polls = 1;
+        while !arduchip.capture_done().unwrap() {
+            polls += 1;
+        }
+        assert_eq!(polls, 3);
+        assert_eq!(arduchip.fifo_length(), Ok(frame.len() as u32));
+
+        let mut read = vec![0u8; frame.len()];
+        arduchip.start_burst_read().unwrap();
+        for chunk in read.chunks_mut(1000) {
+            arduchip.read_burst(chunk).unwrap();
+        }
+        arduchip.end_burst_read().unwrap();
+        assert_eq!(read, frame);
+        assert_eq!(chip.captures(), 1);
+    }
+
+    #[test]
+    fn clearing_the_fifo_flag_clears_capture_done() {
+        let frame = frame(16);
+        let chip = FakeArduChip::new(&frame);
+        let mut arduchip = ArduChip::new(chip.spi(), chip.cs());
+
+        assert_eq!(arduchip.capture_done(), Ok(false));
+        arduchip.start_capture().unwrap();
+        assert_eq!(arduchip.capture_done(), Ok(true));
+        arduchip.clear_fifo_flag().unwrap();
+        assert_eq!(arduchip.capture_done(), Ok(false));
+        assert_eq!(arduchip.fifo_length(), Ok(16));
+    }
+
+    #[test]
+    fn stuck_capture_never_completes() {
+        let frame = frame(16);
+        let chip = FakeArduChip::new(&frame).with_capture_polls(None);
+        let mut arduchip = ArduChip::new(chip.spi(), chip.cs());
+
+        arduchip.start_capture().unwrap();
+        for _ in 0..100 {
+            assert_eq!(arduchip.capture_done(), Ok(false));
+        }
+        assert_eq!(arduchip.fifo_length(), Ok(0));
+    }
+
+    #[test]
+    fn reset_clears_the_registers() {
+        let chip = FakeArduChip::new(&[]);
+        let mut arduchip = ArduChip::new(chip.spi(), chip.cs());
+
+        arduchip.write_reg(ARDUCHIP_TEST1, 0x55).unwrap();
+        assert_eq!(chip.register(ARDUCHIP_TEST1), 0x55);
+        arduchip.reset(&mut NoopDelay).unwrap();
+        assert_eq!(arduchip.read_reg(ARDUCHIP_TEST1), Ok(0x00));
+    }
+
+    #[test]
+    fn sensor_registers_are_banked() {
+        let mut sensor = Ov2640::new(FakeOv2640::new());
+        sensor.write(Register::dsp(0x12), 0x11).unwrap();
+        sensor.write(Register::sensor(0x11), 0x22).unwrap();
+        assert_eq!(sensor.read(Register::dsp(0x12)), Ok(0x11));
+        assert_eq!(sensor.read(Register::sensor(0x12)), Ok(0x00));
+
+        let fake = sensor.release();
+        assert_eq!(fake.register(Register::sensor(0x11)), 0x22);
+        assert_eq!(fake.bank_sel(), Bank::Sensor as u8);
+    }
+
+    #[test]
+    fn sensor_soft_reset_restores_defaults() {
+        let mut sensor = Ov2640::new(FakeOv2640::new());
+        sensor.write_table(&OV2640_QVGA).unwrap();
+        sensor.soft_reset(&mut NoopDelay).unwrap();
+        let fake = sensor.release();
+        assert_eq!(fake.register(Register::dsp(0x2C)), 0x00);
+        assert_eq!(fake.register(OV2640_CHIPID_HIGH), 0x26);
+    }
+}
@@ -0,0 +1,790 @@
+//! HTTP/1.1 requests of the clients, parsed in place in the receive buffer,
+//! the routes of the server and the heads of its responses.
+//!
+//! The parser takes the bytes received so far and returns either a whole
+//! request with its length, so that the pipelined requests following it are
+//! parsed next, or [`Parsed::Partial`] if more bytes are needed. Lines may
+//! end with CRLF or a bare LF.
+//!
+//! ```ignore
+//! match http::parse(&buffer[..received], buffer.len())? {
+//!     Parsed::Complete { request, length } => match http::route(ROUTES, &request) {
+//!         Ok(handler) => ...,
+//!         Err(rejection) => respond(rejection.status()),
+//!     },
+//!     Parsed::Partial => receive_more(),
+//! }
+//! ```
+//!
+//! The heads of the responses are written by the [`fmt::Display`]
+//! implementation of [`Response`], with CRLF line endings, so that the
+//! firmware and the simulator frame their responses the same way. The
+//! streams of frames are `multipart/x-mixed-replace` bodies, each frame a
+//! [`Part`] replacing the previous one, which browsers, VLC and OpenCV play
+//! as a video.
+
+use core::fmt;
+
+/// Headers kept for the handlers, the others make the request invalid.
+pub const MAX_HEADERS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+    /// The request line is not a method, an origin-form target and an
+    /// HTTP/1.x version separated by single spaces.
+    RequestLine,
+    /// A header line has no colon, an invalid name or is not UTF-8.
+    Header,
+    /// The request has more than [`MAX_HEADERS`] headers.
+    TooManyHeaders,
+    /// The `Content-Length` header is not a number, is repeated with another
+    /// value, or announces a body that does not fit in the receive buffer.
+    ContentLength,
+    /// The body has a transfer coding, which is not supported.
+    TransferEncoding,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Options,
+    Patch,
+    /// A method no route accepts.
+    Other,
+}
+
+impl Method {
+    /// Reads the method token of a request line, `None` if it is not a token.
+    fn parse(token: &str) -> Option<Self> {
+        if token.is_empty() || !token.bytes().all(is_token_byte) {
+            return None;
+        }
+        Some(match token {
+            "GET" => Method::Get,
+            "HEAD" => Method::Head,
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            "DELETE" => Method::Delete,
+            "OPTIONS" => Method::Options,
+            "PATCH" => Method::Patch,
+            _ => Method::Other,
+        })
+    }
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Options => "OPTIONS",
+            Method::Patch => "PATCH",
+            Method::Other => "OTHER",
+        }
+    }
+}
+
+/// Status of the responses of the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Status {
+    Ok,
+    BadRequest,
+    NotFound,
+    MethodNotAllowed,
+    InternalServerError,
+    ServiceUnavailable,
+}
+
+impl Status {
+    pub const fn code(self) -> u16 {
+        match self {
+            Status::Ok => 200,
+            Status::BadRequest => 400,
+            Status::NotFound => 404,
+            Status::MethodNotAllowed => 405,
+            Status::InternalServerError => 500,
+            Status::ServiceUnavailable => 503,
+        }
+    }
+
+    pub const fn reason(self) -> &'static str {
+        match self {
+            Status::Ok => "OK",
+            Status::BadRequest => "Bad Request",
+            Status::NotFound => "Not Found",
+            Status::MethodNotAllowed => "Method Not Allowed",
+            Status::InternalServerError => "Internal Server Error",
+            Status::ServiceUnavailable => "Service Unavailable",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header<'a> {
+    pub name: &'a str,
+    /// The value without the whitespace around it.
+    pub value: &'a str,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Request<'a> {
+    pub method: Method,
+    /// Path and query, as received.
+    pub target: &'a str,
+    pub path: &'a str,
+    pub query: Option<&'a str>,
+    /// Minor version of HTTP/1.
+    pub version: u8,
+    headers: [Header<'a>; MAX_HEADERS],
+    header_count: usize,
+    /// The `Content-Length` bytes following the headers.
+    pub body: &'a [u8],
+}
+
+impl<'a> Request<'a> {
+    pub fn headers(&self) -> &[Header<'a>] {
+        &self.headers[..self.header_count]
+    }
+
+    /// Value of the first header named `name`, compared without case.
+    pub fn header(&self, name: &str) -> Option<&'a str> {
+        self.headers()
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case(name))
+            .map(|header| header.value)
+    }
+
+    /// Whether the client keeps the connection open after the response,
+    /// unless it sends `Connection: close`, or with HTTP/1.0 only if it sends
+    /// `Connection: keep-alive`.
+    pub fn connection(&self) -> Connection {
+        let has_option = |option: &str| {
+            self.headers()
+                .iter()
+                .filter(|header| header.name.eq_ignore_ascii_case("connection"))
+                .flat_map(|header| header.value.split(','))
+                .any(|value| value.trim().eq_ignore_ascii_case(option))
+        };
+        if has_option("close") || (self.version == 0 && !has_option("keep-alive")) {
+            Connection::Close
+        } else {
+            Connection::KeepAlive
+        }
+    }
+}
+
+/// What becomes of the connection after a response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Connection {
+    KeepAlive,
+    Close,
+}
+
+impl Connection {
+    /// Value of the `Connection` header.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Connection::KeepAlive => "keep-alive",
+            Connection::Close => "close",
+        }
+    }
+}
+
+/// Outcome of [`parse`].
+// Matched as soon as it is returned, never stored, so its size hardly matters.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parsed<'a> {
+    /// A request, of `length` bytes with its body.
+    Complete { request: Request<'a>, length: usize },
+    /// The request is not whole yet.
+    Partial,
+}
+
+/// Parses the request at the start of `data`, received in a buffer of
+/// `capacity` bytes which the request must fit in.
+///
+/// The empty lines in front of the request line are skipped, as clients may
+/// send them after the body of the previous request.
+pub fn parse(data: &[u8], capacity: usize) -> Result<Parsed<'_>, Error> {
+    let mut lines = Lines { data, position: 0 };
+    let request_line = loop {
+        match lines.next() {
+            Some([]) => continue,
+            Some(line) => break line,
+            None => return Ok(Parsed::Partial),
+        }
+    };
+    let request_line = str::from_utf8(request_line).map_err(|_| Error::RequestLine)?;
+    let mut parts = request_line.split(' ');
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(Error::RequestLine);
+    };
+    let method = Method::parse(method).ok_or(Error::RequestLine)?;
+    let version = match version {
+        "HTTP/1.0" => 0,
+        "HTTP/1.1" => 1,
+        _ => return Err(Error::RequestLine),
+    };
+    if !target.starts_with('/') || !target.bytes().all(|byte| byte.is_ascii_graphic()) {
+        return Err(Error::RequestLine);
+    }
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (target, None),
+    };
+
+    let mut headers = [Header {
+        name: "",
+        value: "",
+    }; MAX_HEADERS];
+    let mut header_count = 0;
+    let mut content_length = None;
+    loop {
+        let Some(line) = lines.next() else {
+            return Ok(Parsed::Partial);
+        };
+        if line.is_empty() {
+            break;
+        }
+        let header = parse_header(line)?;
+        if header.name.eq_ignore_ascii_case("content-length") {
+            let length = parse_decimal(header.value).ok_or(Error::ContentLength)?;
+            if content_length.is_some_and(|previous| previous != length) {
+                return Err(Error::ContentLength);
+            }
+            content_length = Some(length);
+        } else if header.name.eq_ignore_ascii_case("transfer-encoding") {
+            return Err(Error::TransferEncoding);
+        }
+        *headers.get_mut(header_count).ok_or(Error::TooManyHeaders)? = header;
+        header_count += 1;
+    }
+
+    let start = lines.position;
+    // Rather than waiting for a body that would never be received whole
+    let end = start
+        .checked_add(content_length.unwrap_or(0))
+        .filter(|&end| end <= capacity)
+        .ok_or(Error::ContentLength)?;
+    let Some(body) = data.get(start..end) else {
+        return Ok(Parsed::Partial);
+    };
+    let request = Request {
+        method,
+        target,
+        path,
+        query,
+        version,
+        headers,
+        header_count,
+        body,
+    };
+    Ok(Parsed::Complete {
+        request,
+        length: end,
+    })
+}
+
+fn parse_header(line: &[u8]) -> Result<Header<'_>, Error> {
+    let line = str::from_utf8(line).map_err(|_| Error::Header)?;
+    let (name, value) = line.split_once(':').ok_or(Error::Header)?;
+    // Whitespace before the colon or folded lines are rejected
+    if name.is_empty() || !name.bytes().all(is_token_byte) {
+        return Err(Error::Header);
+    }
+    Ok(Header {
+        name,
+        value: value.trim_matches([' ', '\t']),
+    })
+}
+
+fn parse_decimal(value: &str) -> Option<usize> {
+    if value.is_empty() || !value.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    value.parse().ok()
+}
+
+/// Characters of the method and header name tokens.
+fn is_token_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte)
+}
+
+/// Lines of the request head, without their CRLF or LF.
+struct Lines<'a> {
+    data: &'a [u8],
+    /// Start of the next line.
+    position: usize,
+}
+
+impl<'a> Lines<'a> {
+    /// Returns the next whole line, `None` if it has not been received.
+    fn next(&mut self) -> Option<&'a [u8]> {
+        let rest = &self.data[self.position..];
+        let end = rest.iter().position(|&byte| byte == b'\n')?;
+        self.position += end + 1;
+        let line = &rest[..end];
+        Some(line.strip_suffix(b"\r").unwrap_or(line))
+    }
+}
+
+/// Parameters of a query or a form body, `key=value` pairs separated by `&`.
+///
+/// The parameters without a `=` have an empty value.
+pub fn parameters(query: &str) -> impl Iterator<Item = (&str, &str)> {
+    query
+        .split('&')
+        .filter(|parameter| !parameter.is_empty())
+        .map(|parameter| parameter.split_once('=').unwrap_or((parameter, "")))
+}
+
+/// Room left in front of the bodies of the responses for their heads.
+///
+/// The longest head the server writes, of a PGM frame with its motion and
+/// the counters of failures, takes up to 255 bytes.
+pub const HEAD_ROOM: usize = 256;
+
+/// Status line and headers of a response, written by its [`fmt::Display`]
+/// implementation.
+///
+/// The responses are never cached, as every frame and status is a new one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Response<'a> {
+    pub status: Status,
+    pub content_type: &'a str,
+    /// Length of the body, `None` if it ends with the connection.
+    pub content_length: Option<usize>,
+    pub connection: Connection,
+    /// Headers following the standard ones.
+    pub headers: &'a [Header<'a>],
+}
+
+impl<'a> Response<'a> {
+    /// Head of a response with a body of `content_length` bytes, keeping the
+    /// connection open.
+    pub const fn new(status: Status, content_type: &'a str, content_length: usize) -> Self {
+        Self {
+            status,
+            content_type,
+            content_length: Some(content_length),
+            connection: Connection::KeepAlive,
+            headers: &[],
+        }
+    }
+
+    /// What becomes of the connection, closed whatever was asked if the body
+    /// has no length.
+    pub fn connection(&self) -> Connection {
+        match self.content_length {
+            Some(_) => self.connection,
+            None => Connection::Close,
+        }
+    }
+}
+
+impl fmt::Display for Response<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "HTTP/1.1 {} {}\r\n",
+            self.status.code(),
+            self.status.reason()
+        )?;
+        write!(f, "Content-Type: {}\r\n", self.content_type)?;
+        if let Some(length) = self.content_length {
+            write!(f, "Content-Length: {}\r\n", length)?;
+        }
+        f.write_str("Cache-Control: no-store\r\n")?;
+        write!(f, "Connection: {}\r\n", self.connection().name())?;
+        for header in self.headers {
+            write!(f, "{}: {}\r\n", header.name, header.value)?;
+        }
+        f.write_str("\r\n")
+    }
+}
+
+/// Separator of the parts of the streams.
+pub const STREAM_BOUNDARY: &str = "frame";
+
+/// Content type of the streams of frames, delimited by [`STREAM_BOUNDARY`].
+pub const STREAM_CONTENT_TYPE: &str = "multipart/x-mixed-replace; boundary=frame";
+
+/// Head of a frame of a stream, written by its [`fmt::Display`]
+/// implementation.
+///
+/// It starts with the CRLF of the delimiter, which makes the previous part
+/// end with its body and the first one follow an empty preamble.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Part<'a> {
+    pub content_type: &'a str,
+    pub content_length: usize,
+}
+
+impl fmt::Display for Part<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\r\n--{}\r\n", STREAM_BOUNDARY)?;
+        write!(f, "Content-Type: {}\r\n", self.content_type)?;
+        write!(f, "Content-Length: {}\r\n\r\n", self.content_length)
+    }
+}
+
+/// Handler of the requests to `path` with one of `methods`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Route<H> {
+    pub path: &'static str,
+    pub methods: &'static [Method],
+    pub handler: H,
+}
+
+impl<H> Route<H> {
+    pub const fn new(path: &'static str, methods: &'static [Method], handler: H) -> Self {
+        Self {
+            path,
+            methods,
+            handler,
+        }
+    }
+}
+
+/// Why no route handles a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    NotFound,
+    /// The path has a route for the `allowed` methods only.
+    MethodNotAllowed {
+        allowed: &'static [Method],
+    },
+}
+
+impl Rejection {
+    pub const fn status(self) -> Status {
+        match self {
+            Rejection::NotFound => Status::NotFound,
+            Rejection::MethodNotAllowed { .. } => Status::MethodNotAllowed,
+        }
+    }
+}
+
+/// Finds the handler of the route matching the path of `request` exactly.
+pub fn route<H: Copy>(routes: &[Route<H>], request: &Request) -> Result<H, Rejection> {
+    let route = routes
+        .iter()
+        .find(|route| route.path == request.path)
+        .ok_or(Rejection::NotFound)?;
+    if route.methods.contains(&request.method) {
+        Ok(route.handler)
+    } else {
+        Err(Rejection::MethodNotAllowed {
+            allowed: route.methods,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Size of the receive buffer of the server.
+    const CAPACITY: usize = 1024;
+
+    fn complete(data: &[u8]) -> (Request<'_>, usize) {
+        match parse(data, CAPACITY) {
+            Ok(Parsed::Complete { request, length }) => (request, length),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn requests_are_parsed() {
+        let data =
+            b"GET /frame.bmp?frame=next HTTP/1.1\r\nHost: camera\r\nAccept:  image/png \r\n\r\n";
+        let (request, length) = complete(data);
+        assert_eq!(length, data.len());
+        assert_eq!(request.method, Method::Get);
+        assert_eq!(request.target, "/frame.bmp?frame=next");
+        assert_eq!(request.path, "/frame.bmp");
+        assert_eq!(request.query, Some("frame=next"));
+        assert_eq!(request.version, 1);
+        assert_eq!(request.headers().len(), 2);
+        assert_eq!(request.header("accept"), Some("image/png"));
+        assert_eq!(request.header("Connection"), None);
+        assert!(request.body.is_empty());
+    }
+
+    #[test]
+    fn bare_line_feeds_are_accepted() {
+        let (request, length) = complete(b"\r\nGET / HTTP/1.0\nHost: camera\n\n");
+        assert_eq!(length, 31);
+        assert_eq!((request.path, request.version), ("/", 0));
+        assert_eq!(request.header("host"), Some("camera"));
+    }
+
+    #[test]
+    fn partial_requests_wait_for_more_bytes() {
+        let data = b"POST /config HTTP/1.1\r\nContent-Length: 10\r\n\r\nframe=next";
+        for end in 0..data.len() {
+            assert_eq!(
+                parse(&data[..end], CAPACITY),
+                Ok(Parsed::Partial),
+                "{}",
+                end
+            );
+        }
+        let (request, _) = complete(data);
+        assert_eq!(request.method, Method::Post);
+        assert_eq!(request.body, b"frame=next");
+    }
+
+    #[test]
+    fn pipelined_requests_are_parsed_one_after_the_other() {
+        let data =
+            b"POST /config HTTP/1.1\r\nContent-Length: 3\r\n\r\na=bGET /status HTTP/1.1\r\n\r\nGET";
+        let (first, length) = complete(data);
+        assert_eq!(first.body, b"a=b");
+        let (second, second_length) = complete(&data[length..]);
+        assert_eq!(second.path, "/status");
+        assert_eq!(
+            parse(&data[length + second_length..], CAPACITY),
+            Ok(Parsed::Partial)
+        );
+    }
+
+    #[test]
+    fn invalid_requests_are_rejected() {
+        let cases: [(&[u8], Error); 12] = [
+            (b"Send me or consequences (plz)\n", Error::RequestLine),
+            (b"GET /\r\n\r\n", Error::RequestLine),
+            (b"GET / HTTP/2.0\r\n\r\n", Error::RequestLine),
+            (b"GET  / HTTP/1.1\r\n\r\n", Error::RequestLine),
+            (b"GET http://camera/ HTTP/1.1\r\n\r\n", Error::RequestLine),
+            (b"GET / HTTP/1.1\r\nHost camera\r\n\r\n", Error::Header),
+            (b"GET / HTTP/1.1\r\nHost : camera\r\n\r\n", Error::Header),
+            (
+                b"GET / HTTP/1.1\r\nContent-Length: -1\r\n\r\n",
+                Error::ContentLength,
+            ),
+            (
+                b"GET / HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\n",
+                Error::ContentLength,
+            ),
+            // Past the end of the address space
+            (
+                b"POST /config HTTP/1.1\r\nContent-Length: 18446744073709551615\r\n\r\n",
+                Error::ContentLength,
+            ),
+            // Past the end of the receive buffer
+            (
+                b"POST /config HTTP/1.1\r\nContent-Length: 1000\r\n\r\n",
+                Error::ContentLength,
+            ),
+            (
+                b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n",
+                Error::TransferEncoding,
+            ),
+        ];
+        for (data, error) in cases {
+            assert_eq!(
+                parse(data, CAPACITY),
+                Err(error),
+                "{:?}",
+                str::from_utf8(data)
+            );
+        }
+
+        let mut data = b"GET / HTTP/1.1\r\n".to_vec();
+        for _ in 0..=MAX_HEADERS {
+            data.extend_from_slice(b"X: y\r\n");
+        }
+        data.extend_from_slice(b"\r\n");
+        assert_eq!(parse(&data, CAPACITY), Err(Error::TooManyHeaders));
+    }
+
+    #[test]
+    fn unknown_methods_are_parsed() {
+        let (request, _) = complete(b"BREW /pot HTTP/1.1\r\n\r\n");
+        assert_eq!(request.method, Method::Other);
+    }
+
+    #[test]
+    fn connections_persist_by_default_in_http_1_1_only() {
+        let cases: [(&[u8], Connection); 6] = [
+            (b"GET / HTTP/1.1\r\n\r\n", Connection::KeepAlive),
+            (
+                b"GET / HTTP/1.1\r\nConnection: Close\r\n\r\n",
+                Connection::Close,
+            ),
+            (
+                b"GET / HTTP/1.1\r\nConnection: upgrade, close\r\n\r\n",
+                Connection::Close,
+            ),
+            (b"GET / HTTP/1.0\r\n\r\n", Connection::Close),
+            (
+                b"GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n",
+                Connection::KeepAlive,
+            ),
+            (
+                b"GET / HTTP/1.0\r\nConnection: keep-alive\r\nConnection: close\r\n\r\n",
+                Connection::Close,
+            ),
+        ];
+        for (data, connection) in cases {
+            let (request, _) = complete(data);
+            assert_eq!(request.connection(), connection, "{:?}", request);
+        }
+    }
+
+    #[test]
+    fn response_heads_end_their_lines_with_crlf() {
+        let headers = [Header {
+            name: "Allow",
+            value: "GET, POST",
+        }];
+        let response = Response {
+            connection: Connection::Close,
+            headers: &headers,
+            ..Response::new(Status::MethodNotAllowed, "text/plain", 19)
+        };
+        assert_eq!(
+            format!("{}", response),
+            "HTTP/1.1 405 Method Not Allowed\r\n\
+             Content-Type: text/plain\r\n\
+             Content-Length: 19\r\n\
+             Cache-Control: no-store\r\n\
+             Connection: close\r\n\
+             Allow: GET, POST\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn responses_without_length_close_the_connection() {
+        let response = Response {
+            content_length: None,
+            ..Response::new(Status::Ok, "image/bmp", 0)
+        };
+        assert_eq!(response.connection(), Connection::Close);
+        assert_eq!(
+            format!("{}", response),
+            "HTTP/1.1 200 OK\r\n\
+             Content-Type: image/bmp\r\n\
+             Cache-Control: no-store\r\n\
+             Connection: close\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn stream_parts_are_delimited_by_the_boundary() {
+        let part = Part {
+            content_type: "image/jpeg",
+            content_length: 1234,
+        };
+        assert_eq!(
+            format!("{}", part),
+            "\r\n--frame\r\nContent-Type: image/jpeg\r\nContent-Length: 1234\r\n\r\n"
+        );
+        assert!(STREAM_CONTENT_TYPE.ends_with(&format!("boundary={}", STREAM_BOUNDARY)));
+    }
+
+    #[test]
+    fn the_longest_heads_of_the_server_fit_in_their_room() {
+        // The counters of the server and the lengths are all u32 on the device
+        let max = u32::MAX.to_string();
+        let counters = [
+            Header {
+                name: "X-Capture-Timeouts",
+                value: &max,
+            },
+            Header {
+                name: "X-Camera-Reinits",
+                value: &max,
+            },
+        ];
+        let frame = [
+            Header {
+                name: "X-Motion-Pixels",
+                value: &max,
+            },
+            Header {
+                name: "X-Motion-Blobs",
+                value: &max,
+            },
+            counters[0],
+            counters[1],
+        ];
+        let allow = [
+            Header {
+                name: "Allow",
+                value: "GET, POST",
+            },
+            counters[0],
+            counters[1],
+        ];
+        let length = u32::MAX as usize;
+        let heads = [
+            Response {
+                headers: &frame,
+                ..Response::new(Status::Ok, "image/x-portable-graymap", length)
+            },
+            Response {
+                content_length: None,
+                headers: &counters,
+                ..Response::new(Status::Ok, STREAM_CONTENT_TYPE, 0)
+            },
+            Response {
+                connection: Connection::Close,
+                headers: &allow,
+                ..Response::new(Status::MethodNotAllowed, "text/plain", length)
+            },
+            Response {
+                connection: Connection::Close,
+                headers: &counters,
+                ..Response::new(Status::InternalServerError, "text/plain", length)
+            },
+        ];
+        for head in heads {
+            let head = head.to_string();
+            assert!(head.len() <= HEAD_ROOM, "{} bytes: {}", head.len(), head);
+        }
+        let part = Part {
+            content_type: "image/x-portable-graymap",
+            content_length: length,
+        };
+        assert!(part.to_string().len() <= HEAD_ROOM);
+    }
+
+    #[test]
+    fn parameters_are_split() {
+        let parameters: Vec<_> = parameters("frame=next&&color=&flag").collect();
+        assert_eq!(parameters, [("frame", "next"), ("color", ""), ("flag", "")]);
+    }
+
+    #[test]
+    fn requests_are_routed_by_path_then_method() {
+        const ROUTES: [Route<u8>; 2] = [
+            Route::new("/status", &[Method::Get], 1),
+            Route::new("/config", &[Method::Get, Method::Post], 2),
+        ];
+        let route_of = |data: &[u8]| route(&ROUTES, &complete(data).0);
+        assert_eq!(route_of(b"GET /status HTTP/1.1\r\n\r\n"), Ok(1));
+        assert_eq!(route_of(b"POST /config?x HTTP/1.1\r\n\r\n"), Ok(2));
+        assert_eq!(
+            route_of(b"GET /status/ HTTP/1.1\r\n\r\n"),
+            Err(Rejection::NotFound)
+        );
+        let rejection = route_of(b"DELETE /config HTTP/1.1\r\n\r\n").unwrap_err();
+        assert_eq!(
+            rejection,
+            Rejection::MethodNotAllowed {
+                allowed: &[Method::Get, Method::Post]
+            }
+        );
+        assert_eq!(rejection.status().code(), 405);
+    }
+}
@@ -0,0 +1,438 @@
+//! Image formats the frames are served in, behind a common [`Encoder`]
+//! interface.
+//!
+//! Encoders are given the rows of a frame from the top, in the [`Input`]
+//! layout they were read in, and append the encoded image to an output
+//! buffer. Like the capture, they do not allocate, so that a frame can be
+//! encoded while it is read from the FIFO.
+//!
+//! ```ignore
+//! let layout = Layout::new(320, 240, Input::Yuv422, Color::Gray);
+//! let mut encoder = AnyEncoder::new(ImageFormat::Png, layout, &Options::default());
+//! encoder.start(&mut out)?;
+//! for rows in frame.chunks(encoder.strip_rows() * layout.row_length()) {
+//!     encoder.write_rows(rows, &mut out)?;
+//! }
+//! let length = encoder.finish(&mut out)?;
+//! ```
+
+use crate::{bmp, jpeg, pixel, png, pnm, qoi};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+    /// The output buffer is too small for the encoded image.
+    BufferFull,
+}
+
+/// Layout of the rows given to the encoders.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Input {
+    /// One byte of luminance per pixel.
+    Gray8,
+    /// Big endian RGB565, as output by the sensor.
+    Rgb565,
+    /// Pixel pairs Y0 U Y1 V, as output by the sensor.
+    Yuv422,
+    /// Red, green and blue bytes, as output by webcams.
+    Rgb888,
+}
+
+/// Luma of the grayscale images encoded from RGB565 rows.
+pub(crate) const GRAY: pixel::Lut = pixel::Lut::new(pixel::BT601);
+
+impl Input {
+    pub const fn bytes_per_pixel(self) -> usize {
+        match self {
+            Input::Gray8 => 1,
+            Input::Rgb565 | Input::Yuv422 => 2,
+            Input::Rgb888 => 3,
+        }
+    }
+
+    /// Red, green and blue bytes of the pixel `x` of `row`.
+    pub fn rgb(self, row: &[u8], x: usize) -> [u8; 3] {
+        match self {
+            Input::Gray8 => [row[x]; 3],
+            Input::Rgb565 => {
+                pixel::rgb565_to_rgb888(u16::from_be_bytes([row[2 * x], row[2 * x + 1]]))
+            }
+            Input::Yuv422 => {
+                let [y, u, v] = self.ycbcr(row, x);
+                pixel::yuv_to_rgb888(y, u, v)
+            }
+            Input::Rgb888 => [row[3 * x], row[3 * x + 1], row[3 * x + 2]],
+        }
+    }
+
+    /// Luma of the pixel `x` of `row`.
+    pub fn gray(self, row: &[u8], x: usize) -> u8 {
+        match self {
+            Input::Gray8 => row[x],
+            Input::Rgb565 => GRAY.gray(u16::from_be_bytes([row[2 * x], row[2 * x + 1]])),
+            Input::Yuv422 => row[2 * x],
+            Input::Rgb888 => pixel::rgb888_to_gray(self.rgb(row, x), pixel::BT601),
+        }
+    }
+
+    /// Y, Cb and Cr bytes of the pixel `x` of `row`.
+    pub fn ycbcr(self, row: &[u8], x: usize) -> [u8; 3] {
+        match self {
+            Input::Gray8 => [row[x], 128, 128],
+            Input::Yuv422 => {
+                let pair = 2 * (x & !1);
+                [row[2 * x], row[pair + 1], row[pair + 3]]
+            }
+            Input::Rgb565 | Input::Rgb888 => pixel::rgb888_to_yuv(self.rgb(row, x)),
+        }
+    }
+}
+
+/// Colours of the encoded images.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Color {
+    /// The luma only.
+    Gray,
+    /// Red, green and blue reduced to 5, 6 and 5 bits, encoded as
+    /// [`Rgb888`](Self::Rgb888) by the formats without such pixels.
+    Rgb565,
+    /// Red, green and blue bytes.
+    Rgb888,
+}
+
+impl From<bmp::Format> for Color {
+    fn from(format: bmp::Format) -> Self {
+        match format {
+            bmp::Format::Gray8 => Color::Gray,
+            bmp::Format::Rgb565 => Color::Rgb565,
+            bmp::Format::Bgr888 => Color::Rgb888,
+        }
+    }
+}
+
+/// Size and pixels of the images given to an encoder and of those it writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Layout {
+    pub width: u16,
+    pub height: u16,
+    pub input: Input,
+    pub color: Color,
+}
+
+impl Layout {
+    pub const fn new(width: u16, height: u16, input: Input, color: Color) -> Self {
+        Self {
+            width,
+            height,
+            input,
+            color,
+        }
+    }
+
+    /// Length of a row given to the encoder.
+    pub const fn row_length(&self) -> usize {
+        self.width as usize * self.input.bytes_per_pixel()
+    }
+
+    pub const fn pixels(&self) -> usize {
+        self.width as usize * self.height as usize
+    }
+
+    /// Channels of the encoded pixels, for the formats with 8-bit channels
+    /// only.
+    pub const fn channels(&self) -> usize {
+        match self.color {
+            Color::Gray => 1,
+            Color::Rgb565 | Color::Rgb888 => 3,
+        }
+    }
+
+    /// The [`channels`](Self::channels) of the pixel `x` of `row`.
+    pub fn pixel(&self, row: &[u8], x: usize) -> [u8; 3] {
+        match self.color {
+            Color::Gray => [self.input.gray(row, x), 0, 0],
+            Color::Rgb565 | Color::Rgb888 => self.input.rgb(row, x),
+        }
+    }
+
+    /// Splits `rows` into whole rows.
+    ///
+    /// Panics if `rows` ends with a partial row.
+    pub(crate) fn rows<'a>(&self, rows: &'a [u8]) -> core::slice::ChunksExact<'a, u8> {
+        assert_eq!(rows.len() % self.row_length(), 0, "partial row");
+        rows.chunks_exact(self.row_length())
+    }
+}
+
+/// Image encoder fed with the rows of a frame.
+pub trait Encoder {
+    /// Media type of the images, for the `Content-Type` header.
+    fn content_type(&self) -> &'static str;
+
+    /// Rows to give at once to [`write_rows`](Self::write_rows).
+    fn strip_rows(&self) -> usize {
+        1
+    }
+
+    /// Upper bound of the length of the encoded image.
+    fn max_length(&self) -> usize;
+
+    /// Writes the start of the image at the start of `out`.
+    fn start(&mut self, out: &mut [u8]) -> Result<(), Error>;
+
+    /// Encodes the next rows of the image, a multiple of
+    /// [`strip_rows`](Self::strip_rows) except for the last ones.
+    ///
+    /// Panics if `rows` is not made of whole rows or has too many of them.
+    fn write_rows(&mut self, rows: &[u8], out: &mut [u8]) -> Result<(), Error>;
+
+    /// Writes the end of the image after the last row and returns its length.
+    fn finish(&mut self, out: &mut [u8]) -> Result<usize, Error>;
+}
+
+/// Appends `bytes` to the `length` bytes already in `out`.
+pub(crate) fn append(out: &mut [u8], length: &mut usize, bytes: &[u8]) -> Result<(), Error> {
+    let end = *length + bytes.len();
+    out.get_mut(*length..end)
+        .ok_or(Error::BufferFull)?
+        .copy_from_slice(bytes);
+    *length = end;
+    Ok(())
+}
+
+/// Formats the frames can be served in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum ImageFormat {
+    Bmp,
+    /// PGM for grayscale images, PPM for colour ones.
+    Pnm,
+    Png,
+    Qoi,
+    Jpeg,
+}
+
+impl ImageFormat {
+    /// Reads the format from a file extension.
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        match extension {
+            "bmp" => Some(Self::Bmp),
+            "pgm" | "ppm" | "pnm" => Some(Self::Pnm),
+            "png" => Some(Self::Png),
+            "qoi" => Some(Self::Qoi),
+            "jpg" | "jpeg" => Some(Self::Jpeg),
+            _ => None,
+        }
+    }
+
+    /// Usual extension of the files of the format.
+    pub const fn extension(self) -> &'static str {
+        match self {
+            Self::Bmp => "bmp",
+            Self::Pnm => "pnm",
+            Self::Png => "png",
+            Self::Qoi => "qoi",
+            Self::Jpeg => "jpg",
+        }
+    }
+
+    /// Reads the format from the extension of the path of a request target.
+    pub fn from_target(target: &str) -> Option<Self> {
+        let path = target.split('?').next()?;
+        let (_, extension) = path.rsplit_once('.')?;
+        Self::from_extension(extension)
+    }
+
+    /// Reads the first supported format listed in an `Accept` header,
+    /// ignoring the quality values and the wildcards.
+    pub fn from_accept(accept: &str) -> Option<Self> {
+        accept.split(',').find_map(|range| {
+            let media_type = range.split(';').next()?.trim();
+            match media_type {
+                "image/bmp" => Some(Self::Bmp),
+                "image/x-portable-anymap"
+                | "image/x-portable-graymap"
+                | "image/x-portable-pixmap" => Some(Self::Pnm),
+                "image/png" => Some(Self::Png),
+                "image/qoi" => Some(Self::Qoi),
+                "image/jpeg" => Some(Self::Jpeg),
+                _ => None,
+            }
+        })
+    }
+}
+
+/// Settings of the encoders that are not given by the request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Options {
+    pub orientation: bmp::Orientation,
+    pub png_compression: png::Compression,
+    /// JPEG quality from 1 to 100
+    pub jpeg_quality: u8,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            orientation: bmp::Orientation::TopDown,
+            png_compression: png::Compression::Fixed,
+            jpeg_quality: 75,
+        }
+    }
+}
+
+/// Encoder of any of the formats, selected per request.
+// A single encoder is kept by the server, boxing the JPEG one would take it
+// to the heap for nothing.
+#[allow(clippy::large_enum_variant)]
+pub enum AnyEncoder {
+    Bmp(bmp::Encoder),
+    Pnm(pnm::Encoder),
+    Png(png::Encoder),
+    Qoi(qoi::Encoder),
+    Jpeg(jpeg::Encoder),
+}
+
+impl AnyEncoder {
+    pub fn new(format: ImageFormat, layout: Layout, options: &Options) -> Self {
+        match format {
+            ImageFormat::Bmp => Self::Bmp(bmp::Encoder::new(layout, options.orientation)),
+            ImageFormat::Pnm => Self::Pnm(pnm::Encoder::new(layout)),
+            ImageFormat::Png => Self::Png(png::Encoder::new(layout, options.png_compression)),
+            ImageFormat::Qoi => Self::Qoi(qoi::Encoder::new(layout)),
+            ImageFormat::Jpeg => Self::Jpeg(jpeg::Encoder::new(layout, options.jpeg_quality)),
+        }
+    }
+
+    fn inner(&mut self) -> &mut dyn Encoder {
+        match self {
+            Self::Bmp(encoder) => encoder,
+            Self::Pnm(encoder) => encoder,
+            Self::Png(encoder) => encoder,
+            Self::Qoi(encoder) => encoder,
+            Self::Jpeg(encoder) => encoder,
+        }
+    }
+
+    fn inner_ref(&self) -> &dyn Encoder {
+        match self {
+            Self::Bmp(encoder) => encoder,
+            Self::Pnm(encoder) => encoder,
+            Self::Png(encoder) => encoder,
+            Self::Qoi(encoder) => encoder,
+            Self::Jpeg(encoder) => encoder,
+        }
+    }
+}
+
+impl Encoder for AnyEncoder {
+    fn content_type(&self) -> &'static str {
+        self.inner_ref().content_type()
+    }
+
+    fn strip_rows(&self) -> usize {
+        self.inner_ref().strip_rows()
+    }
+
+    fn max_length(&self) -> usize {
+        self.inner_ref().max_length()
+    }
+
+    fn start(&mut self, out: &mut [u8]) -> Result<(), Error> {
+        self.inner().start(out)
+    }
+
+    fn write_rows(&mut self, rows: &[u8], out: &mut [u8]) -> Result<(), Error> {
+        self.inner().write_rows(rows, out)
+    }
+
+    fn finish(&mut self, out: &mut [u8]) -> Result<usize, Error> {
+        self.inner().finish(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORMATS: [ImageFormat; 5] = [
+        ImageFormat::Bmp,
+        ImageFormat::Pnm,
+        ImageFormat::Png,
+        ImageFormat::Qoi,
+        ImageFormat::Jpeg,
+    ];
+
+    #[test]
+    fn format_is_read_from_the_extension() {
+        assert_eq!(
+            ImageFormat::from_target("/frame.png"),
+            Some(ImageFormat::Png)
+        );
+        assert_eq!(
+            ImageFormat::from_target("/frame.pgm?frame=next"),
+            Some(ImageFormat::Pnm)
+        );
+        assert_eq!(
+            ImageFormat::from_target("/frame.jpeg"),
+            Some(ImageFormat::Jpeg)
+        );
+        assert_eq!(ImageFormat::from_target("/frame"), None);
+        assert_eq!(ImageFormat::from_target("/?name=a.png"), None);
+        for format in FORMATS {
+            assert_eq!(
+                ImageFormat::from_extension(format.extension()),
+                Some(format)
+            );
+        }
+    }
+
+    #[test]
+    fn format_is_read_from_the_accept_header() {
+        assert_eq!(
+            ImageFormat::from_accept("text/html, image/qoi;q=0.9, image/png"),
+            Some(ImageFormat::Qoi)
+        );
+        assert_eq!(
+            ImageFormat::from_accept("image/x-portable-graymap"),
+            Some(ImageFormat::Pnm)
+        );
+        assert_eq!(ImageFormat::from_accept("image/*, */*;q=0.8"), None);
+    }
+
+    #[test]
+    fn inputs_agree_on_the_pixels() {
+        let rgb = [200, 100, 50];
+        let rgb565 = pixel::rgb888_to_rgb565(rgb).to_be_bytes();
+        let expanded = pixel::rgb565_to_rgb888(pixel::rgb888_to_rgb565(rgb));
+        assert_eq!(Input::Rgb565.rgb(&rgb565, 0), expanded);
+        assert_eq!(Input::Rgb888.rgb(&rgb, 0), rgb);
+        assert_eq!(
+            Input::Rgb565.gray(&rgb565, 0),
+            pixel::rgb888_to_gray(expanded, pixel::BT601)
+        );
+
+        let yuv = [10, 100, 20, 200];
+        assert_eq!(Input::Yuv422.gray(&yuv, 1), 20);
+        assert_eq!(Input::Yuv422.ycbcr(&yuv, 1), [20, 100, 200]);
+        assert_eq!(Input::Gray8.rgb(&[7], 0), [7, 7, 7]);
+    }
+
+    /// Every format encodes a frame within its bound, through the common
+    /// interface.
+    #[test]
+    fn every_format_fits_its_bound() {
+        let layout = Layout::new(17, 9, Input::Rgb565, Color::Rgb888);
+        let frame: Vec<u8> = (0..layout.row_length() * 9)
+            .map(|i| (i * 31) as u8)
+            .collect();
+        for format in FORMATS {
+            let mut encoder = AnyEncoder::new(format, layout, &Options::default());
+            let mut out = vec![0; encoder.max_length()];
+            encoder.start(&mut out).unwrap();
+            let strip = encoder.strip_rows() * layout.row_length();
+            for rows in frame.chunks(strip) {
+                encoder.write_rows(rows, &mut out).unwrap();
+            }
+            assert!(encoder.finish(&mut out).unwrap() <= out.len());
+        }
+    }
+}
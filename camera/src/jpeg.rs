@@ -0,0 +1,702 @@
+//! Helpers for the JPEG frames written to the FIFO by the sensor, and a
+//! baseline encoder for the frames it outputs uncompressed.
+//!
+//! The [`Encoder`] takes the image in strips of [`Encoder::strip_rows`] rows,
+//! so that a frame can be compressed while it is read from the FIFO without
+//! being held whole in memory.
+//!
+//! ```ignore
+//! let layout = Layout::new(320, 240, Input::Yuv422, Color::Gray);
+//! let mut encoder = Encoder::new(layout, 75);
+//! encoder.start(&mut out)?;
+//! for strip in frame.chunks(encoder.strip_length()) {
+//!     encoder.write_rows(strip, &mut out)?;
+//! }
+//! let length = encoder.finish(&mut out)?;
+//! ```
+
+use core::ops::Range;
+
+use crate::image::{self, Color, Error, Input, Layout};
+
+/// Longest headers, those of the colour images, with the padding of the
+/// entropy coded data and the EOI marker.
+const MAX_HEADERS_LENGTH: usize = 640;
+/// Longest coded block: an 11-bit DC difference and 63 10-bit AC
+/// coefficients with their longest codes, every byte stuffed.
+const MAX_BLOCK_LENGTH: usize = 2 * (11 + 11 + 63 * (16 + 10usize)).div_ceil(8);
+
+/// Start of image marker.
+pub const SOI: [u8; 2] = [0xFF, 0xD8];
+/// End of image marker.
+pub const EOI: [u8; 2] = [0xFF, 0xD9];
+
+/// Locates the JPEG image in the bytes read from the FIFO, from its SOI
+/// marker to the end of its EOI marker.
+///
+/// The FIFO length reported by the ArduChip is rounded up and the sensor may
+/// pad the frame, so bytes before the SOI and after the EOI are skipped.
+/// Returns `None` if either marker is missing.
+pub fn frame_bounds(data: &[u8]) -> Option<Range<usize>> {
+    let start = find(data, SOI)?;
+    let end = start + 2 + find(&data[start + 2..], EOI)? + 2;
+    Some(start..end)
+}
+
+fn find(data: &[u8], marker: [u8; 2]) -> Option<usize> {
+    data.windows(2).position(|window| window == marker)
+}
+
+/// Components of the encoded images.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+enum Sampling {
+    /// The luma only.
+    Gray,
+    /// The luma, and the chroma at half the resolution in both directions.
+    Yuv420,
+}
+
+impl Sampling {
+    const fn new(color: Color) -> Self {
+        match color {
+            Color::Gray => Sampling::Gray,
+            Color::Rgb565 | Color::Rgb888 => Sampling::Yuv420,
+        }
+    }
+
+    /// Blocks of a minimum coded unit.
+    const fn blocks(self) -> usize {
+        match self {
+            Sampling::Gray => 1,
+            Sampling::Yuv420 => 6,
+        }
+    }
+
+    /// Size of a square of pixels coded together, a minimum coded unit.
+    const fn mcu_size(self) -> usize {
+        match self {
+            Sampling::Gray => 8,
+            Sampling::Yuv420 => 16,
+        }
+    }
+}
+
+/// Baseline JPEG encoder, with the example tables of the specification.
+pub struct Encoder {
+    width: u16,
+    height: u16,
+    input: Input,
+    sampling: Sampling,
+    /// Quantization tables in zig-zag order, as written in the file.
+    tables: [[u8; 64]; 2],
+    /// Inverses of the quantization tables in natural order.
+    divisors: [[f32; 64]; 2],
+    /// DC coefficient of the last block of each component.
+    predictions: [i32; 3],
+    /// Bits not written yet, in the low `bit_count` bits.
+    bits: u32,
+    bit_count: u32,
+    /// Rows already encoded.
+    rows: usize,
+    /// Bytes written to the output.
+    length: usize,
+}
+
+impl Encoder {
+    /// Creates an encoder of the images of `layout` at `quality`, from 1
+    /// (smallest) to 100 (best), the scale of the IJG library. The colour
+    /// images have their chroma subsampled, 4:2:0.
+    pub fn new(layout: Layout, quality: u8) -> Self {
+        let tables = [
+            scale_table(&LUMA_QUANTIZATION, quality),
+            scale_table(&CHROMA_QUANTIZATION, quality),
+        ];
+        let mut divisors = [[0.0; 64]; 2];
+        for (divisors, table) in divisors.iter_mut().zip(&tables) {
+            for (i, &q) in table.iter().enumerate() {
+                divisors[ZIGZAG[i] as usize] = 1.0 / q as f32;
+            }
+        }
+        Self {
+            width: layout.width,
+            height: layout.height,
+            input: layout.input,
+            sampling: Sampling::new(layout.color),
+            tables,
+            divisors,
+            predictions: [0; 3],
+            bits: 0,
+            bit_count: 0,
+            rows: 0,
+            length: 0,
+        }
+    }
+
+    /// Length of a whole strip given to
+    /// [`write_rows`](image::Encoder::write_rows).
+    pub const fn strip_length(&self) -> usize {
+        self.sampling.mcu_size() * self.width as usize * self.input.bytes_per_pixel()
+    }
+
+    /// Bytes written so far.
+    pub const fn length(&self) -> usize {
+        self.length
+    }
+
+    /// Encodes the next [`strip_rows`](image::Encoder::strip_rows) rows of
+    /// the image, fewer for the last strip if the height is not a multiple,
+    /// appending them to `out`.
+    ///
+    /// Panics if `strip` is shorter than these rows.
+    fn encode_strip(&mut self, strip: &[u8], out: &mut [u8]) -> Result<(), Error> {
+        let mcu = self.sampling.mcu_size();
+        let rows = mcu.min(self.height as usize - self.rows);
+        let stride = self.width as usize * self.input.bytes_per_pixel();
+        assert!(strip.len() >= rows * stride, "strip too short");
+        let strip = Strip {
+            data: strip,
+            input: self.input,
+            width: self.width as usize,
+            rows,
+            stride,
+        };
+
+        let mut block = [0.0; 64];
+        for x in (0..self.width as usize).step_by(mcu) {
+            match self.sampling {
+                Sampling::Gray => {
+                    strip.luma_block(x, 0, &mut block);
+                    self.encode_block(&block, 0, out)?;
+                }
+                Sampling::Yuv420 => {
+                    for (dx, dy) in [(0, 0), (8, 0), (0, 8), (8, 8)] {
+                        strip.luma_block(x + dx, dy, &mut block);
+                        self.encode_block(&block, 0, out)?;
+                    }
+                    for component in 1..3 {
+                        strip.chroma_block(x, component, &mut block);
+                        self.encode_block(&block, component, out)?;
+                    }
+                }
+            }
+        }
+        self.rows += rows;
+        Ok(())
+    }
+
+    /// Transforms, quantizes and codes a block of level shifted samples.
+    fn encode_block(
+        &mut self,
+        block: &[f32; 64],
+        component: usize,
+        out: &mut [u8],
+    ) -> Result<(), Error> {
+        let table = (component > 0) as usize;
+        let (dc_table, ac_table) = match table {
+            0 => (&LUMA_DC_CODES, &LUMA_AC_CODES),
+            _ => (&CHROMA_DC_CODES, &CHROMA_AC_CODES),
+        };
+
+        let coefficients = fdct(block);
+        let mut quantized = [0i32; 64];
+        for (i, &index) in ZIGZAG.iter().enumerate() {
+            let value = coefficients[index as usize] * self.divisors[table][index as usize];
+            quantized[i] = round(value);
+        }
+
+        let difference = quantized[0] - self.predictions[component];
+        self.predictions[component] = quantized[0];
+        let (size, bits) = magnitude(difference);
+        self.put_code(dc_table, size, out)?;
+        self.put_bits(bits, size as u32, out)?;
+
+        let mut zeroes = 0;
+        for &value in &quantized[1..] {
+            if value == 0 {
+                zeroes += 1;
+                continue;
+            }
+            while zeroes >= 16 {
+                // Run of 16 zeroes
+                self.put_code(ac_table, 0xF0, out)?;
+                zeroes -= 16;
+            }
+            let (size, bits) = magnitude(value);
+            self.put_code(ac_table, (zeroes << 4) | size, out)?;
+            self.put_bits(bits, size as u32, out)?;
+            zeroes = 0;
+        }
+        if zeroes > 0 {
+            // End of block
+            self.put_code(ac_table, 0x00, out)?;
+        }
+        Ok(())
+    }
+
+    fn put_code(&mut self, codes: &HuffmanCodes, symbol: u8, out: &mut [u8]) -> Result<(), Error> {
+        let (code, length) = codes.0[symbol as usize];
+        self.put_bits(code as u32, length as u32, out)
+    }
+
+    /// Appends the low `count` bits of `bits`, stuffing a zero byte after
+    /// every 0xFF byte of the entropy coded data.
+    fn put_bits(&mut self, bits: u32, count: u32, out: &mut [u8]) -> Result<(), Error> {
+        self.bits = (self.bits << count) | (bits & ((1 << count) - 1));
+        self.bit_count += count;
+        while self.bit_count >= 8 {
+            self.bit_count -= 8;
+            let byte = (self.bits >> self.bit_count) as u8;
+            if byte == 0xFF {
+                self.write(out, &[0xFF, 0x00])?;
+            } else {
+                self.write(out, &[byte])?;
+            }
+        }
+        self.bits &= (1 << self.bit_count) - 1;
+        Ok(())
+    }
+
+    fn write(&mut self, out: &mut [u8], bytes: &[u8]) -> Result<(), Error> {
+        image::append(out, &mut self.length, bytes)
+    }
+}
+
+impl image::Encoder for Encoder {
+    fn content_type(&self) -> &'static str {
+        "image/jpeg"
+    }
+
+    fn strip_rows(&self) -> usize {
+        self.sampling.mcu_size()
+    }
+
+    fn max_length(&self) -> usize {
+        let mcu = self.sampling.mcu_size();
+        let mcus = (self.width as usize).div_ceil(mcu) * (self.height as usize).div_ceil(mcu);
+        MAX_HEADERS_LENGTH + mcus * self.sampling.blocks() * MAX_BLOCK_LENGTH
+    }
+
+    /// Writes the headers at the start of `out`, before the first strip.
+    fn start(&mut self, out: &mut [u8]) -> Result<(), Error> {
+        self.length = 0;
+        self.rows = 0;
+        self.predictions = [0; 3];
+        self.bits = 0;
+        self.bit_count = 0;
+        let components: u8 = match self.sampling {
+            Sampling::Gray => 1,
+            Sampling::Yuv420 => 3,
+        };
+
+        self.write(out, &SOI)?;
+        // JFIF, 1:1 pixel aspect ratio, no thumbnail
+        self.write(out, &[0xFF, 0xE0, 0x00, 0x10])?;
+        self.write(out, b"JFIF\0\x01\x01\x00\x00\x01\x00\x01\x00\x00")?;
+
+        let tables = self.tables;
+        for (id, table) in tables.iter().enumerate().take(components.min(2) as usize) {
+            self.write(out, &[0xFF, 0xDB, 0x00, 0x43, id as u8])?;
+            self.write(out, table)?;
+        }
+
+        // Baseline frame, 8-bit samples
+        let length = 8 + 3 * components as u16;
+        self.write(out, &[0xFF, 0xC0])?;
+        self.write(out, &length.to_be_bytes())?;
+        self.write(out, &[8])?;
+        self.write(out, &self.height.to_be_bytes())?;
+        self.write(out, &self.width.to_be_bytes())?;
+        self.write(out, &[components])?;
+        match self.sampling {
+            Sampling::Gray => self.write(out, &[1, 0x11, 0])?,
+            Sampling::Yuv420 => self.write(out, &[1, 0x22, 0, 2, 0x11, 1, 3, 0x11, 1])?,
+        }
+
+        let tables = [
+            (0x00, &LUMA_DC),
+            (0x10, &LUMA_AC),
+            (0x01, &CHROMA_DC),
+            (0x11, &CHROMA_AC),
+        ];
+        for (class, table) in tables.iter().take(components.min(2) as usize * 2) {
+            let length = 3 + 16 + table.values.len() as u16;
+            self.write(out, &[0xFF, 0xC4])?;
+            self.write(out, &length.to_be_bytes())?;
+            self.write(out, &[*class])?;
+            self.write(out, &table.counts)?;
+            self.write(out, table.values)?;
+        }
+
+        let length = 6 + 2 * components as u16;
+        self.write(out, &[0xFF, 0xDA])?;
+        self.write(out, &length.to_be_bytes())?;
+        self.write(out, &[components])?;
+        match self.sampling {
+            Sampling::Gray => self.write(out, &[1, 0x00])?,
+            Sampling::Yuv420 => self.write(out, &[1, 0x00, 2, 0x11, 3, 0x11])?,
+        }
+        // Spectral selection and approximation of a baseline scan
+        self.write(out, &[0, 63, 0])
+    }
+
+    fn write_rows(&mut self, rows: &[u8], out: &mut [u8]) -> Result<(), Error> {
+        for strip in rows.chunks(self.strip_length()) {
+            self.encode_strip(strip, out)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the end of the image after the last strip and returns the
+    /// length of the image.
+    fn finish(&mut self, out: &mut [u8]) -> Result<usize, Error> {
+        // Pad the last byte with ones
+        let padding = (8 - self.bit_count % 8) % 8;
+        self.put_bits((1 << padding) - 1, padding, out)?;
+        self.write(out, &EOI)?;
+        Ok(self.length)
+    }
+}
+
+/// Rows of the image given to [`Encoder::encode_strip`], whose edges are
+/// repeated to fill the blocks past the right and bottom of the image.
+struct Strip<'a> {
+    data: &'a [u8],
+    input: Input,
+    width: usize,
+    rows: usize,
+    stride: usize,
+}
+
+impl Strip<'_> {
+    /// Y, Cb and Cr of a pixel, clamped to the strip.
+    fn ycbcr(&self, x: usize, y: usize) -> [u8; 3] {
+        let x = x.min(self.width - 1);
+        let row = &self.data[y.min(self.rows - 1) * self.stride..];
+        self.input.ycbcr(row, x)
+    }
+
+    /// Level shifted luma of the 8x8 block at `x`, `y`.
+    fn luma_block(&self, x: usize, y: usize, block: &mut [f32; 64]) {
+        for (i, sample) in block.iter_mut().enumerate() {
+            let [luma, _, _] = self.ycbcr(x + i % 8, y + i / 8);
+            *sample = luma as f32 - 128.0;
+        }
+    }
+
+    /// Level shifted chroma `component` of the 16x16 pixels at `x`, averaged
+    /// over 2x2 pixels.
+    fn chroma_block(&self, x: usize, component: usize, block: &mut [f32; 64]) {
+        for (i, sample) in block.iter_mut().enumerate() {
+            let (px, py) = (x + 2 * (i % 8), 2 * (i / 8));
+            let sum: u32 = [(0, 0), (1, 0), (0, 1), (1, 1)]
+                .into_iter()
+                .map(|(dx, dy)| self.ycbcr(px + dx, py + dy)[component] as u32)
+                .sum();
+            *sample = sum as f32 / 4.0 - 128.0;
+        }
+    }
+}
+
+/// Two dimensional DCT of a block, row by row then column by column.
+fn fdct(block: &[f32; 64]) -> [f32; 64] {
+    let mut rows = [0.0; 64];
+    for y in 0..8 {
+        for u in 0..8 {
+            rows[y * 8 + u] = (0..8).map(|x| DCT[u][x] * block[y * 8 + x]).sum();
+        }
+    }
+    let mut coefficients = [0.0; 64];
+    for u in 0..8 {
+        for v in 0..8 {
+            coefficients[v * 8 + u] = (0..8).map(|y| DCT[v][y] * rows[y * 8 + u]).sum();
+        }
+    }
+    coefficients
+}
+
+/// Rounds half away from zero, without the `round` of `std`.
+fn round(value: f32) -> i32 {
+    if value < 0.0 {
+        (value - 0.5) as i32
+    } else {
+        (value + 0.5) as i32
+    }
+}
+
+/// Size category of a coefficient and its bits: the value if positive, its
+/// ones' complement otherwise.
+fn magnitude(value: i32) -> (u8, u32) {
+    let size = 32 - value.unsigned_abs().leading_zeros();
+    let bits = if value < 0 { value - 1 } else { value };
+    (size as u8, bits as u32 & ((1 << size) - 1))
+}
+
+/// Scales a quantization table by `quality`, as the IJG library does.
+fn scale_table(table: &[u8; 64], quality: u8) -> [u8; 64] {
+    let quality = quality.clamp(1, 100) as u32;
+    let scale = if quality < 50 {
+        5000 / quality
+    } else {
+        200 - 2 * quality
+    };
+    table.map(|q| ((q as u32 * scale + 50) / 100).clamp(1, 255) as u8)
+}
+
+/// Basis of the DCT, `C(u) / 2 * cos((2x + 1) u pi / 16)`.
+#[rustfmt::skip]
+const DCT: [[f32; 8]; 8] = [
+    [0.35355339, 0.35355339, 0.35355339, 0.35355339, 0.35355339, 0.35355339, 0.35355339, 0.35355339],
+    [0.49039264, 0.4157348, 0.27778512, 0.09754516, -0.09754516, -0.27778512, -0.4157348, -0.49039264],
+    [0.46193975, 0.19134172, -0.19134172, -0.46193975, -0.46193975, -0.19134172, 0.19134172, 0.46193975],
+    [0.4157348, -0.09754516, -0.49039264, -0.27778512, 0.27778512, 0.49039264, 0.09754516, -0.4157348],
+    [0.35355339, -0.35355339, -0.35355339, 0.35355339, 0.35355339, -0.35355339, -0.35355339, 0.35355339],
+    [0.27778512, -0.49039264, 0.09754516, 0.4157348, -0.4157348, -0.09754516, 0.49039264, -0.27778512],
+    [0.19134172, -0.46193975, 0.46193975, -0.19134172, -0.19134172, 0.46193975, -0.46193975, 0.19134172],
+    [0.09754516, -0.27778512, 0.4157348, -0.49039264, 0.49039264, -0.4157348, 0.27778512, -0.09754516],
+];
+
+/// Index in the block of the coefficients, in zig-zag order.
+const ZIGZAG: [u8; 64] = [
+    0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5, 12, 19, 26, 33, 40, 48, 41, 34, 27, 20,
+    13, 6, 7, 14, 21, 28, 35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44, 51, 58, 59,
+    52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+/// Table K.1 of the specification, in zig-zag order.
+const LUMA_QUANTIZATION: [u8; 64] = [
+    16, 11, 12, 14, 12, 10, 16, 14, 13, 14, 18, 17, 16, 19, 24, 40, 26, 24, 22, 22, 24, 49, 35, 37,
+    29, 40, 58, 51, 61, 60, 57, 51, 56, 55, 64, 72, 92, 78, 64, 68, 87, 69, 55, 56, 80, 109, 81,
+    87, 95, 98, 103, 104, 103, 62, 77, 113, 121, 112, 100, 120, 92, 101, 103, 99,
+];
+
+/// Table K.2 of the specification, in zig-zag order.
+const CHROMA_QUANTIZATION: [u8; 64] = [
+    17, 18, 18, 24, 21, 24, 47, 26, 26, 47, 99, 66, 56, 66, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99,
+];
+
+/// Huffman table as written in the file: the number of codes of each length
+/// from 1 to 16 bits, then the symbols by increasing code.
+struct HuffmanTable {
+    counts: [u8; 16],
+    values: &'static [u8],
+}
+
+/// Code and length of the code of every symbol.
+struct HuffmanCodes([(u16, u8); 256]);
+
+impl HuffmanCodes {
+    /// Assigns the canonical codes of the table.
+    const fn new(table: &HuffmanTable) -> Self {
+        let mut codes = [(0, 0); 256];
+        let mut code = 0u16;
+        let mut k = 0;
+        let mut length = 0;
+        while length < 16 {
+            let mut count = 0;
+            while count < table.counts[length] {
+                codes[table.values[k] as usize] = (code, length as u8 + 1);
+                code += 1;
+                k += 1;
+                count += 1;
+            }
+            code <<= 1;
+            length += 1;
+        }
+        Self(codes)
+    }
+}
+
+/// Table K.3 of the specification.
+const LUMA_DC: HuffmanTable = HuffmanTable {
+    counts: [0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0],
+    values: &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11],
+};
+
+/// Table K.4 of the specification.
+const CHROMA_DC: HuffmanTable = HuffmanTable {
+    counts: [0, 3, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0],
+    values: &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11],
+};
+
+/// Table K.5 of the specification.
+const LUMA_AC: HuffmanTable = HuffmanTable {
+    counts: [0, 2, 1, 3, 3, 2, 4, 3, 5, 5, 4, 4, 0, 0, 1, 0x7D],
+    values: &[
+        0x01, 0x02, 0x03, 0x00, 0x04, 0x11, 0x05, 0x12, 0x21, 0x31, 0x41, 0x06, 0x13, 0x51, 0x61,
+        0x07, 0x22, 0x71, 0x14, 0x32, 0x81, 0x91, 0xA1, 0x08, 0x23, 0x42, 0xB1, 0xC1, 0x15, 0x52,
+        0xD1, 0xF0, 0x24, 0x33, 0x62, 0x72, 0x82, 0x09, 0x0A, 0x16, 0x17, 0x18, 0x19, 0x1A, 0x25,
+        0x26, 0x27, 0x28, 0x29, 0x2A, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3A, 0x43, 0x44, 0x45,
+        0x46, 0x47, 0x48, 0x49, 0x4A, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5A, 0x63, 0x64,
+        0x65, 0x66, 0x67, 0x68, 0x69, 0x6A, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7A, 0x83,
+        0x84, 0x85, 0x86, 0x87, 0x88, 0x89, 0x8A, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99,
+        0x9A, 0xA2, 0xA3, 0xA4, 0xA5, 0xA6, 0xA7, 0xA8, 0xA9, 0xAA, 0xB2, 0xB3, 0xB4, 0xB5, 0xB6,
+        0xB7, 0xB8, 0xB9, 0xBA, 0xC2, 0xC3, 0xC4, 0xC5, 0xC6, 0xC7, 0xC8, 0xC9, 0xCA, 0xD2, 0xD3,
+        0xD4, 0xD5, 0xD6, 0xD7, 0xD8, 0xD9, 0xDA, 0xE1, 0xE2, 0xE3, 0xE4, 0xE5, 0xE6, 0xE7, 0xE8,
+        0xE9, 0xEA, 0xF1, 0xF2, 0xF3, 0xF4, 0xF5, 0xF6, 0xF7, 0xF8, 0xF9, 0xFA,
+    ],
+};
+
+/// Table K.6 of the specification.
+const CHROMA_AC: HuffmanTable = HuffmanTable {
+    counts: [0, 2, 1, 2, 4, 4, 3, 4, 7, 5, 4, 4, 0, 1, 2, 0x77],
+    values: &[
+        0x00, 0x01, 0x02, 0x03, 0x11, 0x04, 0x05, 0x21, 0x31, 0x06, 0x12, 0x41, 0x51, 0x07, 0x61,
+        0x71, 0x13, 0x22, 0x32, 0x81, 0x08, 0x14, 0x42, 0x91, 0xA1, 0xB1, 0xC1, 0x09, 0x23, 0x33,
+        0x52, 0xF0, 0x15, 0x62, 0x72, 0xD1, 0x0A, 0x16, 0x24, 0x34, 0xE1, 0x25, 0xF1, 0x17, 0x18,
+        0x19, 0x1A, 0x26, 0x27, 0x28, 0x29, 0x2A, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3A, 0x43, 0x44,
+        0x45, 0x46, 0x47, 0x48, 0x49, 0x4A, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5A, 0x63,
+        0x64, 0x65, 0x66, 0x67, 0x68, 0x69, 0x6A, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7A,
+        0x82, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89, 0x8A, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97,
+        0x98, 0x99, 0x9A, 0xA2, 0xA3, 0xA4, 0xA5, 0xA6, 0xA7, 0xA8, 0xA9, 0xAA, 0xB2, 0xB3, 0xB4,
+        0xB5, 0xB6, 0xB7, 0xB8, 0xB9, 0xBA, 0xC2, 0xC3, 0xC4, 0xC5, 0xC6, 0xC7, 0xC8, 0xC9, 0xCA,
+        0xD2, 0xD3, 0xD4, 0xD5, 0xD6, 0xD7, 0xD8, 0xD9, 0xDA, 0xE2, 0xE3, 0xE4, 0xE5, 0xE6, 0xE7,
+        0xE8, 0xE9, 0xEA, 0xF2, 0xF3, 0xF4, 0xF5, 0xF6, 0xF7, 0xF8, 0xF9, 0xFA,
+    ],
+};
+
+static LUMA_DC_CODES: HuffmanCodes = HuffmanCodes::new(&LUMA_DC);
+static LUMA_AC_CODES: HuffmanCodes = HuffmanCodes::new(&LUMA_AC);
+static CHROMA_DC_CODES: HuffmanCodes = HuffmanCodes::new(&CHROMA_DC);
+static CHROMA_AC_CODES: HuffmanCodes = HuffmanCodes::new(&CHROMA_AC);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::Encoder as _;
+    use crate::pixel;
+
+    #[test]
+    fn padding_around_the_frame_is_skipped() {
+        let data = [0x00, 0xFF, 0xD8, 0xFF, 0xE0, 0x12, 0xFF, 0xD9, 0x00, 0x00];
+        assert_eq!(frame_bounds(&data), Some(1..8));
+    }
+
+    #[test]
+    fn end_marker_before_the_start_marker_is_ignored() {
+        let data = [0xFF, 0xD9, 0xFF, 0xD8, 0x01, 0xFF, 0xD9];
+        assert_eq!(frame_bounds(&data), Some(2..7));
+    }
+
+    /// Encodes an image in strips, the way it is read from the FIFO.
+    fn encode(
+        width: u16,
+        height: u16,
+        input: Input,
+        color: Color,
+        quality: u8,
+        pixels: &[u8],
+    ) -> Vec<u8> {
+        let mut encoder = Encoder::new(Layout::new(width, height, input, color), quality);
+        let mut out = vec![0; encoder.max_length()];
+        encoder.start(&mut out).unwrap();
+        for strip in pixels.chunks(encoder.strip_length()) {
+            encoder.write_rows(strip, &mut out).unwrap();
+        }
+        let length = encoder.finish(&mut out).unwrap();
+        out.truncate(length);
+        out
+    }
+
+    fn decode(image: &[u8]) -> (jpeg_decoder::ImageInfo, Vec<u8>) {
+        let mut decoder = jpeg_decoder::Decoder::new(image);
+        let pixels = decoder.decode().unwrap();
+        (decoder.info().unwrap(), pixels)
+    }
+
+    fn max_error(a: &[u8], b: &[u8]) -> u8 {
+        assert_eq!(a.len(), b.len());
+        a.iter().zip(b).map(|(a, b)| a.abs_diff(*b)).max().unwrap()
+    }
+
+    /// Deterministic noise, to exercise every coefficient and 0xFF bytes.
+    fn noise(length: usize) -> Vec<u8> {
+        let mut state = 0x12345678u32;
+        (0..length)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn grayscale_images_decode_to_the_input() {
+        // Sizes that are not multiples of the blocks
+        let (width, height) = (37, 21);
+        let pixels = noise(width * height);
+        let image = encode(
+            width as u16,
+            height as u16,
+            Input::Gray8,
+            Color::Gray,
+            100,
+            &pixels,
+        );
+        assert_eq!(frame_bounds(&image), Some(0..image.len()));
+        let (info, decoded) = decode(&image);
+        assert_eq!((info.width, info.height), (37, 21));
+        assert_eq!(info.pixel_format, jpeg_decoder::PixelFormat::L8);
+        assert!(max_error(&decoded, &pixels) <= 2);
+    }
+
+    #[test]
+    fn colour_images_decode_to_the_input() {
+        let (width, height) = (40, 24);
+        let mut rgb565 = Vec::new();
+        let mut rgb888 = Vec::new();
+        for y in 0..height {
+            for x in 0..width {
+                // Smooth colours, which survive the subsampling of the chroma
+                let rgb = [(x * 6) as u8, (y * 10) as u8, 200 - (x * 2) as u8];
+                let pixel = pixel::rgb888_to_rgb565(rgb);
+                rgb565.extend_from_slice(&pixel.to_be_bytes());
+                rgb888.extend_from_slice(&pixel::rgb565_to_rgb888(pixel));
+            }
+        }
+        let image = encode(width, height, Input::Rgb565, Color::Rgb888, 95, &rgb565);
+        let (info, decoded) = decode(&image);
+        assert_eq!(info.pixel_format, jpeg_decoder::PixelFormat::RGB24);
+        assert!(max_error(&decoded, &rgb888) <= 12);
+    }
+
+    #[test]
+    fn yuv422_luma_is_encoded_as_is() {
+        let yuv = noise(16 * 8 * 2);
+        let luma: Vec<u8> = yuv.iter().step_by(2).copied().collect();
+        assert_eq!(
+            encode(16, 8, Input::Yuv422, Color::Gray, 75, &yuv),
+            encode(16, 8, Input::Gray8, Color::Gray, 75, &luma)
+        );
+    }
+
+    #[test]
+    fn lower_quality_gives_smaller_images() {
+        let pixels = noise(64 * 64);
+        let best = encode(64, 64, Input::Gray8, Color::Gray, 90, &pixels);
+        let worst = encode(64, 64, Input::Gray8, Color::Gray, 10, &pixels);
+        assert!(worst.len() < best.len() / 2);
+    }
+
+    #[test]
+    fn small_buffers_are_reported() {
+        let layout = Layout::new(8, 8, Input::Gray8, Color::Gray);
+        let mut encoder = Encoder::new(layout, 75);
+        let mut out = [0; 600];
+        encoder.start(&mut out).unwrap();
+        assert_eq!(
+            encoder.write_rows(&noise(64), &mut out[..encoder.length() + 4]),
+            Err(Error::BufferFull)
+        );
+    }
+
+    #[test]
+    fn truncated_frames_are_rejected() {
+        assert_eq!(frame_bounds(&[0xFF, 0xD8, 0x01, 0x02, 0xFF]), None);
+        assert_eq!(frame_bounds(&[0x01, 0xFF, 0xD9]), None);
+        assert_eq!(frame_bounds(&[]), None);
+    }
+}
@@ -1,19 +1,53 @@
-#![no_main]
-#![no_std]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(not(test), no_std)]
 
+#[cfg(target_os = "none")]
 use defmt_rtt as _; // global logger
 
+#[cfg(target_os = "none")]
 use panic_probe as _;
 
+pub mod arduchip;
+pub mod blob;
+pub mod bmp;
+pub mod capture;
+pub mod clock;
+pub mod compat;
+#[cfg(target_os = "none")]
+pub mod dma;
+pub mod error;
+#[cfg(any(test, feature = "fake"))]
+pub mod fake;
+pub mod http;
+pub mod image;
+pub mod jpeg;
+pub mod morphology;
+pub mod motion;
+pub mod ov2640;
+pub mod pixel;
+pub mod png;
+pub mod pnm;
+pub mod qoi;
+pub mod reset;
+
 // same panicking *behavior* as `panic-probe` but doesn't print a panic message
 // this prevents the panic message being printed *twice* when `defmt::panic` is invoked
+#[cfg(target_os = "none")]
 #[defmt::panic_handler]
 fn panic() -> ! {
     cortex_m::asm::udf()
 }
 
+/// Allocator of the binaries, which the HAL needs as it links smoltcp, built
+/// with `alloc` for the server. It is empty until a binary that allocates
+/// gives it a heap with `ALLOCATOR.init`.
+#[cfg(target_os = "none")]
+#[global_allocator]
+pub static ALLOCATOR: embedded_alloc::LlffHeap = embedded_alloc::LlffHeap::empty();
+
 /// Terminates the application and makes a semihosting-capable debug tool exit
 /// with status code 0.
+#[cfg(target_os = "none")]
 pub fn exit() -> ! {
     semihosting::process::exit(0);
 }
@@ -23,6 +57,7 @@ pub fn exit() -> ! {
 /// Terminates the application and makes a semihosting-capable debug tool exit
 /// with an error. This seems better than the default, which is to spin in a
 /// loop.
+#[cfg(target_os = "none")]
 #[cortex_m_rt::exception]
 unsafe fn HardFault(_frame: &cortex_m_rt::ExceptionFrame) -> ! {
     semihosting::process::exit(1);
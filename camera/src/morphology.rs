@@ -0,0 +1,520 @@
+//! Morphological filters of binary masks, one bit per pixel.
+//!
+//! `server.py` removes the noise of its mask with a 3x3 box convolution
+//! followed by a second threshold, which is the [`Mask::rank`] filter of a
+//! 3x3 square keeping the pixels with at least 8 of its 9 pixels set. Erosion,
+//! dilation and majority are the ranks of all, one and more than half of the
+//! pixels of the structuring element; opening and closing chain an erosion
+//! and a dilation through a scratch mask.
+//!
+//! A QVGA mask takes 9.6 KB, 32 pixels per word from the least significant
+//! bit like the rows labelled by [`crate::blob::Labeller`], and is filtered 32
+//! pixels at a time: the rank filter counts the neighbours of the pixels of a
+//! word in bit-sliced counters. The pixels outside the mask are unset, like
+//! the zero padding of `convolve2d`.
+//!
+//! ```ignore
+//! let noisy = Mask::new(320, 240, &mut noisy_words);
+//! let mut opened = Mask::new(320, 240, &mut opened_words);
+//! noisy.open(&Element::rectangle(3, 3), &mut scratch, &mut opened);
+//! ```
+
+/// Widest and tallest structuring elements.
+pub const MAX_ELEMENT_SIZE: usize = 15;
+
+/// Bit planes of the neighbour counts, up to 15x15.
+const COUNT_BITS: usize = 8;
+
+/// Structuring element, centred on the pixel it filters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Element {
+    width: u8,
+    height: u8,
+    /// Pixels of each row, from the least significant bit on the left.
+    rows: [u16; MAX_ELEMENT_SIZE],
+}
+
+impl Element {
+    /// Element of `width` by `height` pixels, both odd, with the pixels of
+    /// `rows` from the top.
+    pub fn new(width: usize, height: usize, rows: &[u16]) -> Self {
+        assert!(
+            width % 2 == 1 && width <= MAX_ELEMENT_SIZE,
+            "element width not odd or too large"
+        );
+        assert!(
+            height % 2 == 1 && height <= MAX_ELEMENT_SIZE,
+            "element height not odd or too large"
+        );
+        assert_eq!(rows.len(), height, "element rows");
+        assert!(
+            rows.iter().all(|&row| row >> width == 0),
+            "element row wider than the element"
+        );
+        assert!(rows.iter().any(|&row| row != 0), "empty element");
+        let mut element = Self {
+            width: width as u8,
+            height: height as u8,
+            rows: [0; MAX_ELEMENT_SIZE],
+        };
+        element.rows[..height].copy_from_slice(rows);
+        element
+    }
+
+    /// Every pixel of a `width` by `height` rectangle.
+    pub fn rectangle(width: usize, height: usize) -> Self {
+        let row = ((1u32 << width) - 1) as u16;
+        Self::new(width, height, &[row; MAX_ELEMENT_SIZE][..height])
+    }
+
+    /// The middle row and column of a `width` by `height` rectangle.
+    pub fn cross(width: usize, height: usize) -> Self {
+        let mut rows = [1 << (width / 2); MAX_ELEMENT_SIZE];
+        rows[height / 2] = ((1u32 << width) - 1) as u16;
+        Self::new(width, height, &rows[..height])
+    }
+
+    /// The pixels of a `width` by `height` rectangle whose centre is in the
+    /// inscribed ellipse.
+    pub fn ellipse(width: usize, height: usize) -> Self {
+        let (a, b) = (width as i32, height as i32);
+        let mut rows = [0; MAX_ELEMENT_SIZE];
+        for (y, row) in rows[..height].iter_mut().enumerate() {
+            for x in 0..width {
+                // (2x - (w - 1))² / w² + (2y - (h - 1))² / h² <= 1
+                let (u, v) = (2 * x as i32 - (a - 1), 2 * y as i32 - (b - 1));
+                if u * u * b * b + v * v * a * a <= a * a * b * b {
+                    *row |= 1 << x;
+                }
+            }
+        }
+        Self::new(width, height, &rows[..height])
+    }
+
+    /// Pixels of the element.
+    pub fn area(&self) -> u32 {
+        self.rows.iter().map(|row| row.count_ones()).sum()
+    }
+
+    /// Offsets from the centre of the pixels of the element.
+    fn offsets(&self) -> impl Iterator<Item = (isize, isize)> + '_ {
+        let (half_width, half_height) = (self.width as isize / 2, self.height as isize / 2);
+        (0..self.height as usize).flat_map(move |y| {
+            (0..self.width as usize)
+                .filter(move |&x| self.rows[y] >> x & 1 == 1)
+                .map(move |x| (x as isize - half_width, y as isize - half_height))
+        })
+    }
+}
+
+/// Binary image of `width` by `height` pixels, each row starting on a new
+/// word, the bits past the width unset.
+pub struct Mask<'a> {
+    width: u16,
+    height: u16,
+    words: &'a mut [u32],
+}
+
+impl<'a> Mask<'a> {
+    /// Words of a mask of `width` by `height` pixels.
+    pub const fn words(width: u16, height: u16) -> usize {
+        (width as usize).div_ceil(32) * height as usize
+    }
+
+    /// Empty mask of `width` by `height` pixels, in the first
+    /// [`Mask::words`] of `words`.
+    pub fn new(width: u16, height: u16, words: &'a mut [u32]) -> Self {
+        let length = Self::words(width, height);
+        assert!(words.len() >= length, "mask buffer too small");
+        let words = &mut words[..length];
+        words.fill(0);
+        Self {
+            width,
+            height,
+            words,
+        }
+    }
+
+    pub fn width(&self) -> u16 {
+        self.width
+    }
+
+    pub fn height(&self) -> u16 {
+        self.height
+    }
+
+    fn stride(&self) -> usize {
+        (self.width as usize).div_ceil(32)
+    }
+
+    /// Pixels of the row `y`, from the least significant bit of the first
+    /// word.
+    pub fn row(&self, y: u16) -> &[u32] {
+        let stride = self.stride();
+        &self.words[y as usize * stride..(y as usize + 1) * stride]
+    }
+
+    pub fn get(&self, x: u16, y: u16) -> bool {
+        self.row(y)[x as usize / 32] >> (x % 32) & 1 == 1
+    }
+
+    pub fn set(&mut self, x: u16, y: u16, value: bool) {
+        assert!(x < self.width && y < self.height, "pixel outside the mask");
+        let i = y as usize * self.stride() + x as usize / 32;
+        let bit = 1 << (x % 32);
+        if value {
+            self.words[i] |= bit;
+        } else {
+            self.words[i] &= !bit;
+        }
+    }
+
+    /// Sets the pixels of the row `y` whose bytes are not 0, like the 255 of
+    /// the masks of `get_motion`.
+    pub fn set_row(&mut self, y: u16, bytes: &[u8]) {
+        assert_eq!(bytes.len(), self.width as usize, "row length");
+        let stride = self.stride();
+        let row = &mut self.words[y as usize * stride..(y as usize + 1) * stride];
+        row.fill(0);
+        for (x, &byte) in bytes.iter().enumerate() {
+            row[x / 32] |= ((byte != 0) as u32) << (x % 32);
+        }
+    }
+
+    /// Set pixels.
+    pub fn count(&self) -> u32 {
+        self.words.iter().map(|word| word.count_ones()).sum()
+    }
+
+    /// Sets the pixels of `out` where the whole element is set.
+    pub fn erode(&self, element: &Element, out: &mut Mask) {
+        self.filter(element, out, |words| words.fold(!0, |all, word| all & word));
+    }
+
+    /// Sets the pixels of `out` where any pixel of the element is set.
+    pub fn dilate(&self, element: &Element, out: &mut Mask) {
+        self.filter(element, out, |words| words.fold(0, |any, word| any | word));
+    }
+
+    /// Erodes then dilates into `out`, removing what the element does not
+    /// fit in.
+    pub fn open(&self, element: &Element, scratch: &mut Mask, out: &mut Mask) {
+        self.erode(element, scratch);
+        scratch.dilate(element, out);
+    }
+
+    /// Dilates then erodes into `out`, filling the holes the element does not
+    /// fit in.
+    pub fn close(&self, element: &Element, scratch: &mut Mask, out: &mut Mask) {
+        self.dilate(element, scratch);
+        scratch.erode(element, out);
+    }
+
+    /// Sets the pixels of `out` where more than half of the element is set.
+    pub fn majority(&self, element: &Element, out: &mut Mask) {
+        self.rank(element, element.area() / 2 + 1, out);
+    }
+
+    /// Sets the pixels of `out` where at least `count` pixels of the element
+    /// are set, from 1 for a dilation to its area for an erosion.
+    pub fn rank(&self, element: &Element, count: u32, out: &mut Mask) {
+        assert!(
+            (1..=element.area()).contains(&count),
+            "rank outside the element"
+        );
+        self.filter(element, out, |words| {
+            // Bit-sliced counters of the 32 pixels
+            let mut planes = [0u32; COUNT_BITS];
+            for mut carry in words {
+                for plane in &mut planes {
+                    if carry == 0 {
+                        break;
+                    }
+                    (*plane, carry) = (*plane ^ carry, *plane & carry);
+                }
+            }
+            // planes >= count, from the most significant bit
+            let (mut greater, mut equal) = (0, !0);
+            for (bit, plane) in planes.iter().enumerate().rev() {
+                if count >> bit & 1 == 1 {
+                    equal &= plane;
+                } else {
+                    greater |= equal & plane;
+                    equal &= !plane;
+                }
+            }
+            greater | equal
+        });
+    }
+
+    /// Sets each word of `out` to `combine` of the words of the pixels of the
+    /// element around its pixels.
+    fn filter<F>(&self, element: &Element, out: &mut Mask, mut combine: F)
+    where
+        F: FnMut(&mut dyn Iterator<Item = u32>) -> u32,
+    {
+        assert!(
+            (out.width, out.height) == (self.width, self.height),
+            "masks of different sizes"
+        );
+        let stride = self.stride();
+        let tail = match self.width % 32 {
+            0 => !0,
+            bits => (1 << bits) - 1,
+        };
+        for y in 0..self.height as isize {
+            for i in 0..stride {
+                let mut words = element.offsets().map(|(dx, dy)| {
+                    let v = y + dy;
+                    if v < 0 || v >= self.height as isize {
+                        0
+                    } else {
+                        self.shifted(v as u16, 32 * i as isize + dx)
+                    }
+                });
+                let mut word = combine(&mut words);
+                if i + 1 == stride {
+                    word &= tail;
+                }
+                out.words[y as usize * stride + i] = word;
+            }
+        }
+    }
+
+    /// The 32 pixels of the row `y` from `x`, unset outside the mask.
+    fn shifted(&self, y: u16, x: isize) -> u32 {
+        let row = self.row(y);
+        let word = |i: isize| {
+            usize::try_from(i)
+                .ok()
+                .and_then(|i| row.get(i))
+                .copied()
+                .unwrap_or(0)
+        };
+        let (i, shift) = (x.div_euclid(32), x.rem_euclid(32));
+        if shift == 0 {
+            word(i)
+        } else {
+            word(i) >> shift | word(i + 1) << (32 - shift)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pixels of `mask` with at least `count` of the element set around
+    /// them, pixel by pixel.
+    fn reference(
+        mask: &[bool],
+        width: usize,
+        height: usize,
+        element: &Element,
+        count: u32,
+    ) -> Vec<bool> {
+        let mut out = vec![false; mask.len()];
+        for y in 0..height as isize {
+            for x in 0..width as isize {
+                let mut set = 0;
+                for (dx, dy) in element.offsets() {
+                    let (u, v) = (x + dx, y + dy);
+                    if u >= 0 && v >= 0 && u < width as isize && v < height as isize {
+                        set += mask[v as usize * width + u as usize] as u32;
+                    }
+                }
+                out[y as usize * width + x as usize] = set >= count;
+            }
+        }
+        out
+    }
+
+    fn to_mask<'a>(
+        pixels: &[bool],
+        width: usize,
+        height: usize,
+        words: &'a mut Vec<u32>,
+    ) -> Mask<'a> {
+        words.resize(Mask::words(width as u16, height as u16), 0xDEAD_BEEF);
+        let mut mask = Mask::new(width as u16, height as u16, words);
+        for (i, &pixel) in pixels.iter().enumerate() {
+            mask.set((i % width) as u16, (i / width) as u16, pixel);
+        }
+        mask
+    }
+
+    fn pixels(mask: &Mask) -> Vec<bool> {
+        let (width, height) = (mask.width(), mask.height());
+        (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| mask.get(x, y))
+            .collect()
+    }
+
+    /// Checks every filter of `element` on `input` against the reference,
+    /// and that the bits past the width stay unset.
+    fn check(input: &[bool], width: usize, height: usize, element: &Element) {
+        let (mut words, mut out_words, mut scratch_words) = (Vec::new(), Vec::new(), Vec::new());
+        let mask = to_mask(input, width, height, &mut words);
+        let mut out = to_mask(&[], width, height, &mut out_words);
+        let mut scratch = to_mask(&[], width, height, &mut scratch_words);
+        let area = element.area();
+        let context = (width, height, *element);
+        let expect = |out: &Mask, expected: Vec<bool>, operation: &str| {
+            assert_eq!(pixels(out), expected, "{} {:?}", operation, context);
+            let bits: u32 = expected.iter().map(|&set| set as u32).sum();
+            assert_eq!(
+                out.count(),
+                bits,
+                "{} {:?} past the width",
+                operation,
+                context
+            );
+        };
+        mask.erode(element, &mut out);
+        expect(
+            &out,
+            reference(input, width, height, element, area),
+            "erode",
+        );
+        mask.dilate(element, &mut out);
+        expect(&out, reference(input, width, height, element, 1), "dilate");
+        mask.majority(element, &mut out);
+        expect(
+            &out,
+            reference(input, width, height, element, area / 2 + 1),
+            "majority",
+        );
+        let eroded = reference(input, width, height, element, area);
+        mask.open(element, &mut scratch, &mut out);
+        expect(&out, reference(&eroded, width, height, element, 1), "open");
+        let dilated = reference(input, width, height, element, 1);
+        mask.close(element, &mut scratch, &mut out);
+        expect(
+            &out,
+            reference(&dilated, width, height, element, area),
+            "close",
+        );
+    }
+
+    fn random_pixels(length: usize, seed: u32, density: u32) -> Vec<bool> {
+        let mut state = seed;
+        (0..length)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state % 256 < density
+            })
+            .collect()
+    }
+
+    #[test]
+    fn every_4x4_mask_agrees_with_the_reference() {
+        let elements = [
+            Element::rectangle(3, 3),
+            Element::cross(3, 3),
+            Element::rectangle(1, 3),
+            Element::new(3, 3, &[0b001, 0b010, 0b110]),
+        ];
+        for bits in 0..1u32 << 16 {
+            let input: Vec<bool> = (0..16).map(|i| bits >> i & 1 == 1).collect();
+            for element in &elements {
+                check(&input, 4, 4, element);
+            }
+        }
+    }
+
+    #[test]
+    fn every_rank_of_a_3x3_square_agrees_with_the_reference() {
+        let element = Element::rectangle(3, 3);
+        for (width, height) in [(5, 5), (31, 3), (33, 4), (64, 2)] {
+            for seed in 1..8 {
+                let input = random_pixels(width * height, seed * 7919, 40 * seed);
+                let (mut words, mut out_words) = (Vec::new(), Vec::new());
+                let mask = to_mask(&input, width, height, &mut words);
+                let mut out = to_mask(&[], width, height, &mut out_words);
+                for count in 1..=9 {
+                    mask.rank(&element, count, &mut out);
+                    let expected = reference(&input, width, height, &element, count);
+                    assert_eq!(
+                        pixels(&out),
+                        expected,
+                        "{}x{} rank {}",
+                        width,
+                        height,
+                        count
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn random_masks_agree_with_the_reference() {
+        let elements = [
+            Element::rectangle(1, 1),
+            Element::rectangle(5, 3),
+            Element::cross(7, 5),
+            Element::ellipse(7, 7),
+            Element::ellipse(15, 15),
+            Element::new(5, 1, &[0b10011]),
+        ];
+        let sizes = [
+            (1, 1),
+            (7, 9),
+            (31, 5),
+            (32, 6),
+            (33, 7),
+            (65, 17),
+            (100, 20),
+        ];
+        for (width, height) in sizes {
+            for (seed, density) in [(1, 30), (2, 128), (3, 220)] {
+                let input = random_pixels(width * height, seed * 104_729, density);
+                for element in &elements {
+                    check(&input, width, height, element);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn the_rank_of_8_in_3x3_is_the_vote_of_server_py() {
+        // A moving 5x4 rectangle of changed pixels and a lone one
+        let (width, height) = (10, 8);
+        let mut changed = vec![0u8; width * height];
+        for y in 2..6 {
+            changed[y * width + 2..y * width + 7].fill(255);
+        }
+        changed[width - 1] = 255;
+        let mut words = vec![0; Mask::words(10, 8)];
+        let mut mask = Mask::new(10, 8, &mut words);
+        for (y, row) in changed.chunks(width).enumerate() {
+            mask.set_row(y as u16, row);
+        }
+        let mut out_words = vec![0; Mask::words(10, 8)];
+        let mut out = Mask::new(10, 8, &mut out_words);
+        mask.rank(&Element::rectangle(3, 3), 8, &mut out);
+        // Only the inside of the rectangle, its 3x2 pixels with 9 set neighbours
+        assert_eq!(out.count(), 6);
+        assert!((3..6).all(|x| out.get(x, 3) && out.get(x, 4)));
+    }
+
+    #[test]
+    fn elements_have_their_shapes() {
+        assert_eq!(Element::rectangle(5, 3).area(), 15);
+        assert_eq!(Element::cross(5, 3).rows[..3], [0b00100, 0b11111, 0b00100]);
+        assert_eq!(
+            Element::ellipse(5, 5).rows[..5],
+            [0b01110, 0b11111, 0b11111, 0b11111, 0b01110]
+        );
+        assert_eq!(Element::ellipse(15, 15).area(), 177);
+    }
+
+    #[test]
+    fn a_qvga_mask_takes_9600_bytes() {
+        assert_eq!(Mask::words(320, 240) * 4, 9600);
+        assert_eq!(Mask::words(33, 2), 4);
+    }
+}
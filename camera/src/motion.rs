@@ -0,0 +1,1185 @@
+//! Motion detection by frame difference, the `get_motion` of
+//! `server/server.py` run on the device, or by background subtraction.
+//!
+//! A pixel moves when more than [`VOTE_THRESHOLD`] of the 9 pixels of its 3x3
+//! neighbourhood changed, the pixels outside the frame counting as unchanged.
+//! The pixels change by more than [`DIFF_THRESHOLD`] since the previous frame
+//! with [`Model::Previous`], or by more than a threshold from a running
+//! average of the frames with [`Model::Background`], which also sees the
+//! subjects moving too slowly to change much between two frames and, with a
+//! running variance, ignores the pixels that flicker. [`Model::Mixture`]
+//! learns two backgrounds per pixel, such as the leaves and the sky of a
+//! swaying tree, with a Gaussian for each.
+//!
+//! The models of a QVGA frame take, next to the 256 KB heap, the 13 KB of
+//! Ethernet descriptors and the 2 KB of the blob labeller in the 512 KB of
+//! AXI SRAM, where the rest is left to the stack:
+//!
+//! | Model                           | Bytes per pixel | QVGA buffers | Stack left   |
+//! |---------------------------------|-----------------|--------------|--------------|
+//! | Previous frame                  | 1               | 75 KB        | 166 KB       |
+//! | Background average              | 2               | 150 KB       | 91 KB        |
+//! | Background average and variance | 4               | 300 KB       | does not fit |
+//! | Mixture of 2 Gaussians          | 3               | 225 KB       | 16 KB        |
+//! | Mixture of 3 Gaussians          | 4.5             | 338 KB       | does not fit |
+//!
+//! On a scene of swaying leaves with someone walking past, the models flag
+//! as moving, of the 11520 pixels of leaves and the 960 pixels of the walker
+//! in the 10 frames of the walk (see the `mixture_ignores_swaying_leaves`
+//! test):
+//!
+//! | Model                           | Leaves flagged | Walker flagged |
+//! |---------------------------------|----------------|----------------|
+//! | Previous frame                  | 1746 (15%)     | 150 (16%)      |
+//! | Background average              | 10098 (88%)    | 600 (63%)      |
+//! | Background frozen on motion     | 3173 (28%)     | 600 (63%)      |
+//! | Mixture of 2 Gaussians          | 0              | 600 (63%)      |
+//!
+//! The frame difference only flags the edges of the walker, and the average
+//! of the leaves is far from both of their colours.
+//!
+//! The moving pixels can be labelled into [`Blob`]s as they are voted on,
+//! see [`Detector::with_blobs`].
+//!
+//! Like the encoders, the detector is given the rows of a frame from the top
+//! in their [`Input`] layout, so that a frame is compared while it is read
+//! from the FIFO, and does not allocate: the model is kept in buffers of the
+//! caller.
+//!
+//! ```ignore
+//! let mut detector = Detector::new(320, 240, Input::Yuv422, Model::Previous(&mut previous))?;
+//! detector.start();
+//! for rows in frame.chunks(16 * 320 * 2) {
+//!     detector.write_rows(rows);
+//! }
+//! if let Some(result) = detector.finish() { ... }
+//! ```
+
+use crate::blob::{Blob, BlobOptions, Blobs, Label, Labeller};
+use crate::image::Input;
+
+/// Luma change above which a pixel changed, the first `cv2.threshold`.
+pub const DIFF_THRESHOLD: u8 = 50;
+
+/// Changed pixels of a 3x3 neighbourhood above which its centre moves, the
+/// second `cv2.threshold`.
+pub const VOTE_THRESHOLD: u32 = 7;
+
+/// Widest frames, of which the changed pixels of three rows are kept.
+pub const MAX_WIDTH: usize = 1600;
+
+const WORDS: usize = MAX_WIDTH / 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+    /// The frames have no pixels, of which no share could move.
+    NoPixels,
+    /// The frames are wider than [`MAX_WIDTH`].
+    TooWide,
+    /// The buffers of the model are smaller than the frames.
+    BufferTooSmall,
+}
+
+/// Moving pixels of a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Score {
+    pub moving: u32,
+    /// Pixels of the frame.
+    pub pixels: u32,
+}
+
+impl Score {
+    /// Thousandths of the frame that move.
+    pub fn permille(self) -> u32 {
+        (self.moving as u64 * 1000 / self.pixels as u64) as u32
+    }
+}
+
+/// Motion of a frame and where it is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct MotionResult {
+    pub score: Score,
+    /// Blobs of the moving pixels, none unless the detector labels them.
+    pub blobs: Blobs,
+}
+
+impl MotionResult {
+    /// The largest blobs of the moving pixels, the largest first.
+    pub fn blobs(&self) -> &[Blob] {
+        self.blobs.as_slice()
+    }
+}
+
+/// What the frames are compared with.
+pub enum Model<'a> {
+    /// The luma of the previous frame, replaced row by row by the current
+    /// one.
+    Previous(&'a mut [u8]),
+    Background(Background<'a>),
+    Mixture(Mixture<'a>),
+}
+
+impl<'a> Model<'a> {
+    /// The model of the first `pixels` pixels of its buffers.
+    fn truncated(self, pixels: usize) -> Result<Self, Error> {
+        Ok(match self {
+            Model::Previous(previous) => {
+                Model::Previous(previous.get_mut(..pixels).ok_or(Error::BufferTooSmall)?)
+            }
+            Model::Background(background) => Model::Background(background.truncated(pixels)?),
+            Model::Mixture(mixture) => Model::Mixture(mixture.truncated(pixels)?),
+        })
+    }
+
+    /// Starts a frame.
+    fn start(&mut self) {
+        if let Model::Mixture(mixture) = self {
+            mixture.frame = mixture.frame.wrapping_add(1);
+        }
+    }
+
+    /// Starts the model of the pixel `i` from its `luma` in the first frame.
+    fn initialise(&mut self, i: usize, luma: u8) {
+        match self {
+            Model::Previous(previous) => previous[i] = luma,
+            Model::Background(background) => background.initialise(i, luma),
+            Model::Mixture(mixture) => mixture.initialise(i, luma),
+        }
+    }
+
+    /// Whether the pixel `i` changed to `luma`, which the model learns if
+    /// `learning`.
+    fn compare(&mut self, i: usize, luma: u8, learning: bool) -> bool {
+        match self {
+            Model::Previous(previous) => {
+                let changed = luma.abs_diff(previous[i]) > DIFF_THRESHOLD;
+                previous[i] = luma;
+                changed
+            }
+            Model::Background(background) => background.compare(i, luma, learning),
+            Model::Mixture(mixture) => mixture.compare(i, luma, learning),
+        }
+    }
+
+    /// Whether the model stops learning after a frame with motion.
+    fn freezes_on_motion(&self) -> bool {
+        match self {
+            Model::Previous(_) | Model::Mixture(_) => false,
+            Model::Background(background) => background.options.freeze_on_motion,
+        }
+    }
+}
+
+/// Settings of the [`Background`] model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct BackgroundOptions {
+    /// Weight of each frame in the average, in 256ths.
+    pub learning_rate: u8,
+    /// Luma difference from the average above which a pixel changed.
+    pub threshold: u8,
+    /// Standard deviations from the average above which a pixel changed, in
+    /// sixteenths, when the variance is kept.
+    pub deviations: u8,
+    /// Whether the model stops learning after a frame with motion, so that a
+    /// subject holding still does not fade into the background.
+    pub freeze_on_motion: bool,
+}
+
+impl Default for BackgroundOptions {
+    fn default() -> Self {
+        Self {
+            // About the last 32 frames
+            learning_rate: 8,
+            threshold: 25,
+            // 2.5 standard deviations
+            deviations: 40,
+            freeze_on_motion: false,
+        }
+    }
+}
+
+/// Running average of the luma of each pixel, and optionally of its
+/// variance, in fixed point.
+pub struct Background<'a> {
+    options: BackgroundOptions,
+    /// Average luma, in 8.8 fixed point.
+    mean: &'a mut [u16],
+    /// Average squared difference from the average luma, in 12.4 fixed point,
+    /// saturated at a standard deviation of 64.
+    variance: Option<&'a mut [u16]>,
+}
+
+impl<'a> Background<'a> {
+    /// Background keeping its average in `mean`, a pixel changing when it
+    /// differs from it by more than the threshold of `options`.
+    pub fn new(options: BackgroundOptions, mean: &'a mut [u16]) -> Self {
+        Self {
+            options,
+            mean,
+            variance: None,
+        }
+    }
+
+    /// Keeps the variance of each pixel in `variance` too, a pixel changing
+    /// only if it also differs by more than the deviations of the options.
+    pub fn with_variance(mut self, variance: &'a mut [u16]) -> Self {
+        self.variance = Some(variance);
+        self
+    }
+
+    fn truncated(self, pixels: usize) -> Result<Self, Error> {
+        let variance = match self.variance {
+            Some(variance) => Some(variance.get_mut(..pixels).ok_or(Error::BufferTooSmall)?),
+            None => None,
+        };
+        Ok(Self {
+            options: self.options,
+            mean: self.mean.get_mut(..pixels).ok_or(Error::BufferTooSmall)?,
+            variance,
+        })
+    }
+
+    fn initialise(&mut self, i: usize, luma: u8) {
+        self.mean[i] = (luma as u16) << 8;
+        if let Some(variance) = &mut self.variance {
+            variance[i] = 0;
+        }
+    }
+
+    fn compare(&mut self, i: usize, luma: u8, learning: bool) -> bool {
+        let options = self.options;
+        let rate = options.learning_rate as i32;
+        // In 8.8 fixed point
+        let difference = ((luma as i32) << 8) - self.mean[i] as i32;
+        let distance = difference.unsigned_abs() as u64;
+        let mut changed = distance > (options.threshold as u64) << 8;
+        if let Some(variance) = &mut self.variance {
+            // distance² > (deviations / 16)² × variance / 16, in 16.16 fixed
+            // point
+            let square = distance * distance;
+            let deviations = options.deviations as u64;
+            changed &= square > deviations * deviations * variance[i] as u64 * 16;
+            if learning {
+                let target = (square >> 12).min(u16::MAX as u64) as i32;
+                let current = variance[i] as i32;
+                variance[i] = (current + (target - current) * rate / 256) as u16;
+            }
+        }
+        if learning {
+            self.mean[i] = (self.mean[i] as i32 + difference * rate / 256) as u16;
+        }
+        changed
+    }
+}
+
+/// Settings of the [`Mixture`] model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct MixtureOptions {
+    /// Weight of each frame in the mean and the deviation of the Gaussian it
+    /// matches, in 256ths.
+    pub learning_rate: u8,
+    /// Weight of each frame in the weights of the Gaussians, in 256ths, so
+    /// that a subject holding still becomes background after about
+    /// `256 / weight_rate * min_weight / 15` frames.
+    pub weight_rate: u8,
+    /// Standard deviations from its mean within which a luma matches a
+    /// Gaussian, in sixteenths.
+    pub deviations: u8,
+    /// Weight out of 15 of the Gaussians that are background.
+    pub min_weight: u8,
+}
+
+impl Default for MixtureOptions {
+    fn default() -> Self {
+        Self {
+            learning_rate: 16,
+            // About the last 64 frames
+            weight_rate: 4,
+            // 2.5 standard deviations
+            deviations: 40,
+            min_weight: 4,
+        }
+    }
+}
+
+/// Standard deviations of the Gaussians, by their 2-bit level.
+const SIGMAS: [u8; 4] = [4, 8, 16, 32];
+
+/// Level of the Gaussians learning a new luma.
+const NEW_LEVEL: u8 = 2;
+
+/// Weight of the two Gaussians of a pixel together.
+const TOTAL_WEIGHT: u8 = 15;
+
+/// Two Gaussians of the luma of each pixel, the most probable first, with
+/// quantised weights, means and standard deviations.
+///
+/// A pixel is packed in 3 bytes: the means of the two Gaussians, then the
+/// weight of the first out of [`TOTAL_WEIGHT`] in the high nibble, the second
+/// having the rest, and the levels of their standard deviations in
+/// [`SIGMAS`] in the low nibble.
+///
+/// The updates are rounded up or down by a dither varying with the pixel and
+/// the frame, so that they are right on average however small they are.
+pub struct Mixture<'a> {
+    options: MixtureOptions,
+    pixels: &'a mut [[u8; 3]],
+    /// Frames started, seeding the dither.
+    frame: u32,
+}
+
+impl<'a> Mixture<'a> {
+    pub fn new(options: MixtureOptions, pixels: &'a mut [[u8; 3]]) -> Self {
+        Self {
+            options,
+            pixels,
+            frame: 0,
+        }
+    }
+
+    fn truncated(self, pixels: usize) -> Result<Self, Error> {
+        Ok(Self {
+            pixels: self.pixels.get_mut(..pixels).ok_or(Error::BufferTooSmall)?,
+            ..self
+        })
+    }
+
+    fn initialise(&mut self, i: usize, luma: u8) {
+        // The first frame is all background, the second Gaussian waits for
+        // another luma
+        self.pixels[i] = [luma, luma, TOTAL_WEIGHT << 4 | 1 << 2 | NEW_LEVEL];
+    }
+
+    fn compare(&mut self, i: usize, luma: u8, learning: bool) -> bool {
+        let options = self.options;
+        let [mean0, mean1, packed] = self.pixels[i];
+        let mut weight = packed >> 4;
+        let mut gaussians = [(mean0, packed >> 2 & 3), (mean1, packed & 3)];
+        let weights = [weight, TOTAL_WEIGHT - weight];
+        let matched = (0..2).find(|&k| {
+            let (mean, level) = gaussians[k];
+            luma.abs_diff(mean) as u32 * 16
+                <= options.deviations as u32 * SIGMAS[level as usize] as u32
+        });
+        let changed = match matched {
+            Some(k) => weights[k] < options.min_weight,
+            None => true,
+        };
+        if !learning {
+            return changed;
+        }
+
+        let dither = (i as u32 ^ self.frame << 20).wrapping_mul(0x9E37_79B9) >> 24;
+        match matched {
+            Some(k) => {
+                let (mean, level) = &mut gaussians[k];
+                let distance = luma.abs_diff(*mean);
+                *mean = dithered_step(*mean, luma, options.learning_rate, dither);
+                // The level follows the spread of the matches, widened by the
+                // far ones and narrowed by the close ones, as often as the
+                // learning rate
+                let sigma = SIGMAS[*level as usize] as u32;
+                if dither < options.learning_rate as u32 {
+                    if 2 * distance as u32 > 3 * sigma && *level < 3 {
+                        *level += 1;
+                    } else if 4 * (distance as u32) < sigma && *level > 0 {
+                        *level -= 1;
+                    }
+                }
+                let target = if k == 0 { TOTAL_WEIGHT } else { 0 };
+                weight = dithered_step(weight, target, options.weight_rate, dither);
+            }
+            None => {
+                // The least probable Gaussian learns the new luma, with the
+                // least weight
+                gaussians[1] = (luma, NEW_LEVEL);
+                weight = TOTAL_WEIGHT - 1;
+            }
+        }
+        if weight < TOTAL_WEIGHT - weight {
+            gaussians.swap(0, 1);
+            weight = TOTAL_WEIGHT - weight;
+        }
+        let [(mean0, level0), (mean1, level1)] = gaussians;
+        self.pixels[i] = [mean0, mean1, weight << 4 | level0 << 2 | level1];
+        changed
+    }
+}
+
+/// `value` moved towards `target` by `rate` 256ths of their difference, the
+/// fraction rounded up if it is above `dither` in 256ths, down otherwise.
+fn dithered_step(value: u8, target: u8, rate: u8, dither: u32) -> u8 {
+    let difference = target as i32 - value as i32;
+    (value as i32 + ((difference * rate as i32 + dither as i32) >> 8)) as u8
+}
+
+/// Compares each frame with a model of the previous ones.
+pub struct Detector<'a> {
+    width: u16,
+    height: u16,
+    input: Input,
+    model: Model<'a>,
+    /// Moving pixels of the last frame compared, 255 or 0 like the mask of
+    /// `get_motion`.
+    mask: Option<&'a mut [u8]>,
+    /// Labels the moving pixels of each frame into blobs.
+    labeller: Option<Labeller<'a>>,
+    /// Whether the model holds a whole frame.
+    primed: bool,
+    /// Whether the model learns the current frame, unless it is frozen by
+    /// the motion of the previous one.
+    learning: bool,
+    /// Changed pixels of the last three rows, one bit per pixel, row `y` in
+    /// `changed[y % 3]`.
+    changed: [[u32; WORDS]; 3],
+    /// Rows of the current frame given so far.
+    row: u16,
+    moving: u32,
+}
+
+impl<'a> Detector<'a> {
+    /// Detector of the motion in frames of `width` by `height` pixels, keeping
+    /// `model` in the first `width * height` pixels of its buffers.
+    ///
+    /// Fails if the frames have no pixels or are too wide, or if the
+    /// buffers of `model` are too small for them.
+    pub fn new(width: u16, height: u16, input: Input, model: Model<'a>) -> Result<Self, Error> {
+        let pixels = width as usize * height as usize;
+        if pixels == 0 {
+            return Err(Error::NoPixels);
+        }
+        if width as usize > MAX_WIDTH {
+            return Err(Error::TooWide);
+        }
+        Ok(Self {
+            width,
+            height,
+            input,
+            model: model.truncated(pixels)?,
+            mask: None,
+            labeller: None,
+            primed: false,
+            learning: true,
+            changed: [[0; WORDS]; 3],
+            row: 0,
+            moving: 0,
+        })
+    }
+
+    /// Writes the moving pixels of each frame to `mask`, one byte per pixel.
+    pub fn with_mask(mut self, mask: &'a mut [u8]) -> Self {
+        let pixels = self.pixels();
+        assert!(mask.len() >= pixels, "mask buffer too small");
+        self.mask = Some(&mut mask[..pixels]);
+        self
+    }
+
+    /// Labels the moving pixels of each frame into blobs, keeping the labels
+    /// of a row in `labels` and the blobs crossing it in `table`.
+    pub fn with_blobs(
+        mut self,
+        options: BlobOptions,
+        labels: &'a mut [u16],
+        table: &'a mut [Label],
+    ) -> Self {
+        let labeller = Labeller::new(self.width, self.height, options, labels, table);
+        self.labeller = Some(labeller);
+        self
+    }
+
+    /// Mask of the moving pixels of the last frame compared.
+    pub fn mask(&self) -> Option<&[u8]> {
+        self.mask.as_deref()
+    }
+
+    /// Forgets the previous frames, so that the next one starts the model
+    /// again.
+    pub fn reset(&mut self) {
+        self.primed = false;
+        self.learning = true;
+        self.row = 0;
+    }
+
+    /// Starts a frame.
+    pub fn start(&mut self) {
+        self.row = 0;
+        self.moving = 0;
+        self.model.start();
+        if let Some(labeller) = &mut self.labeller {
+            labeller.start();
+        }
+    }
+
+    /// Compares whole rows of the frame, the rows of a pixel being voted on
+    /// once the next one is given.
+    pub fn write_rows(&mut self, rows: &[u8]) {
+        let row_length = self.width as usize * self.input.bytes_per_pixel();
+        assert!(rows.len().is_multiple_of(row_length), "partial row");
+        for row in rows.chunks_exact(row_length) {
+            let y = self.row as usize;
+            assert!(y < self.height as usize, "more rows than the frame");
+            self.compare(y, row);
+            if self.primed && y > 0 {
+                self.vote(y - 1);
+            }
+            self.row += 1;
+        }
+    }
+
+    /// Ends the frame and returns its motion, `None` for the first frame,
+    /// which has nothing to be compared with.
+    pub fn finish(&mut self) -> Option<MotionResult> {
+        assert_eq!(self.row, self.height, "frame not whole");
+        if !core::mem::replace(&mut self.primed, true) {
+            return None;
+        }
+        self.vote(self.height as usize - 1);
+        self.learning = !(self.model.freezes_on_motion() && self.moving > 0);
+        let score = Score {
+            moving: self.moving,
+            pixels: self.pixels() as u32,
+        };
+        let blobs = match &mut self.labeller {
+            Some(labeller) => labeller.finish(),
+            None => Blobs::EMPTY,
+        };
+        Some(MotionResult { score, blobs })
+    }
+
+    fn pixels(&self) -> usize {
+        self.width as usize * self.height as usize
+    }
+
+    /// Marks the pixels of the row `y` that changed and updates their model.
+    fn compare(&mut self, y: usize, row: &[u8]) {
+        let width = self.width as usize;
+        let input = self.input;
+        let changed = &mut self.changed[y % 3];
+        changed.fill(0);
+        for x in 0..width {
+            let luma = input.gray(row, x);
+            let i = y * width + x;
+            if !self.primed {
+                self.model.initialise(i, luma);
+            } else if self.model.compare(i, luma, self.learning) {
+                changed[x / 32] |= 1 << (x % 32);
+            }
+        }
+    }
+
+    /// Counts the changed pixels around each pixel of the row `y`, whose
+    /// neighbour rows are compared.
+    fn vote(&mut self, y: usize) {
+        let width = self.width as usize;
+        let height = self.height as usize;
+        let rows = [
+            y.checked_sub(1),
+            Some(y),
+            Some(y + 1).filter(|&y| y < height),
+        ];
+        let rows = rows.map(|row| row.map(|row| &self.changed[row % 3]));
+        let mut mask = self
+            .mask
+            .as_deref_mut()
+            .map(|mask| &mut mask[y * width..(y + 1) * width]);
+        let mut moving_row = [0; WORDS];
+        for x in 0..width {
+            let count: u32 = rows
+                .iter()
+                .flatten()
+                .map(|row| neighbours(row, x, width))
+                .sum();
+            let moving = count > VOTE_THRESHOLD;
+            self.moving += moving as u32;
+            moving_row[x / 32] |= (moving as u32) << (x % 32);
+            if let Some(mask) = &mut mask {
+                mask[x] = if moving { 255 } else { 0 };
+            }
+        }
+        if let Some(labeller) = &mut self.labeller {
+            labeller.write_row(&moving_row);
+        }
+    }
+}
+
+/// Changed pixels among `x - 1`, `x` and `x + 1` of a row `width` pixels
+/// wide.
+fn neighbours(changed: &[u32; WORDS], x: usize, width: usize) -> u32 {
+    let bit = |x: usize| changed[x / 32] >> (x % 32) & 1;
+    let left = if x > 0 { bit(x - 1) } else { 0 };
+    let right = if x + 1 < width { bit(x + 1) } else { 0 };
+    left + bit(x) + right
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `get_motion` of `server.py`, transcribed operation by operation with
+    /// the semantics of OpenCV and SciPy on `uint8` arrays.
+    fn get_motion(prev: &[u8], curr: &[u8], width: usize, height: usize) -> Vec<u8> {
+        // diff = cv2.absdiff(prev, curr)
+        let diff: Vec<u8> = prev
+            .iter()
+            .zip(curr)
+            .map(|(&a, &b)| a.abs_diff(b))
+            .collect();
+        // _, mask = cv2.threshold(diff, 50, 1, cv2.THRESH_BINARY)
+        let mask: Vec<u8> = diff.iter().map(|&d| if d > 50 { 1 } else { 0 }).collect();
+        // mask = scipy.signal.convolve2d(mask, ones((3, 3)), mode="same"),
+        // the default boundary filling with zeros
+        let mut sum = vec![0u8; width * height];
+        for y in 0..height as isize {
+            for x in 0..width as isize {
+                let mut total = 0u8;
+                for dy in -1..=1 {
+                    for dx in -1..=1 {
+                        let (u, v) = (x + dx, y + dy);
+                        if u >= 0 && v >= 0 && u < width as isize && v < height as isize {
+                            total += mask[v as usize * width + u as usize];
+                        }
+                    }
+                }
+                sum[y as usize * width + x as usize] = total;
+            }
+        }
+        // _, mask = cv2.threshold(mask, 7, 1, cv2.THRESH_BINARY)
+        // return mask * 255
+        sum.iter().map(|&s| if s > 7 { 255 } else { 0 }).collect()
+    }
+
+    /// Detects the motion between two grayscale frames, given `strip` rows
+    /// at a time.
+    fn detect(
+        prev: &[u8],
+        curr: &[u8],
+        width: usize,
+        height: usize,
+        strip: usize,
+    ) -> (Score, Vec<u8>) {
+        let mut previous = vec![0; width * height];
+        let mut mask = vec![0x55; width * height];
+        let mut detector = Detector::new(
+            width as u16,
+            height as u16,
+            Input::Gray8,
+            Model::Previous(&mut previous),
+        )
+        .unwrap()
+        .with_mask(&mut mask);
+        detector.start();
+        detector.write_rows(prev);
+        assert_eq!(detector.finish(), None);
+        detector.start();
+        for rows in curr.chunks(strip * width) {
+            detector.write_rows(rows);
+        }
+        let score = detector.finish().unwrap().score;
+        (score, detector.mask().unwrap().to_vec())
+    }
+
+    /// Frame pair of a textured scene with noise where a bright square moved,
+    /// its changes spread around the thresholds.
+    fn scene(width: usize, height: usize, seed: u32) -> (Vec<u8>, Vec<u8>) {
+        let mut state = seed;
+        let mut random = move || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state
+        };
+        let background: Vec<u8> = (0..width * height)
+            .map(|i| ((i % width) * 3 + (i / width) * 2) as u8 ^ (random() % 16) as u8)
+            .collect();
+        let square = |frame: &mut Vec<u8>, left: usize, top: usize| {
+            for y in top..(top + 18).min(height) {
+                for x in left..(left + 24).min(width) {
+                    frame[y * width + x] = 230;
+                }
+            }
+        };
+        let mut prev = background.clone();
+        square(&mut prev, width / 4, height / 3);
+        let mut curr = background;
+        square(&mut curr, width / 4 + 8, height / 3 + 6);
+        for pixel in curr.iter_mut() {
+            // Noise crossing the diff threshold now and then
+            let noise = random() % 128;
+            if noise < 24 {
+                *pixel = pixel.wrapping_add(40 + noise as u8 / 2);
+            }
+        }
+        (prev, curr)
+    }
+
+    #[test]
+    fn agrees_with_the_python_reference() {
+        for (width, height) in [(320, 240), (33, 17), (64, 9), (1, 1), (3, 2)] {
+            for seed in 1..4 {
+                let (prev, curr) = scene(width, height, seed * 7919);
+                let expected = get_motion(&prev, &curr, width, height);
+                let moving = expected.iter().filter(|&&m| m == 255).count() as u32;
+                // The square moved
+                assert!(width < 32 || moving > 0, "{}x{}: {}", width, height, moving);
+                for strip in [1, 2, 16, height] {
+                    let (score, mask) = detect(&prev, &curr, width, height, strip);
+                    assert_eq!(
+                        mask, expected,
+                        "{}x{} seed {} strip {}",
+                        width, height, seed, strip
+                    );
+                    assert_eq!(score.moving, moving);
+                    assert_eq!(score.pixels, (width * height) as u32);
+                }
+            }
+        }
+    }
+
+    /// Frame pairs of `testdata/motion` and the masks of `get_motion` of
+    /// `server/server.py`.
+    const RECORDED: [[&[u8]; 3]; 3] = [
+        [
+            include_bytes!("../testdata/motion/01-previous.pgm"),
+            include_bytes!("../testdata/motion/01-current.pgm"),
+            include_bytes!("../testdata/motion/01-mask.pgm"),
+        ],
+        [
+            include_bytes!("../testdata/motion/02-previous.pgm"),
+            include_bytes!("../testdata/motion/02-current.pgm"),
+            include_bytes!("../testdata/motion/02-mask.pgm"),
+        ],
+        [
+            include_bytes!("../testdata/motion/03-previous.pgm"),
+            include_bytes!("../testdata/motion/03-current.pgm"),
+            include_bytes!("../testdata/motion/03-mask.pgm"),
+        ],
+    ];
+
+    /// Width, height and pixels of a binary PGM file of 8-bit pixels.
+    fn pgm(file: &[u8]) -> (usize, usize, &[u8]) {
+        let mut fields = file.splitn(5, |byte| byte.is_ascii_whitespace());
+        assert_eq!(fields.next(), Some(&b"P5"[..]));
+        let mut number = || -> usize {
+            let field = fields.next().unwrap();
+            str::from_utf8(field).unwrap().parse().unwrap()
+        };
+        let (width, height, max) = (number(), number(), number());
+        assert_eq!(max, 255);
+        let pixels = fields.next().unwrap();
+        assert_eq!(pixels.len(), width * height);
+        (width, height, pixels)
+    }
+
+    #[test]
+    fn matches_the_masks_of_server_py() {
+        for (pair, [prev, curr, expected]) in RECORDED.iter().enumerate() {
+            let (width, height, prev) = pgm(prev);
+            let (_, _, curr) = pgm(curr);
+            let (_, _, expected) = pgm(expected);
+            for strip in [1, 16, height] {
+                let (score, mask) = detect(prev, curr, width, height, strip);
+                assert_eq!(mask, expected, "pair {} strip {}", pair + 1, strip);
+                let moving = expected.iter().filter(|&&m| m == 255).count();
+                assert_eq!(score.moving, moving as u32);
+            }
+        }
+    }
+
+    #[test]
+    fn frames_must_fit_the_detector_and_its_model() {
+        let new = |width, height, previous: &mut [u8]| {
+            Detector::new(width, height, Input::Gray8, Model::Previous(previous)).err()
+        };
+        assert_eq!(new(320, 0, &mut []), Some(Error::NoPixels));
+        assert_eq!(new(1632, 1, &mut [0; 1632]), Some(Error::TooWide));
+        assert_eq!(new(4, 4, &mut [0; 15]), Some(Error::BufferTooSmall));
+        assert_eq!(new(4, 4, &mut [0; 16]), None);
+    }
+
+    #[test]
+    fn changes_must_exceed_the_diff_threshold() {
+        let prev = [100; 25];
+        // Changed by the threshold, then by one more
+        for (change, moving) in [(50, 0), (51, 1)] {
+            let curr = [100 + change; 25];
+            let (score, _) = detect(&prev, &curr, 5, 5, 1);
+            // Only the 3x3 centre has 9 neighbours in the frame
+            assert_eq!(score.moving, 9 * moving, "{}", change);
+        }
+    }
+
+    #[test]
+    fn pixels_move_with_eight_changed_neighbours() {
+        let prev = [0u8; 9];
+        let mut curr = [200u8; 9];
+        curr[0] = 0;
+        let (score, mask) = detect(&prev, &curr, 3, 3, 3);
+        assert_eq!(
+            score,
+            Score {
+                moving: 1,
+                pixels: 9
+            }
+        );
+        assert_eq!(mask, [0, 0, 0, 0, 255, 0, 0, 0, 0]);
+        curr[8] = 0;
+        assert_eq!(detect(&prev, &curr, 3, 3, 3).0.moving, 0);
+    }
+
+    #[test]
+    fn the_first_frame_has_no_score() {
+        let mut previous = [0; 4];
+        let mut detector =
+            Detector::new(2, 2, Input::Gray8, Model::Previous(&mut previous)).unwrap();
+        detector.start();
+        detector.write_rows(&[1, 2, 3, 4]);
+        assert_eq!(detector.finish(), None);
+        detector.start();
+        detector.write_rows(&[1, 2, 3, 4]);
+        assert_eq!(
+            detector.finish().map(|result| result.score),
+            Some(Score {
+                moving: 0,
+                pixels: 4
+            })
+        );
+        detector.reset();
+        detector.start();
+        detector.write_rows(&[255, 255, 255, 255]);
+        assert_eq!(detector.finish(), None);
+    }
+
+    #[test]
+    fn the_luma_of_yuv422_rows_is_compared() {
+        let (prev, curr) = scene(32, 12, 5);
+        let yuv = |gray: &[u8]| gray.iter().flat_map(|&y| [y, 90]).collect::<Vec<u8>>();
+        let mut previous = vec![0; 32 * 12];
+        let mut mask = vec![0; 32 * 12];
+        let mut detector = Detector::new(32, 12, Input::Yuv422, Model::Previous(&mut previous))
+            .unwrap()
+            .with_mask(&mut mask);
+        detector.start();
+        detector.write_rows(&yuv(&prev));
+        detector.finish();
+        detector.start();
+        detector.write_rows(&yuv(&curr));
+        detector.finish().unwrap();
+        assert_eq!(detector.mask().unwrap(), get_motion(&prev, &curr, 32, 12));
+    }
+
+    #[test]
+    fn scores_are_in_thousandths_of_the_frame() {
+        assert_eq!(
+            Score {
+                moving: 384,
+                pixels: 76800
+            }
+            .permille(),
+            5
+        );
+        assert_eq!(
+            Score {
+                moving: 0,
+                pixels: 4
+            }
+            .permille(),
+            0
+        );
+    }
+
+    /// Moving pixels of each frame of a sequence after the first.
+    fn sequence(model: Model, width: usize, height: usize, frames: &[Vec<u8>]) -> Vec<u32> {
+        let mut detector = Detector::new(width as u16, height as u16, Input::Gray8, model).unwrap();
+        frames
+            .iter()
+            .filter_map(|frame| {
+                detector.start();
+                detector.write_rows(frame);
+                detector.finish().map(|result| result.score.moving)
+            })
+            .collect()
+    }
+
+    /// Flat frame with a `size` square of `luma` at `left` and `top`.
+    fn square(
+        width: usize,
+        height: usize,
+        left: usize,
+        top: usize,
+        size: usize,
+        luma: u8,
+    ) -> Vec<u8> {
+        let mut frame = vec![100; width * height];
+        for y in top..top + size {
+            frame[y * width + left..y * width + left + size].fill(luma);
+        }
+        frame
+    }
+
+    #[test]
+    fn the_background_sees_slow_movers() {
+        // A dark square moving a pixel per frame
+        let frames: Vec<_> = (0..12).map(|x| square(40, 30, 4 + x, 10, 10, 20)).collect();
+        let mut previous = vec![0; 40 * 30];
+        let difference = sequence(Model::Previous(&mut previous), 40, 30, &frames);
+        assert!(
+            difference.iter().all(|&moving| moving == 0),
+            "{:?}",
+            difference
+        );
+        let mut mean = vec![0; 40 * 30];
+        let background = Background::new(BackgroundOptions::default(), &mut mean);
+        let background = sequence(Model::Background(background), 40, 30, &frames);
+        // Once the square left the 3x3 neighbourhoods of its first position
+        assert!(
+            background[2..].iter().all(|&moving| moving > 0),
+            "{:?}",
+            background
+        );
+    }
+
+    #[test]
+    fn the_variance_ignores_flickering_pixels() {
+        let frames: Vec<_> = (0..60)
+            .map(|i| square(40, 30, 10, 10, 8, if i % 2 == 0 { 100 } else { 170 }))
+            .collect();
+        let options = BackgroundOptions {
+            learning_rate: 32,
+            ..BackgroundOptions::default()
+        };
+        let mut previous = vec![0; 40 * 30];
+        let difference = sequence(Model::Previous(&mut previous), 40, 30, &frames);
+        assert_eq!(difference.last(), Some(&36));
+        let mut mean = vec![0; 40 * 30];
+        let background = Model::Background(Background::new(options, &mut mean));
+        assert_eq!(sequence(background, 40, 30, &frames).last(), Some(&36));
+        let mut variance = vec![0; 40 * 30];
+        let background = Background::new(options, &mut mean).with_variance(&mut variance);
+        assert_eq!(
+            sequence(Model::Background(background), 40, 30, &frames).last(),
+            Some(&0)
+        );
+    }
+
+    #[test]
+    fn freezing_keeps_still_subjects_in_the_foreground() {
+        let mut frames = vec![vec![100; 40 * 30]];
+        frames.extend((0..80).map(|_| square(40, 30, 10, 10, 10, 200)));
+        for (freeze_on_motion, moving) in [(false, 0), (true, 64)] {
+            let options = BackgroundOptions {
+                learning_rate: 32,
+                freeze_on_motion,
+                ..BackgroundOptions::default()
+            };
+            let mut mean = vec![0; 40 * 30];
+            let background = Model::Background(Background::new(options, &mut mean));
+            let scores = sequence(background, 40, 30, &frames);
+            assert_eq!(scores[0], 64);
+            assert_eq!(scores.last(), Some(&moving), "{}", freeze_on_motion);
+        }
+    }
+
+    #[test]
+    fn the_background_learns_in_fixed_point() {
+        let options = BackgroundOptions {
+            learning_rate: 128,
+            ..BackgroundOptions::default()
+        };
+        let (mut mean, mut variance) = ([0; 1], [0; 1]);
+        let mut background = Background::new(options, &mut mean).with_variance(&mut variance);
+        background.initialise(0, 100);
+        assert!(!background.compare(0, 110, true));
+        // Halfway to 110, and to a variance of 10²
+        assert_eq!(background.mean[0], 105 << 8);
+        assert_eq!(background.variance.as_ref().unwrap()[0], 50 << 4);
+        // Far from the average, but not from the deviation of 2.5 × √50
+        assert!(!background.compare(0, 80, false));
+        assert!(background.compare(0, 70, false));
+        assert_eq!(background.mean[0], 105 << 8);
+    }
+
+    /// Frames of swaying leaves on the left and of someone walking past on
+    /// the right, with the moving pixels expected of the walk.
+    fn swaying_leaves() -> (Vec<Vec<u8>>, Vec<Vec<bool>>) {
+        const WARMUP: usize = 200;
+        let (width, height) = (64, 48);
+        let mut state = 0x2545_f491u32;
+        let mut random = move || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state
+        };
+        let mut leaves = [false; 6 * 12];
+        let mut frames = Vec::new();
+        let mut walker = Vec::new();
+        for frame in 0..WARMUP + 10 {
+            for leaf in leaves.iter_mut() {
+                *leaf ^= random() % 3 == 0;
+            }
+            let left = (frame >= WARMUP).then(|| 28 + 3 * (frame - WARMUP));
+            let mut pixels = vec![0; width * height];
+            let mut body = vec![false; width * height];
+            for y in 0..height {
+                for x in 0..width {
+                    let i = y * width + x;
+                    pixels[i] = if x < 24 {
+                        if leaves[y / 4 * 6 + x / 4] { 160 } else { 60 }
+                    } else if left
+                        .is_some_and(|left| (left..left + 8).contains(&x) && (18..30).contains(&y))
+                    {
+                        body[i] = true;
+                        230
+                    } else {
+                        (80 + x / 2) as u8 - (random() % 3) as u8
+                    };
+                }
+            }
+            frames.push(pixels);
+            walker.push(body);
+        }
+        (frames, walker)
+    }
+
+    /// Moving pixels of the leaves and of the walker during the walk.
+    fn walk_scores(model: Model) -> (u32, u32) {
+        let (frames, walker) = swaying_leaves();
+        let mut mask = vec![0; 64 * 48];
+        let mut detector = Detector::new(64, 48, Input::Gray8, model)
+            .unwrap()
+            .with_mask(&mut mask);
+        let (mut leaves, mut walking) = (0, 0);
+        for (frame, body) in frames.iter().zip(&walker) {
+            detector.start();
+            detector.write_rows(frame);
+            detector.finish();
+            if body.iter().any(|&body| body) {
+                for (i, &mask) in detector.mask().unwrap().iter().enumerate() {
+                    if mask == 255 {
+                        if i % 64 < 24 {
+                            leaves += 1;
+                        } else if body[i] {
+                            walking += 1;
+                        }
+                    }
+                }
+            }
+        }
+        (leaves, walking)
+    }
+
+    #[test]
+    fn mixture_ignores_swaying_leaves() {
+        let mut previous = vec![0; 64 * 48];
+        let difference = walk_scores(Model::Previous(&mut previous));
+        let mut pixels = vec![[0; 3]; 64 * 48];
+        let mixture = Mixture::new(MixtureOptions::default(), &mut pixels);
+        let mixture = walk_scores(Model::Mixture(mixture));
+        let mut mean = vec![0; 64 * 48];
+        let options = BackgroundOptions::default();
+        let background = walk_scores(Model::Background(Background::new(options, &mut mean)));
+        let mut mean = vec![0; 64 * 48];
+        let options = BackgroundOptions {
+            freeze_on_motion: true,
+            ..options
+        };
+        let frozen = walk_scores(Model::Background(Background::new(options, &mut mean)));
+        // The table of the module documentation
+        assert_eq!(difference, (1746, 150));
+        assert_eq!(background, (10098, 600));
+        assert_eq!(frozen, (3173, 600));
+        assert_eq!(mixture, (0, 600));
+        assert!(difference.0 > 1000, "{:?}", difference);
+        assert!(
+            mixture.0 * 10 < difference.0,
+            "{:?} {:?}",
+            difference,
+            mixture
+        );
+        assert!(
+            mixture.1 >= 3 * difference.1,
+            "{:?} {:?}",
+            difference,
+            mixture
+        );
+    }
+
+    #[test]
+    fn still_subjects_fade_into_the_mixture() {
+        let mut frames = vec![vec![100; 40 * 30]];
+        frames.extend((0..60).map(|_| square(40, 30, 10, 10, 10, 200)));
+        let mut pixels = vec![[0; 3]; 40 * 30];
+        let mixture = Mixture::new(MixtureOptions::default(), &mut pixels);
+        let scores = sequence(Model::Mixture(mixture), 40, 30, &frames);
+        // After about 256 / 4 * 4 / 15 frames
+        assert!(
+            scores[..10].iter().all(|&moving| moving == 64),
+            "{:?}",
+            scores
+        );
+        assert!(
+            scores[25..].iter().all(|&moving| moving == 0),
+            "{:?}",
+            scores
+        );
+    }
+
+    #[test]
+    fn mixture_pixels_are_packed_in_three_bytes() {
+        let mut pixels = [[0; 3]; 1];
+        let mut mixture = Mixture::new(MixtureOptions::default(), &mut pixels);
+        mixture.initialise(0, 90);
+        assert_eq!(mixture.pixels[0], [90, 90, 0xF0 | 1 << 2 | 2]);
+        // A new luma replaces the second Gaussian, with a weight of 1
+        assert!(mixture.compare(0, 200, true));
+        assert_eq!(mixture.pixels[0], [90, 200, 0xE0 | 1 << 2 | 2]);
+        // Within 2.5 × 16 of its mean, but not background yet
+        assert!(mixture.compare(0, 230, false));
+        assert!(!mixture.compare(0, 100, false));
+        assert!(mixture.compare(0, 111, false));
+    }
+
+    #[test]
+    fn moving_pixels_are_labelled_into_blobs() {
+        let (width, height) = (96, 64);
+        // Two squares appearing in a still frame
+        let mut curr = square(width, height, 8, 8, 20, 220);
+        for y in 40..50 {
+            curr[y * width + 60..y * width + 70].fill(220);
+        }
+        let prev = vec![100; width * height];
+        let mut previous = vec![0; width * height];
+        let mut mask = vec![0; width * height];
+        let (mut labels, mut table) = ([0; 96], [Label::FREE; 16]);
+        let options = BlobOptions {
+            min_area: 50,
+            ..BlobOptions::default()
+        };
+        let mut detector = Detector::new(
+            width as u16,
+            height as u16,
+            Input::Gray8,
+            Model::Previous(&mut previous),
+        )
+        .unwrap()
+        .with_mask(&mut mask)
+        .with_blobs(options, &mut labels, &mut table);
+        detector.start();
+        detector.write_rows(&prev);
+        assert_eq!(detector.finish(), None);
+        detector.start();
+        detector.write_rows(&curr);
+        let result = detector.finish().unwrap();
+        // The 3x3 vote shaves the edges of the squares
+        let areas: Vec<_> = result.blobs().iter().map(|blob| blob.area).collect();
+        assert_eq!(areas, [18 * 18, 8 * 8]);
+        assert_eq!(result.blobs.found, 2);
+        let large = result.blobs()[0];
+        assert_eq!(
+            (large.left, large.top, large.right, large.bottom),
+            (9, 9, 26, 26)
+        );
+        assert_eq!(result.blobs()[1].centroid(), (65, 45));
+        assert_eq!(result.score.moving, 18 * 18 + 8 * 8);
+    }
+}
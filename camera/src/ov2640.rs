@@ -0,0 +1,905 @@
+//! Driver for the OV2640 image sensor of the ArduCAM, configured over I2C.
+//!
+//! The registers of the OV2640 are split in two banks sharing the same
+//! addresses, the DSP bank and the sensor bank, and the bank addressed by
+//! every other access is selected by writing to [`BANK_SEL`]. The driver keeps
+//! track of the selected bank so that it is only switched when needed.
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::i2c::{self, I2c};
+
+use crate::arduchip::FIFO_SIZE;
+use crate::bmp;
+
+/// 7-bit I2C address of the OV2640.
+pub const SENSOR_ADDRESS: u8 = 0x30;
+
+/// Register selecting the bank of every other register, shared by both banks.
+pub const BANK_SEL: u8 = 0xFF;
+
+pub const OV2640_CHIPID_HIGH: Register = Register::sensor(0x0A);
+pub const OV2640_CHIPID_LOW: Register = Register::sensor(0x0B);
+pub const REG04: Register = Register::sensor(0x04);
+pub const COM7: Register = Register::sensor(0x12);
+pub const CTRLI: Register = Register::dsp(0x50);
+pub const ZMOW: Register = Register::dsp(0x5A);
+pub const ZMOH: Register = Register::dsp(0x5B);
+pub const ZMHH: Register = Register::dsp(0x5C);
+pub const RESET: Register = Register::dsp(0xE0);
+pub const QS: Register = Register::dsp(0x44);
+
+/// Product ID of the OV2640, read from [`OV2640_CHIPID_HIGH`] and
+/// [`OV2640_CHIPID_LOW`]. The low byte is the silicon revision, 0x41 or 0x42.
+const OV2640_PID: u8 = 0x26;
+const OV2640_VERSIONS: [u8; 2] = [0x41, 0x42];
+
+const COM7_SRST: u8 = 0x80;
+const REG04_HFLIP: u8 = 0x80;
+const REG04_VFLIP: u8 = 0x40;
+/// Shifts the first row by one, to keep the Bayer pattern aligned when the
+/// rows are read in reverse.
+const REG04_VREF_EN: u8 = 0x10;
+const RESET_DVP: u8 = 0x04;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Bank {
+    Dsp = 0x00,
+    Sensor = 0x01,
+}
+
+/// Register of the OV2640, addressed within its bank.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Register {
+    pub bank: Bank,
+    pub address: u8,
+}
+
+impl Register {
+    pub const fn dsp(address: u8) -> Self {
+        Self {
+            bank: Bank::Dsp,
+            address,
+        }
+    }
+
+    pub const fn sensor(address: u8) -> Self {
+        Self {
+            bank: Bank::Sensor,
+            address,
+        }
+    }
+}
+
+/// Format of the frames written to the FIFO.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum OutputFormat {
+    /// Two bytes per pixel, big endian.
+    Rgb565,
+    /// Two bytes per pixel, a luma byte then alternately the blue and red
+    /// chroma shared by each pair of pixels: Y0 U Y1 V.
+    Yuv422,
+    /// JPEG compressed by the sensor, of variable length.
+    Jpeg,
+}
+
+/// Readout direction of the pixel array, to compensate how the camera is
+/// mounted.
+///
+/// [`OV2640_QVGA`] mirrors the image, which with the ArduCAM upright and
+/// bottom-up BMP frames came out rotated by half a turn.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, defmt::Format)]
+pub struct Mounting {
+    /// Reverse the order of the columns.
+    pub mirror: bool,
+    /// Reverse the order of the rows.
+    pub flip: bool,
+}
+
+impl Mounting {
+    pub const UPRIGHT: Self = Self {
+        mirror: false,
+        flip: false,
+    };
+    pub const UPSIDE_DOWN: Self = Self {
+        mirror: true,
+        flip: true,
+    };
+
+    /// The mounting named `name`: `upright`, `mirrored`, `flipped` or
+    /// `upside-down`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        let (mirror, flip) = match name {
+            "upright" => (false, false),
+            "mirrored" => (true, false),
+            "flipped" => (false, true),
+            "upside-down" => (true, true),
+            _ => return None,
+        };
+        Some(Self { mirror, flip })
+    }
+
+    pub const fn name(self) -> &'static str {
+        match (self.mirror, self.flip) {
+            (false, false) => "upright",
+            (true, false) => "mirrored",
+            (false, true) => "flipped",
+            (true, true) => "upside-down",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+    /// The I2C bus reported an error.
+    I2c(i2c::ErrorKind),
+    /// The chip ID read from the sensor is not the one of an OV2640.
+    UnexpectedChipId(u16),
+}
+
+/// OV2640 on an I2C bus.
+pub struct Ov2640<I2C> {
+    i2c: I2C,
+    /// Bank currently selected, `None` when unknown.
+    bank: Option<Bank>,
+}
+
+impl<I2C: I2c> Ov2640<I2C> {
+    pub fn new(i2c: I2C) -> Self {
+        Self { i2c, bank: None }
+    }
+
+    /// Returns the bus.
+    pub fn release(self) -> I2C {
+        self.i2c
+    }
+
+    /// Bank currently selected, `None` when it is unknown.
+    pub fn bank(&self) -> Option<Bank> {
+        self.bank
+    }
+
+    pub fn select_bank(&mut self, bank: Bank) -> Result<(), Error> {
+        if self.bank != Some(bank) {
+            // Forget the bank first so that it is written again if this fails.
+            self.bank = None;
+            self.write_raw(BANK_SEL, bank as u8)?;
+            self.bank = Some(bank);
+        }
+        Ok(())
+    }
+
+    pub fn read(&mut self, reg: Register) -> Result<u8, Error> {
+        self.select_bank(reg.bank)?;
+        self.read_raw(reg.address)
+    }
+
+    pub fn write(&mut self, reg: Register, value: u8) -> Result<(), Error> {
+        self.select_bank(reg.bank)?;
+        self.write_raw(reg.address, value)
+    }
+
+    /// Reads the chip ID, [`OV2640_CHIPID_HIGH`] in the high byte.
+    pub fn chip_id(&mut self) -> Result<u16, Error> {
+        let high = self.read(OV2640_CHIPID_HIGH)?;
+        let low = self.read(OV2640_CHIPID_LOW)?;
+        Ok(u16::from_be_bytes([high, low]))
+    }
+
+    /// Checks that the sensor answers with the chip ID of an OV2640.
+    pub fn probe(&mut self) -> Result<(), Error> {
+        let id = self.chip_id()?;
+        let [pid, version] = id.to_be_bytes();
+        if pid != OV2640_PID || !OV2640_VERSIONS.contains(&version) {
+            return Err(Error::UnexpectedChipId(id));
+        }
+        Ok(())
+    }
+
+    /// Resets every register of the sensor to its default value.
+    pub fn soft_reset(&mut self, delay: &mut impl DelayNs) -> Result<(), Error> {
+        self.start_soft_reset()?;
+        delay.delay_ms(100);
+        Ok(())
+    }
+
+    /// Starts resetting every register of the sensor to its default value,
+    /// which takes 100 ms.
+    pub fn start_soft_reset(&mut self) -> Result<(), Error> {
+        self.write(COM7, COM7_SRST)?;
+        // The reset also restores the bank selection register.
+        self.bank = None;
+        Ok(())
+    }
+
+    /// Writes a table of `[address, value]` pairs in order, as found in the
+    /// ArduCAM sources. Writes to [`BANK_SEL`] in the table switch banks.
+    pub fn write_table(&mut self, table: &[[u8; 2]]) -> Result<(), Error> {
+        for &[address, value] in table {
+            if address == BANK_SEL {
+                match value {
+                    0x00 => self.select_bank(Bank::Dsp)?,
+                    0x01 => self.select_bank(Bank::Sensor)?,
+                    _ => {
+                        self.bank = None;
+                        self.write_raw(BANK_SEL, value)?;
+                    }
+                }
+            } else {
+                self.write_raw(address, value)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Resets the sensor and configures it for `format` frames of
+    /// `resolution`.
+    pub fn init(
+        &mut self,
+        format: OutputFormat,
+        resolution: Resolution,
+        delay: &mut impl DelayNs,
+    ) -> Result<(), Error> {
+        self.soft_reset(delay)?;
+        self.configure(format, resolution)
+    }
+
+    /// Configures the sensor, once reset, for `format` frames of
+    /// `resolution`.
+    pub fn configure(&mut self, format: OutputFormat, resolution: Resolution) -> Result<(), Error> {
+        self.write_table(&OV2640_QVGA)?;
+        match format {
+            OutputFormat::Rgb565 => {}
+            OutputFormat::Yuv422 => self.write_table(&OV2640_YUV422)?,
+            OutputFormat::Jpeg => self.write_table(&OV2640_JPEG_INIT)?,
+        }
+        self.set_resolution(resolution)
+    }
+
+    /// Sets the quantization scale of the JPEG encoder, from 2 (best quality,
+    /// largest frames) to 255.
+    pub fn set_jpeg_quality(&mut self, scale: u8) -> Result<(), Error> {
+        self.write(QS, scale.max(2))
+    }
+
+    /// Sets the readout direction, keeping the other bits of [`REG04`].
+    pub fn set_mounting(&mut self, mounting: Mounting) -> Result<(), Error> {
+        let mut value = self.read(REG04)? & !(REG04_HFLIP | REG04_VFLIP | REG04_VREF_EN);
+        if mounting.mirror {
+            value |= REG04_HFLIP;
+        }
+        if mounting.flip {
+            value |= REG04_VFLIP | REG04_VREF_EN;
+        }
+        self.write(REG04, value)
+    }
+
+    /// Sets the output size, after the sensor was initialised with
+    /// [`OV2640_QVGA`].
+    pub fn set_resolution(&mut self, resolution: Resolution) -> Result<(), Error> {
+        self.write_table(&resolution.registers())
+    }
+
+    /// Reads a register of the currently selected bank.
+    fn read_raw(&mut self, address: u8) -> Result<u8, Error> {
+        let mut value = [0u8];
+        self.i2c
+            .write_read(SENSOR_ADDRESS, &[address], &mut value)
+            .map_err(|e| Error::I2c(i2c::Error::kind(&e)))?;
+        Ok(value[0])
+    }
+
+    /// Writes a register of the currently selected bank.
+    fn write_raw(&mut self, address: u8, value: u8) -> Result<(), Error> {
+        self.i2c
+            .write(SENSOR_ADDRESS, &[address, value])
+            .map_err(|e| Error::I2c(i2c::Error::kind(&e)))
+    }
+}
+
+/// Buffers the RGB565 and YUV422 frames are served from, besides the FIFO.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameBuffers {
+    /// Bytes of the file a frame is encoded into, up to a 24-bit BMP.
+    pub file: usize,
+    /// Pixels of the model of the motion detector.
+    pub motion_pixels: usize,
+    /// Widest frames of the motion detector.
+    pub motion_width: usize,
+}
+
+/// Output sizes of the OV2640.
+///
+/// The sensor always runs on its full 1600x1200 window, the DSP pre-divides
+/// the image then zooms it down to the output size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Resolution {
+    /// 160x120
+    Qqvga,
+    /// 320x240
+    Qvga,
+    /// 352x288
+    Cif,
+    /// 640x480
+    Vga,
+    /// 800x600
+    Svga,
+    /// 1600x1200
+    Uxga,
+}
+
+impl Resolution {
+    pub const ALL: [Resolution; 6] = [
+        Resolution::Qqvga,
+        Resolution::Qvga,
+        Resolution::Cif,
+        Resolution::Vga,
+        Resolution::Svga,
+        Resolution::Uxga,
+    ];
+
+    /// The resolution named `name`, such as `qvga`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|resolution| resolution.name() == name)
+    }
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Resolution::Qqvga => "qqvga",
+            Resolution::Qvga => "qvga",
+            Resolution::Cif => "cif",
+            Resolution::Vga => "vga",
+            Resolution::Svga => "svga",
+            Resolution::Uxga => "uxga",
+        }
+    }
+
+    pub const fn width(self) -> u32 {
+        match self {
+            Resolution::Qqvga => 160,
+            Resolution::Qvga => 320,
+            Resolution::Cif => 352,
+            Resolution::Vga => 640,
+            Resolution::Svga => 800,
+            Resolution::Uxga => 1600,
+        }
+    }
+
+    pub const fn height(self) -> u32 {
+        match self {
+            Resolution::Qqvga => 120,
+            Resolution::Qvga => 240,
+            Resolution::Cif => 288,
+            Resolution::Vga => 480,
+            Resolution::Svga => 600,
+            Resolution::Uxga => 1200,
+        }
+    }
+
+    pub const fn pixels(self) -> u32 {
+        self.width() * self.height()
+    }
+
+    /// Size in bytes of an RGB565 frame, or of a YUV422 one.
+    pub const fn rgb565_length(self) -> u32 {
+        self.pixels() * 2
+    }
+
+    /// Whether an RGB565 frame fits in the FIFO of the ArduChip.
+    pub const fn rgb565_fits_fifo(self) -> bool {
+        self.rgb565_length() <= FIFO_SIZE
+    }
+
+    /// Whether the frames of `format` fit in the FIFO and in `buffers`, the
+    /// JPEG frames varying in size being checked as they are read.
+    pub const fn fits(self, format: OutputFormat, buffers: &FrameBuffers) -> bool {
+        match format {
+            OutputFormat::Jpeg => true,
+            OutputFormat::Rgb565 | OutputFormat::Yuv422 => {
+                let bmp = bmp::Header::new(self.width(), self.height(), bmp::Format::Bgr888);
+                self.rgb565_fits_fifo()
+                    && bmp.file_size() <= buffers.file
+                    && self.pixels() as usize <= buffers.motion_pixels
+                    && self.width() as usize <= buffers.motion_width
+            }
+        }
+    }
+
+    /// Value of [`CTRLI`], dividing the sensor window down to at least the
+    /// output size before the zoom.
+    const fn ctrli(self) -> u8 {
+        match self {
+            // 400x300
+            Resolution::Qqvga | Resolution::Qvga | Resolution::Cif => 0x92,
+            // 800x600
+            Resolution::Vga | Resolution::Svga => 0x89,
+            // 1600x1200
+            Resolution::Uxga => 0x00,
+        }
+    }
+
+    /// Register sequence setting the output size, written after
+    /// [`OV2640_QVGA`].
+    pub const fn registers(self) -> [[u8; 2]; 7] {
+        let zmow = self.width() / 4;
+        let zmoh = self.height() / 4;
+        let zmhh = (zmow >> 8) & 0x03 | ((zmoh >> 8) & 0x01) << 2;
+        [
+            [BANK_SEL, Bank::Dsp as u8],
+            [RESET.address, RESET_DVP],
+            [CTRLI.address, self.ctrli()],
+            [ZMOW.address, zmow as u8],
+            [ZMOH.address, zmoh as u8],
+            [ZMHH.address, zmhh as u8],
+            [RESET.address, 0x00],
+        ]
+    }
+}
+
+/// Switches the output of a sensor initialised with [`OV2640_QVGA`] to JPEG.
+///
+/// The sensor keeps the full window set by [`OV2640_QVGA`] so that
+/// [`Resolution::registers`] applies to both formats.
+pub const OV2640_JPEG_INIT: [[u8; 2]; 10] = [
+    // DSP bank
+    [0xff, 0x00],
+    // Enable JPEG
+    [0x2c, 0xff],
+    [0x2e, 0xdf],
+    // Reset the JPEG encoder and DVP while configuring them
+    [0xe0, 0x14],
+    [0xe1, 0x77],
+    [0xe5, 0x1f],
+    [0xd7, 0x03],
+    // JPEG output
+    [0xda, 0x10],
+    [0xe0, 0x00],
+    // Default quantization scale
+    [0x44, 0x0c],
+];
+
+/// Switches the output of a sensor initialised with [`OV2640_QVGA`] to
+/// YUV422, luma first.
+pub const OV2640_YUV422: [[u8; 2]; 5] = [
+    // DSP bank
+    [0xff, 0x00],
+    // Reset the DVP while configuring it
+    [0xe0, 0x04],
+    // YUV422 output, high byte first
+    [0xda, 0x00],
+    [0xd7, 0x03],
+    [0xe0, 0x00],
+];
+
+/// Initialisation for RGB565 output at 320x240, see
+/// [`Resolution::registers`] for the other sizes.
+pub const OV2640_QVGA: [[u8; 2]; 193] = [
+    [0xff, 0x0],
+    [0x2c, 0xff],
+    [0x2e, 0xdf],
+    [0xff, 0x1],
+    [0x3c, 0x32],
+    [0x11, 0x0],
+    [0x9, 0x2],
+    [0x4, 0xa8],
+    [0x13, 0xe5],
+    [0x14, 0x48],
+    [0x2c, 0xc],
+    [0x33, 0x78],
+    [0x3a, 0x33],
+    [0x3b, 0xfb],
+    [0x3e, 0x0],
+    [0x43, 0x11],
+    [0x16, 0x10],
+    [0x39, 0x2],
+    [0x35, 0x88],
+    [0x22, 0xa],
+    [0x37, 0x40],
+    [0x23, 0x0],
+    [0x34, 0xa0],
+    [0x6, 0x2],
+    [0x6, 0x88],
+    [0x7, 0xc0],
+    [0xd, 0xb7],
+    [0xe, 0x1],
+    [0x4c, 0x0],
+    [0x4a, 0x81],
+    [0x21, 0x99],
+    [0x24, 0x40],
+    [0x25, 0x38],
+    [0x26, 0x82],
+    [0x5c, 0x0],
+    [0x63, 0x0],
+    [0x46, 0x22],
+    [0xc, 0x3a],
+    [0x5d, 0x55],
+    [0x5e, 0x7d],
+    [0x5f, 0x7d],
+    [0x60, 0x55],
+    [0x61, 0x70],
+    [0x62, 0x80],
+    [0x7c, 0x5],
+    [0x20, 0x80],
+    [0x28, 0x30],
+    [0x6c, 0x0],
+    [0x6d, 0x80],
+    [0x6e, 0x0],
+    [0x70, 0x2],
+    [0x71, 0x94],
+    [0x73, 0xc1],
+    [0x3d, 0x34],
+    [0x12, 0x4],
+    [0x5a, 0x57],
+    [0x4f, 0xbb],
+    [0x50, 0x9c],
+    [0xff, 0x0],
+    [0xe5, 0x7f],
+    [0xf9, 0xc0],
+    [0x41, 0x24],
+    [0xe0, 0x14],
+    [0x76, 0xff],
+    [0x33, 0xa0],
+    [0x42, 0x20],
+    [0x43, 0x18],
+    [0x4c, 0x0],
+    [0x87, 0xd0],
+    [0x88, 0x3f],
+    [0xd7, 0x3],
+    [0xd9, 0x10],
+    [0xd3, 0x82],
+    [0xc8, 0x8],
+    [0xc9, 0x80],
+    [0x7c, 0x0],
+    [0x7d, 0x0],
+    [0x7c, 0x3],
+    [0x7d, 0x48],
+    [0x7d, 0x48],
+    [0x7c, 0x8],
+    [0x7d, 0x20],
+    [0x7d, 0x10],
+    [0x7d, 0xe],
+    [0x90, 0x0],
+    [0x91, 0xe],
+    [0x91, 0x1a],
+    [0x91, 0x31],
+    [0x91, 0x5a],
+    [0x91, 0x69],
+    [0x91, 0x75],
+    [0x91, 0x7e],
+    [0x91, 0x88],
+    [0x91, 0x8f],
+    [0x91, 0x96],
+    [0x91, 0xa3],
+    [0x91, 0xaf],
+    [0x91, 0xc4],
+    [0x91, 0xd7],
+    [0x91, 0xe8],
+    [0x91, 0x20],
+    [0x92, 0x0],
+    [0x93, 0x6],
+    [0x93, 0xe3],
+    [0x93, 0x3],
+    [0x93, 0x3],
+    [0x93, 0x0],
+    [0x93, 0x2],
+    [0x93, 0x0],
+    [0x93, 0x0],
+    [0x93, 0x0],
+    [0x93, 0x0],
+    [0x93, 0x0],
+    [0x93, 0x0],
+    [0x93, 0x0],
+    [0x96, 0x0],
+    [0x97, 0x8],
+    [0x97, 0x19],
+    [0x97, 0x2],
+    [0x97, 0xc],
+    [0x97, 0x24],
+    [0x97, 0x30],
+    [0x97, 0x28],
+    [0x97, 0x26],
+    [0x97, 0x2],
+    [0x97, 0x98],
+    [0x97, 0x80],
+    [0x97, 0x0],
+    [0x97, 0x0],
+    [0xa4, 0x0],
+    [0xa8, 0x0],
+    [0xc5, 0x11],
+    [0xc6, 0x51],
+    [0xbf, 0x80],
+    [0xc7, 0x10],
+    [0xb6, 0x66],
+    [0xb8, 0xa5],
+    [0xb7, 0x64],
+    [0xb9, 0x7c],
+    [0xb3, 0xaf],
+    [0xb4, 0x97],
+    [0xb5, 0xff],
+    [0xb0, 0xc5],
+    [0xb1, 0x94],
+    [0xb2, 0xf],
+    [0xc4, 0x5c],
+    [0xa6, 0x0],
+    [0xa7, 0x20],
+    [0xa7, 0xd8],
+    [0xa7, 0x1b],
+    [0xa7, 0x31],
+    [0xa7, 0x0],
+    [0xa7, 0x18],
+    [0xa7, 0x20],
+    [0xa7, 0xd8],
+    [0xa7, 0x19],
+    [0xa7, 0x31],
+    [0xa7, 0x0],
+    [0xa7, 0x18],
+    [0xa7, 0x20],
+    [0xa7, 0xd8],
+    [0xa7, 0x19],
+    [0xa7, 0x31],
+    [0xa7, 0x0],
+    [0xa7, 0x18],
+    [0x7f, 0x0],
+    [0xe5, 0x1f],
+    [0xe1, 0x77],
+    [0xdd, 0x7f],
+    [0xc2, 0xe],
+    [0xff, 0x0],
+    [0xe0, 0x4],
+    [0xc0, 0xc8],
+    [0xc1, 0x96],
+    [0x86, 0x3d],
+    [0x51, 0x90],
+    [0x52, 0x2c],
+    [0x53, 0x0],
+    [0x54, 0x0],
+    [0x55, 0x88],
+    [0x57, 0x0],
+    [0x50, 0x92],
+    [0x5a, 0x50],
+    [0x5b, 0x3c],
+    [0x5c, 0x0],
+    [0xd3, 0x4],
+    [0xe0, 0x0],
+    [0xff, 0x0],
+    [0x5, 0x0],
+    [0xda, 0x8],
+    [0xd7, 0x3],
+    [0xe0, 0x0],
+    [0x5, 0x0],
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake::FakeOv2640;
+    use embedded_hal_mock::eh1::delay::NoopDelay;
+    use embedded_hal_mock::eh1::i2c::{Mock, Transaction};
+
+    fn bank_sel(bank: Bank) -> Transaction {
+        Transaction::write(SENSOR_ADDRESS, vec![BANK_SEL, bank as u8])
+    }
+
+    #[test]
+    fn bank_is_only_selected_when_it_changes() {
+        let expectations = [
+            bank_sel(Bank::Sensor),
+            Transaction::write_read(SENSOR_ADDRESS, vec![0x0A], vec![0x26]),
+            Transaction::write_read(SENSOR_ADDRESS, vec![0x0B], vec![0x42]),
+            bank_sel(Bank::Dsp),
+            Transaction::write(SENSOR_ADDRESS, vec![0xE0, 0x04]),
+        ];
+        let mut sensor = Ov2640::new(Mock::new(&expectations));
+        assert_eq!(sensor.chip_id(), Ok(0x2642));
+        sensor.write(Register::dsp(0xE0), 0x04).unwrap();
+        assert_eq!(sensor.bank(), Some(Bank::Dsp));
+        sensor.release().done();
+    }
+
+    #[test]
+    fn probe_rejects_other_sensors() {
+        let expectations = [
+            bank_sel(Bank::Sensor),
+            Transaction::write_read(SENSOR_ADDRESS, vec![0x0A], vec![0x26]),
+            Transaction::write_read(SENSOR_ADDRESS, vec![0x0B], vec![0x41]),
+            Transaction::write_read(SENSOR_ADDRESS, vec![0x0A], vec![0x56]),
+            Transaction::write_read(SENSOR_ADDRESS, vec![0x0B], vec![0x42]),
+        ];
+        let mut sensor = Ov2640::new(Mock::new(&expectations));
+        assert_eq!(sensor.probe(), Ok(()));
+        assert_eq!(sensor.probe(), Err(Error::UnexpectedChipId(0x5642)));
+        sensor.release().done();
+    }
+
+    #[test]
+    fn soft_reset_forgets_the_bank() {
+        let expectations = [
+            bank_sel(Bank::Sensor),
+            Transaction::write(SENSOR_ADDRESS, vec![0x12, 0x80]),
+            bank_sel(Bank::Sensor),
+            Transaction::write(SENSOR_ADDRESS, vec![0x12, 0x40]),
+        ];
+        let mut sensor = Ov2640::new(Mock::new(&expectations));
+        sensor.soft_reset(&mut NoopDelay).unwrap();
+        assert_eq!(sensor.bank(), None);
+        sensor.write(COM7, 0x40).unwrap();
+        sensor.release().done();
+    }
+
+    #[test]
+    fn table_bank_switches_are_tracked() {
+        let expectations = [
+            bank_sel(Bank::Dsp),
+            Transaction::write(SENSOR_ADDRESS, vec![0x2C, 0xFF]),
+            bank_sel(Bank::Sensor),
+            Transaction::write(SENSOR_ADDRESS, vec![0x12, 0x40]),
+            Transaction::write(SENSOR_ADDRESS, vec![0x12, 0x80]),
+        ];
+        let mut sensor = Ov2640::new(Mock::new(&expectations));
+        sensor
+            .write_table(&[
+                [0xFF, 0x00],
+                [0x2C, 0xFF],
+                [0xFF, 0x00],
+                [0xFF, 0x01],
+                [0x12, 0x40],
+            ])
+            .unwrap();
+        sensor.write(COM7, 0x80).unwrap();
+        sensor.release().done();
+    }
+
+    #[test]
+    fn qvga_registers_match_the_initialisation_table() {
+        let registers = Resolution::Qvga.registers();
+        for [address, value] in &registers[2..6] {
+            let last = OV2640_QVGA.iter().rev().find(|[a, _]| a == address);
+            assert_eq!(last, Some(&[*address, *value]));
+        }
+    }
+
+    #[test]
+    fn mountings_are_named() {
+        for (mirror, flip) in [(false, false), (true, false), (false, true), (true, true)] {
+            let mounting = Mounting { mirror, flip };
+            assert_eq!(Mounting::from_name(mounting.name()), Some(mounting));
+        }
+        assert_eq!(
+            Mounting::from_name("upside-down"),
+            Some(Mounting::UPSIDE_DOWN)
+        );
+        assert_eq!(Mounting::from_name("sideways"), None);
+    }
+
+    #[test]
+    fn resolutions_are_named() {
+        for resolution in Resolution::ALL {
+            assert_eq!(Resolution::from_name(resolution.name()), Some(resolution));
+        }
+        assert_eq!(Resolution::from_name("QVGA"), None);
+    }
+
+    #[test]
+    fn resolutions_must_fit_the_fifo_and_the_buffers() {
+        // Those of the server
+        let buffers = FrameBuffers {
+            file: 240 * 1024 - 256,
+            motion_pixels: 320 * 240,
+            motion_width: 320,
+        };
+        assert!(Resolution::Qqvga.fits(OutputFormat::Yuv422, &buffers));
+        assert!(Resolution::Qvga.fits(OutputFormat::Rgb565, &buffers));
+        // Its 24-bit BMP does not fit
+        assert!(!Resolution::Cif.fits(OutputFormat::Yuv422, &buffers));
+        let unbounded = FrameBuffers {
+            file: usize::MAX,
+            motion_pixels: usize::MAX,
+            motion_width: usize::MAX,
+        };
+        assert!(Resolution::Cif.fits(OutputFormat::Yuv422, &unbounded));
+        // Larger than the FIFO
+        assert!(!Resolution::Vga.fits(OutputFormat::Yuv422, &unbounded));
+        // Too many pixels, then too wide, for the motion detector
+        let motion = FrameBuffers {
+            motion_pixels: 160 * 120,
+            ..unbounded
+        };
+        assert!(!Resolution::Qvga.fits(OutputFormat::Yuv422, &motion));
+        let motion = FrameBuffers {
+            motion_width: 160,
+            ..unbounded
+        };
+        assert!(!Resolution::Qvga.fits(OutputFormat::Yuv422, &motion));
+        // Checked as they are read
+        assert!(Resolution::Uxga.fits(OutputFormat::Jpeg, &buffers));
+    }
+
+    #[test]
+    fn uxga_output_size_uses_the_high_bits() {
+        let registers = Resolution::Uxga.registers();
+        assert_eq!(registers[3], [0x5A, 0x90]);
+        assert_eq!(registers[4], [0x5B, 0x2C]);
+        assert_eq!(registers[5], [0x5C, 0x05]);
+    }
+
+    #[test]
+    fn only_small_rgb565_frames_fit_the_fifo() {
+        let fitting: Vec<_> = Resolution::ALL
+            .into_iter()
+            .filter(|r| r.rgb565_fits_fifo())
+            .collect();
+        assert_eq!(
+            fitting,
+            [Resolution::Qqvga, Resolution::Qvga, Resolution::Cif]
+        );
+    }
+
+    #[test]
+    fn failed_bank_selection_is_retried() {
+        let expectations = [
+            bank_sel(Bank::Dsp).with_error(i2c::ErrorKind::Other),
+            bank_sel(Bank::Dsp),
+            Transaction::write(SENSOR_ADDRESS, vec![0xE0, 0x00]),
+        ];
+        let mut sensor = Ov2640::new(Mock::new(&expectations));
+        assert_eq!(
+            sensor.write(Register::dsp(0xE0), 0x00),
+            Err(Error::I2c(i2c::ErrorKind::Other))
+        );
+        sensor.write(Register::dsp(0xE0), 0x00).unwrap();
+        sensor.release().done();
+    }
+
+    #[test]
+    fn jpeg_init_selects_jpeg_output_at_the_requested_size() {
+        let mut sensor = Ov2640::new(FakeOv2640::new());
+        sensor
+            .init(OutputFormat::Jpeg, Resolution::Vga, &mut NoopDelay)
+            .unwrap();
+        sensor.set_jpeg_quality(0).unwrap();
+        let fake = sensor.release();
+        assert_eq!(fake.register(Register::dsp(0xDA)), 0x10);
+        assert_eq!(fake.register(ZMOW), (640 / 4) as u8);
+        assert_eq!(fake.register(ZMOH), (480 / 4) as u8);
+        assert_eq!(fake.register(QS), 2);
+    }
+
+    #[test]
+    fn yuv422_init_only_changes_the_output_format() {
+        let mut rgb565 = Ov2640::new(FakeOv2640::new());
+        rgb565
+            .init(OutputFormat::Rgb565, Resolution::Qvga, &mut NoopDelay)
+            .unwrap();
+        let mut yuv422 = Ov2640::new(FakeOv2640::new());
+        yuv422
+            .init(OutputFormat::Yuv422, Resolution::Qvga, &mut NoopDelay)
+            .unwrap();
+        let (rgb565, yuv422) = (rgb565.release(), yuv422.release());
+        assert_eq!(rgb565.register(Register::dsp(0xDA)), 0x08);
+        assert_eq!(yuv422.register(Register::dsp(0xDA)), 0x00);
+        for address in (0..=0xFF).filter(|&address| address != 0xDA) {
+            for reg in [Register::dsp(address), Register::sensor(address)] {
+                assert_eq!(rgb565.register(reg), yuv422.register(reg));
+            }
+        }
+    }
+
+    #[test]
+    fn mounting_only_changes_the_readout_bits() {
+        let mut sensor = Ov2640::new(FakeOv2640::new());
+        sensor
+            .init(OutputFormat::Rgb565, Resolution::Qvga, &mut NoopDelay)
+            .unwrap();
+        sensor.set_mounting(Mounting::UPRIGHT).unwrap();
+        assert_eq!(sensor.read(REG04), Ok(0x28));
+        sensor.set_mounting(Mounting::UPSIDE_DOWN).unwrap();
+        assert_eq!(sensor.read(REG04), Ok(0xF8));
+        let flip = Mounting {
+            mirror: false,
+            flip: true,
+        };
+        sensor.set_mounting(flip).unwrap();
+        assert_eq!(sensor.read(REG04), Ok(0x78));
+    }
+}
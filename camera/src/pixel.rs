@@ -0,0 +1,334 @@
+//! Pixel format conversions, in fixed point.
+//!
+//! RGB565 pixels are read from the FIFO big endian, RGB888 pixels are in red,
+//! green, blue order and YUV pixels use the full range of the bytes, as output
+//! by the OV2640.
+//!
+//! ```ignore
+//! const GRAY: Lut = Lut::new(BT601);
+//! GRAY.rgb565_to_gray_slice(&fifo_bytes, &mut gray);
+//! ```
+
+/// Weights of the red, green and blue channels in the luma, in 8.8 fixed
+/// point summing to 256.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Weights {
+    r: u16,
+    g: u16,
+    b: u16,
+}
+
+impl Weights {
+    /// Weights of the red, green and blue channels, `None` unless they sum
+    /// to 256, so that white stays white and the products fit in 16 bits.
+    pub const fn new(r: u16, g: u16, b: u16) -> Option<Self> {
+        if r as u32 + g as u32 + b as u32 == 256 {
+            Some(Self { r, g, b })
+        } else {
+            None
+        }
+    }
+}
+
+/// ITU-R BT.601, the weights of standard definition video and JPEG.
+pub const BT601: Weights = Weights::new(77, 150, 29).unwrap();
+
+/// ITU-R BT.709, the weights of high definition video and sRGB.
+pub const BT709: Weights = Weights::new(54, 183, 19).unwrap();
+
+/// Expands a channel of `bits` bits to 8 bits by replicating its high bits,
+/// so that 0 and the maximum map to 0 and 255.
+const fn expand(value: u16, bits: u32) -> u8 {
+    let value = value << (8 - bits);
+    (value | value >> bits) as u8
+}
+
+/// Splits an RGB565 pixel into its red, green and blue bytes.
+pub const fn rgb565_to_rgb888(pixel: u16) -> [u8; 3] {
+    [
+        expand(pixel >> 11, 5),
+        expand((pixel >> 5) & 0x3F, 6),
+        expand(pixel & 0x1F, 5),
+    ]
+}
+
+/// Packs red, green and blue bytes into an RGB565 pixel, truncating them.
+pub const fn rgb888_to_rgb565([r, g, b]: [u8; 3]) -> u16 {
+    (r as u16 >> 3) << 11 | (g as u16 >> 2) << 5 | b as u16 >> 3
+}
+
+/// Luma of red, green and blue bytes, rounded.
+pub const fn rgb888_to_gray([r, g, b]: [u8; 3], weights: Weights) -> u8 {
+    let y = weights.r as u32 * r as u32 + weights.g as u32 * g as u32 + weights.b as u32 * b as u32;
+    ((y + 128) >> 8) as u8
+}
+
+/// Luma of an RGB565 pixel.
+pub const fn rgb565_to_gray(pixel: u16, weights: Weights) -> u8 {
+    rgb888_to_gray(rgb565_to_rgb888(pixel), weights)
+}
+
+/// Red, green and blue bytes of a YUV pixel, with the BT.601 full range
+/// equations of JPEG.
+pub const fn yuv_to_rgb888(y: u8, u: u8, v: u8) -> [u8; 3] {
+    let y = (y as i32) << 8;
+    let u = u as i32 - 128;
+    let v = v as i32 - 128;
+    [
+        clamp(y + 359 * v),
+        clamp(y - 88 * u - 183 * v),
+        clamp(y + 454 * u),
+    ]
+}
+
+/// Y, Cb and Cr bytes of red, green and blue bytes, with the BT.601 full
+/// range equations of JPEG.
+pub const fn rgb888_to_yuv(rgb: [u8; 3]) -> [u8; 3] {
+    let [r, g, b] = rgb;
+    let (r, g, b) = (r as i32, g as i32, b as i32);
+    [
+        rgb888_to_gray(rgb, BT601),
+        clamp(-43 * r - 85 * g + 128 * b + (128 << 8)),
+        clamp(128 * r - 107 * g - 21 * b + (128 << 8)),
+    ]
+}
+
+/// Rounds an 8.8 fixed point value to a byte, saturating.
+const fn clamp(value: i32) -> u8 {
+    let value = (value + 128) >> 8;
+    if value < 0 {
+        0
+    } else if value > 255 {
+        255
+    } else {
+        value as u8
+    }
+}
+
+/// Products of the weights with every value of the RGB565 channels, 256 bytes
+/// instead of the 64 KB of a table indexed by whole pixels.
+///
+/// Gives the same luma as [`rgb565_to_gray`] with one addition per channel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lut {
+    r: [u16; 32],
+    g: [u16; 64],
+    b: [u16; 32],
+}
+
+impl Lut {
+    pub const fn new(weights: Weights) -> Self {
+        let mut lut = Self {
+            r: [0; 32],
+            g: [0; 64],
+            b: [0; 32],
+        };
+        let mut i = 0;
+        while i < 64 {
+            if i < 32 {
+                lut.r[i] = weights.r * expand(i as u16, 5) as u16;
+                lut.b[i] = weights.b * expand(i as u16, 5) as u16;
+            }
+            lut.g[i] = weights.g * expand(i as u16, 6) as u16;
+            i += 1;
+        }
+        lut
+    }
+
+    /// Luma of an RGB565 pixel.
+    pub fn gray(&self, pixel: u16) -> u8 {
+        let y = self.r[(pixel >> 11) as usize] as u32
+            + self.g[((pixel >> 5) & 0x3F) as usize] as u32
+            + self.b[(pixel & 0x1F) as usize] as u32;
+        ((y + 128) >> 8) as u8
+    }
+
+    /// Converts big endian RGB565 pixels to luma bytes, as many as fit `dst`.
+    pub fn rgb565_to_gray_slice(&self, src: &[u8], dst: &mut [u8]) {
+        for (gray, pixel) in dst.iter_mut().zip(src.chunks_exact(2)) {
+            *gray = self.gray(u16::from_be_bytes([pixel[0], pixel[1]]));
+        }
+    }
+}
+
+/// Converts RGB888 pixels to luma bytes, as many as fit `dst`.
+pub fn rgb888_to_gray_slice(src: &[u8], dst: &mut [u8], weights: Weights) {
+    for (gray, pixel) in dst.iter_mut().zip(src.chunks_exact(3)) {
+        *gray = rgb888_to_gray([pixel[0], pixel[1], pixel[2]], weights);
+    }
+}
+
+/// Extracts the luma of YUV422 pixels, skipping the chroma bytes.
+pub fn yuv422_to_gray_slice(src: &[u8], dst: &mut [u8]) {
+    for (gray, &y) in dst.iter_mut().zip(src.iter().step_by(2)) {
+        *gray = y;
+    }
+}
+
+/// Swaps big endian RGB565 pixels to little endian.
+pub fn rgb565_swap_slice(src: &[u8], dst: &mut [u8]) {
+    for (out, pixel) in dst.chunks_exact_mut(2).zip(src.chunks_exact(2)) {
+        out.copy_from_slice(&[pixel[1], pixel[0]]);
+    }
+}
+
+/// Converts big endian RGB565 pixels to blue, green and red bytes, the order
+/// of BMP images.
+pub fn rgb565_to_bgr888_slice(src: &[u8], dst: &mut [u8]) {
+    for (out, pixel) in dst.chunks_exact_mut(3).zip(src.chunks_exact(2)) {
+        let [r, g, b] = rgb565_to_rgb888(u16::from_be_bytes([pixel[0], pixel[1]]));
+        out.copy_from_slice(&[b, g, r]);
+    }
+}
+
+/// Converts YUV422 pixel pairs, Y0 U Y1 V, to little endian RGB565 pixels.
+pub fn yuv422_to_rgb565_slice(src: &[u8], dst: &mut [u8]) {
+    for (out, pair) in dst.chunks_exact_mut(4).zip(src.chunks_exact(4)) {
+        let (u, v) = (pair[1], pair[3]);
+        for (out, y) in out.chunks_exact_mut(2).zip([pair[0], pair[2]]) {
+            out.copy_from_slice(&rgb888_to_rgb565(yuv_to_rgb888(y, u, v)).to_le_bytes());
+        }
+    }
+}
+
+/// Converts YUV422 pixel pairs, Y0 U Y1 V, to blue, green and red bytes.
+pub fn yuv422_to_bgr888_slice(src: &[u8], dst: &mut [u8]) {
+    for (out, pair) in dst.chunks_exact_mut(6).zip(src.chunks_exact(4)) {
+        let (u, v) = (pair[1], pair[3]);
+        for (out, y) in out.chunks_exact_mut(3).zip([pair[0], pair[2]]) {
+            let [r, g, b] = yuv_to_rgb888(y, u, v);
+            out.copy_from_slice(&[b, g, r]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn all_rgb565() -> impl Iterator<Item = u16> {
+        0..=u16::MAX
+    }
+
+    /// Luma computed in floating point from the exact channel values.
+    fn reference_gray(pixel: u16, weights: [f64; 3]) -> f64 {
+        let r = (pixel >> 11) as f64 * 255.0 / 31.0;
+        let g = ((pixel >> 5) & 0x3F) as f64 * 255.0 / 63.0;
+        let b = (pixel & 0x1F) as f64 * 255.0 / 31.0;
+        weights[0] * r + weights[1] * g + weights[2] * b
+    }
+
+    #[test]
+    fn lut_matches_the_direct_conversion_for_every_pixel() {
+        for weights in [BT601, BT709] {
+            let lut = Lut::new(weights);
+            for pixel in all_rgb565() {
+                assert_eq!(
+                    lut.gray(pixel),
+                    rgb565_to_gray(pixel, weights),
+                    "{pixel:#06X}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn gray_is_close_to_the_exact_luma_for_every_pixel() {
+        let standards = [
+            (BT601, [0.299, 0.587, 0.114]),
+            (BT709, [0.2126, 0.7152, 0.0722]),
+        ];
+        for (weights, exact) in standards {
+            for pixel in all_rgb565() {
+                let error = rgb565_to_gray(pixel, weights) as f64 - reference_gray(pixel, exact);
+                // Rounding of the weights, of the expanded channels and of the
+                // luma
+                assert!(error.abs() < 1.5, "{pixel:#06X}: {error}");
+            }
+        }
+    }
+
+    #[test]
+    fn rgb565_round_trips_through_rgb888() {
+        for pixel in all_rgb565() {
+            assert_eq!(rgb888_to_rgb565(rgb565_to_rgb888(pixel)), pixel);
+        }
+        assert_eq!(rgb565_to_rgb888(0xFFFF), [255, 255, 255]);
+        assert_eq!(rgb565_to_rgb888(0xF800), [255, 0, 0]);
+    }
+
+    #[test]
+    fn weights_keep_white_white() {
+        for weights in [BT601, BT709] {
+            assert_eq!(weights.r + weights.g + weights.b, 256);
+            assert_eq!(rgb888_to_gray([255, 255, 255], weights), 255);
+            assert_eq!(rgb888_to_gray([0, 0, 0], weights), 0);
+        }
+    }
+
+    #[test]
+    fn weights_must_sum_to_256() {
+        assert_eq!(Weights::new(300, 0, 0), None);
+        assert_eq!(Weights::new(77, 150, 30), None);
+        assert_eq!(Weights::new(u16::MAX, 1, 1), None);
+        // All of the luma from one channel
+        let red = Weights::new(256, 0, 0).unwrap();
+        let lut = Lut::new(red);
+        assert_eq!(lut.gray(0xF800), 255);
+        assert_eq!(lut.gray(0x07FF), 0);
+    }
+
+    #[test]
+    fn gray_yuv_is_gray_rgb() {
+        for y in 0..=255 {
+            assert_eq!(yuv_to_rgb888(y, 128, 128), [y, y, y]);
+        }
+        assert_eq!(yuv_to_rgb888(255, 0, 255), [255, 208, 28]);
+        assert_eq!(yuv_to_rgb888(0, 255, 0), [0, 48, 225]);
+    }
+
+    #[test]
+    fn yuv_round_trips_through_rgb888() {
+        for pixel in all_rgb565().step_by(7) {
+            let rgb = rgb565_to_rgb888(pixel);
+            let [y, u, v] = rgb888_to_yuv(rgb);
+            for (back, channel) in yuv_to_rgb888(y, u, v).into_iter().zip(rgb) {
+                assert!(back.abs_diff(channel) <= 2, "{pixel:#06X}");
+            }
+        }
+    }
+
+    #[test]
+    fn slices_convert_every_pixel() {
+        let lut = Lut::new(BT601);
+        let rgb565 = [0xF8, 0x00, 0x07, 0xE0, 0x00, 0x1F];
+        let mut gray = [0; 3];
+        lut.rgb565_to_gray_slice(&rgb565, &mut gray);
+        assert_eq!(gray, [77, 149, 29]);
+
+        let mut bgr = [0; 9];
+        rgb565_to_bgr888_slice(&rgb565, &mut bgr);
+        assert_eq!(bgr, [0, 0, 255, 0, 255, 0, 255, 0, 0]);
+
+        let mut swapped = [0; 6];
+        rgb565_swap_slice(&rgb565, &mut swapped);
+        assert_eq!(swapped, [0x00, 0xF8, 0xE0, 0x07, 0x1F, 0x00]);
+
+        let mut gray = [0; 3];
+        rgb888_to_gray_slice(&[255, 0, 0, 0, 255, 0, 0, 0, 255], &mut gray, BT709);
+        assert_eq!(gray, [54, 182, 19]);
+
+        let yuv = [10, 128, 20, 128, 30, 128, 40, 128];
+        let mut gray = [0; 4];
+        yuv422_to_gray_slice(&yuv, &mut gray);
+        assert_eq!(gray, [10, 20, 30, 40]);
+
+        let mut bgr = [0; 12];
+        yuv422_to_bgr888_slice(&yuv, &mut bgr);
+        assert_eq!(bgr, [10, 10, 10, 20, 20, 20, 30, 30, 30, 40, 40, 40]);
+
+        let mut rgb565 = [0; 8];
+        yuv422_to_rgb565_slice(&[255, 128, 0, 128, 0, 0, 0, 0], &mut rgb565);
+        assert_eq!(rgb565[..4], [0xFF, 0xFF, 0x00, 0x00]);
+    }
+}
@@ -0,0 +1,454 @@
+//! PNG encoder, with the pixels stored or lightly deflated, for the clients
+//! wanting lossless frames without BMP.
+//!
+//! The image is a single IDAT chunk holding a zlib stream written as the
+//! rows come, whose length and CRC are filled in at the end. The light
+//! compression applies the Sub filter and codes the runs of equal bytes with
+//! the fixed Huffman codes of deflate, which shrinks the flat areas of the
+//! frames without the memory of a dictionary search.
+
+use crate::image::{self, Color, Error, Layout};
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+/// Length, type and CRC of a chunk, around its data.
+const CHUNK_OVERHEAD: usize = 12;
+const IHDR_LENGTH: usize = 13;
+/// Header and Adler-32 checksum of the zlib stream.
+const ZLIB_OVERHEAD: usize = 2 + 4;
+/// Header of a stored deflate block: its type, length and inverted length.
+const STORED_BLOCK_HEADER: usize = 5;
+
+/// Filter type of the rows.
+const FILTER_NONE: u8 = 0;
+const FILTER_SUB: u8 = 1;
+
+/// Shortest and longest matches of deflate.
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+/// Shortest match of each length code, from 257.
+const LENGTH_BASES: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+/// Extra bits of each length code, from 257.
+const LENGTH_EXTRA_BITS: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const END_OF_BLOCK: u16 = 256;
+
+/// How the pixels are stored in the zlib stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Compression {
+    /// Uncompressed, in a stored block per row.
+    Stored,
+    /// Sub filter and runs coded with the fixed Huffman codes.
+    Fixed,
+}
+
+impl Compression {
+    /// Reads the compression from its name, `stored` or `fixed`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "stored" => Some(Self::Stored),
+            "fixed" => Some(Self::Fixed),
+            _ => None,
+        }
+    }
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Stored => "stored",
+            Self::Fixed => "fixed",
+        }
+    }
+}
+
+/// Encoder of the frames into PNG files, through the [`image::Encoder`]
+/// interface.
+pub struct Encoder {
+    layout: Layout,
+    compression: Compression,
+    /// Offset of the IDAT chunk.
+    idat: usize,
+    /// Rows already written.
+    rows: u16,
+    adler: Adler32,
+    /// Bits not written yet, in the low `bit_count` bits.
+    bits: u32,
+    bit_count: u32,
+    /// Last byte given to the deflate stream, and how many times it was
+    /// repeated since it was coded.
+    previous: Option<u8>,
+    run: usize,
+    /// Bytes written to the output.
+    length: usize,
+}
+
+impl Encoder {
+    pub fn new(layout: Layout, compression: Compression) -> Self {
+        Self {
+            layout,
+            compression,
+            idat: 0,
+            rows: 0,
+            adler: Adler32::new(),
+            bits: 0,
+            bit_count: 0,
+            previous: None,
+            run: 0,
+            length: 0,
+        }
+    }
+
+    /// Length of a row in the zlib stream, with its filter type.
+    const fn filtered_row_length(&self) -> usize {
+        1 + self.layout.width as usize * self.layout.channels()
+    }
+
+    fn write(&mut self, out: &mut [u8], bytes: &[u8]) -> Result<(), Error> {
+        image::append(out, &mut self.length, bytes)
+    }
+
+    fn write_chunk(&mut self, out: &mut [u8], kind: &[u8; 4], data: &[u8]) -> Result<(), Error> {
+        let start = self.length;
+        self.write(out, &(data.len() as u32).to_be_bytes())?;
+        self.write(out, kind)?;
+        self.write(out, data)?;
+        let crc = crc32(&out[start + 4..self.length]);
+        self.write(out, &crc.to_be_bytes())
+    }
+
+    /// Writes a row in a stored block, the last one ending the stream.
+    fn store_row(&mut self, row: &[u8], out: &mut [u8]) -> Result<(), Error> {
+        let layout = self.layout;
+        let length = self.filtered_row_length();
+        let last = self.rows + 1 == layout.height;
+        self.write(out, &[last as u8])?;
+        self.write(out, &(length as u16).to_le_bytes())?;
+        self.write(out, &(!(length as u16)).to_le_bytes())?;
+
+        let start = self.length;
+        let end = start + length;
+        let dst = out.get_mut(start..end).ok_or(Error::BufferFull)?;
+        dst[0] = FILTER_NONE;
+        let channels = layout.channels();
+        for (x, pixel) in dst[1..].chunks_exact_mut(channels).enumerate() {
+            pixel.copy_from_slice(&layout.pixel(row, x)[..channels]);
+        }
+        self.adler.update(dst);
+        self.length = end;
+        Ok(())
+    }
+
+    /// Filters a row with Sub and deflates it.
+    fn deflate_row(&mut self, row: &[u8], out: &mut [u8]) -> Result<(), Error> {
+        let layout = self.layout;
+        let channels = layout.channels();
+        self.put_byte(FILTER_SUB, out)?;
+        let mut left = [0u8; 3];
+        for x in 0..layout.width as usize {
+            let pixel = layout.pixel(row, x);
+            for c in 0..channels {
+                self.put_byte(pixel[c].wrapping_sub(left[c]), out)?;
+            }
+            left = pixel;
+        }
+        Ok(())
+    }
+
+    /// Adds a byte to the deflate stream, extending the run of the previous
+    /// byte if it is the same.
+    fn put_byte(&mut self, byte: u8, out: &mut [u8]) -> Result<(), Error> {
+        self.adler.update(&[byte]);
+        if self.previous == Some(byte) {
+            self.run += 1;
+            if self.run == MAX_MATCH {
+                self.flush_run(out)?;
+            }
+            return Ok(());
+        }
+        self.flush_run(out)?;
+        self.put_symbol(byte as u16, out)?;
+        self.previous = Some(byte);
+        Ok(())
+    }
+
+    /// Codes the repeats of the previous byte, as a match at a distance of 1
+    /// if it is long enough.
+    fn flush_run(&mut self, out: &mut [u8]) -> Result<(), Error> {
+        let run = core::mem::take(&mut self.run);
+        if run >= MIN_MATCH {
+            let code = LENGTH_BASES
+                .iter()
+                .rposition(|&base| base as usize <= run)
+                .unwrap();
+            self.put_symbol(257 + code as u16, out)?;
+            let extra = (run - LENGTH_BASES[code] as usize) as u32;
+            self.put_bits(extra, LENGTH_EXTRA_BITS[code] as u32, out)?;
+            // Distance code 0, a distance of 1
+            self.put_bits(0, 5, out)?;
+        } else if let Some(byte) = self.previous {
+            for _ in 0..run {
+                self.put_symbol(byte as u16, out)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the fixed Huffman code of a literal or length symbol.
+    fn put_symbol(&mut self, symbol: u16, out: &mut [u8]) -> Result<(), Error> {
+        let (code, length) = match symbol {
+            0..=143 => (0x30 + symbol, 8),
+            144..=255 => (0x190 + symbol - 144, 9),
+            256..=279 => (symbol - 256, 7),
+            _ => (0xC0 + symbol - 280, 8),
+        };
+        // Huffman codes are packed starting with their most significant bit
+        let reversed = (code as u32).reverse_bits() >> (32 - length);
+        self.put_bits(reversed, length, out)
+    }
+
+    /// Appends the low `count` bits of `bits`, least significant first.
+    fn put_bits(&mut self, bits: u32, count: u32, out: &mut [u8]) -> Result<(), Error> {
+        self.bits |= bits << self.bit_count;
+        self.bit_count += count;
+        while self.bit_count >= 8 {
+            self.write(out, &[self.bits as u8])?;
+            self.bits >>= 8;
+            self.bit_count -= 8;
+        }
+        Ok(())
+    }
+}
+
+impl image::Encoder for Encoder {
+    fn content_type(&self) -> &'static str {
+        "image/png"
+    }
+
+    fn max_length(&self) -> usize {
+        let rows = self.layout.height as usize;
+        let data = rows * self.filtered_row_length();
+        let deflated = match self.compression {
+            Compression::Stored => data + rows * STORED_BLOCK_HEADER,
+            // 9 bits per literal at most, and the block header and end
+            Compression::Fixed => (data * 9 + 3 + 7).div_ceil(8),
+        };
+        SIGNATURE.len()
+            + CHUNK_OVERHEAD
+            + IHDR_LENGTH
+            + CHUNK_OVERHEAD
+            + ZLIB_OVERHEAD
+            + deflated
+            + CHUNK_OVERHEAD
+    }
+
+    fn start(&mut self, out: &mut [u8]) -> Result<(), Error> {
+        *self = Self::new(self.layout, self.compression);
+        self.write(out, &SIGNATURE)?;
+
+        let color_type: u8 = match self.layout.color {
+            Color::Gray => 0,
+            Color::Rgb565 | Color::Rgb888 => 2,
+        };
+        let mut header = [0u8; IHDR_LENGTH];
+        header[0..4].copy_from_slice(&(self.layout.width as u32).to_be_bytes());
+        header[4..8].copy_from_slice(&(self.layout.height as u32).to_be_bytes());
+        // 8-bit samples, deflate, adaptive filtering, not interlaced
+        header[8..].copy_from_slice(&[8, color_type, 0, 0, 0]);
+        self.write_chunk(out, b"IHDR", &header)?;
+
+        // The length is filled in when the stream ends
+        self.idat = self.length;
+        self.write(out, &[0; 4])?;
+        self.write(out, b"IDAT")?;
+        // Deflate with a 256-byte window, no dictionary, fastest
+        self.write(out, &[0x08, 0x1D])?;
+        if self.compression == Compression::Fixed {
+            // Last block, fixed Huffman codes
+            self.put_bits(0b011, 3, out)?;
+        }
+        Ok(())
+    }
+
+    fn write_rows(&mut self, rows: &[u8], out: &mut [u8]) -> Result<(), Error> {
+        for row in self.layout.rows(rows) {
+            assert!(self.rows < self.layout.height, "too many rows");
+            match self.compression {
+                Compression::Stored => self.store_row(row, out)?,
+                Compression::Fixed => self.deflate_row(row, out)?,
+            }
+            self.rows += 1;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self, out: &mut [u8]) -> Result<usize, Error> {
+        if self.compression == Compression::Fixed {
+            self.flush_run(out)?;
+            self.put_symbol(END_OF_BLOCK, out)?;
+            self.put_bits(0, 7, out)?;
+            self.bit_count = 0;
+        }
+        let adler = self.adler.value();
+        self.write(out, &adler.to_be_bytes())?;
+
+        let data_length = self.length - self.idat - 8;
+        out[self.idat..self.idat + 4].copy_from_slice(&(data_length as u32).to_be_bytes());
+        let crc = crc32(&out[self.idat + 4..self.length]);
+        self.write(out, &crc.to_be_bytes())?;
+        self.write_chunk(out, b"IEND", &[])?;
+        Ok(self.length)
+    }
+}
+
+/// Checksum of the uncompressed data of a zlib stream.
+struct Adler32 {
+    a: u32,
+    b: u32,
+}
+
+impl Adler32 {
+    const MODULUS: u32 = 65521;
+
+    const fn new() -> Self {
+        Self { a: 1, b: 0 }
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        // The sums stay below 2^32 over 5552 bytes
+        for chunk in bytes.chunks(5552) {
+            for &byte in chunk {
+                self.a += byte as u32;
+                self.b += self.a;
+            }
+            self.a %= Self::MODULUS;
+            self.b %= Self::MODULUS;
+        }
+    }
+
+    const fn value(&self) -> u32 {
+        (self.b << 16) | self.a
+    }
+}
+
+/// CRC-32 of the chunks, over their type and data.
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0, |crc, &byte| {
+        CRC_TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+static CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut crc = n as u32;
+        let mut k = 0;
+        while k < 8 {
+            crc = if crc & 1 != 0 {
+                0xEDB88320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            k += 1;
+        }
+        table[n] = crc;
+        n += 1;
+    }
+    table
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::{Encoder as _, Input};
+    use crate::pixel;
+
+    fn encode(layout: Layout, compression: Compression, pixels: &[u8]) -> Vec<u8> {
+        let mut encoder = Encoder::new(layout, compression);
+        let mut out = vec![0; encoder.max_length()];
+        encoder.start(&mut out).unwrap();
+        // A few rows at a time, the way they are read from the FIFO
+        for rows in pixels.chunks(3 * layout.row_length()) {
+            encoder.write_rows(rows, &mut out).unwrap();
+        }
+        let length = encoder.finish(&mut out).unwrap();
+        out.truncate(length);
+        out
+    }
+
+    fn decode(image: &[u8]) -> (::png::OutputInfo, Vec<u8>) {
+        let mut reader = ::png::Decoder::new(image).read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+        pixels.truncate(info.buffer_size());
+        (info, pixels)
+    }
+
+    /// Deterministic noise with flat areas, to exercise the literals and the
+    /// runs of every length.
+    fn frame(length: usize) -> Vec<u8> {
+        let mut state = 0x12345678u32;
+        let mut pixels = Vec::new();
+        while pixels.len() < length {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            let run = match state % 4 {
+                0 => 300 + state as usize % 300,
+                1 => (state >> 8) as usize % 8,
+                _ => 1,
+            };
+            pixels.extend(core::iter::repeat_n((state >> 16) as u8, run.max(1)));
+        }
+        pixels.truncate(length);
+        pixels
+    }
+
+    #[test]
+    fn grayscale_images_decode_to_the_input() {
+        let layout = Layout::new(61, 37, Input::Gray8, Color::Gray);
+        let pixels = frame(61 * 37);
+        for compression in [Compression::Stored, Compression::Fixed] {
+            let image = encode(layout, compression, &pixels);
+            let (info, decoded) = decode(&image);
+            assert_eq!((info.width, info.height), (61, 37));
+            assert_eq!(info.color_type, ::png::ColorType::Grayscale);
+            assert_eq!(decoded, pixels);
+        }
+    }
+
+    #[test]
+    fn colour_images_decode_to_the_input() {
+        let layout = Layout::new(40, 30, Input::Rgb565, Color::Rgb565);
+        let rgb565 = frame(40 * 30 * 2);
+        let rgb888: Vec<u8> = rgb565
+            .chunks(2)
+            .flat_map(|pixel| pixel::rgb565_to_rgb888(u16::from_be_bytes([pixel[0], pixel[1]])))
+            .collect();
+        for compression in [Compression::Stored, Compression::Fixed] {
+            let (info, decoded) = decode(&encode(layout, compression, &rgb565));
+            assert_eq!(info.color_type, ::png::ColorType::Rgb);
+            assert_eq!(decoded, rgb888);
+        }
+    }
+
+    #[test]
+    fn flat_images_are_compressed() {
+        let layout = Layout::new(320, 240, Input::Gray8, Color::Gray);
+        let pixels = vec![128; 320 * 240];
+        let stored = encode(layout, Compression::Stored, &pixels);
+        let fixed = encode(layout, Compression::Fixed, &pixels);
+        assert!(fixed.len() < stored.len() / 40);
+        assert_eq!(decode(&fixed).1, pixels);
+    }
+
+    #[test]
+    fn checksums_match_the_reference_values() {
+        assert_eq!(crc32(b"IEND"), 0xAE426082);
+        let mut adler = Adler32::new();
+        adler.update(b"Wikipedia");
+        assert_eq!(adler.value(), 0x11E60398);
+    }
+}
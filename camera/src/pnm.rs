@@ -0,0 +1,130 @@
+//! PGM and PPM encoder, the binary Netpbm formats, for the clients reading
+//! raw pixels.
+//!
+//! The grayscale images are written as PGM (`P5`) and the colour ones as PPM
+//! (`P6`), with a maximum value of 255.
+
+use crate::image::{self, Color, Error, Layout};
+
+/// Longest header: the magic number, two 5-digit sizes and the maximum value,
+/// each followed by a whitespace.
+const MAX_HEADER_LENGTH: usize = 3 + 2 * 6 + 4;
+
+/// Encoder of the frames into PGM or PPM files, through the
+/// [`image::Encoder`] interface.
+pub struct Encoder {
+    layout: Layout,
+    /// Bytes written to the output.
+    length: usize,
+}
+
+impl Encoder {
+    pub fn new(layout: Layout) -> Self {
+        Self { layout, length: 0 }
+    }
+}
+
+impl image::Encoder for Encoder {
+    fn content_type(&self) -> &'static str {
+        match self.layout.color {
+            Color::Gray => "image/x-portable-graymap",
+            Color::Rgb565 | Color::Rgb888 => "image/x-portable-pixmap",
+        }
+    }
+
+    fn max_length(&self) -> usize {
+        MAX_HEADER_LENGTH + self.layout.pixels() * self.layout.channels()
+    }
+
+    fn start(&mut self, out: &mut [u8]) -> Result<(), Error> {
+        self.length = 0;
+        let magic = match self.layout.color {
+            Color::Gray => b"P5\n",
+            Color::Rgb565 | Color::Rgb888 => b"P6\n",
+        };
+        image::append(out, &mut self.length, magic)?;
+        write_decimal(out, &mut self.length, self.layout.width, b' ')?;
+        write_decimal(out, &mut self.length, self.layout.height, b'\n')?;
+        image::append(out, &mut self.length, b"255\n")
+    }
+
+    fn write_rows(&mut self, rows: &[u8], out: &mut [u8]) -> Result<(), Error> {
+        let layout = self.layout;
+        let channels = layout.channels();
+        for row in layout.rows(rows) {
+            let end = self.length + layout.width as usize * channels;
+            let dst = out.get_mut(self.length..end).ok_or(Error::BufferFull)?;
+            for (x, pixel) in dst.chunks_exact_mut(channels).enumerate() {
+                pixel.copy_from_slice(&layout.pixel(row, x)[..channels]);
+            }
+            self.length = end;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self, _out: &mut [u8]) -> Result<usize, Error> {
+        Ok(self.length)
+    }
+}
+
+/// Appends `value` in decimal followed by `separator`.
+fn write_decimal(
+    out: &mut [u8],
+    length: &mut usize,
+    value: u16,
+    separator: u8,
+) -> Result<(), Error> {
+    let mut digits = [0u8; 6];
+    let mut start = digits.len() - 1;
+    digits[start] = separator;
+    let mut value = value;
+    loop {
+        start -= 1;
+        digits[start] = b'0' + (value % 10) as u8;
+        value /= 10;
+        if value == 0 {
+            break;
+        }
+    }
+    image::append(out, length, &digits[start..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::{Encoder as _, Input};
+
+    fn encode(layout: Layout, pixels: &[u8]) -> Vec<u8> {
+        let mut encoder = Encoder::new(layout);
+        let mut out = vec![0; encoder.max_length()];
+        encoder.start(&mut out).unwrap();
+        encoder.write_rows(pixels, &mut out).unwrap();
+        let length = encoder.finish(&mut out).unwrap();
+        out.truncate(length);
+        out
+    }
+
+    #[test]
+    fn grayscale_images_are_pgm() {
+        let yuv = [10, 128, 20, 128, 30, 128, 40, 128, 50, 128, 60, 128];
+        let image = encode(Layout::new(3, 2, Input::Yuv422, Color::Gray), &yuv);
+        assert_eq!(image, b"P5\n3 2\n255\n\x0a\x14\x1e\x28\x32\x3c");
+    }
+
+    #[test]
+    fn colour_images_are_ppm() {
+        let rgb565 = [0xF8, 0x00, 0x07, 0xE0];
+        let image = encode(Layout::new(2, 1, Input::Rgb565, Color::Rgb565), &rgb565);
+        assert_eq!(image, b"P6\n2 1\n255\n\xff\x00\x00\x00\xff\x00");
+    }
+
+    #[test]
+    fn header_fits_the_largest_sizes() {
+        let layout = Layout::new(u16::MAX, u16::MAX, Input::Gray8, Color::Rgb888);
+        let mut encoder = Encoder::new(layout);
+        let mut out = [0; MAX_HEADER_LENGTH];
+        encoder.start(&mut out).unwrap();
+        assert_eq!(&out, b"P6\n65535 65535\n255\n");
+        assert_eq!(encoder.start(&mut out[..10]), Err(Error::BufferFull));
+    }
+}
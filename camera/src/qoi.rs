@@ -0,0 +1,220 @@
+//! QOI encoder, a lossless format about as small as PNG that is decoded much
+//! faster.
+//!
+//! Follows the specification 1.0 of <https://qoiformat.org>. The images
+//! always have 3 channels, the grayscale ones are coded with equal red,
+//! green and blue, which the differences code in a byte.
+
+use crate::image::{self, Error, Layout};
+
+const HEADER_LENGTH: usize = 14;
+const END_MARKER: [u8; 8] = [0, 0, 0, 0, 0, 0, 0, 1];
+
+const OP_INDEX: u8 = 0x00;
+const OP_DIFF: u8 = 0x40;
+const OP_LUMA: u8 = 0x80;
+const OP_RUN: u8 = 0xC0;
+const OP_RGB: u8 = 0xFE;
+/// Longest run of an [`OP_RUN`], whose values 63 and 64 are the RGB and RGBA
+/// operations.
+const MAX_RUN: u8 = 62;
+
+/// Encoder of the frames into QOI files, through the [`image::Encoder`]
+/// interface.
+pub struct Encoder {
+    layout: Layout,
+    /// Pixels seen, by their hash, with their alpha so that the initial
+    /// transparent black does not match.
+    index: [[u8; 4]; 64],
+    previous: [u8; 3],
+    /// Pixels equal to the previous one not coded yet.
+    run: u8,
+    /// Bytes written to the output.
+    length: usize,
+}
+
+impl Encoder {
+    pub fn new(layout: Layout) -> Self {
+        Self {
+            layout,
+            index: [[0; 4]; 64],
+            previous: [0; 3],
+            run: 0,
+            length: 0,
+        }
+    }
+
+    fn write(&mut self, out: &mut [u8], bytes: &[u8]) -> Result<(), Error> {
+        image::append(out, &mut self.length, bytes)
+    }
+
+    fn flush_run(&mut self, out: &mut [u8]) -> Result<(), Error> {
+        if self.run > 0 {
+            self.write(out, &[OP_RUN | (self.run - 1)])?;
+            self.run = 0;
+        }
+        Ok(())
+    }
+
+    fn put_pixel(&mut self, pixel: [u8; 3], out: &mut [u8]) -> Result<(), Error> {
+        if pixel == self.previous {
+            self.run += 1;
+            if self.run == MAX_RUN {
+                self.flush_run(out)?;
+            }
+            return Ok(());
+        }
+        self.flush_run(out)?;
+
+        let [r, g, b] = pixel;
+        // The images are opaque
+        let rgba = [r, g, b, 255];
+        let hash = (r as usize * 3 + g as usize * 5 + b as usize * 7 + 255 * 11) % 64;
+        if self.index[hash] == rgba {
+            self.write(out, &[OP_INDEX | hash as u8])?;
+        } else {
+            self.index[hash] = rgba;
+            let [pr, pg, pb] = self.previous;
+            let dr = r.wrapping_sub(pr) as i8;
+            let dg = g.wrapping_sub(pg) as i8;
+            let db = b.wrapping_sub(pb) as i8;
+            let dr_dg = dr.wrapping_sub(dg);
+            let db_dg = db.wrapping_sub(dg);
+            if [dr, dg, db].iter().all(|d| (-2..=1).contains(d)) {
+                let diff = ((dr + 2) as u8) << 4 | ((dg + 2) as u8) << 2 | (db + 2) as u8;
+                self.write(out, &[OP_DIFF | diff])?;
+            } else if (-32..=31).contains(&dg)
+                && (-8..=7).contains(&dr_dg)
+                && (-8..=7).contains(&db_dg)
+            {
+                let second = ((dr_dg + 8) as u8) << 4 | (db_dg + 8) as u8;
+                self.write(out, &[OP_LUMA | (dg + 32) as u8, second])?;
+            } else {
+                self.write(out, &[OP_RGB, r, g, b])?;
+            }
+        }
+        self.previous = pixel;
+        Ok(())
+    }
+}
+
+impl image::Encoder for Encoder {
+    fn content_type(&self) -> &'static str {
+        "image/qoi"
+    }
+
+    fn max_length(&self) -> usize {
+        // An RGB operation per pixel at most
+        HEADER_LENGTH + self.layout.pixels() * 4 + END_MARKER.len()
+    }
+
+    fn start(&mut self, out: &mut [u8]) -> Result<(), Error> {
+        *self = Self::new(self.layout);
+        let mut header = [0u8; HEADER_LENGTH];
+        header[..4].copy_from_slice(b"qoif");
+        header[4..8].copy_from_slice(&(self.layout.width as u32).to_be_bytes());
+        header[8..12].copy_from_slice(&(self.layout.height as u32).to_be_bytes());
+        // RGB, sRGB with linear alpha
+        header[12..].copy_from_slice(&[3, 0]);
+        self.write(out, &header)
+    }
+
+    fn write_rows(&mut self, rows: &[u8], out: &mut [u8]) -> Result<(), Error> {
+        let layout = self.layout;
+        for row in layout.rows(rows) {
+            for x in 0..layout.width as usize {
+                let pixel = match layout.channels() {
+                    1 => [layout.pixel(row, x)[0]; 3],
+                    _ => layout.pixel(row, x),
+                };
+                self.put_pixel(pixel, out)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self, out: &mut [u8]) -> Result<usize, Error> {
+        self.flush_run(out)?;
+        self.write(out, &END_MARKER)?;
+        Ok(self.length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::{Color, Encoder as _, Input};
+
+    fn encode(layout: Layout, pixels: &[u8]) -> Vec<u8> {
+        let mut encoder = Encoder::new(layout);
+        let mut out = vec![0; encoder.max_length()];
+        encoder.start(&mut out).unwrap();
+        for row in pixels.chunks(layout.row_length()) {
+            encoder.write_rows(row, &mut out).unwrap();
+        }
+        let length = encoder.finish(&mut out).unwrap();
+        out.truncate(length);
+        out
+    }
+
+    /// Pixels close to their neighbours, repeated or far from them, to
+    /// exercise every operation.
+    fn frame(pixels: usize) -> Vec<u8> {
+        let mut state = 0x12345678u32;
+        let mut rgb = [0u8; 3];
+        let mut frame = Vec::new();
+        for _ in 0..pixels {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            let [a, b, c, d] = state.to_le_bytes();
+            match a % 8 {
+                0 => rgb = [b, c, d],
+                1 => rgb = [b % 4, c % 4, d % 4],
+                2 | 3 => {
+                    let dg = b % 40;
+                    rgb = [
+                        rgb[0].wrapping_add(dg + c % 12),
+                        rgb[1].wrapping_add(dg),
+                        rgb[2].wrapping_add(dg + d % 12),
+                    ]
+                }
+                4 => rgb = [rgb[0].wrapping_add(b % 3), rgb[1], rgb[2].wrapping_sub(1)],
+                _ => {}
+            }
+            frame.extend_from_slice(&rgb);
+        }
+        frame
+    }
+
+    #[test]
+    fn colour_images_decode_to_the_input() {
+        let pixels = frame(97 * 43);
+        let image = encode(Layout::new(97, 43, Input::Rgb888, Color::Rgb888), &pixels);
+        let (header, decoded) = ::qoi::decode_to_vec(&image).unwrap();
+        assert_eq!((header.width, header.height), (97, 43));
+        assert_eq!(header.channels, ::qoi::Channels::Rgb);
+        assert_eq!(decoded, pixels);
+    }
+
+    #[test]
+    fn grayscale_images_have_equal_channels() {
+        let luma: Vec<u8> = (0..64 * 8).map(|i| (i / 3 * 7 % 256) as u8).collect();
+        let image = encode(Layout::new(64, 8, Input::Gray8, Color::Gray), &luma);
+        let (_, decoded) = ::qoi::decode_to_vec(&image).unwrap();
+        let expected: Vec<u8> = luma.iter().flat_map(|&y| [y; 3]).collect();
+        assert_eq!(decoded, expected);
+    }
+
+    #[test]
+    fn long_runs_are_split() {
+        let black = vec![0; 200 * 3];
+        let image = encode(Layout::new(200, 1, Input::Rgb888, Color::Rgb888), &black);
+        // 3 runs of 62 and one of 14, the first pixel equals the initial one
+        assert_eq!(
+            image[HEADER_LENGTH..image.len() - 8],
+            [0xFD, 0xFD, 0xFD, 0xCD]
+        );
+        assert_eq!(::qoi::decode_to_vec(&image).unwrap().1, black);
+    }
+}
@@ -0,0 +1,251 @@
+//! Reset of the ArduCAM, advanced without blocking so that the network stack
+//! keeps being serviced through the delays the ArduChip and the sensor need.
+
+use embedded_hal::digital::OutputPin;
+use embedded_hal::i2c::I2c;
+use embedded_hal::spi::SpiBus;
+
+use crate::arduchip::ArduChip;
+use crate::error::CameraError;
+use crate::ov2640::{self, Ov2640};
+
+/// Time the CPLD of the ArduChip is held in reset, then given to restart.
+const ARDUCHIP_RESET_MS: u64 = 100;
+/// Time the sensor takes to reset its registers.
+const SENSOR_RESET_MS: u64 = 100;
+/// Time the sensor is given to adjust its exposure once configured.
+pub const SETTLE_MS: u64 = 1000;
+/// Time before the first retry of a reset that failed, doubled by each
+/// failure after it.
+const RETRY_MS: u64 = 250;
+/// Longest time between the retries of a camera that keeps failing.
+pub const MAX_RETRY_MS: u64 = 4000;
+
+/// Where the reset is, each step waiting for the delay of the previous one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+enum Step {
+    HoldArduChip,
+    ReleaseArduChip,
+    ResetSensor,
+    ConfigureSensor,
+    Settle,
+}
+
+/// Reset of the ArduChip and the sensor in progress, advanced by
+/// [`poll`](Self::poll) like a [`Capture`](crate::capture::Capture).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Reset {
+    step: Step,
+    /// When the step may run, once the delay of the previous one is over.
+    ready_at: u64,
+    /// Resets that failed before this one.
+    failures: u32,
+}
+
+impl Reset {
+    /// Reset starting at the next poll.
+    pub fn new() -> Self {
+        Self {
+            step: Step::HoldArduChip,
+            ready_at: 0,
+            failures: 0,
+        }
+    }
+
+    /// Reset retrying this one, which failed at `now_ms`, once the time
+    /// between the retries is over.
+    pub fn retry(self, now_ms: u64) -> Self {
+        let failures = self.failures + 1;
+        Self {
+            step: Step::HoldArduChip,
+            ready_at: now_ms + retry_ms(failures),
+            failures,
+        }
+    }
+
+    /// Resets that failed before this one.
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// Runs the next step of the reset once its delay is over, and returns
+    /// whether the camera is ready. The ArduChip and the sensor are checked
+    /// once out of reset, then `configure` sets up the sensor.
+    pub fn poll<SPI: SpiBus, CS: OutputPin, I2C: I2c>(
+        &mut self,
+        arduchip: &mut ArduChip<SPI, CS>,
+        sensor: &mut Ov2640<I2C>,
+        now_ms: u64,
+        configure: impl FnOnce(&mut Ov2640<I2C>) -> Result<(), ov2640::Error>,
+    ) -> Result<bool, CameraError> {
+        if now_ms < self.ready_at {
+            return Ok(false);
+        }
+        let (step, delay_ms) = match self.step {
+            Step::HoldArduChip => {
+                arduchip.hold_reset()?;
+                (Step::ReleaseArduChip, ARDUCHIP_RESET_MS)
+            }
+            Step::ReleaseArduChip => {
+                arduchip.release_reset()?;
+                (Step::ResetSensor, ARDUCHIP_RESET_MS)
+            }
+            Step::ResetSensor => {
+                // SPI and I2C tests
+                arduchip.check_test_register()?;
+                sensor.probe()?;
+                sensor.start_soft_reset()?;
+                (Step::ConfigureSensor, SENSOR_RESET_MS)
+            }
+            Step::ConfigureSensor => {
+                configure(sensor)?;
+                (Step::Settle, SETTLE_MS)
+            }
+            Step::Settle => return Ok(true),
+        };
+        self.step = step;
+        self.ready_at = now_ms + delay_ms;
+        Ok(false)
+    }
+}
+
+/// Time before retrying a reset after `failures` failures, doubled by each
+/// up to [`MAX_RETRY_MS`].
+fn retry_ms(failures: u32) -> u64 {
+    let doublings = failures.saturating_sub(1).min(MAX_RETRY_MS.ilog2());
+    (RETRY_MS << doublings).min(MAX_RETRY_MS)
+}
+
+impl Default for Reset {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arduchip::ARDUCHIP_TEST1;
+    use crate::fake::{FakeArduChip, FakeOv2640};
+    use crate::ov2640::{OV2640_CHIPID_HIGH, OutputFormat, Register, Resolution};
+
+    #[test]
+    fn steps_wait_for_the_delays_without_blocking() {
+        let chip = FakeArduChip::new(&[]);
+        let mut arduchip = ArduChip::new(chip.spi(), chip.cs());
+        let mut sensor = Ov2640::new(FakeOv2640::new());
+        arduchip.write_reg(ARDUCHIP_TEST1, 0x12).unwrap();
+
+        let mut reset = Reset::new();
+        let mut configured = false;
+        let mut ready_at = None;
+        // Polled every 10 ms, like the main loop between network polls
+        for now in (0..2000).step_by(10) {
+            let ready = reset
+                .poll(&mut arduchip, &mut sensor, now, |sensor| {
+                    configured = true;
+                    sensor.configure(OutputFormat::Yuv422, Resolution::Qvga)
+                })
+                .unwrap();
+            if now == 0 {
+                // The CPLD reset cleared the test register
+                assert_eq!(chip.register(ARDUCHIP_TEST1), 0x00);
+            }
+            if ready {
+                ready_at = Some(now);
+                break;
+            }
+        }
+        assert!(configured);
+        assert_eq!(
+            ready_at,
+            Some(2 * ARDUCHIP_RESET_MS + SENSOR_RESET_MS + SETTLE_MS)
+        );
+        // Configured after the soft reset
+        let fake = sensor.release();
+        assert_ne!(fake.register(Register::dsp(0x2C)), 0x00);
+    }
+
+    #[test]
+    fn polls_before_the_delay_do_nothing() {
+        let chip = FakeArduChip::new(&[]);
+        let mut arduchip = ArduChip::new(chip.spi(), chip.cs());
+        let mut sensor = Ov2640::new(FakeOv2640::new());
+
+        let mut reset = Reset::new();
+        let configure = |_: &mut Ov2640<FakeOv2640>| Ok(());
+        assert_eq!(
+            reset.poll(&mut arduchip, &mut sensor, 50, configure),
+            Ok(false)
+        );
+        let held = reset;
+        assert_eq!(
+            reset.poll(&mut arduchip, &mut sensor, 149, configure),
+            Ok(false)
+        );
+        assert_eq!(reset, held);
+        assert_eq!(
+            reset.poll(&mut arduchip, &mut sensor, 150, configure),
+            Ok(false)
+        );
+        assert_ne!(reset, held);
+    }
+
+    #[test]
+    fn a_failed_check_stops_the_reset() {
+        let chip = FakeArduChip::new(&[]);
+        let mut arduchip = ArduChip::new(chip.spi(), chip.cs());
+        let mut sensor = Ov2640::new(FakeOv2640::new());
+        // Not an OV2640
+        sensor.write(OV2640_CHIPID_HIGH, 0x77).unwrap();
+        let configure = |_: &mut Ov2640<FakeOv2640>| Ok(());
+
+        let mut reset = Reset::new();
+        reset
+            .poll(&mut arduchip, &mut sensor, 0, configure)
+            .unwrap();
+        reset
+            .poll(&mut arduchip, &mut sensor, 100, configure)
+            .unwrap();
+        let result = reset.poll(&mut arduchip, &mut sensor, 200, configure);
+        assert!(matches!(
+            result,
+            Err(CameraError::Sensor(ov2640::Error::UnexpectedChipId(_)))
+        ));
+    }
+
+    #[test]
+    fn retries_back_off_up_to_the_maximum() {
+        let mut reset = Reset::new();
+        let mut delays = Vec::new();
+        for _ in 0..7 {
+            reset = reset.retry(1000);
+            delays.push(reset.ready_at - 1000);
+        }
+        assert_eq!(delays, [250, 500, 1000, 2000, 4000, 4000, 4000]);
+        assert_eq!(reset.failures(), 7);
+        assert_eq!(reset.step, Step::HoldArduChip);
+    }
+
+    #[test]
+    fn retries_wait_before_resetting_again() {
+        let chip = FakeArduChip::new(&[]);
+        let mut arduchip = ArduChip::new(chip.spi(), chip.cs());
+        let mut sensor = Ov2640::new(FakeOv2640::new());
+        let configure = |_: &mut Ov2640<FakeOv2640>| Ok(());
+
+        let mut reset = Reset::new().retry(500);
+        arduchip.write_reg(ARDUCHIP_TEST1, 0x12).unwrap();
+        assert_eq!(
+            reset.poll(&mut arduchip, &mut sensor, 700, configure),
+            Ok(false)
+        );
+        assert_eq!(chip.register(ARDUCHIP_TEST1), 0x12);
+        assert_eq!(
+            reset.poll(&mut arduchip, &mut sensor, 750, configure),
+            Ok(false)
+        );
+        // The CPLD reset cleared the test register
+        assert_eq!(chip.register(ARDUCHIP_TEST1), 0x00);
+    }
+}
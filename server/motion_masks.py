@@ -0,0 +1,60 @@
+"""Writes the masks of get_motion of server.py for the frame pairs of a
+directory, NN-previous.pgm and NN-current.pgm giving NN-mask.pgm, which the
+motion detector of the camera is tested against.
+
+    python motion_masks.py ../camera/testdata/motion
+
+With --record, a pair NN is first recorded from the camera at URL, two
+grayscale frames a second apart, something moving in front of it.
+
+    python motion_masks.py --record http://192.168.122.100 ../camera/testdata/motion 04
+"""
+import ast
+import pathlib
+import sys
+import time
+
+import cv2
+import numpy as np
+import requests
+import scipy.signal
+
+
+def load_get_motion():
+    # server.py runs its client when imported, only get_motion is taken
+    path = pathlib.Path(__file__).with_name("server.py")
+    tree = ast.parse(path.read_text())
+    function = next(
+        node for node in tree.body
+        if isinstance(node, ast.FunctionDef) and node.name == "get_motion"
+    )
+    namespace = {"cv2": cv2, "np": np, "scipy": scipy}
+    exec(compile(ast.Module([function], []), path, "exec"), namespace)
+    return namespace["get_motion"]
+
+
+def record(url, directory, pair):
+    # The PGM frames of the camera are its luma, which the detector compares
+    for name in ("previous", "current"):
+        response = requests.get(f"{url}/frame.pgm?frame=next&color=gray", timeout=10)
+        response.raise_for_status()
+        (directory / f"{pair}-{name}.pgm").write_bytes(response.content)
+        time.sleep(1)
+
+
+get_motion = load_get_motion()
+
+if sys.argv[1] == "--record":
+    url, directory, pair = sys.argv[2:5]
+    directory = pathlib.Path(directory)
+    record(url, directory, pair)
+else:
+    directory = pathlib.Path(sys.argv[1])
+
+for previous_path in sorted(directory.glob("*-previous.pgm")):
+    pair = previous_path.name.removesuffix("-previous.pgm")
+    previous = cv2.imread(str(previous_path), cv2.IMREAD_GRAYSCALE)
+    current = cv2.imread(str(previous_path.with_name(f"{pair}-current.pgm")), cv2.IMREAD_GRAYSCALE)
+    mask = get_motion(previous, current).astype(np.uint8)
+    cv2.imwrite(str(previous_path.with_name(f"{pair}-mask.pgm")), mask)
+    print(f"{pair}: {np.count_nonzero(mask)} moving pixels")
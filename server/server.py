@@ -49,7 +49,6 @@ class SeleniumImageGrabber(ImageGrabber):
         image = self.driver.find_element(By.CSS_SELECTOR, "img")
         png_bytes = image.screenshot_as_png
         image = cv2.imdecode(np.frombuffer(png_bytes, np.uint8), cv2.IMREAD_GRAYSCALE)
-        image = cv2.flip(image, -1)
         image = cv2.resize(image, (960, 720))
         return image
     def __del__(self):
@@ -70,18 +69,19 @@ class SocketImageGrabber(ImageGrabber):
         self.sock.connect((IP, PORT))
     @override
     def grab(self):
-        self.sock.send(b"Send me or consequences (plz)")
+        self.sock.send(f"GET /frame.bmp HTTP/1.1\r\nHost: {IP}\r\n\r\n".encode())
         res = b""
-        fully_received_header = False
-        while len(res) < 320 * 240 + 1078:
+        while b"\r\n\r\n" not in res:
             res += self.sock.recv(1024)
-            if not fully_received_header:
-                end_of_header = res.find(b"\n\n")
-                if end_of_header != -1:
-                    res = res[end_of_header + 2 :]
-                    fully_received_header = True
+        header, res = res.split(b"\r\n\r\n", 1)
+        content_length = 0
+        for line in header.split(b"\r\n")[1:]:
+            name, _, value = line.partition(b":")
+            if name.strip().lower() == b"content-length":
+                content_length = int(value)
+        while len(res) < content_length:
+            res += self.sock.recv(content_length - len(res))
         image = cv2.imdecode(np.frombuffer(res, np.uint8), cv2.IMREAD_GRAYSCALE)
-        image = cv2.flip(image, -1)
         image = cv2.resize(image, (960, 720))
         return image
 